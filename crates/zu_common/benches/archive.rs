@@ -0,0 +1,179 @@
+//! Pack and unpack throughput over archives laid out like the ones the client ships
+//!
+//! Run with `cargo bench -p zu_common`, the `rayon` feature compresses blocks in parallel.
+//! Run again with `--features mmap` to compare opening files by mapping them, which only loads
+//! the pages that are read instead of the whole archive.
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use std::env::temp_dir;
+use std::fs::remove_file;
+use zu_common::archive::prelude::*;
+
+/// Contents that compress about as well as zone geometry, a repeating pattern with some noise
+fn geometry(len: usize, seed: u32) -> Vec<u8> {
+    (0..len as u32)
+        .map(|i| ((i % 97) ^ (i.wrapping_mul(seed) >> 13)) as u8)
+        .collect()
+}
+
+/// Contents that barely compress, like texture data
+fn texture(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(2654435761) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// A zone archive: a few large geometry files and many small textures
+fn zone_files() -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    for i in 0..4 {
+        files.push((format!("zone{}.wld", i), geometry(1 << 20, i + 3)));
+    }
+    for i in 0..96 {
+        files.push((format!("texture{}.bmp", i), texture(48 * 1024, i + 1)));
+    }
+    files
+}
+
+fn total_len(files: &[(String, Vec<u8>)]) -> u64 {
+    files.iter().map(|(_, data)| data.len() as u64).sum()
+}
+
+fn writable(files: &[(String, Vec<u8>)]) -> WritableArchive {
+    let mut archive = WritableArchive::new();
+    for (name, data) in files {
+        archive.set(name, data).unwrap();
+    }
+    archive
+}
+
+fn save(c: &mut Criterion) {
+    let files = zone_files();
+    let writable = writable(&files);
+    let mut readwrite = ReadWriteArchive::new();
+    readwrite
+        .open_from_bytes(writable.save_to_bytes().unwrap())
+        .unwrap();
+
+    let mut group = c.benchmark_group("save_to_bytes");
+    group.throughput(Throughput::Bytes(total_len(&files)));
+    group.sample_size(10);
+    group.bench_function("writable", |b| b.iter(|| writable.save_to_bytes().unwrap()));
+    group.bench_function("readwrite", |b| {
+        b.iter(|| readwrite.save_to_bytes().unwrap())
+    });
+    group.finish();
+}
+
+fn open(c: &mut Criterion) {
+    let files = zone_files();
+    let data = writable(&files).save_to_bytes().unwrap();
+
+    let mut group = c.benchmark_group("open_from_bytes");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("readable", |b| {
+        b.iter(|| {
+            let mut archive = ReadableArchive::new();
+            archive.open_from_bytes(black_box(&data)).unwrap();
+            archive
+        })
+    });
+    group.bench_function("readwrite", |b| {
+        b.iter(|| {
+            let mut archive = ReadWriteArchive::new();
+            archive.open_from_bytes(black_box(&data)).unwrap();
+            archive
+        })
+    });
+    group.finish();
+
+    let mut archive = ReadableArchive::new();
+    archive.open_from_bytes(&data).unwrap();
+    let mut group = c.benchmark_group("unpack");
+    group.throughput(Throughput::Bytes(total_len(&files)));
+    group.sample_size(10);
+    group.bench_function("readable", |b| {
+        let mut out = Vec::new();
+        b.iter(|| {
+            for (name, _) in &files {
+                out.clear();
+                archive.get_into(name, &mut out).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn open_file(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_file_get_one");
+    group.sample_size(10);
+    for megabytes in [16, 128] {
+        // stored so building a large archive is quick, only the icon is read back
+        let mut writable = WritableArchive::new();
+        for i in 0..megabytes {
+            writable
+                .set_stored(&format!("texture{}.dds", i), texture(1 << 20, i + 1))
+                .unwrap();
+        }
+        writable.set("icon.dds", texture(4096, 0)).unwrap();
+
+        let path = temp_dir().join(format!("zu_common_bench_{}mb.s3d", megabytes));
+        let path = path.to_str().unwrap();
+        writable.save_to_file(path).unwrap();
+        drop(writable);
+
+        group.bench_with_input(BenchmarkId::from_parameter(megabytes), path, |b, path| {
+            b.iter(|| {
+                let mut archive = ReadableArchive::new();
+                archive.open_file(path).unwrap();
+                archive.get("icon.dds").unwrap()
+            })
+        });
+        remove_file(path).unwrap();
+    }
+    group.finish();
+}
+
+fn blocks(c: &mut Criterion) {
+    let data = geometry(1 << 20, 7);
+    let blocks: Vec<&[u8]> = data.chunks(8192).collect();
+
+    // a fresh deflate stream per block against one stream and pooled buffers reused throughout
+    let mut group = c.benchmark_group("compress_blocks");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("fresh", |b| {
+        b.iter(|| {
+            for block in &blocks {
+                black_box(BlockCodec::Zlib.compress(block).unwrap());
+            }
+        })
+    });
+    group.bench_function("pooled", |b| {
+        let pool = BufferPool::new();
+        b.iter_batched_ref(
+            BlockCompressor::new,
+            |compressor| {
+                for block in &blocks {
+                    let mut out = pool.take();
+                    compressor
+                        .compress_into(BlockCodec::Zlib, block, 6, &mut out)
+                        .unwrap();
+                    pool.give(black_box(out));
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, save, open, open_file, blocks);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+//! Metadata describing the files in an archive
+
+/// Size information about a file in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveEntry {
+    /// Name of the file in the archive
+    pub name: String,
+    /// Total size of the file's compressed block data, not counting block headers
+    pub compressed_size: usize,
+    /// Size of the file once inflated
+    pub uncompressed_size: usize,
+    /// Number of blocks the file is split into
+    pub block_count: usize,
+}
+
+/// Totals over every file in an archive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveSummary {
+    /// Number of files in the archive
+    pub file_count: usize,
+    /// Total compressed size of every file
+    pub compressed_size: usize,
+    /// Total inflated size of every file
+    pub uncompressed_size: usize,
+    /// The largest files by inflated size, largest first
+    pub largest: Vec<ArchiveEntry>,
+}
+
+impl ArchiveSummary {
+    /// Summarize entries keeping the `top` largest
+    pub fn from_entries(mut entries: Vec<ArchiveEntry>, top: usize) -> Self {
+        let mut ret = ArchiveSummary {
+            file_count: entries.len(),
+            compressed_size: entries.iter().map(|e| e.compressed_size).sum(),
+            uncompressed_size: entries.iter().map(|e| e.uncompressed_size).sum(),
+            largest: Vec::new(),
+        };
+
+        // stable so files of the same size stay sorted by name
+        entries.sort_by_key(|e| std::cmp::Reverse(e.uncompressed_size));
+        entries.truncate(top);
+        ret.largest = entries;
+        ret
+    }
+
+    /// Compressed size as a fraction of the inflated size, 1.0 for an empty archive
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_size == 0 {
+            return 1.0;
+        }
+
+        self.compressed_size as f64 / self.uncompressed_size as f64
+    }
+}
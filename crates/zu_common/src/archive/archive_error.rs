@@ -1,5 +1,6 @@
 use nom::error::ErrorKind;
 use nom::error::ParseError;
+use nom::AsBytes;
 use std::str::Utf8Error;
 use thiserror::Error;
 
@@ -11,16 +12,27 @@ pub enum ArchiveError {
     #[error("I/O error")]
     Io(#[from] std::io::Error),
 
-    ///
-    ///
+    /// Wrong version
+    /// The archive header contained a version this library doesn't understand
     #[error("Wrong version found: {version:?}")]
     WrongVersion { version: u32 },
 
-    ///
-    ///
-    #[error("Parse Error")]
+    /// Parse error
+    /// The archive data was malformed and could not be parsed
+    #[error("Parse error: {0}")]
     Parse(String),
 
+    /// Not a PFS archive
+    /// The data doesn't start with a PFS header so it's some other kind of file
+    #[error("Not a PFS archive, the \"PFS \" magic wasn't found at offset 4")]
+    NotPfs,
+
+    /// Parse error at an offset
+    /// The data didn't match what the parser expected at this byte offset from the start of
+    /// the buffer that was being parsed
+    #[error("Parse error of type {kind} at offset {offset:#x}")]
+    ParseAt { kind: String, offset: usize },
+
     /// Compression failed
     /// Zlib compression encountered and error
     #[error("Compression failed")]
@@ -46,6 +58,49 @@ pub enum ArchiveError {
     #[error("Destination file already exists in archive")]
     DestFileAlreadyExists,
 
+    /// Archive too large
+    /// The archive layout doesn't fit in the 32-bit offsets of the standard format
+    #[error("Archive too large for the standard format")]
+    TooLarge,
+
+    /// Limit exceeded
+    /// An operation ran past the limit it was given
+    #[error("Limit exceeded")]
+    LimitExceeded,
+
+    /// Invalid block size
+    /// Files are split into blocks of at least one byte whose length fits in 32 bits
+    #[error("Invalid block size: {size}")]
+    InvalidBlockSize { size: usize },
+
+    /// Overlapping files
+    /// The data of these files overlaps the data of another file in the archive
+    #[error("Overlapping file data: {}", .0.join(", "))]
+    OverlappingFiles(Vec<String>),
+
+    /// Files outside the data
+    /// The data of these files doesn't start between the header and the directory or runs into
+    /// the directory
+    #[error("File data outside of the data region: {}", .0.join(", "))]
+    FilesOutsideData(Vec<String>),
+
+    /// Invalid name
+    /// Names are written to the filename table as NUL terminated strings so they can't hold a
+    /// NUL or any other control character
+    #[error("Invalid name: {0:?}")]
+    InvalidName(String),
+
+    /// Reserved name
+    /// The name has the CRC the filename table or the compression table is stored under, so
+    /// readers would mistake the file for that table
+    #[error("Reserved name: {0:?} has the CRC of an archive table")]
+    ReservedName(String),
+
+    /// Unsafe path
+    /// A file name in the archive would be extracted outside of the directory it's extracted to
+    #[error("Unsafe path: {0}")]
+    UnsafePath(String),
+
     /// Bad Regular Expression
     /// Regular expression was malformed
     #[error("Bad Regular Expression")]
@@ -62,9 +117,61 @@ pub enum ArchiveError {
     Unknown,
 }
 
-impl<I> ParseError<I> for ArchiveError {
-    fn from_error_kind(_: I, kind: ErrorKind) -> Self {
-        ArchiveError::Parse(format!("Parse error of type: {:?}", kind))
+impl ArchiveError {
+    /// Convert the error of a nom parser run over `input`
+    /// Parse failures are located relative to the start of `input`.
+    pub(crate) fn from_nom(e: nom::Err<ArchiveError>, input: &[u8]) -> ArchiveError {
+        match e {
+            nom::Err::Error(e) => e.locate(input),
+            _ => ArchiveError::Unknown,
+        }
+    }
+
+    /// Count `len` bytes cut off the end of the input a parser failed on
+    /// A parse error only knows how much input was left until `from_nom` locates it, so a
+    /// parser run over a slice that ends early has to add back what was cut off.
+    pub(crate) fn trailing(e: nom::Err<ArchiveError>, len: usize) -> nom::Err<ArchiveError> {
+        e.map(|e| match e {
+            ArchiveError::ParseAt { kind, offset } => ArchiveError::ParseAt {
+                kind,
+                offset: offset + len,
+            },
+            e => e,
+        })
+    }
+
+    /// Move the offset of a parse error by `start` bytes
+    /// Used when the buffer that was parsed began part way into the original data.
+    pub(crate) fn offset_by(self, start: usize) -> ArchiveError {
+        match self {
+            ArchiveError::ParseAt { kind, offset } => ArchiveError::ParseAt {
+                kind,
+                offset: offset + start,
+            },
+            e => e,
+        }
+    }
+
+    /// Turn the remaining length held in a parse error into an offset from the start of input
+    fn locate(self, input: &[u8]) -> ArchiveError {
+        match self {
+            ArchiveError::ParseAt { kind, offset } => match input.len().checked_sub(offset) {
+                Some(offset) => ArchiveError::ParseAt { kind, offset },
+                None => ArchiveError::Parse(format!("{} at an unknown offset", kind)),
+            },
+            e => e,
+        }
+    }
+}
+
+impl<I: AsBytes> ParseError<I> for ArchiveError {
+    // only the length of input left at the failure is known here so that's what the offset
+    // holds until `from_nom` turns it into an offset from the start of the buffer parsed
+    fn from_error_kind(input: I, kind: ErrorKind) -> Self {
+        ArchiveError::ParseAt {
+            kind: format!("{:?}", kind),
+            offset: input.as_bytes().len(),
+        }
     }
 
     fn append(_: I, _: ErrorKind, other: Self) -> Self {
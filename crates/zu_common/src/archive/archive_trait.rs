@@ -2,7 +2,13 @@
 //!
 //! Currently used by the various PFS archives to implement a common interface
 
+use super::archive_entry::ArchiveEntry;
 use super::archive_error::ArchiveError;
+use super::atomic::write_atomic;
+use super::pfs::NameEncoding;
+use super::searcher::SearchOptions;
+use regex::Regex;
+use std::io::Read;
 
 /// All archives implement this
 /// Indicates an archive that can be created and closed
@@ -11,6 +17,12 @@ pub trait IArchive {
     fn new() -> Self;
     /// Put the archive into an empty state
     fn close(&mut self);
+    /// Number of files in the archive
+    fn len(&self) -> usize;
+    /// Whether the archive holds no files
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 /// Provides read access to an archive
@@ -21,26 +33,123 @@ pub trait IReadableArchive {
         T: AsRef<[u8]>;
     /// Open an archive by parsing it from a file on the file system
     fn open_file(&mut self, filename: &str) -> Result<(), ArchiveError>;
+    /// Open an archive by reading all of a stream and parsing it
+    fn open_from_reader<R>(&mut self, reader: R) -> Result<(), ArchiveError>
+    where
+        R: Read;
     /// Extract a file from the archive into a Vec<u8>
     fn get(&self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError>;
+    /// Extract a file from the archive into an existing Vec<u8>, replacing its contents
+    fn get_into(&self, in_archive_path: &str, out: &mut Vec<u8>) -> Result<(), ArchiveError>;
     /// Check to see if a file exists in the archive
     fn exists(&self, in_archive_path: &str) -> Result<bool, ArchiveError>;
+    /// Get how the name of a file is encoded in the filename table
+    /// Archives that only hold UTF-8 names can leave this as is.
+    fn name_encoding(&self, in_archive_path: &str) -> Result<NameEncoding, ArchiveError> {
+        match self.exists(in_archive_path)? {
+            true => Ok(NameEncoding::Utf8),
+            false => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
     /// Search for files in the archive by passing a regex string
+    /// Matching names are returned sorted.
     fn search(&self, search_regex: &str) -> Result<Vec<String>, ArchiveError>;
+    /// Search for files in the archive with an already compiled regex
+    /// Matching names are returned sorted.
+    fn search_compiled(&self, regex: &Regex) -> Vec<String>;
+    /// Search for files in the archive by passing a regex string with options
+    /// Matching names are returned sorted.
+    fn search_opts(
+        &self,
+        search_regex: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<String>, ArchiveError> {
+        Ok(self.search_compiled(&options.compile(search_regex)?))
+    }
+    /// Get the size information of every file in the archive sorted by name
+    fn entries(&self) -> Vec<ArchiveEntry>;
 }
 
 /// Provides write access to an archive
 pub trait IWritableArchive {
     /// Save the contents of an archive to a block of bytes
     fn save_to_bytes(&self) -> Result<Vec<u8>, ArchiveError>;
+    /// Save the contents of an archive to a block of bytes
+    /// Progress is called with the number of files written so far and the total. By default the
+    /// archive is saved with `save_to_bytes` and progress is never called.
+    fn save_to_bytes_with_progress<F>(&self, _progress: F) -> Result<Vec<u8>, ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
+        self.save_to_bytes()
+    }
     /// Save the contents of an archive to a file on the file system
-    fn save_to_file(&self, filename: &str) -> Result<(), ArchiveError>;
+    fn save_to_file(&self, filename: &str) -> Result<(), ArchiveError> {
+        self.save_to_file_with_progress(filename, |_, _| {})
+    }
+    /// Save the contents of an archive to a file on the file system
+    /// Progress is called with the number of files written so far and the total. The archive is
+    /// written to a temporary file that replaces the file once complete, so an existing archive
+    /// is left as it was if saving fails.
+    fn save_to_file_with_progress<F>(&self, filename: &str, progress: F) -> Result<(), ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
+        write_atomic(filename, &self.save_to_bytes_with_progress(progress)?)?;
+        Ok(())
+    }
     /// Sets a file in the archive to a specific block of bytes
+    /// Any existing file with the same name is replaced.
     fn set<T>(&mut self, in_archive_path: &str, input: T) -> Result<(), ArchiveError>
     where
         T: AsRef<[u8]>;
+    /// Sets every file from pairs of names and contents in order
+    /// Stops at the first file that can't be set, such as one with an invalid name, leaving the
+    /// files before it set.
+    fn try_extend<I, S, D>(&mut self, iter: I) -> Result<(), ArchiveError>
+    where
+        I: IntoIterator<Item = (S, D)>,
+        S: AsRef<str>,
+        D: AsRef<[u8]>,
+    {
+        for (name, data) in iter {
+            self.set(name.as_ref(), data)?;
+        }
+        Ok(())
+    }
+    /// Sets a file in the archive to a specific block of bytes
+    /// Returns the decompressed contents of the file it replaced, if there was one. By default
+    /// the old contents are read with `get` before the file is set.
+    fn replace<T>(
+        &mut self,
+        in_archive_path: &str,
+        input: T,
+    ) -> Result<Option<Vec<u8>>, ArchiveError>
+    where
+        T: AsRef<[u8]>,
+        Self: IReadableArchive,
+    {
+        let previous = match self.exists(in_archive_path)? {
+            true => Some(self.get(in_archive_path)?),
+            false => None,
+        };
+
+        self.set(in_archive_path, input)?;
+        Ok(previous)
+    }
     /// Removes a file in the archive
     fn remove(&mut self, in_archive_path: &str) -> Result<(), ArchiveError>;
+    /// Removes a file in the archive, returning its decompressed contents
+    /// By default the contents are read with `get` first so a file that can't be read is left
+    /// in place.
+    fn take(&mut self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError>
+    where
+        Self: IReadableArchive,
+    {
+        let data = self.get(in_archive_path)?;
+        self.remove(in_archive_path)?;
+        Ok(data)
+    }
     /// Renames a file in the archive
     fn rename(
         &mut self,
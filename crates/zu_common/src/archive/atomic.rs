@@ -0,0 +1,126 @@
+//! Writing files so a failure never leaves a partly written file behind
+
+use log::warn;
+use std::fs::{canonicalize, copy, remove_file, rename, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Write data to a file by writing a temporary file next to it and renaming it over the file
+/// Until the rename the original file is untouched, so a crash or a full disk while writing
+/// leaves it as it was. Symlinks are followed so the link is kept and the temporary file is on
+/// the same file system as the file it replaces. If the rename still fails the complete
+/// temporary file is copied over the original instead.
+pub(crate) fn write_atomic(filename: &str, data: &[u8]) -> io::Result<()> {
+    write_atomic_with(filename, |mut file| {
+        file.write_all(data)?;
+        Ok((file, ()))
+    })
+}
+
+/// Write a file the same way as `write_atomic` with the contents written by a function
+/// The function is given the temporary file and hands it back once it's done writing.
+pub(crate) fn write_atomic_with<F, T, E>(filename: &str, write: F) -> Result<T, E>
+where
+    F: FnOnce(File) -> Result<(File, T), E>,
+    E: From<io::Error>,
+{
+    let target = canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let mut tmp = target.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let ret = match write_synced(&tmp, write) {
+        Ok(ret) => ret,
+        Err(e) => {
+            let _ = remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = rename(&tmp, &target) {
+        warn!(
+            "Unable to rename {} over {}, copying it instead: {}",
+            tmp.display(),
+            target.display(),
+            e
+        );
+        // the temporary file is kept if the copy fails since it's the only complete copy left
+        copy(&tmp, &target)?;
+        remove_file(&tmp)?;
+    }
+
+    Ok(ret)
+}
+
+fn write_synced<F, T, E>(filename: &Path, write: F) -> Result<T, E>
+where
+    F: FnOnce(File) -> Result<(File, T), E>,
+    E: From<io::Error>,
+{
+    let (file, ret) = write(File::create(filename)?)?;
+    file.sync_all()?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::prelude::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir, create_dir_all, read, remove_dir_all};
+
+    #[test]
+    fn failed_save_test() {
+        let root = temp_dir().join("zu_common_failed_save_test");
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        let path = root.join("test.s3d");
+        let filename = path.to_str().unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"original").unwrap();
+        writable.save_to_file(filename).unwrap();
+        let original = read(&path).unwrap();
+        assert!(!root.join("test.s3d.tmp").exists());
+
+        // the temporary file can't be written when a directory is in the way
+        create_dir(root.join("test.s3d.tmp")).unwrap();
+        writable.set("test.txt", b"changed").unwrap();
+        assert!(matches!(
+            writable.save_to_file(filename),
+            Err(ArchiveError::Io(_))
+        ));
+        assert_eq!(read(&path).unwrap(), original);
+
+        let mut archive = open_edit(filename).unwrap();
+        archive.set("test.txt", b"changed").unwrap();
+        assert!(archive.save_to_file(filename).is_err());
+        assert_eq!(read(&path).unwrap(), original);
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_through_symlink_test() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir().join("zu_common_save_through_symlink_test");
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        let target = root.join("target.s3d");
+        let link = root.join("link.s3d");
+
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"original").unwrap();
+        writable.save_to_file(target.to_str().unwrap()).unwrap();
+        symlink(&target, &link).unwrap();
+
+        writable.set("test.txt", b"changed").unwrap();
+        writable.save_to_file(link.to_str().unwrap()).unwrap();
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        let archive = open_read(target.to_str().unwrap()).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"changed");
+
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Comparing the contents of two archives
+
+use super::archive_error::ArchiveError;
+use super::archive_trait::IReadableArchive;
+use std::collections::HashMap;
+
+/// The differences between two archives
+/// Names are matched ignoring case and every list is sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveDiff {
+    /// Files only in the first archive
+    pub only_in_a: Vec<String>,
+    /// Files only in the second archive
+    pub only_in_b: Vec<String>,
+    /// Files in both archives whose contents differ, named as in the first archive
+    pub changed: Vec<String>,
+}
+
+impl ArchiveDiff {
+    /// Whether the archives hold the same files with the same contents
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare two archives by name and inflated contents
+pub fn diff<A, B>(a: &A, b: &B) -> Result<ArchiveDiff, ArchiveError>
+where
+    A: IReadableArchive,
+    B: IReadableArchive,
+{
+    let mut b_names: HashMap<String, String> = b
+        .search(".*")?
+        .into_iter()
+        .map(|name| (name.to_lowercase(), name))
+        .collect();
+
+    let mut ret = ArchiveDiff::default();
+    let mut a_data = Vec::new();
+    let mut b_data = Vec::new();
+    for name in a.search(".*")? {
+        match b_names.remove(&name.to_lowercase()) {
+            Some(b_name) => {
+                a.get_into(&name, &mut a_data)?;
+                b.get_into(&b_name, &mut b_data)?;
+                if a_data != b_data {
+                    ret.changed.push(name);
+                }
+            }
+            None => ret.only_in_a.push(name),
+        }
+    }
+
+    ret.only_in_b = b_names.into_values().collect();
+    ret.only_in_b.sort();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::prelude::*;
+
+    #[test]
+    fn diff_test() {
+        let mut a = WritableArchive::new();
+        a.set("same.txt", b"same").unwrap();
+        a.set("changed.wld", b"before").unwrap();
+        a.set("removed.bmp", b"gone").unwrap();
+        a.set("Case.txt", b"case").unwrap();
+
+        let mut b = ReadWriteArchive::new();
+        b.open_from_bytes(a.save_to_bytes().unwrap()).unwrap();
+        assert!(diff(&b, &b).unwrap().is_empty());
+
+        b.set("changed.wld", b"after").unwrap();
+        b.remove("removed.bmp").unwrap();
+        b.set("zadded.txt", b"new").unwrap();
+        b.set("added.txt", b"new").unwrap();
+        b.rename("Case.txt", "CASE.TXT").unwrap();
+
+        let mut readable = ReadableArchive::new();
+        readable
+            .open_from_bytes(a.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(
+            diff(&readable, &b).unwrap(),
+            ArchiveDiff {
+                only_in_a: vec!["removed.bmp".to_string()],
+                only_in_b: vec!["added.txt".to_string(), "zadded.txt".to_string()],
+                changed: vec!["changed.wld".to_string()],
+            }
+        );
+    }
+}
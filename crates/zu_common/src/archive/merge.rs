@@ -0,0 +1,59 @@
+//! Results of merging one archive into another
+
+use super::archive_error::ArchiveError;
+use super::archive_trait::{IReadableArchive, IWritableArchive};
+use super::pfs::NameEncoding;
+
+/// How many files a merge copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// Files that weren't in the destination archive
+    pub added: usize,
+    /// Files that replaced one with the same name in the destination archive
+    pub replaced: usize,
+    /// Files left out because the destination archive already had one with the same name
+    pub skipped: usize,
+}
+
+/// An archive files can be merged into
+pub(crate) trait MergeTarget: IWritableArchive {
+    /// Whether the archive has a file by a name, ignoring case
+    fn contains(&self, in_archive_path: &str) -> bool;
+    /// Set how the name of a file is encoded in the filename table
+    fn set_name_encoding(
+        &mut self,
+        in_archive_path: &str,
+        encoding: NameEncoding,
+    ) -> Result<(), ArchiveError>;
+}
+
+/// Copy every file from another archive into one through `set`
+/// Files with the same name are replaced when overwrite is set and skipped otherwise. Names keep
+/// the encoding they had in the other archive so their CRCs don't change.
+pub(crate) fn merge_into<W, A>(
+    dest: &mut W,
+    other: &A,
+    overwrite: bool,
+) -> Result<MergeSummary, ArchiveError>
+where
+    W: MergeTarget,
+    A: IReadableArchive,
+{
+    let mut summary = MergeSummary::default();
+    for name in other.search(".*")? {
+        if dest.contains(&name) {
+            if !overwrite {
+                summary.skipped += 1;
+                continue;
+            }
+            summary.replaced += 1;
+        } else {
+            summary.added += 1;
+        }
+
+        dest.set(&name, other.get(&name)?)?;
+        dest.set_name_encoding(&name, other.name_encoding(&name)?)?;
+    }
+
+    Ok(summary)
+}
@@ -1,4 +1,12 @@
+pub mod archive_entry;
 pub mod archive_error;
 pub mod archive_trait;
+mod atomic;
+pub mod diff;
+pub mod merge;
+pub mod open;
+pub mod path;
 pub mod pfs;
 pub mod prelude;
+pub mod searcher;
+pub mod transcode;
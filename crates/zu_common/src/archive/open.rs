@@ -0,0 +1,166 @@
+//! Convenience functions for opening archives on the file system
+//!
+//! Picking an archive type:
+//! * `ReadableArchive` keeps the raw archive bytes and only inflates files when asked for them.
+//!   It's the cheapest way to read an archive and what `open_read` returns.
+//! * `ReadWriteArchive` keeps every file's compressed blocks so the archive can be modified and
+//!   saved again. It costs more memory and time to open and is what `open_edit` returns.
+//! * `WritableArchive` can't read existing archives at all; use it to build a new archive from
+//!   scratch.
+//! * `StreamPacker` also builds a new archive but writes each file as it's added, so only the
+//!   directory is kept in memory. `pack_file` uses one to write an archive on the file system.
+
+use super::archive_error::ArchiveError;
+use super::archive_trait::{IArchive, IReadableArchive, IWritableArchive};
+use super::atomic::write_atomic_with;
+use super::pfs::readable::ReadableArchive;
+use super::pfs::readwrite::ReadWriteArchive;
+use super::pfs::stream::StreamPacker;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Open an archive on the file system for reading only
+pub fn open_read(filename: &str) -> Result<ReadableArchive, ArchiveError> {
+    let mut archive = ReadableArchive::new();
+    archive.open_file(filename)?;
+    Ok(archive)
+}
+
+/// Open an archive on the file system so it can be modified and saved
+pub fn open_edit(filename: &str) -> Result<ReadWriteArchive, ArchiveError> {
+    let mut archive = ReadWriteArchive::new();
+    archive.open_file(filename)?;
+    Ok(archive)
+}
+
+/// Open an archive on the file system, change it and save it back
+/// Nothing is saved if the change fails. The archive is written next to the original and
+/// renamed over it once complete so a failed save never leaves a truncated archive behind.
+pub fn edit_file<F, T>(filename: &str, f: F) -> Result<T, ArchiveError>
+where
+    F: FnOnce(&mut ReadWriteArchive) -> Result<T, ArchiveError>,
+{
+    let mut archive = open_edit(filename)?;
+    let ret = f(&mut archive)?;
+    archive.save_to_file(filename)?;
+    Ok(ret)
+}
+
+/// Pack a new archive on the file system, adding files to it with a function
+/// Files are compressed and written as they're added so packing needs little memory however
+/// large the files are. The archive only replaces an existing file once it's complete and
+/// nothing is written if the function fails.
+pub fn pack_file<F, T>(filename: &str, f: F) -> Result<T, ArchiveError>
+where
+    F: FnOnce(&mut StreamPacker<BufWriter<File>>) -> Result<T, ArchiveError>,
+{
+    write_atomic_with(filename, |file| {
+        let mut packer = StreamPacker::new(BufWriter::new(file))?;
+        let ret = f(&mut packer)?;
+        let file = packer
+            .finish()?
+            .into_inner()
+            .map_err(|e| ArchiveError::Io(e.into_error()))?;
+        Ok((file, ret))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::prelude::*;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+    use std::io::Cursor;
+
+    #[test]
+    fn open_read_edit_test() {
+        let path = temp_dir().join("zu_common_open_read_edit_test.s3d");
+        let filename = path.to_str().unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"original").unwrap();
+        writable.save_to_file(filename).unwrap();
+
+        let archive = open_read(filename).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"original");
+
+        let mut archive = open_edit(filename).unwrap();
+        archive.set("test.txt", b"edited").unwrap();
+        archive.save_to_file(filename).unwrap();
+
+        let archive = open_read(filename).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"edited");
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn edit_file_test() {
+        let path = temp_dir().join("zu_common_edit_file_test.s3d");
+        let filename = path.to_str().unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set_footer_date(0);
+        writable.set("test.txt", b"original").unwrap();
+        writable.save_to_file(filename).unwrap();
+        let original = read(&path).unwrap();
+
+        let len = edit_file(filename, |archive| {
+            archive.set("new.txt", b"new")?;
+            archive.rename("test.txt", "renamed.txt")?;
+            Ok(archive.len())
+        })
+        .unwrap();
+        assert_eq!(len, 2);
+
+        let archive = open_read(filename).unwrap();
+        assert_eq!(
+            archive.search(".*").unwrap(),
+            vec!["new.txt", "renamed.txt"]
+        );
+        assert!(!temp_dir().join("zu_common_edit_file_test.s3d.tmp").exists());
+
+        // a failed change leaves the archive alone
+        writable.save_to_file(filename).unwrap();
+        let res = edit_file(filename, |archive| {
+            archive.remove("test.txt")?;
+            archive.remove("missing.txt")
+        });
+        assert!(matches!(res, Err(ArchiveError::SrcFileNotFound)));
+        assert_eq!(read(&path).unwrap(), original);
+
+        assert!(edit_file(filename, |archive| archive.remove("test.txt")).is_ok());
+        assert!(open_read(filename).unwrap().is_empty());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pack_file_test() {
+        let path = temp_dir().join("zu_common_pack_file_test.s3d");
+        let filename = path.to_str().unwrap();
+        let big: Vec<u8> = (0..100000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        let count = pack_file(filename, |packer| {
+            packer.add("big.wld", Cursor::new(&big))?;
+            packer.add("small.txt", &b"small"[..])?;
+            Ok(2)
+        })
+        .unwrap();
+        assert_eq!(count, 2);
+
+        let archive = open_read(filename).unwrap();
+        assert_eq!(archive.get("big.wld").unwrap(), big);
+        assert_eq!(archive.get("small.txt").unwrap(), b"small");
+        assert!(!temp_dir().join("zu_common_pack_file_test.s3d.tmp").exists());
+
+        // a failed pack leaves the existing archive alone
+        let original = read(&path).unwrap();
+        let res = pack_file(filename, |packer| {
+            packer.add("test.txt", &b"test"[..])?;
+            packer.add("TEST.txt", &b"test"[..])
+        });
+        assert!(matches!(res, Err(ArchiveError::DestFileAlreadyExists)));
+        assert_eq!(read(&path).unwrap(), original);
+        assert!(!temp_dir().join("zu_common_pack_file_test.s3d.tmp").exists());
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Mapping in archive names to paths on the file system
+//!
+//! Names in an archive are untrusted data. Legacy archives can use `\` as a separator and a
+//! crafted archive can try to write outside of the directory it's extracted to.
+
+use super::archive_error::ArchiveError;
+use std::ffi::OsStr;
+use std::fs::{create_dir, create_dir_all, symlink_metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Get the path a file in an archive should be extracted to underneath a root directory
+/// Both `/` and `\` are treated as separators. Names that would leave the root directory,
+/// such as ones with `..` components, are rejected.
+pub fn extract_path(root: &Path, name: &str) -> Result<PathBuf, ArchiveError> {
+    let mut path = root.to_path_buf();
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(ArchiveError::UnsafePath(name.to_string())),
+            _ => path.push(component),
+        }
+    }
+
+    if path.as_os_str().len() == root.as_os_str().len() {
+        return Err(ArchiveError::UnsafePath(name.to_string()));
+    }
+
+    Ok(path)
+}
+
+/// Get the path a file in an archive should be extracted to and create its parent directories
+/// Each directory is checked to really be inside the root directory before anything is created
+/// in it so symbolic links already in the root can't be used to write elsewhere.
+pub fn prepare_extract_path(root: &Path, name: &str) -> Result<PathBuf, ArchiveError> {
+    let path = extract_path(root, name)?;
+    let base = if root.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        root
+    };
+    create_dir_all(base)?;
+    let real_root = base.canonicalize()?;
+
+    // the path is the root followed by one or more normal components
+    let components: Vec<&OsStr> = path
+        .strip_prefix(root)
+        .map_err(|_| ArchiveError::UnsafePath(name.to_string()))?
+        .iter()
+        .collect();
+    let (file_name, dirs) = components
+        .split_last()
+        .ok_or_else(|| ArchiveError::UnsafePath(name.to_string()))?;
+
+    let mut real_dir = real_root.clone();
+    for dir in dirs {
+        let next = real_dir.join(dir);
+        match create_dir(&next) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        real_dir = contained(&real_root, &next, name)?;
+    }
+
+    // an existing file could itself be a link to somewhere else, one to nowhere would be
+    // followed when written
+    let target = real_dir.join(file_name);
+    if let Ok(metadata) = symlink_metadata(&target) {
+        if metadata.file_type().is_symlink() {
+            contained(&real_root, &target, name)
+                .map_err(|_| ArchiveError::UnsafePath(name.to_string()))?;
+        }
+    }
+
+    Ok(path)
+}
+
+/// Resolve a path that exists and check it's inside the real root directory
+fn contained(real_root: &Path, path: &Path, name: &str) -> Result<PathBuf, ArchiveError> {
+    let real_path = path.canonicalize()?;
+    if !real_path.starts_with(real_root) {
+        return Err(ArchiveError::UnsafePath(name.to_string()));
+    }
+
+    Ok(real_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::path::{extract_path, prepare_extract_path};
+    use crate::archive::prelude::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::Path;
+
+    #[test]
+    fn extract_path_test() {
+        let root = Path::new("out");
+        assert_eq!(
+            extract_path(root, "textures/rock.bmp").unwrap(),
+            root.join("textures").join("rock.bmp")
+        );
+        assert_eq!(
+            extract_path(root, "textures\\rock.bmp").unwrap(),
+            root.join("textures").join("rock.bmp")
+        );
+        assert_eq!(
+            extract_path(root, "/./rock.bmp").unwrap(),
+            root.join("rock.bmp")
+        );
+
+        for name in [
+            "../rock.bmp",
+            "textures/../../rock.bmp",
+            "..\\rock.bmp",
+            "",
+            "/",
+        ] {
+            assert!(matches!(
+                extract_path(root, name),
+                Err(ArchiveError::UnsafePath(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn escape_test() {
+        let root = temp_dir().join("zu_common_escape_test");
+        let output = root.join("output");
+        let _ = remove_dir_all(&root);
+        create_dir_all(&output).unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set("../escape.txt", b"escape").unwrap();
+        writable.set("inside.txt", b"inside").unwrap();
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            archive.extract_preserving_path("../escape.txt", &output),
+            Err(ArchiveError::UnsafePath(_))
+        ));
+        assert!(!root.join("escape.txt").exists());
+        archive
+            .extract_preserving_path("inside.txt", &output)
+            .unwrap();
+        assert!(output.join("inside.txt").exists());
+
+        // a link inside the output directory that points outside of it
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&root, output.join("link")).unwrap();
+            assert!(matches!(
+                prepare_extract_path(&output, "link/escape.txt"),
+                Err(ArchiveError::UnsafePath(_))
+            ));
+
+            // nothing is created through the link before it's rejected
+            assert!(matches!(
+                prepare_extract_path(&output, "link/made/escape.txt"),
+                Err(ArchiveError::UnsafePath(_))
+            ));
+            assert!(!root.join("made").exists());
+
+            // nor through a file that links outside
+            std::os::unix::fs::symlink(root.join("escape.txt"), output.join("file.txt")).unwrap();
+            assert!(matches!(
+                prepare_extract_path(&output, "file.txt"),
+                Err(ArchiveError::UnsafePath(_))
+            ));
+        }
+
+        let nested = prepare_extract_path(&output, "a/b/c.txt").unwrap();
+        assert_eq!(nested, output.join("a").join("b").join("c.txt"));
+        assert!(output.join("a").join("b").is_dir());
+
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+//! Storage for the bytes of an opened archive
+
+use crate::archive::archive_error::ArchiveError;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::ops::Deref;
+
+/// The bytes of an archive, owned, borrowed for the life of the program or mapped from a file
+/// with the `mmap` feature
+pub enum Backing {
+    Owned(Vec<u8>),
+    Static(&'static [u8]),
+    #[cfg(feature = "mmap")]
+    Mapped(Mmap),
+}
+
+impl Backing {
+    /// Load the bytes of a file
+    /// With the `mmap` feature the file is mapped so only the pages that are read are loaded.
+    #[cfg(not(feature = "mmap"))]
+    pub fn open(filename: &str) -> Result<Backing, ArchiveError> {
+        Ok(Backing::Owned(std::fs::read(filename)?))
+    }
+
+    /// Load the bytes of a file
+    /// With the `mmap` feature the file is mapped so only the pages that are read are loaded.
+    #[cfg(feature = "mmap")]
+    pub fn open(filename: &str) -> Result<Backing, ArchiveError> {
+        let file = std::fs::File::open(filename)?;
+        // SAFETY: the archive is treated as read only, the same as the client does; if another
+        // process truncates the file while it's mapped reads can fault, which is the accepted
+        // tradeoff of mapping files
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Backing::Mapped(map))
+    }
+}
+
+impl Default for Backing {
+    fn default() -> Self {
+        Backing::Owned(Vec::new())
+    }
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Backing::Owned(data) => data,
+            Backing::Static(data) => data,
+            #[cfg(feature = "mmap")]
+            Backing::Mapped(map) => map,
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Block compression
+//!
+//! Every file in a PFS archive is split into blocks that are compressed independently.
+//! Archives written by the client use zlib streams but some third party tools wrote raw
+//! deflate streams without the zlib wrapper so both are supported. Data that doesn't compress
+//! can also be stored as is; since a small deflate stream can happen to be exactly as long as its
+//! input, blocks with equal lengths are only treated as stored when they don't inflate.
+
+use crate::archive::archive_error::ArchiveError;
+use flate2::{
+    read::{DeflateDecoder, ZlibDecoder},
+    Compress, Compression, FlushCompress, Status,
+};
+use log::{debug, trace};
+use std::io::Read;
+use std::sync::Mutex;
+
+/// The compression level used unless another is set, the same as zlib's default
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// The compression used for the data of a single block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BlockCodec {
+    /// A zlib stream, what the client writes
+    #[default]
+    Zlib,
+    /// A raw deflate stream with no zlib header or checksum
+    RawDeflate,
+    /// The data is stored uncompressed
+    Stored,
+}
+
+/// How the blocks of a file were compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileCompression {
+    pub codec: BlockCodec,
+    /// Compression level from 0 to 9, always 0 for stored files
+    pub level: u32,
+}
+
+impl FileCompression {
+    /// Whether this is how files are compressed unless told otherwise
+    pub fn is_default(&self) -> bool {
+        self.codec == BlockCodec::default() && self.level == DEFAULT_COMPRESSION_LEVEL
+    }
+}
+
+impl BlockCodec {
+    /// Detect the codec of block data by checking for a valid zlib header
+    pub fn detect(data: &[u8]) -> BlockCodec {
+        if data.len() >= 2 {
+            let cmf = data[0] as u16;
+            let flg = data[1] as u16;
+            if cmf & 0x0F == 8 && cmf >> 4 <= 7 && (cmf << 8 | flg).is_multiple_of(31) {
+                return BlockCodec::Zlib;
+            }
+        }
+
+        BlockCodec::RawDeflate
+    }
+
+    /// Compress the data of a single block at the default level
+    pub fn compress(self, input: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+        self.compress_with_level(input, DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Compress the data of a single block at a level from 0 (none) to 9 (best)
+    /// Use a `BlockCompressor` when compressing many blocks.
+    pub fn compress_with_level(self, input: &[u8], level: u32) -> Result<Vec<u8>, ArchiveError> {
+        let mut compressed = Vec::new();
+        BlockCompressor::new().compress_into(self, input, level, &mut compressed)?;
+        Ok(compressed)
+    }
+
+    /// Decompress the data of a single block appending it to out
+    /// Fails if the block doesn't inflate to exactly inflate_length bytes. At most one byte more
+    /// than that is inflated so a block can't blow up past the length it claims.
+    pub fn decompress(
+        self,
+        input: &[u8],
+        inflate_length: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), ArchiveError> {
+        let limit = inflate_length as u64 + 1;
+        let sz = match self {
+            BlockCodec::Zlib => ZlibDecoder::new(input).take(limit).read_to_end(out),
+            BlockCodec::RawDeflate => DeflateDecoder::new(input).take(limit).read_to_end(out),
+            BlockCodec::Stored => {
+                out.extend_from_slice(input);
+                Ok(input.len())
+            }
+        }
+        .map_err(|_| ArchiveError::Decompression)?;
+
+        trace!(
+            "Inflated a {} byte {:?} block to {} bytes",
+            input.len(),
+            self,
+            sz
+        );
+        if sz != inflate_length {
+            debug!(
+                "Block inflated to {} bytes but should be {} bytes",
+                sz, inflate_length
+            );
+            return Err(ArchiveError::Decompression);
+        }
+
+        Ok(())
+    }
+}
+
+/// Compresses blocks with one deflate stream that's reset between blocks
+/// Setting up a stream allocates a few hundred KB so reusing one is much cheaper than starting
+/// a new stream for every block, the compressed data is the same either way.
+#[derive(Default)]
+pub struct BlockCompressor {
+    stream: Option<(BlockCodec, u32, Compress)>,
+}
+
+impl BlockCompressor {
+    pub fn new() -> Self {
+        BlockCompressor::default()
+    }
+
+    /// Compress the data of a single block at a level from 0 (none) to 9 (best), appending it
+    /// to out
+    pub fn compress_into(
+        &mut self,
+        codec: BlockCodec,
+        input: &[u8],
+        level: u32,
+        out: &mut Vec<u8>,
+    ) -> Result<(), ArchiveError> {
+        let start = out.len();
+        if codec == BlockCodec::Stored {
+            out.extend_from_slice(input);
+        } else {
+            if !matches!(&self.stream, Some((c, l, _)) if *c == codec && *l == level) {
+                self.stream = None;
+            }
+            let (_, _, stream) = self.stream.get_or_insert_with(|| {
+                let zlib_header = codec == BlockCodec::Zlib;
+                (
+                    codec,
+                    level,
+                    Compress::new(Compression::new(level), zlib_header),
+                )
+            });
+            stream.reset();
+
+            let mut consumed = 0;
+            loop {
+                // compress_vec only writes to spare capacity
+                out.reserve(input.len() - consumed + 64);
+                let before = stream.total_in();
+                let status = stream
+                    .compress_vec(&input[consumed..], out, FlushCompress::Finish)
+                    .map_err(|_| ArchiveError::Compression)?;
+                consumed += (stream.total_in() - before) as usize;
+                if status == Status::StreamEnd {
+                    break;
+                }
+            }
+        }
+
+        trace!(
+            "Deflated a {} byte block to {} bytes with {:?} at level {}",
+            input.len(),
+            out.len() - start,
+            codec,
+            level
+        );
+        Ok(())
+    }
+}
+
+/// Most buffers a `BufferPool` keeps for reuse
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+/// Buffers for compressed blocks that are given back once written so later blocks reuse them
+/// Can be shared between threads.
+#[derive(Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::default()
+    }
+
+    /// Take an empty buffer, reusing one that was given back if there is one
+    pub fn take(&self) -> Vec<u8> {
+        match self.buffers.lock() {
+            Ok(mut buffers) => buffers.pop().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Give a buffer back so it can be taken again
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(buffer);
+            }
+        }
+    }
+}
+
+/// Decompress block data using whichever codec it was written with, returning that codec
+pub fn inflate_block(
+    input: &[u8],
+    inflate_length: usize,
+    out: &mut Vec<u8>,
+) -> Result<BlockCodec, ArchiveError> {
+    let start = out.len();
+    let codec = BlockCodec::detect(input);
+    let res = match codec.decompress(input, inflate_length, out) {
+        // a raw deflate stream can start with bytes that look like a zlib header
+        Err(_) if codec == BlockCodec::Zlib => {
+            debug!("Block with a zlib header didn't inflate, trying it as raw deflate");
+            out.truncate(start);
+            BlockCodec::RawDeflate
+                .decompress(input, inflate_length, out)
+                .map(|_| BlockCodec::RawDeflate)
+        }
+        res => res.map(|_| codec),
+    };
+
+    match res {
+        Err(_) if input.len() == inflate_length => {
+            debug!("Block didn't inflate, reading it as stored");
+            out.truncate(start);
+            BlockCodec::Stored
+                .decompress(input, inflate_length, out)
+                .map(|_| BlockCodec::Stored)
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::pfs::codec::{inflate_block, BlockCodec};
+    use crate::archive::pfs::testing::zlib_uncompress;
+    use crate::archive::prelude::*;
+    use flate2::write::{DeflateEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn detect_test() {
+        let data = b"some block data some block data";
+        let zlib = BlockCodec::Zlib.compress(data).unwrap();
+        let raw = BlockCodec::RawDeflate.compress(data).unwrap();
+
+        assert_eq!(BlockCodec::detect(&zlib), BlockCodec::Zlib);
+        assert_eq!(BlockCodec::detect(&raw), BlockCodec::RawDeflate);
+
+        let mut out = Vec::new();
+        inflate_block(&raw, data.len(), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn block_compressor_test() {
+        let blocks: Vec<Vec<u8>> = vec![
+            (0..8192u32).map(|i| (i % 13) as u8).collect(),
+            (0..8192u32).map(|i| (i * 31 % 251) as u8).collect(),
+            b"short".to_vec(),
+            Vec::new(),
+            (0..8192u32).map(|i| (i * i % 256) as u8).collect(),
+        ];
+
+        // one compressor reused across codecs, levels and blocks writes what a fresh encoder does
+        let mut compressor = BlockCompressor::new();
+        let pool = BufferPool::new();
+        for level in [0, 1, 6, 9] {
+            for codec in [BlockCodec::Zlib, BlockCodec::RawDeflate, BlockCodec::Stored] {
+                for block in &blocks {
+                    let expected = match codec {
+                        BlockCodec::Zlib => {
+                            let mut e = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                            e.write_all(block).unwrap();
+                            e.finish().unwrap()
+                        }
+                        BlockCodec::RawDeflate => {
+                            let mut e = DeflateEncoder::new(Vec::new(), Compression::new(level));
+                            e.write_all(block).unwrap();
+                            e.finish().unwrap()
+                        }
+                        BlockCodec::Stored => block.clone(),
+                    };
+
+                    let mut out = pool.take();
+                    assert!(out.is_empty());
+                    compressor
+                        .compress_into(codec, block, level, &mut out)
+                        .unwrap();
+                    assert_eq!(out, expected);
+                    assert_eq!(codec.compress_with_level(block, level).unwrap(), expected);
+                    pool.give(out);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn raw_deflate_roundtrip_test() {
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 13) as u8).collect();
+
+        let mut writable = WritableArchive::new();
+        writable.set_block_codec(BlockCodec::RawDeflate);
+        writable.set("test.wld", &contents).unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.get("test.wld").unwrap(), contents);
+
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite.open_from_bytes(&data).unwrap();
+        assert_eq!(readwrite.get("test.wld").unwrap(), contents);
+    }
+
+    #[test]
+    fn stored_roundtrip_test() {
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i * 7919 % 256) as u8).collect();
+
+        let mut writable = WritableArchive::new();
+        writable.set_stored("stored.dds", &contents).unwrap();
+        writable.set("zlib.wld", &contents).unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.get("stored.dds").unwrap(), contents);
+        assert_eq!(readable.get("zlib.wld").unwrap(), contents);
+        assert!(readable
+            .compressed_blocks("stored.dds")
+            .unwrap()
+            .all(|(data, len)| data.len() == len));
+
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite.open_from_bytes(&data).unwrap();
+        readwrite.set_stored("copy.dds", &contents).unwrap();
+        assert_eq!(readwrite.get("stored.dds").unwrap(), contents);
+
+        readable
+            .open_from_bytes(readwrite.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.get("copy.dds").unwrap(), contents);
+        assert!(readable
+            .compressed_blocks("copy.dds")
+            .unwrap()
+            .all(|(data, len)| data.len() == len));
+    }
+
+    #[test]
+    fn decompress_bomb_test() {
+        // 64 MB of zeros deflates to a block of a few dozen KB
+        let bomb = BlockCodec::Zlib.compress(&vec![0u8; 64 << 20]).unwrap();
+        assert!(bomb.len() < 1 << 20);
+
+        for (codec, block) in [
+            (BlockCodec::Zlib, bomb),
+            (
+                BlockCodec::RawDeflate,
+                BlockCodec::RawDeflate
+                    .compress(&vec![0u8; 64 << 20])
+                    .unwrap(),
+            ),
+        ] {
+            let mut out = Vec::new();
+            assert!(matches!(
+                codec.decompress(&block, 100, &mut out),
+                Err(ArchiveError::Decompression)
+            ));
+            // only one byte past the declared length was inflated
+            assert_eq!(out.len(), 101);
+            assert!(out.capacity() < 1 << 20);
+        }
+    }
+
+    /// Compress with stock zlib, the library the client reads archives with
+    fn zlib_compress(input: &[u8], level: i32) -> Vec<u8> {
+        let mut len = unsafe { libz_sys::compressBound(input.len() as _) };
+        let mut out = vec![0u8; len as usize];
+        let res = unsafe {
+            libz_sys::compress2(
+                out.as_mut_ptr(),
+                &mut len,
+                input.as_ptr(),
+                input.len() as _,
+                level,
+            )
+        };
+        assert_eq!(res, libz_sys::Z_OK);
+        out.truncate(len as usize);
+        out
+    }
+
+    #[test]
+    fn backend_compatibility_test() {
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i * i % 251) as u8).collect();
+
+        for level in [0, 1, 6, 9] {
+            // blocks written with the backend this was built with decode with stock zlib
+            let block = BlockCodec::Zlib
+                .compress_with_level(&contents, level)
+                .unwrap();
+            assert_eq!(zlib_uncompress(&block, contents.len()), contents);
+
+            // and blocks written by stock zlib decode with the backend
+            let block = zlib_compress(&contents, level as i32);
+            let mut out = Vec::new();
+            BlockCodec::Zlib
+                .decompress(&block, contents.len(), &mut out)
+                .unwrap();
+            assert_eq!(out, contents);
+        }
+    }
+}
@@ -1,32 +1,124 @@
 use crate::archive::archive_error::ArchiveError;
+use crate::archive::pfs::codec::{BlockCodec, BlockCompressor};
+use crate::archive::pfs::constants::{
+    COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE, FOOTER_MAGIC, HEADER_SCAN_WINDOW,
+    MAX_BLOCK_SIZE, PFS_CRC_ALGO, PFS_MAGIC, PFS_VERSION, PFS_VERSION_EXTENDED,
+};
+use crate::archive::pfs::options::OpenOptions;
 use bytes::{BufMut, Bytes, BytesMut};
+use crc::Crc;
+use log::{debug, trace, warn};
 use nom::Err::Error;
-use nom::{bytes::complete::take, number::complete::le_u32, IResult};
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::map,
+    multi::count,
+    number::complete::{le_u32, le_u64},
+    sequence::tuple,
+    IResult,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn parse_filenames(input: &[u8]) -> Result<Vec<String>, ArchiveError> {
-    match _parse_filenames(input) {
-        Ok((_, filenames)) => Ok(filenames),
-        Err(e) => {
-            if let Error(ae) = e {
-                Err(ae)
-            } else {
-                Err(ArchiveError::Unknown)
+/// How a name is encoded in the filename table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameEncoding {
+    #[default]
+    Utf8,
+    /// Some legacy tools wrote names as Latin-1, they're kept that way when saving so the name
+    /// and its CRC don't change
+    Latin1,
+}
+
+impl NameEncoding {
+    /// Decode a name from the filename table
+    /// Names that aren't valid UTF-8 are decoded as Latin-1 unless `strict` is set.
+    fn decode(bytes: &[u8], strict: bool) -> Result<(String, NameEncoding), ArchiveError> {
+        match std::str::from_utf8(bytes) {
+            Ok(name) => Ok((name.to_string(), NameEncoding::Utf8)),
+            Err(e) if strict => Err(ArchiveError::Utf8(e)),
+            Err(_) => {
+                let name: String = bytes.iter().map(|&b| char::from(b)).collect();
+                warn!(
+                    "Filename {:?} isn't valid UTF-8, reading it as Latin-1",
+                    name
+                );
+                Ok((name, NameEncoding::Latin1))
             }
         }
     }
+
+    /// Encode a name for the filename table
+    /// Characters Latin-1 can't hold are written as `?`.
+    pub fn encode(self, name: &str) -> Vec<u8> {
+        match self {
+            NameEncoding::Utf8 => name.as_bytes().to_vec(),
+            NameEncoding::Latin1 => name
+                .chars()
+                .map(|c| u8::try_from(c).unwrap_or(b'?'))
+                .collect(),
+        }
+    }
+
+    /// Get the CRC a name is found by in the directory when written with this encoding
+    pub fn crc(self, name: &str) -> u32 {
+        crc_bytes(&self.encode(&name.to_lowercase()))
+    }
+}
+
+/// Parse the filename table, only keeping the names
+#[cfg(test)]
+pub fn parse_filenames(input: &[u8]) -> Result<Vec<String>, ArchiveError> {
+    Ok(parse_encoded_filenames(input, false)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Parse the filename table keeping how each name was encoded
+/// Names that aren't valid UTF-8 are an error if `strict` is set, otherwise they're read as
+/// Latin-1.
+pub fn parse_encoded_filenames(
+    input: &[u8],
+    strict: bool,
+) -> Result<Vec<(String, NameEncoding)>, ArchiveError> {
+    match _parse_filenames(input, strict) {
+        Ok((_, filenames)) => Ok(filenames),
+        Err(e) => Err(ArchiveError::from_nom(e, input)),
+    }
 }
 
-fn _parse_filenames(input: &[u8]) -> IResult<&[u8], Vec<String>, ArchiveError> {
+fn _parse_filenames(
+    input: &[u8],
+    strict: bool,
+) -> IResult<&[u8], Vec<(String, NameEncoding)>, ArchiveError> {
     let mut ret = Vec::new();
     let (mut current, count) = le_u32(input)?;
 
-    for _ in 0..count {
+    for i in 0..count {
         let (pos, len) = le_u32(current)?;
+
+        if len == 0 {
+            return Err(Error(ArchiveError::Parse(format!(
+                "Filename {} has a length of zero",
+                i
+            ))));
+        }
+
+        if len as usize > pos.len() {
+            return Err(Error(ArchiveError::Parse(format!(
+                "Filename {} has a length of {} but only {} bytes remain",
+                i,
+                len,
+                pos.len()
+            ))));
+        }
+
         let (pos, str) = take(len as usize)(pos)?;
 
-        match std::str::from_utf8(&str[..(len as usize - 1)]) {
-            Ok(utf_str) => ret.push(utf_str.to_string()),
-            Err(e) => return Err(Error(ArchiveError::Utf8(e))),
+        match NameEncoding::decode(&str[..(len as usize - 1)], strict) {
+            Ok(filename) => ret.push(filename),
+            Err(e) => return Err(Error(e)),
         }
         current = pos;
     }
@@ -34,16 +126,786 @@ fn _parse_filenames(input: &[u8]) -> IResult<&[u8], Vec<String>, ArchiveError> {
     Ok((current, ret))
 }
 
+/// Write the filename table
+/// Names are matched to entries by a CRC of the lowercased name so names that only differ by case
+/// would collide, only the first of them is written.
 pub fn write_filenames(filenames: &[String]) -> Bytes {
+    write_encoded_filenames(filenames.iter().map(|f| (f.as_str(), NameEncoding::Utf8)))
+}
+
+/// Write the filename table with each name in its own encoding
+/// The writers key files by their lowercased name so they never pass names that only differ by
+/// case, dropping them here is just a guard.
+pub fn write_encoded_filenames<'a, I>(filenames: I) -> Bytes
+where
+    I: IntoIterator<Item = (&'a str, NameEncoding)>,
+{
+    let mut seen = HashSet::new();
+    let filenames: Vec<Vec<u8>> = filenames
+        .into_iter()
+        .filter(|(filename, _)| seen.insert(filename.to_lowercase()))
+        .map(|(filename, encoding)| encoding.encode(filename))
+        .collect();
+
     let mut buffer = BytesMut::with_capacity(1024);
     buffer.put_u32_le(filenames.len() as u32);
 
-    for filename in filenames {
-        let filename_bytes = filename.as_bytes();
+    for filename_bytes in filenames {
         buffer.put_u32_le(filename_bytes.len() as u32 + 1);
-        buffer.put(filename_bytes);
+        buffer.put(&filename_bytes[..]);
         buffer.put_u8(0);
     }
 
     buffer.freeze()
 }
+
+/// Compress the filename table into blocks with their headers, ready to be written
+/// The client only reads the table as zlib whatever codec the files use. The table of a small
+/// archive can grow when compressed so it's written at level 0 when that's smaller.
+pub fn write_filename_table_blocks(table: &[u8], level: u32) -> Result<Bytes, ArchiveError> {
+    let mut compressor = BlockCompressor::new();
+    let mut deflate = |level| -> Result<Bytes, ArchiveError> {
+        let mut buffer = BytesMut::with_capacity(table.len() + 64);
+        let mut block = Vec::new();
+        for chunk in table.chunks(MAX_BLOCK_SIZE) {
+            block.clear();
+            compressor.compress_into(BlockCodec::Zlib, chunk, level, &mut block)?;
+            buffer.put_u32_le(block.len() as u32);
+            buffer.put_u32_le(chunk.len() as u32);
+            buffer.put(&block[..]);
+        }
+        Ok(buffer.freeze())
+    };
+
+    let compressed = deflate(level)?;
+    let stored = deflate(0)?;
+    if stored.len() <= compressed.len() {
+        Ok(stored)
+    } else {
+        Ok(compressed)
+    }
+}
+
+/// A single entry of the archive directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub crc: u32,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Size of the archive header that precedes the file data
+pub fn header_size(extended: bool) -> usize {
+    if extended {
+        20
+    } else {
+        12
+    }
+}
+
+/// Parse the archive header returning the directory offset and whether the
+/// archive uses the extended 64-bit format
+pub fn parse_header(input: &[u8]) -> IResult<&[u8], (u64, bool), ArchiveError> {
+    parse_header_with_options(input, &OpenOptions::default())
+}
+
+/// Parse the archive header with specific options
+/// Versions that aren't known are parsed as the standard format when the options allow it.
+pub fn parse_header_with_options<'a>(
+    input: &'a [u8],
+    options: &OpenOptions,
+) -> IResult<&'a [u8], (u64, bool), ArchiveError> {
+    let (current, dir_offset) = le_u32(input)?;
+    // only as much of the magic as there's room for is checked so a truncated archive is still
+    // reported as a parse error
+    let magic = current.len().min(PFS_MAGIC.len());
+    if current[..magic] != PFS_MAGIC[..magic] {
+        return Err(Error(ArchiveError::NotPfs));
+    }
+    let (current, _) = tag(PFS_MAGIC)(current)?;
+    let (current, version) = le_u32(current)?;
+    trace!("Archive version {:#010x}", version);
+
+    let (current, dir_offset, extended) = match version {
+        PFS_VERSION => (current, dir_offset as u64, false),
+        PFS_VERSION_EXTENDED => {
+            let (current, dir_offset) = le_u64(current)?;
+            (current, dir_offset, true)
+        }
+        _ if options.allow_unknown_version => {
+            warn!(
+                "Unknown archive version {:#010x}, reading it as the standard format",
+                version
+            );
+            (current, dir_offset as u64, false)
+        }
+        _ => return Err(Error(ArchiveError::WrongVersion { version })),
+    };
+
+    // the directory can't overlap the header, an offset of 0 would read the header as one
+    if dir_offset < header_size(extended) as u64 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "Directory offset {} is inside the {} byte header",
+            dir_offset,
+            header_size(extended)
+        ))));
+    }
+
+    Ok((current, (dir_offset, extended)))
+}
+
+/// Find where the archive starts in the input
+/// This is always 0 unless the options allow scanning, in which case the first
+/// `HEADER_SCAN_WINDOW` bytes are searched for a valid header when one isn't at the start.
+pub fn find_archive_start(input: &[u8], options: &OpenOptions) -> usize {
+    if !options.scan_for_header || parse_header_with_options(input, options).is_ok() {
+        return 0;
+    }
+
+    let window = input.len().min(HEADER_SCAN_WINDOW);
+    match (1..window).find(|start| parse_header_with_options(&input[*start..], options).is_ok()) {
+        Some(start) => {
+            warn!("Archive header found after {} bytes of junk", start);
+            start
+        }
+        None => 0,
+    }
+}
+
+/// Compute the CRC used to identify a file in the archive directory
+/// Names are lowercased to match how they're stored in the archive.
+pub fn pfs_crc(filename: &str) -> u32 {
+    crc_bytes(filename.to_lowercase().as_bytes())
+}
+
+fn crc_bytes(filename: &[u8]) -> u32 {
+    let crc_provider = Crc::<u32>::new(&PFS_CRC_ALGO);
+    let mut digest = crc_provider.digest();
+    digest.update(filename);
+    digest.update(b"\0");
+    digest.finalize()
+}
+
+/// Match the names in the filename table to directory entries
+/// Returns each resolved name with the index of its entry. When several entries share a CRC
+/// they are matched to names in the order both appear in the archive, which is the order our
+/// writers lay them out in, so the same file is always recovered for each name.
+pub fn resolve_filenames<T, F>(
+    filenames: &[(String, NameEncoding)],
+    entries: &[T],
+    key: F,
+) -> Vec<(String, NameEncoding, usize)>
+where
+    F: Fn(&T) -> (u32, u64),
+{
+    let mut by_crc: HashMap<u32, Vec<(u64, usize)>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let (crc, offset) = key(entry);
+        by_crc.entry(crc).or_default().push((offset, i));
+    }
+
+    for (crc, group) in by_crc.iter_mut() {
+        if group.len() > 1 {
+            warn!(
+                "{} directory entries share the crc {:#010x}",
+                group.len(),
+                crc
+            );
+        }
+
+        // reversed so matching can pop from the back
+        group.sort_by(|a, b| b.cmp(a));
+    }
+
+    let mut ret = Vec::with_capacity(filenames.len());
+    for (filename, encoding) in filenames {
+        let crc = encoding.crc(filename);
+        if let Some((_, i)) = by_crc.get_mut(&crc).and_then(|group| group.pop()) {
+            ret.push((filename.clone(), *encoding, i));
+        }
+    }
+
+    ret
+}
+
+/// Slice the input from an offset read out of the archive
+/// Returns a parse error instead of panicking if the offset is past the end of the input.
+pub fn slice_from<'a>(
+    input: &'a [u8],
+    offset: u64,
+    what: &str,
+) -> Result<&'a [u8], nom::Err<ArchiveError>> {
+    if offset > input.len() as u64 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "{} offset {} is past the end of the {} byte archive",
+            what,
+            offset,
+            input.len()
+        ))));
+    }
+
+    Ok(&input[offset as usize..])
+}
+
+/// Get the data of a directory entry
+/// The returned slice ends at the directory so blocks can't run into it. None if the entry
+/// doesn't start inside the data region between the header and the directory.
+fn entry_data<'a>(
+    input: &'a [u8],
+    entry: &DirectoryEntry,
+    dir_offset: u64,
+    extended: bool,
+) -> Option<&'a [u8]> {
+    if entry.offset < header_size(extended) as u64 || entry.offset > dir_offset {
+        return None;
+    }
+
+    Some(&input[entry.offset as usize..dir_offset as usize])
+}
+
+/// Entries paired with their parsed blocks, then the entries outside the data region
+type EntryBlocks<T> = (Vec<(DirectoryEntry, T)>, Vec<DirectoryEntry>);
+
+/// Parse the blocks of every directory entry with `parse`
+/// Entries whose data isn't inside the data region are returned separately so they can be
+/// reported by name with `check_entries_inside` once the filename table has been read.
+pub fn parse_entry_blocks<'a, T, F>(
+    input: &'a [u8],
+    entries: Vec<DirectoryEntry>,
+    dir_offset: u64,
+    extended: bool,
+    parse: F,
+) -> Result<EntryBlocks<T>, nom::Err<ArchiveError>>
+where
+    F: Fn(&'a [u8], usize, usize) -> IResult<&'a [u8], T, ArchiveError>,
+{
+    let mut parsed = Vec::with_capacity(entries.len());
+    let mut outside = Vec::new();
+    for entry in entries {
+        let Some(data) = entry_data(input, &entry, dir_offset, extended) else {
+            outside.push(entry);
+            continue;
+        };
+
+        let (offset, size) = (entry.offset as usize, entry.size as usize);
+        match parse(data, offset, size) {
+            Ok((_, blocks)) => parsed.push((entry, blocks)),
+            // blocks that only fit when they're allowed past the directory run into it
+            Err(_) if parse(&input[offset..], offset, size).is_ok() => outside.push(entry),
+            Err(e) => return Err(ArchiveError::trailing(e, input.len() - dir_offset as usize)),
+        }
+    }
+
+    Ok((parsed, outside))
+}
+
+/// Fail if any entries were outside the data region, naming them by the filename table
+/// Entries the table doesn't name are labelled by their CRC.
+pub fn check_entries_inside(
+    filenames: &[(String, NameEncoding)],
+    outside: &[DirectoryEntry],
+) -> Result<(), nom::Err<ArchiveError>> {
+    if outside.is_empty() {
+        return Ok(());
+    }
+
+    let mut labels = entry_labels(filenames, outside);
+    labels.sort();
+    Err(Error(ArchiveError::FilesOutsideData(labels)))
+}
+
+/// Label each directory entry with its name from the filename table or else its CRC
+pub fn entry_labels(
+    filenames: &[(String, NameEncoding)],
+    entries: &[DirectoryEntry],
+) -> Vec<String> {
+    let mut labels: Vec<String> = entries.iter().map(|e| crc_label(e.crc)).collect();
+    for (filename, _, i) in resolve_filenames(filenames, entries, |e| (e.crc, e.offset)) {
+        labels[i] = filename;
+    }
+    labels
+}
+
+/// Check a name can be written to the filename table
+/// Names can't hold control characters or have the CRC of one of the tables.
+pub fn check_filename(name: &str) -> Result<(), ArchiveError> {
+    if name.chars().any(char::is_control) {
+        return Err(ArchiveError::InvalidName(name.to_string()));
+    }
+
+    check_reserved_crc(name, pfs_crc(name))
+}
+
+/// Check the CRC a name is written under isn't one the tables are stored under
+pub fn check_reserved_crc(name: &str, crc: u32) -> Result<(), ArchiveError> {
+    if crc == FILENAMES_CRC_VALUE || crc == COMPRESSION_TABLE_CRC_VALUE {
+        return Err(ArchiveError::ReservedName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check a block size can be written, returning it if so
+pub fn check_block_size(size: usize) -> Result<usize, ArchiveError> {
+    if size == 0 || size > u32::MAX as usize {
+        return Err(ArchiveError::InvalidBlockSize { size });
+    }
+
+    Ok(size)
+}
+
+/// Get the data of the block at a position inside an entry's data
+/// `offset` is where the entry starts in the archive and is only used for the error message.
+pub fn next_block(
+    input: &[u8],
+    position: usize,
+    offset: usize,
+) -> Result<&[u8], nom::Err<ArchiveError>> {
+    input.get(position..).ok_or_else(|| {
+        Error(ArchiveError::Parse(format!(
+            "Block at offset {} is past the end of the file data",
+            offset.saturating_add(position)
+        )))
+    })
+}
+
+/// Reject a block that inflates to nothing
+/// The block loops only stop once enough data has been inflated so such a block would let a
+/// corrupt archive claim an unbounded number of blocks.
+pub fn check_block_progress(
+    inflate_length: usize,
+    offset: usize,
+) -> Result<(), nom::Err<ArchiveError>> {
+    if inflate_length == 0 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "Block at offset {} has an inflated length of 0",
+            offset
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Move past a block header and its deflated data
+pub fn advance_block(
+    position: usize,
+    deflate_length: usize,
+    offset: usize,
+) -> Result<usize, nom::Err<ArchiveError>> {
+    position
+        .checked_add(deflate_length)
+        .and_then(|position| position.checked_add(8))
+        .ok_or_else(|| {
+            Error(ArchiveError::Parse(format!(
+                "Block at offset {} has an out of range deflated length {}",
+                offset.saturating_add(position),
+                deflate_length
+            )))
+        })
+}
+
+/// Name an entry by its CRC when the filename table doesn't name it
+pub fn crc_label(crc: u32) -> String {
+    format!("crc {:#010x}", crc)
+}
+
+/// Find the regions of file data that overlap each other
+/// Each region is a label with its `[start, end)` range in the archive. Regions with the exact
+/// same range are allowed since files with the same contents share their data. Returns the
+/// sorted labels of the overlapping regions.
+pub fn overlapping_regions(regions: &[(String, usize, usize)]) -> Vec<String> {
+    let mut sorted: Vec<&(String, usize, usize)> = regions
+        .iter()
+        .filter(|(_, start, end)| start < end)
+        .collect();
+    sorted.sort_by_key(|(_, start, end)| (*start, *end));
+
+    // group the regions sharing a range so an overlap reports every file in the group
+    let mut groups: Vec<(usize, usize, Vec<&String>)> = Vec::new();
+    for (label, start, end) in sorted {
+        match groups.last_mut() {
+            Some(group) if (group.0, group.1) == (*start, *end) => group.2.push(label),
+            _ => groups.push((*start, *end, vec![label])),
+        }
+    }
+
+    let mut ret = Vec::new();
+    let mut current: Option<&(usize, usize, Vec<&String>)> = None;
+    for group in &groups {
+        if let Some(cur) = current {
+            if group.0 < cur.1 {
+                ret.extend(cur.2.iter().map(|label| label.to_string()));
+                ret.extend(group.2.iter().map(|label| label.to_string()));
+            }
+
+            if group.1 <= cur.1 {
+                continue;
+            }
+        }
+
+        current = Some(group);
+    }
+
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+pub fn parse_directory_entries<'a>(
+    input: &'a [u8],
+    extended: bool,
+    options: &OpenOptions,
+) -> IResult<&'a [u8], Vec<DirectoryEntry>, ArchiveError> {
+    let (current, mut dir_count) = le_u32(input)?;
+
+    let entry_size = if extended { 20 } else { 12 };
+    let available = current.len() / entry_size;
+    if dir_count as usize > available {
+        if !options.repair_directory {
+            return Err(Error(ArchiveError::Parse(format!(
+                "Directory count of {} needs {} bytes but only {} are available",
+                dir_count,
+                dir_count as usize * entry_size,
+                current.len()
+            ))));
+        }
+
+        warn!(
+            "Directory count of {} is larger than the {} entries available, repairing",
+            dir_count, available
+        );
+        dir_count = available as u32;
+    }
+
+    let (rest, entries) = if extended {
+        count(
+            map(tuple((le_u32, le_u64, le_u64)), |(crc, offset, size)| {
+                DirectoryEntry { crc, offset, size }
+            }),
+            dir_count as usize,
+        )(current)?
+    } else {
+        count(
+            map(tuple((le_u32, le_u32, le_u32)), |(crc, offset, size)| {
+                DirectoryEntry {
+                    crc,
+                    offset: offset as u64,
+                    size: size as u64,
+                }
+            }),
+            dir_count as usize,
+        )(current)?
+    };
+
+    debug!("Directory holds {} entries", entries.len());
+    for entry in &entries {
+        trace!(
+            "Entry {:#010x} at offset {} with size {}",
+            entry.crc,
+            entry.offset,
+            entry.size
+        );
+    }
+
+    Ok((rest, entries))
+}
+
+pub fn write_directory(entries: &[DirectoryEntry], extended: bool) -> Result<Bytes, ArchiveError> {
+    let mut buffer = BytesMut::with_capacity(4 + entries.len() * 20);
+    buffer.put_u32_le(entries.len() as u32);
+
+    for entry in entries {
+        buffer.put_u32_le(entry.crc);
+        if extended {
+            buffer.put_u64_le(entry.offset);
+            buffer.put_u64_le(entry.size);
+        } else {
+            buffer.put_u32_le(u32::try_from(entry.offset).map_err(|_| ArchiveError::TooLarge)?);
+            buffer.put_u32_le(u32::try_from(entry.size).map_err(|_| ArchiveError::TooLarge)?);
+        }
+    }
+
+    Ok(buffer.freeze())
+}
+
+/// Write the archive header pointing at the directory
+pub fn write_header(dir_offset: u64, extended: bool) -> Result<Bytes, ArchiveError> {
+    let mut buffer = BytesMut::with_capacity(header_size(extended));
+    if extended {
+        buffer.put_u32_le(u32::MAX);
+    } else {
+        buffer.put_u32_le(u32::try_from(dir_offset).map_err(|_| ArchiveError::TooLarge)?);
+    }
+    buffer.put(PFS_MAGIC);
+    if extended {
+        buffer.put_u32_le(PFS_VERSION_EXTENDED);
+        buffer.put_u64_le(dir_offset);
+    } else {
+        buffer.put_u32_le(PFS_VERSION);
+    }
+
+    Ok(buffer.freeze())
+}
+
+/// Write the footer that follows the directory, the "STEVE" token then the date
+pub fn write_footer(date: u32) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(FOOTER_MAGIC.len() + 4);
+    buffer.put(FOOTER_MAGIC);
+    buffer.put_u32_le(date);
+    buffer.freeze()
+}
+
+/// Parse the optional footer from the data following the directory, returning its date
+pub fn parse_footer(input: &[u8]) -> Option<u32> {
+    let date = input.strip_prefix(FOOTER_MAGIC)?.get(..4)?;
+    Some(u32::from_le_bytes(date.try_into().ok()?))
+}
+
+/// The current time as a footer date, seconds since the Unix epoch
+pub fn footer_date_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Assemble a complete archive from its file data and directory
+/// The directory offsets are expected to already account for the header size.
+/// Directory entries are written sorted by CRC as the client binary searches them.
+/// The footer is only written when given a date.
+pub fn write_archive(
+    data: Bytes,
+    mut entries: Vec<DirectoryEntry>,
+    extended: bool,
+    footer: Option<u32>,
+) -> Result<Vec<u8>, ArchiveError> {
+    entries.sort_by_key(|e| e.crc);
+    let directory = write_directory(&entries, extended)?;
+    let dir_offset = (header_size(extended) + data.len()) as u64;
+
+    let mut final_data =
+        BytesMut::with_capacity(header_size(extended) + data.len() + directory.len());
+    final_data.put(write_header(dir_offset, extended)?);
+    final_data.put(data);
+    final_data.put(directory);
+    if let Some(date) = footer {
+        final_data.put(write_footer(date));
+    }
+
+    Ok(final_data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::archive_error::ArchiveError;
+    use crate::archive::pfs::common::{
+        overlapping_regions, parse_directory_entries, parse_filenames, pfs_crc, write_directory,
+        write_filenames, DirectoryEntry,
+    };
+    use crate::archive::pfs::constants::{COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE};
+    use crate::archive::pfs::options::OpenOptions;
+    use crate::archive::prelude::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reserved_name_test() {
+        // names solved to hash to the CRCs of the filename and compression tables
+        let filenames = "reserved_abbabbbbbbbbbbbaabbbabaaabbbbbabaaaaaaaa.txt";
+        let compression = "reserved_bbababbabbabaaabaaabbbabaaaababbaaaaaaaa.txt";
+        assert_eq!(pfs_crc(filenames), FILENAMES_CRC_VALUE);
+        assert_eq!(
+            pfs_crc(&compression.to_uppercase()),
+            COMPRESSION_TABLE_CRC_VALUE
+        );
+
+        let reserved = |res: Result<(), ArchiveError>| matches!(res, Err(ArchiveError::ReservedName(name)) if name.starts_with("reserved_"));
+
+        let mut writable = WritableArchive::new();
+        assert!(reserved(writable.set(filenames, b"data")));
+        assert!(reserved(writable.set_stored(compression, b"data")));
+
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite.set("test.txt", b"data").unwrap();
+        assert!(reserved(readwrite.rename("test.txt", filenames)));
+        assert!(reserved(readwrite.copy("test.txt", compression)));
+        assert_eq!(readwrite.search(".*").unwrap(), vec!["test.txt"]);
+
+        let mut packer = StreamPacker::new(Cursor::new(Vec::new())).unwrap();
+        assert!(reserved(packer.add(filenames, &b"data"[..])));
+    }
+
+    #[test]
+    fn overlapping_regions_test() {
+        let region = |name: &str, start, end| (name.to_string(), start, end);
+
+        assert!(overlapping_regions(&[
+            region("a", 12, 20),
+            region("b", 20, 30),
+            region("c", 20, 30),
+            region("empty", 25, 25),
+        ])
+        .is_empty());
+
+        assert_eq!(
+            overlapping_regions(&[
+                region("a", 12, 40),
+                region("b", 20, 30),
+                region("c", 40, 50),
+                region("d", 45, 60),
+            ]),
+            vec!["a", "b", "c", "d"]
+        );
+    }
+
+    #[test]
+    fn file_crc_test() {
+        assert_eq!(pfs_crc("innch0003.bmp"), 0xD32DA54A);
+        assert_eq!(pfs_crc("innhe0004.bmp"), 0xD33312A3);
+        assert_eq!(pfs_crc("beahe0204.bmp"), 0xD46B03A5);
+        assert_eq!(pfs_crc("BEAHE0204.BMP"), 0xD46B03A5);
+    }
+
+    #[test]
+    fn extended_directory_test() {
+        // offsets past 4GB without actually writing that much data
+        let entries = vec![
+            DirectoryEntry {
+                crc: 0x1234,
+                offset: 20,
+                size: 5_000_000_000,
+            },
+            DirectoryEntry {
+                crc: 0x5678,
+                offset: 4_300_000_000,
+                size: 10,
+            },
+        ];
+
+        assert!(matches!(
+            write_directory(&entries, false),
+            Err(ArchiveError::TooLarge)
+        ));
+
+        let directory = write_directory(&entries, true).unwrap();
+        let (_, parsed) =
+            parse_directory_entries(&directory[..], true, &OpenOptions::default()).unwrap();
+        assert_eq!(parsed, entries);
+    }
+
+    fn directory_crcs(data: &[u8]) -> Vec<u32> {
+        let dir_offset = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let (_, entries) =
+            parse_directory_entries(&data[dir_offset..], false, &OpenOptions::default()).unwrap();
+        entries.iter().map(|e| e.crc).collect()
+    }
+
+    #[test]
+    fn sorted_directory_test() {
+        let names = [
+            "zone.wld",
+            "objects.wld",
+            "lights.wld",
+            "a.bmp",
+            "b.bmp",
+            "c.dds",
+        ];
+
+        let mut writable = WritableArchive::new();
+        let mut readwrite = ReadWriteArchive::new();
+        for name in names {
+            writable.set(name, name.as_bytes()).unwrap();
+            readwrite.set(name, name.as_bytes()).unwrap();
+        }
+
+        for data in [
+            writable.save_to_bytes().unwrap(),
+            readwrite.save_to_bytes().unwrap(),
+        ] {
+            let crcs = directory_crcs(&data);
+            assert_eq!(crcs.len(), names.len() + 1);
+            assert!(crcs.windows(2).all(|w| w[0] < w[1]));
+
+            let mut archive = ReadableArchive::new();
+            archive.open_from_bytes(&data).unwrap();
+            for name in names {
+                assert_eq!(archive.get(name).unwrap(), name.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn zero_length_filename_test() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        assert!(matches!(
+            parse_filenames(&data[..]),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn zero_length_filename_after_valid_test() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(b"a.bmp\0");
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        match parse_filenames(&data[..]) {
+            Err(ArchiveError::Parse(msg)) => assert!(msg.contains("Filename 1")),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn overlong_filename_test() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&50u32.to_le_bytes());
+        data.extend_from_slice(b"short\0");
+
+        assert!(matches!(
+            parse_filenames(&data[..]),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn duplicate_filenames_test() {
+        let names: Vec<String> = ["Foo.bmp", "bar.wld", "foo.bmp", "Foo.bmp"]
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        let table = write_filenames(&names);
+        assert_eq!(parse_filenames(&table).unwrap(), vec!["Foo.bmp", "bar.wld"]);
+    }
+
+    #[test]
+    fn crc_collision_test() {
+        // both names hash to 0x2875f903
+        let first = "collide_aaaaaaaaaa.bmp";
+        let second = "collide_bamengie``.bmp";
+
+        for _ in 0..8 {
+            let mut writable = WritableArchive::new();
+            writable.set(first, b"first").unwrap();
+            writable.set(second, b"second").unwrap();
+            let data = writable.save_to_bytes().unwrap();
+
+            let crcs = directory_crcs(&data);
+            assert_eq!(crcs.iter().filter(|crc| **crc == 0x2875f903).count(), 2);
+
+            let mut readable = ReadableArchive::new();
+            readable.open_from_bytes(&data).unwrap();
+            assert_eq!(readable.get(first).unwrap(), b"first");
+            assert_eq!(readable.get(second).unwrap(), b"second");
+
+            let mut readwrite = ReadWriteArchive::new();
+            readwrite.open_from_bytes(&data).unwrap();
+            assert_eq!(readwrite.get(first).unwrap(), b"first");
+            assert_eq!(readwrite.get(second).unwrap(), b"second");
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! EQG archives
+//!
+//! Zones from later expansions ship as `.eqg` files instead of `.s3d`. They're the same PFS
+//! container, read with the same header, block and directory parsing, but they hold a
+//! different set of files and some tools that write them are careless with the header:
+//! * Geometry is in `.zon`, `.ter`, `.mod` and `.mds` files rather than WLD files.
+//! * The version word isn't always the standard one. An archive with an unknown version is
+//!   still read as the standard format when its contents show it's an EQG.
+//! * The "STEVE" footer is frequently missing, which the readers already treat as optional.
+
+use super::common::NameEncoding;
+use super::options::OpenOptions;
+use super::readable::ReadableArchive;
+use crate::archive::{
+    archive_entry::ArchiveEntry,
+    archive_error::ArchiveError,
+    archive_trait::{IArchive, IReadableArchive},
+};
+use nom::{Err::Error, IResult};
+use regex::Regex;
+use std::io::Read;
+use std::ops::Deref;
+
+/// File extensions only found in EQG archives
+const EQG_EXTENSIONS: [&str; 6] = ["zon", "ter", "mod", "mds", "ani", "lay"];
+
+/// Which kind of zone archive a PFS container is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// The original `.s3d` archives holding WLD files
+    S3d,
+    /// The later `.eqg` archives holding EQG geometry
+    Eqg,
+}
+
+impl ArchiveKind {
+    /// Decide the kind of an archive from the names of the files in it
+    pub fn detect<'a, I>(names: I) -> ArchiveKind
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let is_eqg = names.into_iter().any(|name| {
+            name.rsplit_once('.')
+                .is_some_and(|(_, ext)| EQG_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+        });
+
+        if is_eqg {
+            ArchiveKind::Eqg
+        } else {
+            ArchiveKind::S3d
+        }
+    }
+}
+
+/// Parse an archive, reading one with an unknown version as the standard format if its
+/// contents show it's an EQG
+/// `kind` decides the kind of a parsed archive from the files in it.
+pub(crate) fn parse_with_eqg_fallback<'a, T, P, K>(
+    input: &'a [u8],
+    options: &OpenOptions,
+    parse: P,
+    kind: K,
+) -> IResult<&'a [u8], T, ArchiveError>
+where
+    P: Fn(&'a [u8], &OpenOptions) -> IResult<&'a [u8], T, ArchiveError>,
+    K: Fn(&T) -> ArchiveKind,
+{
+    match parse(input, options) {
+        Err(Error(ArchiveError::WrongVersion { version })) if !options.allow_unknown_version => {
+            let lenient = OpenOptions {
+                allow_unknown_version: true,
+                ..options.clone()
+            };
+            match parse(input, &lenient) {
+                Ok((rest, archive)) if kind(&archive) == ArchiveKind::Eqg => Ok((rest, archive)),
+                _ => Err(Error(ArchiveError::WrongVersion { version })),
+            }
+        }
+        parsed => parsed,
+    }
+}
+
+/// A readable EQG archive
+/// Always reads archives with an unknown version as the standard format. Everything else
+/// `ReadableArchive` offers is available through `Deref`.
+pub struct EqgArchive {
+    archive: ReadableArchive,
+}
+
+impl EqgArchive {
+    /// Names of the `.zon` files in the archive, usually one for a zone
+    pub fn zone_files(&self) -> Vec<String> {
+        self.archive
+            .search_compiled(&Regex::new(r"(?i)\.zon$").unwrap())
+    }
+
+    /// Get the readable archive back out
+    pub fn into_inner(self) -> ReadableArchive {
+        self.archive
+    }
+
+    fn options() -> OpenOptions {
+        OpenOptions {
+            allow_unknown_version: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Deref for EqgArchive {
+    type Target = ReadableArchive;
+
+    fn deref(&self) -> &ReadableArchive {
+        &self.archive
+    }
+}
+
+impl Default for EqgArchive {
+    fn default() -> Self {
+        EqgArchive::new()
+    }
+}
+
+impl IArchive for EqgArchive {
+    fn new() -> Self {
+        EqgArchive {
+            archive: ReadableArchive::new(),
+        }
+    }
+
+    fn close(&mut self) {
+        self.archive.close();
+    }
+
+    fn len(&self) -> usize {
+        self.archive.len()
+    }
+}
+
+impl IReadableArchive for EqgArchive {
+    fn open_from_bytes<T>(&mut self, input: T) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.archive
+            .open_from_bytes_with_options(input, &EqgArchive::options())
+    }
+
+    fn open_file(&mut self, filename: &str) -> Result<(), ArchiveError> {
+        self.archive
+            .open_file_with_options(filename, &EqgArchive::options())
+    }
+
+    fn open_from_reader<R>(&mut self, mut reader: R) -> Result<(), ArchiveError>
+    where
+        R: Read,
+    {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.open_from_bytes(data)
+    }
+
+    fn get(&self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError> {
+        self.archive.get(in_archive_path)
+    }
+
+    fn get_into(&self, in_archive_path: &str, out: &mut Vec<u8>) -> Result<(), ArchiveError> {
+        self.archive.get_into(in_archive_path, out)
+    }
+
+    fn exists(&self, in_archive_path: &str) -> Result<bool, ArchiveError> {
+        self.archive.exists(in_archive_path)
+    }
+
+    fn name_encoding(&self, in_archive_path: &str) -> Result<NameEncoding, ArchiveError> {
+        self.archive.name_encoding(in_archive_path)
+    }
+
+    fn search(&self, search_regex: &str) -> Result<Vec<String>, ArchiveError> {
+        self.archive.search(search_regex)
+    }
+
+    fn search_compiled(&self, regex: &Regex) -> Vec<String> {
+        self.archive.search_compiled(regex)
+    }
+
+    fn entries(&self) -> Vec<ArchiveEntry> {
+        self.archive.entries()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::prelude::*;
+
+    /// A minimal EQG with an unusual version word and no footer
+    fn eqg_sample() -> Vec<u8> {
+        let mut writable = WritableArchive::new();
+        writable.set("Tutorial.ZON", b"EQGZ zone").unwrap();
+        writable.set("tutorial.ter", b"EQGT terrain").unwrap();
+        writable.set("wall.dds", b"DDS texture").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        let footer = data.len() - 9;
+        assert_eq!(&data[footer..footer + 5], b"STEVE");
+        data.truncate(footer);
+        data[8..12].copy_from_slice(&0x00020001u32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn detect_test() {
+        assert_eq!(
+            ArchiveKind::detect(["zone.wld", "objects.wld", "grass.bmp"]),
+            ArchiveKind::S3d
+        );
+        assert_eq!(
+            ArchiveKind::detect(["zone.ter", "grass.dds"]),
+            ArchiveKind::Eqg
+        );
+        assert_eq!(ArchiveKind::detect(["ZONE.ZON"]), ArchiveKind::Eqg);
+        assert_eq!(ArchiveKind::detect(["mod"]), ArchiveKind::S3d);
+    }
+
+    #[test]
+    fn open_eqg_test() {
+        let data = eqg_sample();
+
+        let mut archive = EqgArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.kind(), ArchiveKind::Eqg);
+        assert_eq!(archive.footer_date(), None);
+        assert_eq!(archive.zone_files(), vec!["Tutorial.ZON".to_string()]);
+        assert_eq!(archive.get("tutorial.zon").unwrap(), b"EQGZ zone");
+        assert_eq!(
+            archive.search(".*").unwrap(),
+            vec!["Tutorial.ZON", "tutorial.ter", "wall.dds"]
+        );
+
+        // the plain reader recognizes the contents and accepts the version too
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.kind(), ArchiveKind::Eqg);
+        assert_eq!(readable.get("tutorial.ter").unwrap(), b"EQGT terrain");
+    }
+
+    #[test]
+    fn open_eqg_readwrite_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(eqg_sample()).unwrap();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.get("tutorial.ter").unwrap(), b"EQGT terrain");
+    }
+
+    #[test]
+    fn open_eqg_file_test() {
+        let path = std::env::temp_dir().join("zu_common_open_eqg_file_test.eqg");
+        std::fs::write(&path, eqg_sample()).unwrap();
+
+        let mut archive = EqgArchive::new();
+        archive.open_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(archive.zone_files(), vec!["Tutorial.ZON".to_string()]);
+        assert_eq!(archive.get("wall.dds").unwrap(), b"DDS texture");
+
+        archive.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_unknown_version_s3d_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("zone.wld", b"wld").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+        data[8..12].copy_from_slice(&0x00020001u32.to_le_bytes());
+
+        // an S3D with an unknown version is still rejected by default
+        let mut readable = ReadableArchive::new();
+        assert!(matches!(
+            readable.open_from_bytes(&data),
+            Err(ArchiveError::WrongVersion {
+                version: 0x00020001
+            })
+        ));
+
+        let mut archive = EqgArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        assert_eq!(archive.kind(), ArchiveKind::S3d);
+        assert_eq!(archive.get("zone.wld").unwrap(), b"wld");
+    }
+}
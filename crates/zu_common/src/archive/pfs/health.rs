@@ -0,0 +1,46 @@
+//! Structural diagnostics for PFS archives
+
+/// Files smaller than this waste a large share of their space on block and directory overhead
+pub const TINY_FILE_SIZE: usize = 256;
+
+/// The oldest generation of client able to read an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClientEra {
+    /// Any client, the archive only uses the original format
+    Classic,
+    /// Only clients that understand the extended header or larger blocks
+    Modern,
+    /// No client, some files are stored or raw deflate and the client only reads zlib blocks
+    Unsupported,
+}
+
+/// A summary of structural problems found in an archive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthReport {
+    /// Number of files in the archive
+    pub file_count: usize,
+    /// Directory entries that no name in the filename table resolves to
+    pub unresolved_entries: usize,
+    /// Files whose compressed data is identical to an earlier file
+    pub duplicate_content: usize,
+    /// Files of at least `TINY_FILE_SIZE` that take up as much space compressed as they do
+    /// inflated, smaller files always grow when compressed and stored files aren't compressed
+    pub incompressible_files: usize,
+    /// Files smaller than `TINY_FILE_SIZE`, only informational
+    pub tiny_files: usize,
+    /// Files of at least `TINY_FILE_SIZE` written uncompressed on purpose, only informational
+    pub stored_files: usize,
+    /// Whether the archive ends with the optional "STEVE" footer
+    pub has_footer: bool,
+}
+
+impl HealthReport {
+    /// Whether no problems were found
+    /// The footer is optional and small files are common in client archives, so neither makes
+    /// an archive unhealthy.
+    pub fn is_healthy(&self) -> bool {
+        self.unresolved_entries == 0
+            && self.duplicate_content == 0
+            && self.incompressible_files == 0
+    }
+}
@@ -0,0 +1,76 @@
+//! Per-file compression metadata
+//!
+//! Archives saved with non-default compression settings carry an extra table recording how each
+//! file was compressed. Like the filename table it's a file with a reserved CRC that no name in
+//! the filename table resolves to, so other readers never look at it.
+
+use super::codec::{BlockCodec, FileCompression};
+use crate::archive::archive_error::ArchiveError;
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+
+/// Parse the compression table into each file's compression keyed by the CRC of its name
+pub fn parse_compression_table(data: &[u8]) -> Result<HashMap<u32, FileCompression>, ArchiveError> {
+    let count = match data.get(..4) {
+        Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        None => {
+            return Err(ArchiveError::Parse(
+                "Compression table is empty".to_string(),
+            ))
+        }
+    };
+
+    let entries = &data[4..];
+    if entries.len() / 6 < count {
+        return Err(ArchiveError::Parse(format!(
+            "Compression table count of {} needs {} bytes but only {} are available",
+            count,
+            count * 6,
+            entries.len()
+        )));
+    }
+
+    let mut ret = HashMap::with_capacity(count);
+    for entry in entries.chunks_exact(6).take(count) {
+        let crc = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        let codec = match entry[4] {
+            0 => BlockCodec::Zlib,
+            1 => BlockCodec::RawDeflate,
+            2 => BlockCodec::Stored,
+            id => {
+                return Err(ArchiveError::Parse(format!(
+                    "Compression table has unknown codec {}",
+                    id
+                )))
+            }
+        };
+
+        ret.insert(
+            crc,
+            FileCompression {
+                codec,
+                level: entry[5] as u32,
+            },
+        );
+    }
+
+    Ok(ret)
+}
+
+/// Write the compression of each file keyed by the CRC of its name
+pub fn write_compression_table(entries: &[(u32, FileCompression)]) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(4 + entries.len() * 6);
+    buffer.put_u32_le(entries.len() as u32);
+
+    for (crc, compression) in entries {
+        buffer.put_u32_le(*crc);
+        buffer.put_u8(match compression.codec {
+            BlockCodec::Zlib => 0,
+            BlockCodec::RawDeflate => 1,
+            BlockCodec::Stored => 2,
+        });
+        buffer.put_u8(compression.level as u8);
+    }
+
+    buffer.to_vec()
+}
@@ -1,5 +1,17 @@
+mod backing;
+pub mod codec;
 mod common;
 mod constants;
+pub mod eqg;
+pub mod health;
+mod metadata;
+pub mod options;
+pub mod raw;
 pub mod readable;
 pub mod readwrite;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod writable;
+
+pub use common::{pfs_crc, NameEncoding};
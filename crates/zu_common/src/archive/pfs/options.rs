@@ -0,0 +1,48 @@
+//! Options that control how PFS archives are parsed when opened and written when saved
+
+use super::codec::{BlockCodec, DEFAULT_COMPRESSION_LEVEL};
+use super::constants::MAX_BLOCK_SIZE;
+
+/// Options used when opening an archive
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// Infer the directory count from the available bytes when the stored count is too large
+    /// instead of failing to open the archive
+    pub repair_directory: bool,
+    /// Search the start of the archive for the header when a tool has prepended junk to it
+    /// instead of failing to open the archive
+    pub scan_for_header: bool,
+    /// Read archives with a version that isn't known as the standard format
+    /// instead of failing to open the archive
+    pub allow_unknown_version: bool,
+    /// Fail to open archives where the data of two files overlaps instead of reading whatever
+    /// bytes the overlapping blocks hold
+    pub check_overlaps: bool,
+    /// Fail to open archives with a name that isn't valid UTF-8 instead of reading the name as
+    /// Latin-1
+    pub strict_filenames: bool,
+}
+
+/// Settings used when writing an archive, the defaults match what the client writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSettings {
+    /// Use the extended format with 64-bit offsets and sizes
+    pub extended_format: bool,
+    /// Codec blocks are compressed with, the client can only read zlib
+    pub block_codec: BlockCodec,
+    /// Compression level from 0 (none) to 9 (best)
+    pub compression_level: u32,
+    /// Size files are split into blocks of
+    pub block_size: usize,
+}
+
+impl Default for ArchiveSettings {
+    fn default() -> Self {
+        ArchiveSettings {
+            extended_format: false,
+            block_codec: BlockCodec::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+        }
+    }
+}
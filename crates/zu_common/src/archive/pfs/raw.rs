@@ -0,0 +1,49 @@
+//! Still compressed file data
+//!
+//! Lets a file be moved between archives without inflating and recompressing its blocks,
+//! which is faster and keeps the compressed bytes exactly as they were.
+
+use crate::archive::{
+    archive_error::ArchiveError,
+    pfs::codec::{inflate_block, FileCompression},
+};
+
+/// The compressed blocks of a file as they're stored in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFile {
+    /// Blocks in the order they're inflated
+    pub blocks: Vec<RawBlock>,
+    /// How the blocks were compressed, if the source archive recorded it
+    pub compression: Option<FileCompression>,
+}
+
+/// A single compressed block of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawBlock {
+    /// Compressed block data without the block header
+    pub data: Vec<u8>,
+    /// Length of the block once inflated
+    pub inflate_length: usize,
+}
+
+impl RawFile {
+    /// Inflated size of the file
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|b| b.inflate_length).sum()
+    }
+
+    /// Whether the file is empty once inflated
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Inflate every block to get the contents of the file
+    pub fn inflate(&self) -> Result<Vec<u8>, ArchiveError> {
+        let mut ret = Vec::with_capacity(self.len());
+        for block in &self.blocks {
+            inflate_block(&block.data, block.inflate_length, &mut ret)?;
+        }
+
+        Ok(ret)
+    }
+}
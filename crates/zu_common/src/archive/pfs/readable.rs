@@ -1,27 +1,61 @@
-use super::{common::parse_filenames, constants::FILENAMES_CRC_VALUE, constants::PFS_CRC_ALGO};
+use super::{
+    backing::Backing,
+    codec::inflate_block,
+    codec::{BlockCodec, FileCompression},
+    common::{
+        advance_block, check_block_progress, check_entries_inside, crc_label, find_archive_start,
+        next_block, overlapping_regions, parse_directory_entries, parse_encoded_filenames,
+        parse_entry_blocks, parse_footer, parse_header, parse_header_with_options,
+        resolve_filenames, slice_from, DirectoryEntry, NameEncoding,
+    },
+    constants::{COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE, MAX_BLOCK_SIZE},
+    eqg::{parse_with_eqg_fallback, ArchiveKind},
+    health::{ClientEra, HealthReport, TINY_FILE_SIZE},
+    metadata::parse_compression_table,
+    options::OpenOptions,
+    raw::{RawBlock, RawFile},
+};
 use crate::archive::{
+    archive_entry::{ArchiveEntry, ArchiveSummary},
     archive_error::ArchiveError,
     archive_trait::{IArchive, IReadableArchive},
+    path::prepare_extract_path,
 };
-use crc::Crc;
-use flate2::read::ZlibDecoder;
+use log::{debug, trace, warn};
 use nom::Err::Error;
-use nom::{
-    bytes::complete::{tag, take},
-    multi::count,
-    number::complete::le_u32,
-    sequence::tuple,
-    IResult,
-};
+use nom::{bytes::complete::take, number::complete::le_u32, IResult};
 use regex::Regex;
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, Values},
+        HashMap, HashSet,
+    },
+    fs::write,
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind, Read},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 pub struct ReadableArchive {
-    data: Vec<u8>,
-    files: HashMap<String, ArchiveFile>,
+    data: Backing,
+    /// Index of each named entry keyed by its lowercased name
+    files: HashMap<String, usize>,
+    entries: Vec<ArchiveFile>,
+    /// Compression of each file keyed by the CRC of its name, if the archive recorded it
+    compression: HashMap<u32, FileCompression>,
+    /// Date from the footer, if the archive has one
+    footer: Option<u32>,
+    /// Hash of the inflated contents of each entry, filled in as `find_by_content` needs them
+    content_hashes: Mutex<HashMap<usize, u64>>,
 }
 
 struct ArchiveFile {
+    name: Option<String>,
+    encoding: NameEncoding,
+    crc: u32,
+    offset: usize,
     size: usize,
     blocks: Vec<ArchiveFileBlock>,
 }
@@ -32,70 +66,723 @@ struct ArchiveFileBlock {
     offset: usize,
 }
 
+/// Reads the contents of a file in an archive
+/// Blocks are only inflated as they're reached so at most one block is held in memory.
+pub struct EntryReader<'a> {
+    blocks: Box<dyn Iterator<Item = (&'a [u8], usize)> + 'a>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<'a> EntryReader<'a> {
+    /// Read from the compressed data and inflated length of each block of a file
+    pub(crate) fn new<I>(blocks: I) -> Self
+    where
+        I: Iterator<Item = (&'a [u8], usize)> + 'a,
+    {
+        EntryReader {
+            blocks: Box::new(blocks),
+            buffer: Vec::with_capacity(MAX_BLOCK_SIZE),
+            position: 0,
+        }
+    }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.blocks.next() {
+                Some((data, inflate_length)) => {
+                    self.buffer.clear();
+                    self.position = 0;
+                    inflate_block(data, inflate_length, &mut self.buffer)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Iterates the files in a ReadableArchive in no particular order
+pub struct Iter<'a> {
+    archive: &'a ReadableArchive,
+    files: Values<'a, String, usize>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, EntryReader<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = &self.archive.entries[*self.files.next()?];
+        Some((
+            entry.name.as_deref().unwrap_or_default(),
+            self.archive.reader(entry),
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.files.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a ReadableArchive {
+    type Item = (&'a str, EntryReader<'a>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+type ParsedArchive = (
+    HashMap<String, usize>,
+    Vec<ArchiveFile>,
+    HashMap<u32, FileCompression>,
+    Option<u32>,
+);
+
 /// A readable PFS archive
 /// The most efficient of the three archive types but can only read data.
 impl ReadableArchive {
-    fn do_parse(input: &[u8]) -> IResult<&[u8], HashMap<String, ArchiveFile>, ArchiveError> {
-        let mut ret: HashMap<String, ArchiveFile> = HashMap::new();
-        let mut parsed_files: HashMap<u32, ArchiveFile> = HashMap::new();
-
-        let (current, dir_offset) = le_u32(input)?;
-        let (current, _) = tag("PFS ")(current)?;
-        let (_, version) = le_u32(current)?;
-
-        if version != 131072 {
-            return Err(Error(ArchiveError::WrongVersion { version }));
-        }
-
-        let current = &input[dir_offset as usize..];
-        let (current, dir_count) = le_u32(current)?;
-        let (_, directory_entries) =
-            count(tuple((le_u32, le_u32, le_u32)), dir_count as usize)(current)?;
-
-        parsed_files.reserve(dir_count as usize);
-        for entry in directory_entries.iter() {
-            let (crc, offset, size) = entry;
-            let (_, blocks) = ReadableArchive::parse_pfs_file_blocks(
-                &input[(*offset as usize)..],
-                *offset as usize,
-                *size as usize,
-            )?;
+    /// Open an archive by parsing it from a block of bytes with specific options
+    pub fn open_from_bytes_with_options<T>(
+        &mut self,
+        input: T,
+        options: &OpenOptions,
+    ) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let input_ref = input.as_ref();
+        self.close();
 
-            parsed_files.insert(
-                *crc,
-                ArchiveFile {
-                    size: *size as usize,
-                    blocks,
-                },
-            );
+        self.data = Backing::Owned(input_ref.to_vec());
+        self.parse_data(options)
+    }
+
+    /// Open an archive file with specific options
+    /// Mapped instead of read when the `mmap` feature is enabled.
+    pub fn open_file_with_options(
+        &mut self,
+        filename: &str,
+        options: &OpenOptions,
+    ) -> Result<(), ArchiveError> {
+        self.close();
+        self.data = Backing::open(filename)?;
+        self.parse_data(options)
+    }
+
+    /// Open an archive embedded in the program, such as with `include_bytes!`
+    /// The archive is read in place without copying it.
+    pub fn from_static(bytes: &'static [u8]) -> Result<ReadableArchive, ArchiveError> {
+        let mut archive = ReadableArchive::new();
+        archive.data = Backing::Static(bytes);
+        archive.parse_data(&OpenOptions::default())?;
+        Ok(archive)
+    }
+
+    /// Parse the data the archive already holds
+    fn parse_data(&mut self, options: &OpenOptions) -> Result<(), ArchiveError> {
+        // block offsets are relative to the header so drop anything in front of it
+        let start = find_archive_start(&self.data[..], options);
+        if start > 0 {
+            self.data = Backing::Owned(self.data[start..].to_vec());
         }
 
-        let mut filenames: Vec<String> = Vec::new();
-        for (crc, f) in &parsed_files {
-            if *crc == FILENAMES_CRC_VALUE {
-                match ReadableArchive::inflate_file_entry(input, f) {
-                    Ok(data) => {
-                        filenames = parse_filenames(&data[..]).unwrap_or_default();
-                        break;
+        // EQG archives are sometimes written with an unexpected version, recognize them by
+        // their contents instead
+        let parsed = parse_with_eqg_fallback(
+            &self.data[..],
+            options,
+            ReadableArchive::do_parse,
+            |archive| ArchiveKind::detect(archive.0.keys().map(String::as_str)),
+        );
+
+        match parsed {
+            Ok((_, (files, entries, compression, footer))) => {
+                if options.check_overlaps {
+                    let overlapping = ReadableArchive::overlapping(&entries);
+                    if !overlapping.is_empty() {
+                        return Err(ArchiveError::OverlappingFiles(overlapping));
                     }
-                    Err(e) => return Err(Error(e)),
                 }
+
+                self.files = files;
+                self.entries = entries;
+                self.compression = compression;
+                self.footer = footer;
+                Ok(())
             }
+            Err(e) => Err(ArchiveError::from_nom(e, &self.data[..]).offset_by(start)),
         }
+    }
 
-        let crc = Crc::<u32>::new(&PFS_CRC_ALGO);
-        for filename in &filenames {
-            let mut digest = crc.digest();
-            digest.update(filename.as_bytes());
-            digest.update(b"\0");
-            let crc = digest.finalize();
+    /// Open an archive by parsing only its directory
+    /// The filename table is never inflated so files can only be accessed by CRC
+    /// through `crc_entries` and `get_by_crc`; name based lookups will find nothing.
+    pub fn open_crc_only<T>(&mut self, input: T) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let input_ref = input.as_ref();
+        self.close();
 
-            if let Some(f) = parsed_files.remove(&crc) {
-                ret.insert(filename.clone(), f);
+        self.data = Backing::Owned(input_ref.to_vec());
+        let parsed = ReadableArchive::parse_directory(&self.data[..], &OpenOptions::default())
+            .and_then(|(rest, (entries, outside))| {
+                check_entries_inside(&[], &outside)?;
+                Ok((rest, entries))
+            });
+        match parsed {
+            Ok((_, entries)) => {
+                self.entries = entries;
+                Ok(())
             }
+            Err(e) => Err(ArchiveError::from_nom(e, &self.data[..])),
         }
+    }
 
-        Ok((input, ret))
+    /// Iterate the CRC and inflated size of every file in the directory
+    /// The filename and compression tables are skipped.
+    pub fn crc_entries(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.entries
+            .iter()
+            .filter(|f| !ReadableArchive::is_table(f))
+            .map(|f| (f.crc, f.size))
+    }
+
+    /// Get how a file was compressed
+    /// Only known for archives saved with non-default compression settings by this library.
+    pub fn entry_compression(&self, name: &str) -> Option<FileCompression> {
+        let ent = self.entry(name).ok()?;
+        self.compression.get(&ent.crc).copied()
+    }
+
+    /// Extract a file from the archive by the CRC of its name
+    /// If several files share the CRC the first one in the archive is returned.
+    pub fn get_by_crc(&self, crc: u32) -> Result<Vec<u8>, ArchiveError> {
+        match ReadableArchive::find_crc(&self.entries, crc) {
+            Some(ent) => ReadableArchive::inflate_file_entry(&self.data[..], ent),
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+
+    /// Extract a file from the archive giving up if it takes longer than the budget
+    /// The deadline is checked between blocks so protects against pathologically large files.
+    pub fn get_with_budget(&self, name: &str, budget: Duration) -> Result<Vec<u8>, ArchiveError> {
+        let deadline = Instant::now() + budget;
+        let ent = self.entry(name)?;
+
+        let mut ret = Vec::with_capacity(ent.size);
+        for block in ent.blocks.iter() {
+            if Instant::now() > deadline {
+                return Err(ArchiveError::LimitExceeded);
+            }
+
+            ret.extend_from_slice(&ReadableArchive::inflate_block(&self.data[..], block)?);
+        }
+
+        Ok(ret)
+    }
+
+    /// Open a file in the archive for streaming reads
+    pub fn open_entry(&self, name: &str) -> Result<EntryReader<'_>, ArchiveError> {
+        Ok(self.reader(self.entry(name)?))
+    }
+
+    /// Iterate the name of every file along with a reader that inflates it on demand
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            archive: self,
+            files: self.files.values(),
+        }
+    }
+
+    /// Iterate the decompressed contents of a file one block at a time
+    pub fn blocks(
+        &self,
+        name: &str,
+    ) -> Result<impl Iterator<Item = Result<Vec<u8>, ArchiveError>> + '_, ArchiveError> {
+        let ent = self.entry(name)?;
+
+        Ok(ent
+            .blocks
+            .iter()
+            .map(|block| ReadableArchive::inflate_block(&self.data[..], block)))
+    }
+
+    /// Get the still compressed data and inflated length of each block of a file
+    pub(crate) fn compressed_blocks(
+        &self,
+        name: &str,
+    ) -> Result<impl Iterator<Item = (&[u8], usize)> + '_, ArchiveError> {
+        let ent = self.entry(name)?;
+
+        Ok(ent.blocks.iter().map(|block| {
+            (
+                &self.data[block.offset..(block.offset + block.deflate_length)],
+                block.inflate_length,
+            )
+        }))
+    }
+
+    /// Get a copy of the still compressed blocks of a file
+    /// Pass it to `WritableArchive::set_raw` to copy the file without recompressing it.
+    pub fn raw_blocks(&self, name: &str) -> Result<RawFile, ArchiveError> {
+        let blocks = self
+            .compressed_blocks(name)?
+            .map(|(data, inflate_length)| RawBlock {
+                data: data.to_vec(),
+                inflate_length,
+            })
+            .collect();
+
+        Ok(RawFile {
+            blocks,
+            compression: self.entry_compression(name),
+        })
+    }
+
+    /// Get the offset and length of every block in the archive sorted by offset
+    /// Each range includes the block's 8 byte header so together they cover all file data.
+    pub fn block_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ret: Vec<(usize, usize)> = self
+            .entries
+            .iter()
+            .flat_map(|f| f.blocks.iter())
+            .map(|block| (block.offset - 8, block.deflate_length + 8))
+            .collect();
+
+        // files can share blocks so each range is only listed once
+        ret.sort_unstable();
+        ret.dedup();
+        ret
+    }
+
+    /// Get the names and compressed sizes of the n files taking up the most space
+    /// Sorted largest first with ties broken by name.
+    pub fn top_by_compressed_size(&self, n: usize) -> Vec<(String, usize)> {
+        let mut ret: Vec<(String, usize)> = self
+            .entries()
+            .into_iter()
+            .map(|e| (e.name, e.compressed_size))
+            .collect();
+
+        ret.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ret.truncate(n);
+        ret
+    }
+
+    /// Hand the name and decompressed contents of every file to a callback in name order
+    /// Meant for loading an archive into a key-value store without depending on any one store.
+    pub fn export_kv<F>(&self, mut put: F) -> Result<(), ArchiveError>
+    where
+        F: FnMut(&str, &[u8]),
+    {
+        let mut files: Vec<(&str, &ArchiveFile)> = self.named_entries().collect();
+        files.sort_unstable_by_key(|(name, _)| *name);
+
+        let mut data = Vec::new();
+        for (name, entry) in files {
+            ReadableArchive::inflate_file_entry_into(&self.data[..], entry, &mut data)?;
+            put(name, &data);
+        }
+
+        Ok(())
+    }
+
+    /// Get the decompressed bytes of the filename table
+    /// Useful for inspecting the exact on-disk name encoding.
+    pub fn raw_filename_table(&self) -> Result<Vec<u8>, ArchiveError> {
+        self.get_by_crc(FILENAMES_CRC_VALUE)
+    }
+
+    /// Extract a file to its in archive path underneath a root directory
+    /// Any directories in the file's name are created as needed and names that would be
+    /// written outside of the root are rejected.
+    pub fn extract_preserving_path(&self, name: &str, root: &Path) -> Result<(), ArchiveError> {
+        let path = prepare_extract_path(root, name)?;
+        write(path, self.get(name)?)?;
+        Ok(())
+    }
+
+    /// Extract every file to its in archive path underneath a root directory
+    pub fn extract_all(&self, root: &Path) -> Result<(), ArchiveError> {
+        self.extract_all_with_progress(root, |_, _| {})
+    }
+
+    /// Extract every file to its in archive path underneath a root directory
+    /// Progress is called with the number of files extracted so far and the total.
+    pub fn extract_all_with_progress<F>(
+        &self,
+        root: &Path,
+        mut progress: F,
+    ) -> Result<(), ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
+        let names: Vec<&str> = self.named_entries().map(|(name, _)| name).collect();
+        let mut data = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let path = prepare_extract_path(root, name)?;
+            self.get_into(name, &mut data)?;
+            write(path, &data)?;
+            progress(i + 1, names.len());
+        }
+
+        Ok(())
+    }
+
+    /// Extract every file underneath a root directory if the archive is newer than a date
+    /// Archives don't record a time per file so the footer date gates the whole archive, one
+    /// without a footer is never considered newer. Returns whether anything was extracted.
+    pub fn extract_newer_than(&self, root: &Path, date: u32) -> Result<bool, ArchiveError> {
+        if !self.is_newer_than(date) {
+            return Ok(false);
+        }
+
+        self.extract_all(root)?;
+        Ok(true)
+    }
+
+    /// Whether the footer date is after a date, false when there's no footer
+    pub fn is_newer_than(&self, date: u32) -> bool {
+        self.footer.is_some_and(|footer| footer > date)
+    }
+
+    /// Inflate every file to check its data isn't corrupt
+    /// Returns the sorted names of files that failed to inflate, whose inflated length doesn't
+    /// match the size in the directory or whose data overlaps another file's.
+    pub fn verify(&self) -> Result<Vec<String>, ArchiveError> {
+        let mut bad = self.overlapping_files();
+        let mut data = Vec::new();
+        for (name, entry) in self.named_entries() {
+            let res = ReadableArchive::inflate_file_entry_into(&self.data[..], entry, &mut data);
+            if res.is_err() || data.len() != entry.size {
+                bad.push(name.to_string());
+            }
+        }
+
+        bad.sort();
+        bad.dedup();
+        Ok(bad)
+    }
+
+    /// Find the files whose blocks overlap the blocks of another file
+    /// Files sharing the exact same data aren't reported. Entries the filename table doesn't
+    /// name are reported by their CRC. Returns the names sorted.
+    pub fn overlapping_files(&self) -> Vec<String> {
+        ReadableArchive::overlapping(&self.entries)
+    }
+
+    fn overlapping(entries: &[ArchiveFile]) -> Vec<String> {
+        let regions: Vec<(String, usize, usize)> = entries
+            .iter()
+            .map(|entry| {
+                let label = match &entry.name {
+                    Some(name) => name.clone(),
+                    None => crc_label(entry.crc),
+                };
+                let end = entry
+                    .blocks
+                    .last()
+                    .map_or(entry.offset, |block| block.offset + block.deflate_length);
+                (label, entry.offset, end)
+            })
+            .collect();
+
+        overlapping_regions(&regions)
+    }
+
+    /// Find every file whose inflated contents are exactly the given bytes
+    /// Only files of the same size are inflated and their hashes are kept so later searches
+    /// are cheap. Returns the matching names sorted.
+    pub fn find_by_content(&self, data: &[u8]) -> Result<Vec<String>, ArchiveError> {
+        let hash = content_hash(data);
+        let mut ret = Vec::new();
+        let mut buffer = Vec::new();
+        for (key, i) in &self.files {
+            let entry = &self.entries[*i];
+            if entry.size != data.len() {
+                continue;
+            }
+
+            let mut hashes = self
+                .content_hashes
+                .lock()
+                .map_err(|_| ArchiveError::Unknown)?;
+            let mut inflated = false;
+            let entry_hash = match hashes.get(i) {
+                Some(entry_hash) => *entry_hash,
+                None => {
+                    ReadableArchive::inflate_file_entry_into(&self.data[..], entry, &mut buffer)?;
+                    inflated = true;
+                    *hashes.entry(*i).or_insert(content_hash(&buffer))
+                }
+            };
+            drop(hashes);
+
+            if entry_hash != hash {
+                continue;
+            }
+
+            // hashes can collide so the contents are compared to be sure
+            if !inflated {
+                ReadableArchive::inflate_file_entry_into(&self.data[..], entry, &mut buffer)?;
+            }
+            if buffer == data {
+                ret.push(entry.name.clone().unwrap_or_else(|| key.clone()));
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Total up the sizes of every file and find the `top` largest
+    /// Only the block metadata is used so nothing is inflated.
+    pub fn summary(&self, top: usize) -> ArchiveSummary {
+        ArchiveSummary::from_entries(self.entries(), top)
+    }
+
+    /// Summarize structural problems with the archive
+    pub fn health_report(&self) -> HealthReport {
+        let resolved: HashSet<usize> = self.files.values().copied().collect();
+        let mut report = HealthReport {
+            has_footer: self.footer.is_some(),
+            ..Default::default()
+        };
+
+        let mut seen: HashMap<&[u8], usize> = HashMap::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if ReadableArchive::is_table(entry) {
+                continue;
+            }
+
+            report.file_count += 1;
+            if !resolved.contains(&i) {
+                report.unresolved_entries += 1;
+            }
+
+            let compressed: usize = entry.blocks.iter().map(|b| b.deflate_length).sum();
+            if entry.size < TINY_FILE_SIZE {
+                report.tiny_files += 1;
+            } else if compressed >= entry.size {
+                if self.entry_codec(entry) == BlockCodec::Stored {
+                    report.stored_files += 1;
+                } else {
+                    report.incompressible_files += 1;
+                }
+            }
+
+            // blocks are laid out back to back so the whole run can be compared at once
+            if let (Some(first), Some(last)) = (entry.blocks.first(), entry.blocks.last()) {
+                // entries pointing at the same blocks share them rather than duplicate them
+                let raw = &self.data[(first.offset - 8)..(last.offset + last.deflate_length)];
+                if *seen.entry(raw).or_insert(entry.offset) != entry.offset {
+                    report.duplicate_content += 1;
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Estimate the oldest client era that can read the archive
+    /// The footer isn't considered, clients that don't know about it never read past the
+    /// directory so it doesn't stop any of them reading the archive.
+    pub fn min_client_era(&self) -> ClientEra {
+        let extended = parse_header(&self.data[..]).is_ok_and(|(_, (_, extended))| extended);
+        let large_blocks = self
+            .entries
+            .iter()
+            .flat_map(|entry| entry.blocks.iter())
+            .any(|block| block.inflate_length > MAX_BLOCK_SIZE);
+        let other_codec = self
+            .entries
+            .iter()
+            .filter(|entry| !ReadableArchive::is_table(entry))
+            .any(|entry| self.entry_codec(entry) != BlockCodec::Zlib);
+
+        if other_codec {
+            ClientEra::Unsupported
+        } else if extended || large_blocks {
+            ClientEra::Modern
+        } else {
+            ClientEra::Classic
+        }
+    }
+
+    /// The codec the blocks of an entry were written with, judged by its first block
+    /// Blocks as long as their inflated length are inflated to tell stored data apart.
+    fn entry_codec(&self, entry: &ArchiveFile) -> BlockCodec {
+        if let Some(compression) = self.compression.get(&entry.crc) {
+            return compression.codec;
+        }
+
+        let block = match entry.blocks.first() {
+            Some(block) => block,
+            None => return BlockCodec::Zlib,
+        };
+        let data = &self.data[block.offset..(block.offset + block.deflate_length)];
+        if block.deflate_length != block.inflate_length {
+            return BlockCodec::detect(data);
+        }
+
+        let mut scratch = Vec::with_capacity(block.inflate_length);
+        inflate_block(data, block.inflate_length, &mut scratch)
+            .unwrap_or_else(|_| BlockCodec::detect(data))
+    }
+
+    /// Whether the archive is an `.s3d` or `.eqg` judging by the files in it
+    pub fn kind(&self) -> ArchiveKind {
+        ArchiveKind::detect(self.files.keys().map(|k| k.as_str()))
+    }
+
+    /// Get the date from the footer following the directory
+    /// Not every archive has a footer; the date is usually seconds since the Unix epoch.
+    pub fn footer_date(&self) -> Option<u32> {
+        self.footer
+    }
+
+    /// Iterate every entry with a name along with that name as it's stored in the archive
+    fn named_entries(&self) -> impl Iterator<Item = (&str, &ArchiveFile)> + '_ {
+        self.files.values().filter_map(|i| {
+            let entry = &self.entries[*i];
+            entry.name.as_deref().map(|name| (name, entry))
+        })
+    }
+
+    fn reader<'a>(&'a self, entry: &'a ArchiveFile) -> EntryReader<'a> {
+        EntryReader::new(entry.blocks.iter().map(|block| {
+            (
+                &self.data[block.offset..(block.offset + block.deflate_length)],
+                block.inflate_length,
+            )
+        }))
+    }
+
+    /// Get a file's name as it's stored in the archive, which can differ in case from the name
+    /// it was looked up by
+    pub(crate) fn stored_name<'a>(
+        &'a self,
+        in_archive_path: &'a str,
+    ) -> Result<&'a str, ArchiveError> {
+        let entry = self.entry(in_archive_path)?;
+        Ok(entry.name.as_deref().unwrap_or(in_archive_path))
+    }
+
+    fn entry(&self, in_archive_path: &str) -> Result<&ArchiveFile, ArchiveError> {
+        let in_archive_path_lower = in_archive_path.to_lowercase();
+        match self.files.get(&in_archive_path_lower) {
+            Some(i) => Ok(&self.entries[*i]),
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+
+    /// Whether an entry is one of the tables describing the archive rather than a file
+    fn is_table(entry: &ArchiveFile) -> bool {
+        entry.crc == FILENAMES_CRC_VALUE || entry.crc == COMPRESSION_TABLE_CRC_VALUE
+    }
+
+    fn find_crc(entries: &[ArchiveFile], crc: u32) -> Option<&ArchiveFile> {
+        let i = entries.partition_point(|f| f.crc < crc);
+        entries.get(i).filter(|f| f.crc == crc)
+    }
+
+    fn do_parse<'a>(
+        input: &'a [u8],
+        options: &OpenOptions,
+    ) -> IResult<&'a [u8], ParsedArchive, ArchiveError> {
+        let (rest, (mut entries, outside)) = ReadableArchive::parse_directory(input, options)?;
+
+        let mut filenames = Vec::new();
+        if let Some(f) = ReadableArchive::find_crc(&entries, FILENAMES_CRC_VALUE) {
+            match ReadableArchive::inflate_file_entry(input, f) {
+                Ok(data) => match parse_encoded_filenames(&data[..], options.strict_filenames) {
+                    Ok(names) => filenames = names,
+                    Err(e @ ArchiveError::Utf8(_)) => return Err(Error(e)),
+                    Err(_) => {}
+                },
+                Err(e) => return Err(Error(e)),
+            }
+        }
+
+        check_entries_inside(&filenames, &outside)?;
+
+        let mut ret = HashMap::new();
+        for (filename, encoding, i) in
+            resolve_filenames(&filenames, &entries, |f| (f.crc, f.offset as u64))
+        {
+            ret.insert(filename.to_lowercase(), i);
+            entries[i].name = Some(filename);
+            entries[i].encoding = encoding;
+        }
+        debug!(
+            "Filename table names {} of {} entries",
+            ret.len(),
+            entries.len()
+        );
+
+        // the compression table is only informational so a broken one doesn't stop the open
+        let mut compression = HashMap::new();
+        if let Some(f) = ReadableArchive::find_crc(&entries, COMPRESSION_TABLE_CRC_VALUE) {
+            match ReadableArchive::inflate_file_entry(input, f)
+                .and_then(|data| parse_compression_table(&data[..]))
+            {
+                Ok(table) => compression = table,
+                Err(e) => warn!("Unable to read the compression table: {}", e),
+            }
+        }
+
+        let footer = parse_footer(rest);
+        Ok((input, (ret, entries, compression, footer)))
+    }
+
+    /// Parse the directory and the block layout of every file
+    /// The files are returned sorted by CRC and then offset along with the entries whose data
+    /// is outside the data region and the data after the directory.
+    fn parse_directory<'a>(
+        input: &'a [u8],
+        options: &OpenOptions,
+    ) -> IResult<&'a [u8], (Vec<ArchiveFile>, Vec<DirectoryEntry>), ArchiveError> {
+        let (_, (dir_offset, extended)) = parse_header_with_options(input, options)?;
+        debug!(
+            "Reading a{} archive with the directory at offset {}",
+            if extended { "n extended" } else { " standard" },
+            dir_offset
+        );
+        let current = slice_from(input, dir_offset, "Directory")?;
+        let (rest, directory_entries) = parse_directory_entries(current, extended, options)?;
+
+        let (parsed, outside) = parse_entry_blocks(
+            input,
+            directory_entries,
+            dir_offset,
+            extended,
+            ReadableArchive::parse_pfs_file_blocks,
+        )?;
+
+        let mut parsed_files: Vec<ArchiveFile> = parsed
+            .into_iter()
+            .map(|(entry, blocks)| ArchiveFile {
+                name: None,
+                encoding: NameEncoding::Utf8,
+                crc: entry.crc,
+                offset: entry.offset as usize,
+                size: entry.size as usize,
+                blocks,
+            })
+            .collect();
+
+        parsed_files.sort_by_key(|f| (f.crc, f.offset));
+        Ok((rest, (parsed_files, outside)))
     }
 
     fn parse_pfs_file_blocks(
@@ -108,16 +795,22 @@ impl ReadableArchive {
         let mut inflate: usize = 0;
 
         while inflate < size {
-            let current = &input[position..];
+            let current = next_block(input, position, offset)?;
             let (_, block) = ReadableArchive::parse_pfs_file_block(current, offset + position)?;
+            check_block_progress(block.inflate_length, offset + position)?;
 
-            inflate += block.inflate_length;
-            position += block.deflate_length;
-            position += 8;
+            inflate = inflate.saturating_add(block.inflate_length);
+            position = advance_block(position, block.deflate_length, offset)?;
 
             ret.push(block);
         }
 
+        trace!(
+            "File at offset {} inflates to {} bytes from {} blocks",
+            offset,
+            size,
+            ret.len()
+        );
         Ok((input, ret))
     }
 
@@ -140,32 +833,99 @@ impl ReadableArchive {
     }
 
     fn inflate_file_entry(data: &[u8], entry: &ArchiveFile) -> Result<Vec<u8>, ArchiveError> {
-        let mut ret = Vec::with_capacity(entry.size);
+        let mut ret = Vec::new();
+        ReadableArchive::inflate_file_entry_into(data, entry, &mut ret)?;
+        Ok(ret)
+    }
 
-        for block in entry.blocks.iter() {
-            let mut temp_buffer = vec![0; block.inflate_length + 1];
-            let mut decoder =
-                ZlibDecoder::new(&data[block.offset..(block.offset + block.deflate_length)]);
-            let sz = decoder.read(&mut temp_buffer)?;
+    fn inflate_file_entry_into(
+        data: &[u8],
+        entry: &ArchiveFile,
+        out: &mut Vec<u8>,
+    ) -> Result<(), ArchiveError> {
+        out.clear();
+        out.reserve(entry.size);
 
-            ret.extend_from_slice(&temp_buffer[0..sz]);
+        for block in entry.blocks.iter() {
+            inflate_block(
+                &data[block.offset..(block.offset + block.deflate_length)],
+                block.inflate_length,
+                out,
+            )?;
         }
 
-        Ok(ret)
+        Ok(())
+    }
+
+    fn inflate_block(data: &[u8], block: &ArchiveFileBlock) -> Result<Vec<u8>, ArchiveError> {
+        let mut temp_buffer = Vec::with_capacity(block.inflate_length);
+        inflate_block(
+            &data[block.offset..(block.offset + block.deflate_length)],
+            block.inflate_length,
+            &mut temp_buffer,
+        )?;
+
+        Ok(temp_buffer)
+    }
+}
+
+fn content_hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TryFrom<&[u8]> for ReadableArchive {
+    type Error = ArchiveError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(input)?;
+        Ok(archive)
+    }
+}
+
+impl TryFrom<Vec<u8>> for ReadableArchive {
+    type Error = ArchiveError;
+
+    /// Takes ownership of the bytes rather than copying them
+    fn try_from(input: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut archive = ReadableArchive::new();
+        archive.data = Backing::Owned(input);
+        archive.parse_data(&OpenOptions::default())?;
+        Ok(archive)
+    }
+}
+
+impl Default for ReadableArchive {
+    fn default() -> Self {
+        ReadableArchive::new()
     }
 }
 
 impl IArchive for ReadableArchive {
     fn new() -> Self {
         ReadableArchive {
-            data: Vec::new(),
+            data: Backing::default(),
             files: HashMap::new(),
+            entries: Vec::new(),
+            compression: HashMap::new(),
+            footer: None,
+            content_hashes: Mutex::new(HashMap::new()),
         }
     }
 
     fn close(&mut self) {
-        self.data.clear();
+        self.data = Backing::default();
         self.files.clear();
+        self.entries.clear();
+        self.compression.clear();
+        self.footer = None;
+        self.content_hashes = Mutex::new(HashMap::new());
+    }
+
+    fn len(&self) -> usize {
+        self.files.len()
     }
 }
 
@@ -174,39 +934,33 @@ impl IReadableArchive for ReadableArchive {
     where
         T: AsRef<[u8]>,
     {
-        let input_ref = input.as_ref();
-        self.close();
-
-        self.data.extend_from_slice(input_ref);
-        match ReadableArchive::do_parse(&self.data[..]) {
-            Ok((_, files)) => {
-                self.files = files;
-                Ok(())
-            }
-            Err(e) => {
-                if let Error(ae) = e {
-                    Err(ae)
-                } else {
-                    Err(ArchiveError::Unknown)
-                }
-            }
-        }
+        self.open_from_bytes_with_options(input, &OpenOptions::default())
     }
 
     fn open_file(&mut self, filename: &str) -> Result<(), ArchiveError> {
-        let data = std::fs::read(filename)?;
-        self.open_from_bytes(&data[..])
+        self.open_file_with_options(filename, &OpenOptions::default())
+    }
+
+    fn open_from_reader<R>(&mut self, mut reader: R) -> Result<(), ArchiveError>
+    where
+        R: Read,
+    {
+        self.close();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.data = Backing::Owned(data);
+        self.parse_data(&OpenOptions::default())
     }
 
     fn get(&self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError> {
-        let in_archive_path_lower = in_archive_path.to_lowercase();
-        match self.files.get(&in_archive_path_lower) {
-            Some(ent) => {
-                let res = ReadableArchive::inflate_file_entry(&self.data[..], ent)?;
-                Ok(res)
-            }
-            None => Err(ArchiveError::SrcFileNotFound),
-        }
+        let mut ret = Vec::new();
+        self.get_into(in_archive_path, &mut ret)?;
+        Ok(ret)
+    }
+
+    fn get_into(&self, in_archive_path: &str, out: &mut Vec<u8>) -> Result<(), ArchiveError> {
+        let ent = self.entry(in_archive_path)?;
+        ReadableArchive::inflate_file_entry_into(&self.data[..], ent, out)
     }
 
     fn exists(&self, in_archive_path: &str) -> Result<bool, ArchiveError> {
@@ -214,16 +968,928 @@ impl IReadableArchive for ReadableArchive {
         Ok(self.files.contains_key(&in_archive_path_lower))
     }
 
+    fn name_encoding(&self, in_archive_path: &str) -> Result<NameEncoding, ArchiveError> {
+        Ok(self.entry(in_archive_path)?.encoding)
+    }
+
     fn search(&self, search_regex: &str) -> Result<Vec<String>, ArchiveError> {
         let regex = Regex::new(search_regex)?;
+        Ok(self.search_compiled(&regex))
+    }
+
+    fn search_compiled(&self, regex: &Regex) -> Vec<String> {
         let mut ret = Vec::new();
 
-        for filename in self.files.keys() {
-            if regex.is_match(filename) {
-                ret.push(filename.clone());
+        for (key, i) in &self.files {
+            if let Some(name) = &self.entries[*i].name {
+                if regex.is_match(key) || regex.is_match(name) {
+                    ret.push(name.clone());
+                }
             }
         }
 
-        Ok(ret)
+        ret.sort();
+        ret
+    }
+
+    fn entries(&self) -> Vec<ArchiveEntry> {
+        let mut ret: Vec<ArchiveEntry> = self
+            .named_entries()
+            .map(|(name, f)| ArchiveEntry {
+                name: name.to_string(),
+                compressed_size: f.blocks.iter().map(|b| b.deflate_length).sum(),
+                uncompressed_size: f.size,
+                block_count: f.blocks.len(),
+            })
+            .collect();
+
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::archive_error::ArchiveError;
+    use crate::archive::archive_trait::{IArchive, IReadableArchive, IWritableArchive};
+    use crate::archive::pfs::backing::Backing;
+    use crate::archive::pfs::codec::BlockCodec;
+    use crate::archive::pfs::common::{parse_filenames, pfs_crc};
+    use crate::archive::pfs::constants::{FILENAMES_CRC_VALUE, PFS_VERSION_EXTENDED};
+    use crate::archive::pfs::health::ClientEra;
+    use crate::archive::pfs::options::OpenOptions;
+    use crate::archive::pfs::readable::ReadableArchive;
+    use crate::archive::pfs::readwrite::ReadWriteArchive;
+    use crate::archive::pfs::testing::{
+        build_archive, corrupt_block, flip_version, overlap_block, read_u32,
+    };
+    use crate::archive::pfs::writable::WritableArchive;
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_dir_all};
+    use std::io::{copy, Cursor, Read};
+    use std::time::Duration;
+
+    #[test]
+    fn open_crc_only_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        // break the inflate length of the filename table so inflating it would fail
+        let dir_offset = read_u32(&data, 0) as usize;
+        let dir_count = read_u32(&data, dir_offset) as usize;
+        for i in 0..dir_count {
+            let entry = dir_offset + 4 + i * 12;
+            if read_u32(&data, entry) == FILENAMES_CRC_VALUE {
+                let offset = read_u32(&data, entry + 4) as usize;
+                data[offset + 4..offset + 8].copy_from_slice(&0xFFFFu32.to_le_bytes());
+            }
+        }
+
+        let mut archive = ReadableArchive::new();
+        assert!(archive.open_from_bytes(&data[..]).is_err());
+        archive.open_crc_only(&data[..]).unwrap();
+
+        let crc = pfs_crc("test.txt");
+
+        let entries: Vec<(u32, usize)> = archive.crc_entries().collect();
+        assert_eq!(entries, vec![(crc, 11)]);
+        assert_eq!(archive.get_by_crc(crc).unwrap(), b"hello world");
+        assert!(!archive.exists("test.txt").unwrap());
+    }
+
+    #[test]
+    fn extended_format_roundtrip_test() {
+        let mut writable = WritableArchive::new();
+        writable.set_extended_format(true);
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        assert_eq!(read_u32(&data, 8), PFS_VERSION_EXTENDED);
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert_eq!(archive.get("big.wld").unwrap(), vec![7u8; 20000]);
+    }
+
+    #[test]
+    fn summary_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![1u8; 30000]).unwrap();
+        writable.set("medium.wld", vec![2u8; 20000]).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let summary = archive.summary(2);
+        assert_eq!(summary.file_count, 3);
+        assert_eq!(summary.uncompressed_size, 50005);
+        assert_eq!(
+            summary.compressed_size,
+            archive
+                .entries()
+                .iter()
+                .map(|e| e.compressed_size)
+                .sum::<usize>()
+        );
+        assert!(summary.ratio() < 0.1);
+        let largest: Vec<&str> = summary.largest.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(largest, vec!["big.wld", "medium.wld"]);
+
+        assert_eq!(ReadableArchive::new().summary(10).ratio(), 1.0);
+    }
+
+    #[test]
+    fn verify_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("good.wld", vec![1u8; 20000]).unwrap();
+        writable.set("bad.wld", vec![2u8; 20000]).unwrap();
+        writable.set("empty.txt", b"").unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        assert!(archive.verify().unwrap().is_empty());
+
+        archive
+            .open_from_bytes(corrupt_block(&data, pfs_crc("bad.wld")))
+            .unwrap();
+        assert_eq!(archive.verify().unwrap(), vec!["bad.wld"]);
+
+        archive
+            .open_from_bytes(overlap_block(
+                &data,
+                pfs_crc("small.txt"),
+                pfs_crc("good.wld"),
+            ))
+            .unwrap();
+        assert_eq!(archive.verify().unwrap(), vec!["good.wld", "small.txt"]);
+    }
+
+    #[test]
+    fn check_overlaps_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+        writable.set("copy.wld", vec![7u8; 20000]).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        let options = OpenOptions {
+            check_overlaps: true,
+            ..OpenOptions::default()
+        };
+
+        // identical files share their data which isn't an overlap
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes_with_options(&data, &options)
+            .unwrap();
+        assert!(archive.overlapping_files().is_empty());
+
+        let data = overlap_block(&data, pfs_crc("small.txt"), pfs_crc("big.wld"));
+        archive.open_from_bytes(&data).unwrap();
+        match archive.open_from_bytes_with_options(&data, &options) {
+            Err(ArchiveError::OverlappingFiles(names)) => {
+                assert_eq!(names, vec!["big.wld", "copy.wld", "small.txt"])
+            }
+            res => panic!("expected overlapping files, got {:?}", res.err()),
+        }
+        match ReadWriteArchive::new().open_from_bytes_with_options(&data, &options) {
+            Err(ArchiveError::OverlappingFiles(names)) => {
+                assert_eq!(names, vec!["big.wld", "copy.wld", "small.txt"])
+            }
+            res => panic!("expected overlapping files, got {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn extract_newer_than_test() {
+        let root = temp_dir().join("zu_common_extract_newer_than_test");
+        let _ = remove_dir_all(&root);
+
+        let mut writable = WritableArchive::new();
+        writable.set("zone.wld", b"zone").unwrap();
+        writable.set_footer_date(500);
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        assert!(!archive.extract_newer_than(&root, 500).unwrap());
+        assert!(!root.join("zone.wld").exists());
+        assert!(archive.extract_newer_than(&root, 499).unwrap());
+        assert_eq!(read(root.join("zone.wld")).unwrap(), b"zone");
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn min_client_era_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.min_client_era(), ClientEra::Classic);
+
+        // the footer doesn't change which clients can read it
+        writable.set_footer_date(1);
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.min_client_era(), ClientEra::Classic);
+
+        writable.set_extended_format(true);
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.min_client_era(), ClientEra::Modern);
+
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+        writable.set_stored("sound.wav", vec![3u8; 1000]).unwrap();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.min_client_era(), ClientEra::Unsupported);
+        // without the compression table the stored blocks are told apart by not inflating
+        archive.compression.clear();
+        assert_eq!(archive.min_client_era(), ClientEra::Unsupported);
+
+        let mut writable = WritableArchive::new();
+        writable.set_block_codec(BlockCodec::RawDeflate);
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.min_client_era(), ClientEra::Unsupported);
+    }
+
+    #[test]
+    fn raw_filename_table_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("one.txt", b"1").unwrap();
+        writable.set("two.txt", b"2").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut names = parse_filenames(&archive.raw_filename_table().unwrap()[..]).unwrap();
+        let mut found = archive.search(".*").unwrap();
+        names.sort();
+        found.sort();
+        assert_eq!(names, found);
+
+        archive.close();
+        assert!(archive.raw_filename_table().is_err());
+    }
+
+    #[test]
+    fn extract_preserving_path_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("a/b/c.txt", b"nested").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let root = temp_dir().join("zu_common_extract_preserving_path_test");
+        let _ = remove_dir_all(&root);
+        archive.extract_preserving_path("a/b/c.txt", &root).unwrap();
+        assert!(matches!(
+            archive.extract_preserving_path("../c.txt", &root),
+            Err(ArchiveError::UnsafePath(_))
+        ));
+
+        assert_eq!(
+            read(root.join("a").join("b").join("c.txt")).unwrap(),
+            b"nested"
+        );
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn find_by_content_test() {
+        let texture: Vec<u8> = (0..20000u32).map(|i| (i % 253) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("rock.bmp", &texture).unwrap();
+        writable.set("copy/rock.bmp", &texture).unwrap();
+        writable.set("dirt.bmp", vec![0u8; texture.len()]).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        let archive = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(
+                archive.find_by_content(&texture).unwrap(),
+                vec!["copy/rock.bmp", "rock.bmp"]
+            );
+        }
+        assert_eq!(
+            archive.find_by_content(b"small").unwrap(),
+            vec!["small.txt"]
+        );
+        assert!(archive.find_by_content(b"other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn try_from_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("one.txt", b"one").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let archive = ReadableArchive::try_from(&data[..]).unwrap();
+        assert_eq!(archive.get("one.txt").unwrap(), b"one");
+
+        let archive = ReadableArchive::try_from(data).unwrap();
+        assert_eq!(archive.get("one.txt").unwrap(), b"one");
+
+        assert!(ReadableArchive::try_from(&b"not an archive"[..]).is_err());
+    }
+
+    #[test]
+    fn progress_test() {
+        let mut writable = WritableArchive::new();
+        for i in 0..100 {
+            writable
+                .set(&format!("dir/{}.txt", i), [i as u8; 10])
+                .unwrap();
+        }
+
+        let mut calls = Vec::new();
+        let data = writable
+            .save_to_bytes_with_progress(|done, total| calls.push((done, total)))
+            .unwrap();
+        assert_eq!(calls.last(), Some(&(100, 100)));
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(data).unwrap();
+
+        let root = temp_dir().join("zu_common_progress_test");
+        let _ = remove_dir_all(&root);
+        let mut calls = Vec::new();
+        archive
+            .extract_all_with_progress(&root, |done, total| calls.push((done, total)))
+            .unwrap();
+        assert_eq!(calls.len(), 100);
+        assert_eq!(calls.last(), Some(&(100, 100)));
+        assert_eq!(read(root.join("dir").join("42.txt")).unwrap(), [42u8; 10]);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn inflated_directory_count_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        let dir_offset = read_u32(&data, 0) as usize;
+        let dir_count = read_u32(&data, dir_offset);
+        data[dir_offset..dir_offset + 4].copy_from_slice(&(dir_count + 5).to_le_bytes());
+
+        let mut archive = ReadableArchive::new();
+        assert!(matches!(
+            archive.open_from_bytes(&data[..]),
+            Err(ArchiveError::Parse(_))
+        ));
+
+        let options = OpenOptions {
+            repair_directory: true,
+            ..Default::default()
+        };
+        archive
+            .open_from_bytes_with_options(&data[..], &options)
+            .unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn truncated_archive_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("one.txt", vec![1u8; 10000]).unwrap();
+        writable.set("two.txt", b"two").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        // the footer is optional so only cutting off more than it fails
+        let mut archive = ReadableArchive::new();
+        for len in 0..data.len() - 9 {
+            assert!(archive.open_from_bytes(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn not_pfs_test() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend((0..100u32).map(|i| (i * 31 % 251) as u8));
+        for data in [&png[..], b"hello world, this isn't an archive"] {
+            assert!(matches!(
+                ReadableArchive::new().open_from_bytes(data),
+                Err(ArchiveError::NotPfs)
+            ));
+            assert!(matches!(
+                ReadWriteArchive::new().open_from_bytes(data),
+                Err(ArchiveError::NotPfs)
+            ));
+        }
+
+        // a truncated archive is still a corrupt archive rather than some other file
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        for len in 0..12 {
+            assert!(!matches!(
+                ReadableArchive::new().open_from_bytes(&data[..len]),
+                Ok(_) | Err(ArchiveError::NotPfs)
+            ));
+        }
+    }
+
+    #[test]
+    fn blocks_test() {
+        let contents: Vec<u8> = (0..30000u32).map(|i| (i % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &contents).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut joined = Vec::new();
+        let mut count = 0;
+        for block in archive.blocks("big.wld").unwrap() {
+            joined.extend_from_slice(&block.unwrap());
+            count += 1;
+        }
+
+        assert_eq!(count, 4);
+        assert_eq!(joined, archive.get("big.wld").unwrap());
+        assert!(archive.blocks("missing.wld").is_err());
+    }
+
+    #[test]
+    fn inflate_length_mismatch_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        // claim the only block of test.txt inflates to more bytes than it does
+        let dir_offset = read_u32(&data, 0) as usize;
+        let dir_count = read_u32(&data, dir_offset) as usize;
+        for i in 0..dir_count {
+            let entry = dir_offset + 4 + i * 12;
+            if read_u32(&data, entry) != FILENAMES_CRC_VALUE {
+                let offset = read_u32(&data, entry + 4) as usize;
+                data[offset + 4..offset + 8].copy_from_slice(&20u32.to_le_bytes());
+            }
+        }
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::Decompression)
+        ));
+    }
+
+    #[test]
+    fn offset_into_directory_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        let dir_offset = read_u32(&data, 0);
+        let first_entry = dir_offset as usize + 4;
+        data[first_entry + 4..first_entry + 8].copy_from_slice(&(dir_offset + 4).to_le_bytes());
+
+        let mut archive = ReadableArchive::new();
+        assert!(matches!(
+            archive.open_from_bytes(&data[..]),
+            Err(ArchiveError::FilesOutsideData(_))
+        ));
+    }
+
+    #[test]
+    fn get_with_budget_test() {
+        let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &contents).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            archive.get_with_budget("big.wld", Duration::ZERO),
+            Err(ArchiveError::LimitExceeded)
+        ));
+        assert_eq!(
+            archive
+                .get_with_budget("big.wld", Duration::from_secs(60))
+                .unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn corrupt_block_decompression_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", vec![3u8; 1000]).unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        let dir_offset = read_u32(&data, 0) as usize;
+        let dir_count = read_u32(&data, dir_offset) as usize;
+        for i in 0..dir_count {
+            let entry = dir_offset + 4 + i * 12;
+            if read_u32(&data, entry) != FILENAMES_CRC_VALUE {
+                let offset = read_u32(&data, entry + 4) as usize;
+                let deflate_length = read_u32(&data, offset) as usize;
+                for b in &mut data[offset + 8..offset + 8 + deflate_length] {
+                    *b = 0xAB;
+                }
+            }
+        }
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::Decompression)
+        ));
+    }
+
+    #[test]
+    fn entries_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![1u8; 20000]).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let entries = archive.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "big.wld");
+        assert_eq!(entries[0].uncompressed_size, 20000);
+        assert_eq!(entries[0].block_count, 3);
+        assert!(entries[0].compressed_size < 20000);
+        assert_eq!(entries[1].name, "small.txt");
+        assert_eq!(entries[1].uncompressed_size, 5);
+        assert_eq!(entries[1].block_count, 1);
+    }
+
+    #[test]
+    fn get_into_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("one.txt", b"first file").unwrap();
+        writable.set("two.txt", b"2").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        archive.get_into("one.txt", &mut buffer).unwrap();
+        assert_eq!(buffer, b"first file");
+        archive.get_into("two.txt", &mut buffer).unwrap();
+        assert_eq!(buffer, b"2");
+    }
+
+    #[test]
+    fn open_entry_test() {
+        let contents: Vec<u8> = (0..50000u32).map(|i| (i % 199) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &contents).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut reader = archive.open_entry("big.wld").unwrap();
+        let mut streamed = Vec::new();
+        copy(&mut reader, &mut streamed).unwrap();
+        assert_eq!(streamed, archive.get("big.wld").unwrap());
+    }
+
+    #[test]
+    fn health_report_test() {
+        let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog "
+            .iter()
+            .copied()
+            .cycle()
+            .take(2000)
+            .collect();
+
+        let mut writable = WritableArchive::new();
+        writable.set("clean.txt", &text).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let report = archive.health_report();
+        assert_eq!(report.file_count, 1);
+        assert!(report.is_healthy());
+        assert!(report.has_footer);
+
+        // a small file grows when compressed but isn't a problem
+        let mut writable = WritableArchive::new();
+        writable.set("small.txt", b"hello world").unwrap();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        let report = archive.health_report();
+        assert_eq!(report.tiny_files, 1);
+        assert_eq!(report.incompressible_files, 0);
+        assert!(report.is_healthy());
+
+        // nor is a file stored uncompressed on purpose
+        let mut writable = WritableArchive::new();
+        writable.set("clean.txt", &text).unwrap();
+        writable.set_stored("sound.wav", &text).unwrap();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        let report = archive.health_report();
+        assert_eq!(report.stored_files, 1);
+        assert_eq!(report.incompressible_files, 0);
+        assert!(report.is_healthy());
+
+        let mut seed: u32 = 12345;
+        let noise: Vec<u8> = (0..2000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut lost = text.clone();
+        lost.reverse();
+
+        // built without deduplication so both copies of the text are written
+        let mut data = build_archive(&[
+            ("dup1.txt", &text),
+            ("dup2.txt", &text),
+            ("noise.bin", &noise),
+            ("tiny.txt", b"hi!"),
+            ("lost.txt", &lost),
+        ]);
+
+        // change the crc of one entry so its name no longer resolves
+        let dir_offset = read_u32(&data, 0) as usize;
+        let count = read_u32(&data, dir_offset) as usize;
+        let lost_crc = pfs_crc("lost.txt");
+        for i in 0..count {
+            let pos = dir_offset + 4 + i * 12;
+            if read_u32(&data, pos) == lost_crc {
+                data[pos..pos + 4].copy_from_slice(&1u32.to_le_bytes());
+            }
+        }
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(data).unwrap();
+
+        let report = archive.health_report();
+        assert_eq!(report.file_count, 5);
+        assert_eq!(report.unresolved_entries, 1);
+        assert_eq!(report.duplicate_content, 1);
+        assert_eq!(report.incompressible_files, 1);
+        assert_eq!(report.tiny_files, 1);
+        assert!(!report.is_healthy());
+    }
+
+    #[test]
+    fn block_ranges_test() {
+        let contents: Vec<u8> = (0..30000u32).map(|i| (i % 211) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &contents).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        let dir_offset = read_u32(&data, 0) as usize;
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+
+        // four blocks for big.wld, one for small.txt and one for the filename table
+        let ranges = archive.block_ranges();
+        assert_eq!(ranges.len(), 6);
+
+        let mut expected = 12;
+        for (offset, len) in ranges {
+            assert_eq!(offset, expected);
+            assert_eq!(read_u32(&data, offset) as usize + 8, len);
+            expected += len;
+        }
+        assert_eq!(expected, dir_offset);
+    }
+
+    #[test]
+    fn open_from_reader_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_reader(Cursor::new(&data)).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+
+        assert!(archive.open_from_reader(Cursor::new(&data[..10])).is_err());
+    }
+
+    #[test]
+    fn export_kv_test() {
+        let big: Vec<u8> = (0..20000u32).map(|i| (i % 7) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &big).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        writable.set("empty.txt", b"").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+        archive
+            .export_kv(|name, data| {
+                store.insert(name.to_string(), data.to_vec());
+            })
+            .unwrap();
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(store["big.wld"], big);
+        assert_eq!(store["small.txt"], b"small");
+        assert!(store["empty.txt"].is_empty());
+    }
+
+    #[test]
+    fn open_file_test() {
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 31) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("test.wld", &contents).unwrap();
+
+        let path = temp_dir().join("zu_common_open_file_test.s3d");
+        writable.save_to_file(path.to_str().unwrap()).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(archive.get("test.wld").unwrap(), contents);
+
+        archive.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_version_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+
+        // both known versions open without allowing unknown ones
+        let mut archive = ReadableArchive::new();
+        writable.set_extended_format(true);
+        let data = writable.save_to_bytes().unwrap();
+        assert_eq!(read_u32(&data, 8), PFS_VERSION_EXTENDED);
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+
+        writable.set_extended_format(false);
+        let data = writable.save_to_bytes().unwrap();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+
+        let data = flip_version(&data);
+        assert!(matches!(
+            archive.open_from_bytes(&data[..]),
+            Err(ArchiveError::WrongVersion {
+                version: 0xdeadbeef
+            })
+        ));
+
+        let options = OpenOptions {
+            allow_unknown_version: true,
+            ..Default::default()
+        };
+        archive
+            .open_from_bytes_with_options(&data[..], &options)
+            .unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite
+            .open_from_bytes_with_options(&data[..], &options)
+            .unwrap();
+        assert_eq!(readwrite.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn scan_for_header_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+
+        let mut data = vec![0xEF, 0xBB, 0xBF, b' ', b'\n'];
+        data.extend_from_slice(&writable.save_to_bytes().unwrap());
+
+        let mut archive = ReadableArchive::new();
+        assert!(matches!(
+            archive.open_from_bytes(&data),
+            Err(ArchiveError::NotPfs)
+        ));
+
+        let options = OpenOptions {
+            scan_for_header: true,
+            ..Default::default()
+        };
+        archive
+            .open_from_bytes_with_options(&data, &options)
+            .unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+
+        let mut archive = ReadWriteArchive::new();
+        archive
+            .open_from_bytes_with_options(&data, &options)
+            .unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn top_by_compressed_size_test() {
+        let mut seed: u32 = 99;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect()
+        };
+
+        let mut writable = WritableArchive::new();
+        writable.set("medium.bmp", noise(5000)).unwrap();
+        writable.set("large.bmp", noise(20000)).unwrap();
+        writable.set("small.bmp", noise(100)).unwrap();
+        writable.set("zeros.bmp", vec![0u8; 50000]).unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let top = archive.top_by_compressed_size(2);
+        let names: Vec<&str> = top.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["large.bmp", "medium.bmp"]);
+        assert!(top[0].1 > top[1].1);
+
+        let all = archive.top_by_compressed_size(10);
+        assert_eq!(all.len(), 4);
+        assert!(all.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn from_static_test() {
+        static FIXTURE: &[u8] =
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/small.s3d"));
+
+        let archive = ReadableArchive::from_static(FIXTURE).unwrap();
+        assert!(matches!(archive.data, Backing::Static(data) if data.as_ptr() == FIXTURE.as_ptr()));
+        assert_eq!(archive.get("hello.txt").unwrap(), b"hello world");
+        assert_eq!(archive.get("zeros.bin").unwrap(), vec![0u8; 10000]);
+    }
+
+    #[test]
+    fn iter_test() {
+        let big: Vec<u8> = (0..20000u32).map(|i| (i % 7) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &big).unwrap();
+        writable.set("Small.txt", b"small").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut seen = Vec::new();
+        for (name, mut reader) in &archive {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, archive.get(name).unwrap());
+            seen.push(name);
+        }
+
+        seen.sort();
+        assert_eq!(seen, vec!["Small.txt", "big.wld"]);
+        assert_eq!(archive.iter().count(), 2);
     }
 }
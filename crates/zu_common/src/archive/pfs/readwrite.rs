@@ -1,107 +1,418 @@
 use crate::archive::{
+    archive_entry::ArchiveEntry,
     archive_error::ArchiveError,
     archive_trait::{IArchive, IReadableArchive, IWritableArchive},
-    pfs::common::parse_filenames,
+    merge::{merge_into, MergeSummary, MergeTarget},
+    pfs::codec::{
+        inflate_block, BlockCodec, BlockCompressor, FileCompression, DEFAULT_COMPRESSION_LEVEL,
+    },
+    pfs::common::{
+        advance_block, check_block_progress, check_block_size, check_entries_inside,
+        check_filename, check_reserved_crc, entry_labels, find_archive_start, footer_date_now,
+        header_size, next_block, overlapping_regions, parse_directory_entries,
+        parse_encoded_filenames, parse_entry_blocks, parse_footer, parse_header_with_options,
+        resolve_filenames, slice_from, write_archive, write_encoded_filenames,
+        write_filename_table_blocks, DirectoryEntry, NameEncoding,
+    },
     pfs::constants::MAX_BLOCK_SIZE,
-    pfs::constants::PFS_CRC_ALGO,
-    pfs::{common::write_filenames, constants::FILENAMES_CRC_VALUE},
+    pfs::constants::{COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE},
+    pfs::eqg::{parse_with_eqg_fallback, ArchiveKind},
+    pfs::metadata::{parse_compression_table, write_compression_table},
+    pfs::options::{ArchiveSettings, OpenOptions},
+    pfs::readable::{EntryReader, ReadableArchive},
 };
 use bytes::{BufMut, BytesMut};
-use crc::Crc;
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
-use nom::{
-    bytes::complete::{tag, take},
-    multi::count,
-    number::complete::le_u32,
-    sequence::tuple,
-    Err::Error,
-    IResult,
-};
+use log::{debug, trace, warn};
+use nom::{bytes::complete::take, number::complete::le_u32, Err::Error, IResult};
 use regex::Regex;
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    collections::{hash_map::Values, HashMap},
+    io::Read,
 };
 
 /// A readable + writable PFS archive
 /// Less efficient than a strictly read or write archive because
 /// it has to cache more things to be able to reconstruct the archive.
+/// Saving only writes the files the archive currently holds, back to back after the header, so
+/// files that were removed, renamed or replaced leave nothing behind in the saved archive.
+#[derive(Clone)]
 pub struct ReadWriteArchive {
     files: HashMap<String, ReadWriteArchiveFile>,
+    extended_format: bool,
+    block_codec: BlockCodec,
+    compression_level: u32,
+    block_size: usize,
+    /// Date written to the footer, the time of saving if not set
+    footer_date: Option<u32>,
 }
 
+/// Files are keyed by their lowercased name and keep the name they were given for saving
+#[derive(Clone)]
 struct ReadWriteArchiveFile {
+    name: String,
+    /// How the name is written to the filename table
+    encoding: NameEncoding,
     blocks: Vec<ReadWriteArchiveFileBlock>,
+    /// How the blocks were compressed, if known
+    compression: Option<FileCompression>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct ReadWriteArchiveFileBlock {
     deflate_length: usize,
     inflate_length: usize,
     data: Vec<u8>,
 }
 
+/// Iterates the files in a ReadWriteArchive in no particular order
+pub struct Iter<'a> {
+    files: Values<'a, String, ReadWriteArchiveFile>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, EntryReader<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let file = self.files.next()?;
+        let blocks = file
+            .blocks
+            .iter()
+            .map(|block| (&block.data[..], block.inflate_length));
+        Some((&file.name, EntryReader::new(blocks)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.files.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a ReadWriteArchive {
+    type Item = (&'a str, EntryReader<'a>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The files of a parsed archive and the date from its footer
+type ParsedArchive = (HashMap<String, ReadWriteArchiveFile>, Option<u32>);
+
 impl ReadWriteArchive {
-    fn do_parse(
-        input: &[u8],
-    ) -> IResult<&[u8], HashMap<String, ReadWriteArchiveFile>, ArchiveError> {
-        let mut ret: HashMap<String, ReadWriteArchiveFile> = HashMap::new();
-        let mut parsed_files: HashMap<u32, ReadWriteArchiveFile> = HashMap::new();
+    /// Save using the extended format with 64-bit offsets and sizes
+    /// Only needed for archives larger than 4GB; standard PFS readers will reject these.
+    pub fn set_extended_format(&mut self, extended: bool) {
+        self.extended_format = extended;
+    }
+
+    /// Set the codec used to compress blocks of files set from now on
+    /// Files that were read from an archive keep their existing blocks. The client only reads
+    /// zlib blocks so it can't load files written with any other codec.
+    pub fn set_block_codec(&mut self, codec: BlockCodec) {
+        self.block_codec = codec;
+    }
+
+    /// Set the compression level from 0 (none) to 9 (best) used for files set from now on
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression_level = level;
+    }
+
+    /// Set the size of the blocks files set from now on are split into, 8192 by default
+    /// Files that were read from an archive keep their existing blocks.
+    pub fn set_block_size(&mut self, size: usize) -> Result<(), ArchiveError> {
+        self.block_size = check_block_size(size)?;
+        Ok(())
+    }
+
+    /// Create an empty archive that's saved with the given settings
+    /// Opening an archive keeps the settings for files set afterwards.
+    pub fn with_settings(settings: ArchiveSettings) -> Result<ReadWriteArchive, ArchiveError> {
+        let mut archive = ReadWriteArchive::new();
+        archive.set_extended_format(settings.extended_format);
+        archive.set_block_codec(settings.block_codec);
+        archive.set_compression_level(settings.compression_level);
+        archive.set_block_size(settings.block_size)?;
+        Ok(archive)
+    }
+
+    /// Set the date written to the footer
+    /// By default an opened archive keeps its footer's date and otherwise the time of saving is
+    /// used; opening an archive resets this.
+    pub fn set_footer_date(&mut self, date: u32) {
+        self.footer_date = Some(date);
+    }
+
+    /// Sets a file in the archive to be stored uncompressed
+    /// Meant for tools working with data such as textures and audio that's already compressed;
+    /// the client only reads zlib blocks so it can't load files stored this way.
+    pub fn set_stored<T>(&mut self, in_archive_path: &str, input: T) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        check_filename(in_archive_path)?;
+        let file = ReadWriteArchiveFile {
+            name: in_archive_path.to_string(),
+            compression: Some(FileCompression {
+                codec: BlockCodec::Stored,
+                level: 0,
+            }),
+            ..ReadWriteArchiveFile::deflate(
+                input,
+                BlockCodec::Stored,
+                self.compression_level,
+                self.block_size,
+            )?
+        };
+        self.files.insert(in_archive_path.to_lowercase(), file);
+        Ok(())
+    }
+
+    /// Copy every file from another archive into this one
+    /// Files with the same name are replaced when overwrite is set and skipped otherwise.
+    pub fn merge<A>(&mut self, other: &A, overwrite: bool) -> Result<MergeSummary, ArchiveError>
+    where
+        A: IReadableArchive,
+    {
+        merge_into(self, other, overwrite)
+    }
+
+    /// Set how the name of a file is encoded in the filename table
+    /// Only needed to keep a Latin-1 name a legacy tool wrote, and its CRC, when copying it from
+    /// another archive. Names that are set, renamed or copied are written as UTF-8.
+    pub fn set_name_encoding(
+        &mut self,
+        in_archive_path: &str,
+        encoding: NameEncoding,
+    ) -> Result<(), ArchiveError> {
+        check_reserved_crc(in_archive_path, encoding.crc(in_archive_path))?;
+        match self.files.get_mut(&in_archive_path.to_lowercase()) {
+            Some(file) => {
+                file.encoding = encoding;
+                Ok(())
+            }
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+
+    /// Get how a file was compressed
+    /// Unknown for files read from archives that didn't record it.
+    pub fn entry_compression(&self, name: &str) -> Option<FileCompression> {
+        self.files.get(&name.to_lowercase())?.compression
+    }
+
+    /// Iterate the name of every file along with a reader that inflates it on demand
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            files: self.files.values(),
+        }
+    }
+
+    /// Release memory left over from files that are no longer in the archive
+    /// Saving never writes removed files so this doesn't change the saved archive, it only trims
+    /// the capacity kept around after many edits.
+    pub fn compact(&mut self) {
+        self.files.shrink_to_fit();
+        for file in self.files.values_mut() {
+            file.blocks.shrink_to_fit();
+            for block in &mut file.blocks {
+                block.data.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Apply several changes to the archive, undoing all of them if any fails
+    /// The archive is cloned before running the changes so this costs a copy of every
+    /// compressed file, which is cheap next to recompressing them but not free for large archives.
+    pub fn transaction<F>(&mut self, f: F) -> Result<(), ArchiveError>
+    where
+        F: FnOnce(&mut ReadWriteArchive) -> Result<(), ArchiveError>,
+    {
+        let snapshot = self.clone();
+        let res = f(self);
+        if res.is_err() {
+            *self = snapshot;
+        }
+
+        res
+    }
+
+    /// Copy a file from a readable archive without recompressing it
+    /// The compressed blocks and the name as it's stored in the source are taken as is,
+    /// replacing any existing file with the same name.
+    pub fn import_from_readable(
+        &mut self,
+        src: &ReadableArchive,
+        name: &str,
+    ) -> Result<(), ArchiveError> {
+        let blocks = src
+            .compressed_blocks(name)?
+            .map(|(data, inflate_length)| ReadWriteArchiveFileBlock {
+                deflate_length: data.len(),
+                inflate_length,
+                data: data.to_vec(),
+            })
+            .collect();
+
+        self.files.insert(
+            name.to_lowercase(),
+            ReadWriteArchiveFile {
+                name: src.stored_name(name)?.to_string(),
+                encoding: src.name_encoding(name)?,
+                blocks,
+                compression: src.entry_compression(name),
+            },
+        );
+        Ok(())
+    }
+
+    /// Open an archive by parsing it from a block of bytes with specific options
+    pub fn open_from_bytes_with_options<T>(
+        &mut self,
+        input: T,
+        options: &OpenOptions,
+    ) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let input_ref = input.as_ref();
+        self.close();
 
-        let (current, dir_offset) = le_u32(input)?;
-        let (current, _) = tag("PFS ")(current)?;
-        let (_, version) = le_u32(current)?;
+        // unknown versions are accepted for EQG archives like the readable archive does
+        let parsed =
+            parse_with_eqg_fallback(input_ref, options, ReadWriteArchive::do_parse, |archive| {
+                ArchiveKind::detect(archive.0.values().map(|f| f.name.as_str()))
+            });
+        match parsed {
+            Ok((_, (files, footer))) => {
+                self.files = files;
+                self.footer_date = footer;
+                Ok(())
+            }
+            Err(e) => Err(ArchiveError::from_nom(e, input_ref)),
+        }
+    }
 
-        if version != 131072 {
-            return Err(Error(ArchiveError::WrongVersion { version }));
+    /// Parse an archive and add its files to the ones already open instead of replacing them
+    /// Files in the input replace open files with the same name, ignoring case. The format
+    /// settings and footer date of the open archive are kept.
+    pub fn open_overlay_from_bytes<T>(&mut self, input: T) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        let input_ref = input.as_ref();
+        match ReadWriteArchive::do_parse(input_ref, &OpenOptions::default()) {
+            Ok((_, (files, _))) => {
+                self.files.extend(files);
+                Ok(())
+            }
+            Err(e) => Err(ArchiveError::from_nom(e, input_ref)),
         }
+    }
 
-        let current = &input[dir_offset as usize..];
-        let (current, dir_count) = le_u32(current)?;
-        let (_, directory_entries) =
-            count(tuple((le_u32, le_u32, le_u32)), dir_count as usize)(current)?;
+    fn do_parse<'a>(
+        input: &'a [u8],
+        options: &OpenOptions,
+    ) -> IResult<&'a [u8], ParsedArchive, ArchiveError> {
+        let mut ret: HashMap<String, ReadWriteArchiveFile> = HashMap::new();
 
-        parsed_files.reserve(dir_count as usize);
-        for entry in directory_entries.iter() {
-            let (crc, offset, size) = entry;
-            let (_, blocks) = ReadWriteArchive::parse_pfs_file_blocks(
-                &input[(*offset as usize)..],
-                *size as usize,
-            )?;
+        let input = &input[find_archive_start(input, options)..];
+        let (_, (dir_offset, extended)) = parse_header_with_options(input, options)?;
+        debug!(
+            "Reading a{} archive with the directory at offset {}",
+            if extended { "n extended" } else { " standard" },
+            dir_offset
+        );
+        let current = slice_from(input, dir_offset, "Directory")?;
+        let (rest, directory_entries) = parse_directory_entries(current, extended, options)?;
 
-            parsed_files.insert(*crc, ReadWriteArchiveFile { blocks });
+        let (parsed, outside) = parse_entry_blocks(
+            input,
+            directory_entries,
+            dir_offset,
+            extended,
+            ReadWriteArchive::parse_pfs_file_blocks,
+        )?;
+        let (directory_entries, mut parsed_files): (Vec<DirectoryEntry>, Vec<_>) = parsed
+            .into_iter()
+            .map(|(entry, blocks)| {
+                let file = ReadWriteArchiveFile {
+                    name: String::new(),
+                    encoding: NameEncoding::Utf8,
+                    blocks,
+                    compression: None,
+                };
+                (entry, Some(file))
+            })
+            .unzip();
+
+        let mut filenames = Vec::new();
+        for (entry, f) in directory_entries.iter().zip(parsed_files.iter().flatten()) {
+            if entry.crc == FILENAMES_CRC_VALUE {
+                let data = f.inflate().map_err(Error)?;
+                match parse_encoded_filenames(&data[..], options.strict_filenames) {
+                    Ok(names) => filenames = names,
+                    Err(e @ ArchiveError::Utf8(_)) => return Err(Error(e)),
+                    Err(_) => {}
+                }
+                break;
+            }
         }
+        check_entries_inside(&filenames, &outside)?;
 
-        let mut filenames: Vec<String> = Vec::new();
-        for (crc, f) in &parsed_files {
-            if *crc == FILENAMES_CRC_VALUE {
-                match f.inflate() {
-                    Ok(data) => {
-                        filenames = parse_filenames(&data[..]).unwrap_or_default();
-                        break;
-                    }
-                    Err(e) => return Err(Error(e)),
+        // the compression table is only informational so a broken one doesn't stop the open
+        let mut compression = HashMap::new();
+        for (entry, f) in directory_entries.iter().zip(parsed_files.iter().flatten()) {
+            if entry.crc == COMPRESSION_TABLE_CRC_VALUE {
+                match f
+                    .inflate()
+                    .and_then(|data| parse_compression_table(&data[..]))
+                {
+                    Ok(table) => compression = table,
+                    Err(e) => warn!("Unable to read the compression table: {}", e),
                 }
+                break;
             }
         }
 
-        let crc = Crc::<u32>::new(&PFS_CRC_ALGO);
-        for filename in &filenames {
-            let mut digest = crc.digest();
-            digest.update(filename.as_bytes());
-            digest.update(b"\0");
-            let crc = digest.finalize();
+        let resolved = resolve_filenames(&filenames, &directory_entries, |e| (e.crc, e.offset));
+        if options.check_overlaps {
+            let labels = entry_labels(&filenames, &directory_entries);
+            let regions: Vec<(String, usize, usize)> = directory_entries
+                .iter()
+                .zip(parsed_files.iter().flatten())
+                .zip(labels)
+                .map(|((entry, f), label)| {
+                    let length: usize = f.blocks.iter().map(|b| b.deflate_length + 8).sum();
+                    (label, entry.offset as usize, entry.offset as usize + length)
+                })
+                .collect();
 
-            if let Some(f) = parsed_files.remove(&crc) {
-                ret.insert(filename.clone(), f);
+            let overlapping = overlapping_regions(&regions);
+            if !overlapping.is_empty() {
+                return Err(Error(ArchiveError::OverlappingFiles(overlapping)));
             }
         }
 
-        Ok((input, ret))
+        for (filename, encoding, i) in resolved {
+            if let Some(f) = parsed_files[i].take() {
+                ret.insert(
+                    filename.to_lowercase(),
+                    ReadWriteArchiveFile {
+                        compression: compression.get(&encoding.crc(&filename)).copied(),
+                        name: filename,
+                        encoding,
+                        ..f
+                    },
+                );
+            }
+        }
+
+        Ok((input, (ret, parse_footer(rest))))
     }
 
     fn parse_pfs_file_blocks(
         input: &[u8],
+        offset: usize,
         size: usize,
     ) -> IResult<&[u8], Vec<ReadWriteArchiveFileBlock>, ArchiveError> {
         let mut ret = Vec::new();
@@ -109,16 +420,22 @@ impl ReadWriteArchive {
         let mut inflate: usize = 0;
 
         while inflate < size {
-            let current = &input[position..];
+            let current = next_block(input, position, offset)?;
             let (_, block) = ReadWriteArchive::parse_pfs_file_block(current)?;
+            check_block_progress(block.inflate_length, offset + position)?;
 
-            inflate += block.inflate_length;
-            position += block.deflate_length;
-            position += 8;
+            inflate = inflate.saturating_add(block.inflate_length);
+            position = advance_block(position, block.deflate_length, offset)?;
 
             ret.push(block);
         }
 
+        trace!(
+            "File at offset {} inflates to {} bytes from {} blocks",
+            offset,
+            size,
+            ret.len()
+        );
         Ok((input, ret))
     }
 
@@ -141,7 +458,12 @@ impl ReadWriteArchive {
 }
 
 impl ReadWriteArchiveFile {
-    fn deflate<T>(input: T) -> Result<ReadWriteArchiveFile, ArchiveError>
+    fn deflate<T>(
+        input: T,
+        codec: BlockCodec,
+        level: u32,
+        block_size: usize,
+    ) -> Result<ReadWriteArchiveFile, ArchiveError>
     where
         T: AsRef<[u8]>,
     {
@@ -149,20 +471,20 @@ impl ReadWriteArchiveFile {
         let mut pos = 0usize;
         let mut remain = input_ref.len();
         let mut blocks: Vec<ReadWriteArchiveFileBlock> = Vec::new();
+        let mut compressor = BlockCompressor::new();
 
         while remain > 0 {
             let sz: usize;
-            if remain > MAX_BLOCK_SIZE {
-                sz = MAX_BLOCK_SIZE;
-                remain -= MAX_BLOCK_SIZE;
+            if remain > block_size {
+                sz = block_size;
+                remain -= block_size;
             } else {
                 sz = remain;
                 remain = 0;
             }
 
-            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&input_ref[pos..pos + sz])?;
-            let compressed = encoder.finish()?;
+            let mut compressed = Vec::new();
+            compressor.compress_into(codec, &input_ref[pos..pos + sz], level, &mut compressed)?;
 
             let block = ReadWriteArchiveFileBlock {
                 deflate_length: compressed.len(),
@@ -174,21 +496,29 @@ impl ReadWriteArchiveFile {
             blocks.push(block);
         }
 
-        Ok(ReadWriteArchiveFile { blocks })
+        Ok(ReadWriteArchiveFile {
+            name: String::new(),
+            encoding: NameEncoding::Utf8,
+            blocks,
+            compression: None,
+        })
     }
 
     fn inflate(&self) -> Result<Vec<u8>, ArchiveError> {
-        let mut ret: Vec<u8> = Vec::with_capacity(self.len());
+        let mut ret: Vec<u8> = Vec::new();
+        self.inflate_into(&mut ret)?;
+        Ok(ret)
+    }
 
-        for block in self.blocks.iter() {
-            let mut temp_buffer: Vec<u8> = vec![0; block.inflate_length + 1];
-            let mut decoder = ZlibDecoder::new(&block.data[..]);
-            let sz = decoder.read(&mut temp_buffer)?;
+    fn inflate_into(&self, out: &mut Vec<u8>) -> Result<(), ArchiveError> {
+        out.clear();
+        out.reserve(self.len());
 
-            ret.extend_from_slice(&temp_buffer[0..sz]);
+        for block in self.blocks.iter() {
+            inflate_block(&block.data[..], block.inflate_length, out)?;
         }
 
-        Ok(ret)
+        Ok(())
     }
 
     fn len(&self) -> usize {
@@ -196,15 +526,81 @@ impl ReadWriteArchiveFile {
     }
 }
 
+impl Default for ReadWriteArchive {
+    fn default() -> Self {
+        ReadWriteArchive::new()
+    }
+}
+
+/// Files are added in order with `set` so a later entry replaces an earlier one with the same
+/// name, ignoring case.
+///
+/// # Panics
+/// If a file has an invalid name or fails to compress, use `try_extend` to get the error
+/// instead.
+impl<S, D> Extend<(S, D)> for ReadWriteArchive
+where
+    S: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    fn extend<I: IntoIterator<Item = (S, D)>>(&mut self, iter: I) {
+        if let Err(e) = self.try_extend(iter) {
+            panic!("unable to add file: {}", e);
+        }
+    }
+}
+
+/// # Panics
+/// If a file has an invalid name or fails to compress.
+impl<S, D> FromIterator<(S, D)> for ReadWriteArchive
+where
+    S: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    fn from_iter<I: IntoIterator<Item = (S, D)>>(iter: I) -> Self {
+        let mut archive = ReadWriteArchive::new();
+        archive.extend(iter);
+        archive
+    }
+}
+
 impl IArchive for ReadWriteArchive {
     fn new() -> Self {
         ReadWriteArchive {
             files: HashMap::new(),
+            extended_format: false,
+            block_codec: BlockCodec::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+            footer_date: None,
         }
     }
 
     fn close(&mut self) {
         self.files.clear();
+        self.footer_date = None;
+    }
+
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+impl TryFrom<&[u8]> for ReadWriteArchive {
+    type Error = ArchiveError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(input)?;
+        Ok(archive)
+    }
+}
+
+impl TryFrom<Vec<u8>> for ReadWriteArchive {
+    type Error = ArchiveError;
+
+    fn try_from(input: Vec<u8>) -> Result<Self, Self::Error> {
+        ReadWriteArchive::try_from(&input[..])
     }
 }
 
@@ -213,21 +609,7 @@ impl IReadableArchive for ReadWriteArchive {
     where
         T: AsRef<[u8]>,
     {
-        let input_ref = input.as_ref();
-        self.close();
-        match ReadWriteArchive::do_parse(input_ref) {
-            Ok((_, files)) => {
-                self.files = files;
-                Ok(())
-            }
-            Err(e) => {
-                if let Error(ae) = e {
-                    Err(ae)
-                } else {
-                    Err(ArchiveError::Unknown)
-                }
-            }
-        }
+        self.open_from_bytes_with_options(input, &OpenOptions::default())
     }
 
     fn open_file(&mut self, filename: &str) -> Result<(), ArchiveError> {
@@ -235,13 +617,25 @@ impl IReadableArchive for ReadWriteArchive {
         self.open_from_bytes(&data[..])
     }
 
+    fn open_from_reader<R>(&mut self, mut reader: R) -> Result<(), ArchiveError>
+    where
+        R: Read,
+    {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.open_from_bytes(&data[..])
+    }
+
     fn get(&self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError> {
+        let mut ret = Vec::new();
+        self.get_into(in_archive_path, &mut ret)?;
+        Ok(ret)
+    }
+
+    fn get_into(&self, in_archive_path: &str, out: &mut Vec<u8>) -> Result<(), ArchiveError> {
         let in_archive_path_lower = in_archive_path.to_lowercase();
         match self.files.get(&in_archive_path_lower) {
-            Some(ent) => {
-                let res = ent.inflate()?;
-                Ok(res)
-            }
+            Some(ent) => ent.inflate_into(out),
             None => Err(ArchiveError::SrcFileNotFound),
         }
     }
@@ -251,91 +645,174 @@ impl IReadableArchive for ReadWriteArchive {
         Ok(self.files.contains_key(&in_archive_path_lower))
     }
 
+    fn name_encoding(&self, in_archive_path: &str) -> Result<NameEncoding, ArchiveError> {
+        match self.files.get(&in_archive_path.to_lowercase()) {
+            Some(file) => Ok(file.encoding),
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+
     fn search(&self, search_regex: &str) -> Result<Vec<String>, ArchiveError> {
         let regex = Regex::new(search_regex)?;
+        Ok(self.search_compiled(&regex))
+    }
+
+    fn search_compiled(&self, regex: &Regex) -> Vec<String> {
         let mut ret = Vec::new();
 
-        for filename in self.files.keys() {
-            if regex.is_match(filename) {
-                ret.push(filename.clone());
+        for (key, file) in &self.files {
+            if regex.is_match(key) || regex.is_match(&file.name) {
+                ret.push(file.name.clone());
             }
         }
 
-        Ok(ret)
+        ret.sort();
+        ret
+    }
+
+    fn entries(&self) -> Vec<ArchiveEntry> {
+        let mut ret: Vec<ArchiveEntry> = self
+            .files
+            .values()
+            .map(|f| ArchiveEntry {
+                name: f.name.clone(),
+                compressed_size: f.blocks.iter().map(|b| b.deflate_length).sum(),
+                uncompressed_size: f.len(),
+                block_count: f.blocks.len(),
+            })
+            .collect();
+
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        ret
+    }
+}
+
+impl MergeTarget for ReadWriteArchive {
+    fn contains(&self, in_archive_path: &str) -> bool {
+        self.files.contains_key(&in_archive_path.to_lowercase())
+    }
+
+    fn set_name_encoding(
+        &mut self,
+        in_archive_path: &str,
+        encoding: NameEncoding,
+    ) -> Result<(), ArchiveError> {
+        ReadWriteArchive::set_name_encoding(self, in_archive_path, encoding)
     }
 }
 
 impl IWritableArchive for ReadWriteArchive {
     fn save_to_bytes(&self) -> Result<Vec<u8>, ArchiveError> {
+        self.save_to_bytes_with_progress(|_, _| {})
+    }
+
+    fn save_to_bytes_with_progress<F>(&self, mut progress: F) -> Result<Vec<u8>, ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
         let mut data = BytesMut::with_capacity(1024);
-        let mut directory = BytesMut::with_capacity(1024);
-        directory.put_u32_le(self.files.len() as u32 + 1);
+        let mut directory = Vec::with_capacity(self.files.len() + 1);
+        let header_size = header_size(self.extended_format);
 
-        let crc_provider = Crc::<u32>::new(&PFS_CRC_ALGO);
+        // files with identical blocks share one copy of them
+        let mut written: HashMap<&[ReadWriteArchiveFileBlock], usize> = HashMap::new();
         let mut filenames = Vec::new();
-        for (filename, file) in &self.files {
-            let offset = data.len() + 12;
-            let mut digest = crc_provider.digest();
-            digest.update(filename.to_lowercase().as_bytes());
-            digest.update(b"\0");
+        let mut compression = Vec::new();
+        // lay files out in the same order on every run rather than the map's
+        let mut files: Vec<&ReadWriteArchiveFile> = self.files.values().collect();
+        files.sort_by_key(|f| (f.encoding.crc(&f.name), f.name.as_str()));
+        for file in files {
+            let crc = file.encoding.crc(&file.name);
+            let offset = *written.entry(&file.blocks[..]).or_insert_with(|| {
+                let offset = data.len() + header_size;
+                for block in &file.blocks {
+                    data.put_u32_le(block.deflate_length as u32);
+                    data.put_u32_le(block.inflate_length as u32);
+                    data.put(&block.data[..]);
+                }
+                offset
+            });
 
-            let crc = digest.finalize();
+            directory.push(DirectoryEntry {
+                crc,
+                offset: offset as u64,
+                size: file.len() as u64,
+            });
+            filenames.push((file.name.as_str(), file.encoding));
+            if let Some(c) = file.compression {
+                compression.push((crc, c));
+            }
+            progress(filenames.len(), self.files.len());
+        }
+        debug!(
+            "Wrote the blocks of {} files, {} of them shared with another file",
+            filenames.len(),
+            filenames.len() - written.len()
+        );
 
-            for block in &file.blocks {
+        if compression.iter().any(|(_, c)| !c.is_default()) {
+            let offset = data.len() + header_size;
+            let table = write_compression_table(&compression);
+            let table_file =
+                ReadWriteArchiveFile::deflate(&table, BlockCodec::Stored, 0, MAX_BLOCK_SIZE)?;
+            for block in &table_file.blocks {
                 data.put_u32_le(block.deflate_length as u32);
                 data.put_u32_le(block.inflate_length as u32);
                 data.put(&block.data[..]);
             }
 
-            directory.put_u32_le(crc);
-            directory.put_u32_le(offset as u32);
-            directory.put_u32_le(file.len() as u32);
-            filenames.push(filename.clone());
+            directory.push(DirectoryEntry {
+                crc: COMPRESSION_TABLE_CRC_VALUE,
+                offset: offset as u64,
+                size: table.len() as u64,
+            });
         }
 
-        let offset = data.len() + 12;
-        let filenames_data = write_filenames(&filenames);
-        let filenames_file = ReadWriteArchiveFile::deflate(filenames_data)?;
-
-        for block in &filenames_file.blocks {
-            data.put_u32_le(block.deflate_length as u32);
-            data.put_u32_le(block.inflate_length as u32);
-            data.put(&block.data[..]);
-        }
-
-        directory.put_u32_le(FILENAMES_CRC_VALUE);
-        directory.put_u32_le(offset as u32);
-        directory.put_u32_le(filenames_file.len() as u32);
-
-        let data = data.freeze();
-        let directory = directory.freeze();
+        let offset = data.len() + header_size;
+        let filenames_data = write_encoded_filenames(filenames);
+        data.put(write_filename_table_blocks(
+            &filenames_data,
+            self.compression_level,
+        )?);
+        directory.push(DirectoryEntry {
+            crc: FILENAMES_CRC_VALUE,
+            offset: offset as u64,
+            size: filenames_data.len() as u64,
+        });
 
-        let mut final_data = BytesMut::with_capacity(12 + data.len() + directory.len());
-        final_data.put_u32_le(data.len() as u32 + 12);
-        final_data.put_u8(b'P');
-        final_data.put_u8(b'F');
-        final_data.put_u8(b'S');
-        final_data.put_u8(b' ');
-        final_data.put_u32_le(131072);
-        final_data.put(data);
-        final_data.put(directory);
-
-        let final_data = final_data.freeze();
-        Ok(final_data.to_vec())
-    }
-
-    fn save_to_file(&self, filename: &str) -> Result<(), ArchiveError> {
-        let data = self.save_to_bytes()?;
-        std::fs::write(filename, data)?;
-        Ok(())
+        write_archive(
+            data.freeze(),
+            directory,
+            self.extended_format,
+            Some(self.footer_date.unwrap_or_else(footer_date_now)),
+        )
     }
 
     fn set<T>(&mut self, in_archive_path: &str, input: T) -> Result<(), ArchiveError>
     where
         T: AsRef<[u8]>,
     {
+        check_filename(in_archive_path)?;
         let in_archive_path_lower = in_archive_path.to_lowercase();
-        let file = ReadWriteArchiveFile::deflate(input)?;
+        let file = ReadWriteArchiveFile {
+            name: in_archive_path.to_string(),
+            compression: Some(match self.block_codec {
+                BlockCodec::Stored => FileCompression {
+                    codec: BlockCodec::Stored,
+                    level: 0,
+                },
+                codec => FileCompression {
+                    codec,
+                    level: self.compression_level,
+                },
+            }),
+            ..ReadWriteArchiveFile::deflate(
+                input,
+                self.block_codec,
+                self.compression_level,
+                self.block_size,
+            )?
+        };
         self.files.insert(in_archive_path_lower, file);
         Ok(())
     }
@@ -353,15 +830,21 @@ impl IWritableArchive for ReadWriteArchive {
         in_archive_path: &str,
         new_in_archive_path: &str,
     ) -> Result<(), ArchiveError> {
+        check_filename(new_in_archive_path)?;
         let in_archive_path_lower = in_archive_path.to_lowercase();
         let new_in_archive_path_lower = new_in_archive_path.to_lowercase();
 
-        if self.files.contains_key(&new_in_archive_path_lower) {
+        // renaming to the same name with different case only changes the saved name
+        if in_archive_path_lower != new_in_archive_path_lower
+            && self.files.contains_key(&new_in_archive_path_lower)
+        {
             return Err(ArchiveError::DestFileAlreadyExists);
         }
 
         match self.files.remove(&in_archive_path_lower) {
-            Some(f) => {
+            Some(mut f) => {
+                f.name = new_in_archive_path.to_string();
+                f.encoding = NameEncoding::Utf8;
                 self.files.insert(new_in_archive_path_lower, f);
                 Ok(())
             }
@@ -374,6 +857,7 @@ impl IWritableArchive for ReadWriteArchive {
         in_archive_path: &str,
         new_in_archive_path: &str,
     ) -> Result<(), ArchiveError> {
+        check_filename(new_in_archive_path)?;
         let in_archive_path_lower = in_archive_path.to_lowercase();
         let new_in_archive_path_lower = new_in_archive_path.to_lowercase();
 
@@ -386,7 +870,10 @@ impl IWritableArchive for ReadWriteArchive {
 
         if let Some(f) = existing {
             new_file = ReadWriteArchiveFile {
+                name: new_in_archive_path.to_string(),
+                encoding: NameEncoding::Utf8,
                 blocks: f.blocks.to_vec(),
+                compression: f.compression,
             }
         } else {
             return Err(ArchiveError::SrcFileNotFound);
@@ -396,3 +883,620 @@ impl IWritableArchive for ReadWriteArchive {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::pfs::testing::{
+        build_archive, build_encoded_archive, filename_table_block, zlib_filename_table,
+    };
+    use crate::archive::prelude::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn truncated_archive_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("one.txt", vec![1u8; 10000]).unwrap();
+        writable.set("two.txt", b"two").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        // the footer is optional so only cutting off more than it fails
+        let mut archive = ReadWriteArchive::new();
+        for len in 0..data.len() - 9 {
+            assert!(archive.open_from_bytes(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn inflate_length_mismatch_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        // test.txt is the first file written so its block starts right after the header
+        data[16..20].copy_from_slice(&20u32.to_le_bytes());
+
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::Decompression)
+        ));
+    }
+
+    #[test]
+    fn corrupt_block_decompression_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", vec![3u8; 1000]).unwrap();
+        let mut data = writable.save_to_bytes().unwrap();
+
+        let deflate_length = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+        for b in &mut data[20..20 + deflate_length] {
+            *b = 0xAB;
+        }
+
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(&data[..]).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::Decompression)
+        ));
+    }
+
+    #[test]
+    fn entries_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("big.wld", vec![1u8; 20000]).unwrap();
+        archive.set("small.txt", b"small").unwrap();
+
+        let entries = archive.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "big.wld");
+        assert_eq!(entries[0].uncompressed_size, 20000);
+        assert_eq!(entries[0].block_count, 3);
+        assert_eq!(entries[1].name, "small.txt");
+        assert_eq!(entries[1].uncompressed_size, 5);
+    }
+
+    #[test]
+    fn get_into_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("one.txt", b"first file").unwrap();
+        archive.set("two.txt", b"2").unwrap();
+
+        let mut buffer = Vec::new();
+        archive.get_into("one.txt", &mut buffer).unwrap();
+        assert_eq!(buffer, b"first file");
+        archive.get_into("two.txt", &mut buffer).unwrap();
+        assert_eq!(buffer, b"2");
+    }
+
+    #[test]
+    fn import_from_readable_test() {
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &contents).unwrap();
+
+        let mut src = ReadableArchive::new();
+        src.open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut archive = ReadWriteArchive::new();
+        archive.set_block_codec(BlockCodec::RawDeflate);
+        archive.import_from_readable(&src, "BIG.wld").unwrap();
+        assert_eq!(archive.get("big.wld").unwrap(), contents);
+        assert!(matches!(
+            archive.import_from_readable(&src, "missing.wld"),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+
+        let mut dest = ReadableArchive::new();
+        dest.open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+
+        let src_blocks: Vec<(Vec<u8>, usize)> = src
+            .compressed_blocks("big.wld")
+            .unwrap()
+            .map(|(data, len)| (data.to_vec(), len))
+            .collect();
+        let dest_blocks: Vec<(Vec<u8>, usize)> = dest
+            .compressed_blocks("big.wld")
+            .unwrap()
+            .map(|(data, len)| (data.to_vec(), len))
+            .collect();
+        assert_eq!(src_blocks.len(), 3);
+        assert_eq!(src_blocks, dest_blocks);
+
+        // the name keeps the case it was stored with rather than the case it was looked up by
+        assert_eq!(dest.search(".*").unwrap(), vec!["big.wld"]);
+    }
+
+    #[test]
+    fn set_overwrite_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("test.txt", b"first").unwrap();
+        archive.set("TEST.txt", b"second").unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"second");
+
+        let mut reopened = ReadWriteArchive::new();
+        reopened
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(reopened.search(".*").unwrap(), vec!["TEST.txt"]);
+        assert_eq!(reopened.get("test.txt").unwrap(), b"second");
+    }
+
+    #[test]
+    fn small_filename_table_zlib_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set_block_codec(BlockCodec::RawDeflate);
+        archive.set("a.txt", b"aaaa").unwrap();
+        archive.set("b.txt", b"bbbb").unwrap();
+        let data = archive.save_to_bytes().unwrap();
+
+        // level 0 only adds the zlib header, one stored deflate block header and the checksum
+        let (deflate_length, inflate_length) = filename_table_block(&data);
+        assert!(deflate_length <= inflate_length + 11);
+        assert_eq!(zlib_filename_table(&data).len(), inflate_length as usize);
+
+        let mut reopened = ReadWriteArchive::new();
+        reopened.open_from_bytes(&data).unwrap();
+        assert_eq!(reopened.get("a.txt").unwrap(), b"aaaa");
+        assert_eq!(reopened.get("b.txt").unwrap(), b"bbbb");
+    }
+
+    #[test]
+    fn compression_level_test() {
+        let contents: Vec<u8> = (0..50000u32).map(|i| (i % 97) as u8).collect();
+
+        let mut sizes = Vec::new();
+        for level in [0, 9] {
+            let mut archive = ReadWriteArchive::new();
+            archive.set_compression_level(level);
+            archive.set("test.wld", &contents).unwrap();
+            assert_eq!(archive.get("test.wld").unwrap(), contents);
+            sizes.push(archive.save_to_bytes().unwrap().len());
+        }
+
+        assert!(sizes[0] > sizes[1]);
+    }
+
+    #[test]
+    fn block_size_test() {
+        let contents: Vec<u8> = (0..100000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut archive = ReadWriteArchive::new();
+        archive.set("small.wld", &contents).unwrap();
+        archive.set_block_size(32768).unwrap();
+        archive.set("large.wld", &contents).unwrap();
+        assert!(archive.set_block_size(0).is_err());
+
+        let mut readable = ReadableArchive::new();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.compressed_blocks("small.wld").unwrap().count(), 13);
+        assert_eq!(readable.compressed_blocks("large.wld").unwrap().count(), 4);
+        assert_eq!(readable.get("small.wld").unwrap(), contents);
+        assert_eq!(readable.get("large.wld").unwrap(), contents);
+        assert_eq!(readable.min_client_era(), ClientEra::Modern);
+
+        let settings = ArchiveSettings {
+            block_size: 32768,
+            ..ArchiveSettings::default()
+        };
+        let mut archive = ReadWriteArchive::with_settings(settings).unwrap();
+        archive.set("large.wld", &contents).unwrap();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.compressed_blocks("large.wld").unwrap().count(), 4);
+        assert_eq!(readable.get("large.wld").unwrap(), contents);
+
+        assert!(matches!(
+            ReadWriteArchive::with_settings(ArchiveSettings {
+                block_size: 0,
+                ..ArchiveSettings::default()
+            }),
+            Err(ArchiveError::InvalidBlockSize { size: 0 })
+        ));
+    }
+
+    #[test]
+    fn invalid_name_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("test.txt", b"hello world").unwrap();
+        assert!(matches!(
+            archive.set("bad\0.txt", b"bad"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert!(matches!(
+            archive.rename("test.txt", "test\r.txt"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn try_extend_test() {
+        let mut archive = ReadWriteArchive::new();
+        assert!(matches!(
+            archive.try_extend([("one.txt", b"1"), ("bad\0.txt", b"2"), ("three.txt", b"3")]),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert_eq!(archive.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid name")]
+    fn extend_invalid_name_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.extend([("bad\0.txt", b"bad")]);
+    }
+
+    #[test]
+    fn latin1_filename_test() {
+        let data = build_encoded_archive(
+            &[("caf\u{e9}.txt", b"coffee"), ("tea.txt", b"tea")],
+            NameEncoding::Latin1,
+        );
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.get("CAF\u{c9}.txt").unwrap(), b"coffee");
+
+        let strict = OpenOptions {
+            strict_filenames: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            ReadableArchive::new().open_from_bytes_with_options(&data, &strict),
+            Err(ArchiveError::Utf8(_))
+        ));
+        assert!(matches!(
+            ReadWriteArchive::new().open_from_bytes_with_options(&data, &strict),
+            Err(ArchiveError::Utf8(_))
+        ));
+
+        // the name is written back as Latin-1, a new name is written as UTF-8
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        archive.set("th\u{e9}.txt", b"more tea").unwrap();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.get("caf\u{e9}.txt").unwrap(), b"coffee");
+        assert_eq!(readable.get("th\u{e9}.txt").unwrap(), b"more tea");
+        let table = readable.raw_filename_table().unwrap();
+        let contains = |name: &[u8]| table.windows(name.len()).any(|w| w == name);
+        assert!(contains(b"caf\xe9.txt\0"));
+        assert!(contains("th\u{e9}.txt\0".as_bytes()));
+        assert_eq!(
+            readable.name_encoding("caf\u{e9}.txt").unwrap(),
+            NameEncoding::Latin1
+        );
+        assert_eq!(
+            readable.name_encoding("th\u{e9}.txt").unwrap(),
+            NameEncoding::Utf8
+        );
+
+        // importing and merging keep the encoding too
+        let mut archive = ReadWriteArchive::new();
+        archive
+            .import_from_readable(&readable, "caf\u{e9}.txt")
+            .unwrap();
+        assert_eq!(
+            archive.name_encoding("caf\u{e9}.txt").unwrap(),
+            NameEncoding::Latin1
+        );
+        let mut archive = ReadWriteArchive::new();
+        archive.merge(&readable, false).unwrap();
+        assert_eq!(
+            archive.name_encoding("caf\u{e9}.txt").unwrap(),
+            NameEncoding::Latin1
+        );
+    }
+
+    #[test]
+    fn open_overlay_test() {
+        let mut base = WritableArchive::new();
+        base.set("base.txt", b"base").unwrap();
+        base.set("Shared.txt", b"from base").unwrap();
+        let base = base.save_to_bytes().unwrap();
+
+        let mut overlay = WritableArchive::new();
+        overlay.set("overlay.txt", b"overlay").unwrap();
+        overlay.set("shared.txt", b"from overlay").unwrap();
+        let overlay = overlay.save_to_bytes().unwrap();
+
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(&base).unwrap();
+        archive.set("local.txt", b"local").unwrap();
+        archive.open_overlay_from_bytes(&overlay).unwrap();
+
+        assert_eq!(archive.len(), 4);
+        assert_eq!(archive.get("base.txt").unwrap(), b"base");
+        assert_eq!(archive.get("local.txt").unwrap(), b"local");
+        assert_eq!(archive.get("overlay.txt").unwrap(), b"overlay");
+        assert_eq!(archive.get("shared.txt").unwrap(), b"from overlay");
+        assert_eq!(
+            archive.search(".*").unwrap(),
+            vec!["base.txt", "local.txt", "overlay.txt", "shared.txt"]
+        );
+
+        // a bad overlay leaves the open files alone
+        assert!(archive.open_overlay_from_bytes(&overlay[..20]).is_err());
+        assert_eq!(archive.len(), 4);
+    }
+
+    #[test]
+    fn open_from_reader_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_reader(Cursor::new(&data)).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn transaction_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("one.txt", b"one").unwrap();
+        archive.set("two.txt", b"two").unwrap();
+
+        let res = archive.transaction(|a| {
+            a.set("one.txt", b"changed")?;
+            a.remove("two.txt")?;
+            a.rename("missing.txt", "three.txt")
+        });
+        assert!(matches!(res, Err(ArchiveError::SrcFileNotFound)));
+
+        let mut files = archive.search(".*").unwrap();
+        files.sort();
+        assert_eq!(files, vec!["one.txt", "two.txt"]);
+        assert_eq!(archive.get("one.txt").unwrap(), b"one");
+        assert_eq!(archive.get("two.txt").unwrap(), b"two");
+
+        archive
+            .transaction(|a| {
+                a.set("one.txt", b"changed")?;
+                a.remove("two.txt")
+            })
+            .unwrap();
+        assert_eq!(archive.search(".*").unwrap(), vec!["one.txt"]);
+        assert_eq!(archive.get("one.txt").unwrap(), b"changed");
+    }
+
+    #[test]
+    fn dedup_test() {
+        let payload: Vec<u8> = (0..16384u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut archive = ReadWriteArchive::new();
+        archive.set("one.bmp", &payload).unwrap();
+        archive.set("two.bmp", &payload).unwrap();
+        archive.copy("two.bmp", "three.bmp").unwrap();
+        let data = archive.save_to_bytes().unwrap();
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        for name in ["one.bmp", "two.bmp", "three.bmp"] {
+            assert_eq!(readable.get(name).unwrap(), payload);
+        }
+
+        let (block, _) = readable
+            .compressed_blocks("one.bmp")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(data.windows(block.len()).filter(|w| *w == block).count(), 1);
+    }
+
+    #[test]
+    fn preserve_case_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("MixedCase.BMP", b"mixed").unwrap();
+        archive.set("Other.txt", b"other").unwrap();
+        archive.rename("other.txt", "OTHER.txt").unwrap();
+
+        let mut reopened = ReadWriteArchive::new();
+        reopened
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mut files = reopened.search(".*").unwrap();
+        files.sort();
+        assert_eq!(files, vec!["MixedCase.BMP", "OTHER.txt"]);
+        assert_eq!(reopened.search("mixedcase").unwrap(), vec!["MixedCase.BMP"]);
+        assert_eq!(reopened.get("mixedcase.bmp").unwrap(), b"mixed");
+    }
+
+    #[test]
+    fn iter_test() {
+        let mut archive = ReadWriteArchive::new();
+        archive.set("One.txt", b"one").unwrap();
+        archive.set("two.txt", vec![2u8; 20000]).unwrap();
+
+        let mut seen = Vec::new();
+        for (name, mut reader) in &archive {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, archive.get(name).unwrap());
+            seen.push(name);
+        }
+
+        seen.sort();
+        assert_eq!(seen, vec!["One.txt", "two.txt"]);
+    }
+
+    #[test]
+    fn entry_compression_test() {
+        let contents = vec![5u8; 10000];
+        let mut writable = WritableArchive::new();
+        writable.set_compression_level(1);
+        writable.set("fast.wld", &contents).unwrap();
+        writable.set_stored("stored.dds", &contents).unwrap();
+
+        let mut archive = ReadWriteArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        archive.set("default.txt", b"default").unwrap();
+
+        let fast = Some(FileCompression {
+            codec: BlockCodec::Zlib,
+            level: 1,
+        });
+        let stored = Some(FileCompression {
+            codec: BlockCodec::Stored,
+            level: 0,
+        });
+        assert_eq!(archive.entry_compression("fast.wld"), fast);
+        assert_eq!(archive.entry_compression("stored.dds"), stored);
+
+        let mut readable = ReadableArchive::new();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.entry_compression("fast.wld"), fast);
+        assert_eq!(readable.entry_compression("stored.dds"), stored);
+        assert!(readable
+            .entry_compression("default.txt")
+            .unwrap()
+            .is_default());
+        assert_eq!(readable.len(), 3);
+    }
+
+    #[test]
+    fn search_sorted_test() {
+        let names = [
+            "zone.wld",
+            "Alpha.bmp",
+            "objects.wld",
+            "beta.bmp",
+            "lights.wld",
+        ];
+        let mut archive = ReadWriteArchive::new();
+        for name in names {
+            archive.set(name, name.as_bytes()).unwrap();
+        }
+
+        let mut expected: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        expected.sort();
+        assert_eq!(archive.search(".*").unwrap(), expected);
+
+        let mut readable = ReadableArchive::new();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(readable.search(".*").unwrap(), expected);
+        assert_eq!(
+            readable.search(r"\.wld$").unwrap(),
+            vec!["lights.wld", "objects.wld", "zone.wld"]
+        );
+    }
+
+    #[test]
+    fn footer_roundtrip_test() {
+        let mut writable = WritableArchive::new();
+        writable.set_footer_date(0x5eadbeef);
+        writable.set("test.txt", b"footer").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        assert!(data.ends_with(b"STEVE\xef\xbe\xad\x5e"));
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.footer_date(), Some(0x5eadbeef));
+        assert!(readable.health_report().has_footer);
+
+        let mut archive = ReadWriteArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        archive.set("other.txt", b"more").unwrap();
+        let saved = archive.save_to_bytes().unwrap();
+        assert_eq!(saved[saved.len() - 9..], data[data.len() - 9..]);
+
+        // archives without a footer still open and get one when saved
+        archive
+            .open_from_bytes(build_archive(&[("test.txt", b"old")]))
+            .unwrap();
+        readable
+            .open_from_bytes(archive.save_to_bytes().unwrap())
+            .unwrap();
+        assert!(readable.footer_date().is_some());
+        assert_eq!(readable.get("test.txt").unwrap(), b"old");
+    }
+
+    #[test]
+    fn try_from_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut archive = ReadWriteArchive::try_from(&data[..]).unwrap();
+        archive.set("other.txt", b"other").unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let archive = ReadWriteArchive::try_from(data).unwrap();
+        assert_eq!(archive.get("test.txt").unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn from_iter_test() {
+        let pairs = vec![
+            (String::from("a.txt"), b"first".to_vec()),
+            (String::from("A.txt"), b"second".to_vec()),
+        ];
+        let mut archive: ReadWriteArchive = pairs.into_iter().collect();
+        archive.extend([("b.txt", "b")]);
+
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive.get("a.txt").unwrap(), b"second");
+        assert_eq!(archive.get("b.txt").unwrap(), b"b");
+    }
+
+    #[test]
+    fn replace_take_test() {
+        let mut archive = ReadWriteArchive::new();
+        assert_eq!(archive.replace("test.txt", b"one").unwrap(), None);
+        assert_eq!(
+            archive.replace("test.txt", b"two").unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(archive.take("TEST.TXT").unwrap(), b"two");
+        assert!(archive.is_empty());
+        assert!(matches!(
+            archive.take("test.txt"),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+    }
+
+    #[test]
+    fn compact_test() {
+        let payload: Vec<u8> = (0..20000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut archive = ReadWriteArchive::new();
+        archive.set_footer_date(0);
+        archive.set("one.wld", &payload).unwrap();
+        archive.set("two.wld", vec![2u8; 20000]).unwrap();
+        archive.set("three.wld", vec![3u8; 20000]).unwrap();
+        let full = archive.save_to_bytes().unwrap();
+
+        archive.remove("one.wld").unwrap();
+        archive.rename("two.wld", "b.wld").unwrap();
+        archive.set("three.wld", b"three").unwrap();
+        archive.compact();
+        let edited = archive.save_to_bytes().unwrap();
+        assert!(edited.len() < full.len());
+
+        // nothing of the removed or replaced files is left behind
+        let mut fresh = ReadWriteArchive::new();
+        fresh.set_footer_date(0);
+        fresh.set("b.wld", vec![2u8; 20000]).unwrap();
+        fresh.set("three.wld", b"three").unwrap();
+        assert_eq!(edited.len(), fresh.save_to_bytes().unwrap().len());
+
+        let mut readable = ReadableArchive::new();
+        readable.open_from_bytes(&edited).unwrap();
+        assert_eq!(readable.len(), 2);
+        assert_eq!(readable.get("b.wld").unwrap(), vec![2u8; 20000]);
+        assert_eq!(readable.get("three.wld").unwrap(), b"three");
+    }
+}
@@ -0,0 +1,302 @@
+//! Streaming archive packer
+//!
+//! Writes each file's blocks to the output as soon as it's added so only the directory is kept
+//! in memory, giving the lowest peak memory when packing very large sets of files.
+
+use crate::archive::{
+    archive_error::ArchiveError,
+    pfs::{
+        codec::{BlockCodec, BlockCompressor, FileCompression, DEFAULT_COMPRESSION_LEVEL},
+        common::{
+            check_block_size, check_filename, footer_date_now, header_size, pfs_crc,
+            write_directory, write_filename_table_blocks, write_filenames, write_footer,
+            write_header, DirectoryEntry,
+        },
+        constants::{COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE, MAX_BLOCK_SIZE},
+        metadata::write_compression_table,
+        options::ArchiveSettings,
+    },
+};
+use log::{debug, warn};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Packs files into an archive written directly to the output
+/// The header is written when the first file is added and filled in by `finish`, so the
+/// format settings must be chosen before then.
+pub struct StreamPacker<W: Write + Seek> {
+    out: W,
+    start: u64,
+    position: u64,
+    started: bool,
+    names: HashSet<String>,
+    filenames: Vec<String>,
+    directory: Vec<DirectoryEntry>,
+    extended_format: bool,
+    block_codec: BlockCodec,
+    compression_level: u32,
+    block_size: usize,
+    /// Date written to the footer, the time of saving if not set
+    footer_date: Option<u32>,
+}
+
+impl StreamPacker<BufWriter<File>> {
+    /// Create a packer writing to a new file on the file system
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, ArchiveError> {
+        StreamPacker::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> StreamPacker<W> {
+    /// Create a packer writing to the output from its current position
+    pub fn new(mut out: W) -> Result<Self, ArchiveError> {
+        let start = out.stream_position()?;
+        Ok(StreamPacker {
+            out,
+            start,
+            position: 0,
+            started: false,
+            names: HashSet::new(),
+            filenames: Vec::new(),
+            directory: Vec::new(),
+            extended_format: false,
+            block_codec: BlockCodec::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+            footer_date: None,
+        })
+    }
+
+    /// Create a packer writing to the output from its current position with the given settings
+    pub fn with_settings(out: W, settings: ArchiveSettings) -> Result<Self, ArchiveError> {
+        let mut packer = StreamPacker::new(out)?;
+        packer.set_extended_format(settings.extended_format);
+        packer.set_block_codec(settings.block_codec);
+        packer.set_compression_level(settings.compression_level);
+        packer.set_block_size(settings.block_size)?;
+        Ok(packer)
+    }
+
+    /// Write using the extended format with 64-bit offsets and sizes
+    /// Ignored once a file has been added.
+    pub fn set_extended_format(&mut self, extended: bool) {
+        if self.started {
+            warn!("The archive format can't be changed after files have been added");
+            return;
+        }
+
+        self.extended_format = extended;
+    }
+
+    /// Set the codec blocks are compressed with
+    /// Only zlib blocks can be read by the client.
+    pub fn set_block_codec(&mut self, codec: BlockCodec) {
+        self.block_codec = codec;
+    }
+
+    /// Set the compression level from 0 (none) to 9 (best)
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression_level = level.min(9);
+    }
+
+    /// Set the size of the blocks files added from now on are split into, 8192 by default
+    pub fn set_block_size(&mut self, size: usize) -> Result<(), ArchiveError> {
+        self.block_size = check_block_size(size)?;
+        Ok(())
+    }
+
+    /// Set the date written to the footer, by default the time the archive is saved
+    pub fn set_footer_date(&mut self, date: u32) {
+        self.footer_date = Some(date);
+    }
+
+    /// Compress a file from a reader and write it to the output
+    /// Files can't be replaced once written so adding a name twice is an error.
+    pub fn add<R: Read>(&mut self, in_archive_path: &str, reader: R) -> Result<(), ArchiveError> {
+        check_filename(in_archive_path)?;
+        if !self.names.insert(in_archive_path.to_lowercase()) {
+            return Err(ArchiveError::DestFileAlreadyExists);
+        }
+
+        let offset = self.start_data()?;
+        let size = self.write_blocks(reader, self.block_codec)?;
+
+        self.directory.push(DirectoryEntry {
+            crc: pfs_crc(in_archive_path),
+            offset,
+            size,
+        });
+        self.filenames.push(in_archive_path.to_string());
+        Ok(())
+    }
+
+    /// Write the tables, directory and footer then fill in the header, returning the output
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        self.start_data()?;
+        debug!(
+            "Finishing an archive of {} files after writing {} bytes",
+            self.directory.len(),
+            self.position
+        );
+
+        let compression = FileCompression {
+            codec: self.block_codec,
+            level: match self.block_codec {
+                BlockCodec::Stored => 0,
+                _ => self.compression_level,
+            },
+        };
+        if !compression.is_default() {
+            let table: Vec<(u32, FileCompression)> = self
+                .directory
+                .iter()
+                .map(|entry| (entry.crc, compression))
+                .collect();
+            let table = write_compression_table(&table);
+            let offset = self.position;
+            self.write_blocks(&table[..], BlockCodec::Stored)?;
+            self.directory.push(DirectoryEntry {
+                crc: COMPRESSION_TABLE_CRC_VALUE,
+                offset,
+                size: table.len() as u64,
+            });
+        }
+
+        let filenames = write_filenames(&self.filenames);
+        let offset = self.position;
+        let blocks = write_filename_table_blocks(&filenames, self.compression_level)?;
+        self.out.write_all(&blocks)?;
+        self.position += blocks.len() as u64;
+        self.directory.push(DirectoryEntry {
+            crc: FILENAMES_CRC_VALUE,
+            offset,
+            size: filenames.len() as u64,
+        });
+
+        self.directory.sort_by_key(|e| e.crc);
+        let dir_offset = self.position;
+        self.out
+            .write_all(&write_directory(&self.directory, self.extended_format)?)?;
+        self.out.write_all(&write_footer(
+            self.footer_date.unwrap_or_else(footer_date_now),
+        ))?;
+        let end = self.out.stream_position()?;
+
+        self.out.seek(SeekFrom::Start(self.start))?;
+        self.out
+            .write_all(&write_header(dir_offset, self.extended_format)?)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Reserve room for the header if it hasn't been yet, returning where the next data goes
+    fn start_data(&mut self) -> Result<u64, ArchiveError> {
+        if !self.started {
+            let size = header_size(self.extended_format);
+            self.out.write_all(&vec![0u8; size])?;
+            self.position = size as u64;
+            self.started = true;
+        }
+
+        Ok(self.position)
+    }
+
+    /// Compress and write everything in the reader, returning its inflated size
+    fn write_blocks<R: Read>(
+        &mut self,
+        mut reader: R,
+        codec: BlockCodec,
+    ) -> Result<u64, ArchiveError> {
+        let mut size = 0;
+        let mut chunk = Vec::with_capacity(self.block_size);
+        let mut compressor = BlockCompressor::new();
+        let mut block = Vec::new();
+        loop {
+            chunk.clear();
+            reader
+                .by_ref()
+                .take(self.block_size as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            block.clear();
+            compressor.compress_into(codec, &chunk, self.compression_level, &mut block)?;
+            self.out.write_all(&(block.len() as u32).to_le_bytes())?;
+            self.out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+            self.out.write_all(&block)?;
+            self.position += 8 + block.len() as u64;
+            size += chunk.len() as u64;
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::pfs::testing::zlib_filename_table;
+    use crate::archive::prelude::*;
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+    use std::io::Cursor;
+
+    #[test]
+    fn stream_pack_test() {
+        let big: Vec<u8> = (0..30000u32).map(|i| (i % 251) as u8).collect();
+        let path = temp_dir().join("zu_stream_pack_test.s3d");
+
+        let mut packer = StreamPacker::create(&path).unwrap();
+        packer.add("big.wld", Cursor::new(&big)).unwrap();
+        packer.add("hello.txt", &b"hello world"[..]).unwrap();
+        packer.add("empty.txt", &b""[..]).unwrap();
+        assert!(matches!(
+            packer.add("HELLO.txt", &b"again"[..]),
+            Err(ArchiveError::DestFileAlreadyExists)
+        ));
+        packer.finish().unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(read(&path).unwrap()).unwrap();
+        remove_file(&path).unwrap();
+
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.get("big.wld").unwrap(), big);
+        assert_eq!(archive.get("hello.txt").unwrap(), b"hello world");
+        assert!(archive.get("empty.txt").unwrap().is_empty());
+    }
+
+    #[test]
+    fn stream_pack_extended_test() {
+        let settings = ArchiveSettings {
+            extended_format: true,
+            block_codec: BlockCodec::Stored,
+            block_size: 2,
+            ..ArchiveSettings::default()
+        };
+        let mut packer = StreamPacker::with_settings(Cursor::new(Vec::new()), settings).unwrap();
+        packer.add("a.txt", &b"aaaa"[..]).unwrap();
+        let data = packer.finish().unwrap().into_inner();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        assert_eq!(archive.get("a.txt").unwrap(), b"aaaa");
+        assert_eq!(archive.compressed_blocks("a.txt").unwrap().count(), 2);
+        assert!(archive.footer_date().is_some());
+        assert_eq!(archive.min_client_era(), ClientEra::Unsupported);
+        assert_eq!(
+            zlib_filename_table(&data),
+            archive.raw_filename_table().unwrap()
+        );
+        assert_eq!(
+            archive.entry_compression("a.txt").unwrap().codec,
+            BlockCodec::Stored
+        );
+    }
+}
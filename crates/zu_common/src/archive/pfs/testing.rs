@@ -0,0 +1,368 @@
+//! Helpers that build archives and corrupt them in specific ways to exercise error handling
+
+use super::codec::BlockCodec;
+use super::common::{
+    header_size, parse_header, write_archive, write_encoded_filenames, DirectoryEntry, NameEncoding,
+};
+use super::constants::FILENAMES_CRC_VALUE;
+use super::constants::MAX_BLOCK_SIZE;
+use bytes::{BufMut, BytesMut};
+
+pub fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], pos: usize, value: u32) {
+    data[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Get the directory offset and the size of each directory entry
+fn directory(data: &[u8]) -> (usize, usize) {
+    let (_, (dir_offset, extended)) = parse_header(data).unwrap();
+    (dir_offset as usize, if extended { 20 } else { 12 })
+}
+
+/// Find the position of the directory entry with a CRC
+fn find_entry(data: &[u8], crc: u32) -> usize {
+    let (dir_offset, entry_size) = directory(data);
+    let count = read_u32(data, dir_offset) as usize;
+
+    (0..count)
+        .map(|i| dir_offset + 4 + i * entry_size)
+        .find(|pos| read_u32(data, *pos) == crc)
+        .expect("no directory entry with that crc")
+}
+
+/// Build an archive the way older tools did, writing every file's blocks even when the
+/// contents are identical to another file's
+pub fn build_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    build_encoded_archive(files, NameEncoding::Utf8)
+}
+
+/// Build an archive like `build_archive` with every name written in an encoding
+pub fn build_encoded_archive(files: &[(&str, &[u8])], encoding: NameEncoding) -> Vec<u8> {
+    let mut data = BytesMut::new();
+    let mut directory = Vec::new();
+
+    let table = write_encoded_filenames(files.iter().map(|(name, _)| (*name, encoding)));
+    let all = files
+        .iter()
+        .map(|(name, contents)| (encoding.crc(name), *contents))
+        .chain(std::iter::once((FILENAMES_CRC_VALUE, &table[..])));
+
+    for (crc, contents) in all {
+        directory.push(DirectoryEntry {
+            crc,
+            offset: (header_size(false) + data.len()) as u64,
+            size: contents.len() as u64,
+        });
+
+        for chunk in contents.chunks(MAX_BLOCK_SIZE) {
+            let block = BlockCodec::Zlib.compress(chunk).unwrap();
+            data.put_u32_le(block.len() as u32);
+            data.put_u32_le(chunk.len() as u32);
+            data.put(&block[..]);
+        }
+    }
+
+    write_archive(data.freeze(), directory, false, None).unwrap()
+}
+
+/// Get the deflate and inflate length of the first block of the filename table
+pub fn filename_table_block(data: &[u8]) -> (u32, u32) {
+    let pos = find_entry(data, FILENAMES_CRC_VALUE);
+    let offset = read_u32(data, pos + 4) as usize;
+    (read_u32(data, offset), read_u32(data, offset + 4))
+}
+
+/// Inflate every block of the filename table with stock zlib, the way the client reads it
+pub fn zlib_filename_table(data: &[u8]) -> Vec<u8> {
+    let pos = find_entry(data, FILENAMES_CRC_VALUE);
+    let (_, entry_size) = directory(data);
+    // offsets and sizes are little endian so the low half of an extended one reads the same
+    let mut offset = read_u32(data, pos + 4) as usize;
+    let size = read_u32(data, pos + 4 + (entry_size - 4) / 2) as usize;
+
+    let mut table = Vec::new();
+    while table.len() < size {
+        let deflate_length = read_u32(data, offset) as usize;
+        let inflate_length = read_u32(data, offset + 4) as usize;
+        let block = &data[offset + 8..offset + 8 + deflate_length];
+        table.extend(zlib_uncompress(block, inflate_length));
+        offset += 8 + deflate_length;
+    }
+    table
+}
+
+/// Decompress with stock zlib, the library the client reads archives with
+pub fn zlib_uncompress(input: &[u8], inflate_length: usize) -> Vec<u8> {
+    let mut len = inflate_length as _;
+    let mut out = vec![0u8; inflate_length];
+    let res = unsafe {
+        libz_sys::uncompress(out.as_mut_ptr(), &mut len, input.as_ptr(), input.len() as _)
+    };
+    assert_eq!(res, libz_sys::Z_OK);
+    assert_eq!(len as usize, inflate_length);
+    out
+}
+
+/// Replace the version in the header with one that isn't supported
+pub fn flip_version(data: &[u8]) -> Vec<u8> {
+    let mut ret = data.to_vec();
+    write_u32(&mut ret, 8, 0xdeadbeef);
+    ret
+}
+
+/// Point the directory at the start of the archive
+pub fn zero_dir_offset(data: &[u8]) -> Vec<u8> {
+    let (_, (_, extended)) = parse_header(data).unwrap();
+    let mut ret = data.to_vec();
+    if extended {
+        ret[12..20].fill(0);
+    } else {
+        write_u32(&mut ret, 0, 0);
+    }
+    ret
+}
+
+/// Point the directory somewhere else in the archive
+pub fn move_dir_offset(data: &[u8], dir_offset: u64) -> Vec<u8> {
+    let (_, (_, extended)) = parse_header(data).unwrap();
+    let mut ret = data.to_vec();
+    if extended {
+        ret[12..20].copy_from_slice(&dir_offset.to_le_bytes());
+    } else {
+        write_u32(&mut ret, 0, dir_offset as u32);
+    }
+    ret
+}
+
+/// Point the directory entry with a CRC at another offset, standard format only
+pub fn move_entry(data: &[u8], crc: u32, offset: u32) -> Vec<u8> {
+    let pos = find_entry(data, crc);
+    let mut ret = data.to_vec();
+    write_u32(&mut ret, pos + 4, offset);
+    ret
+}
+
+/// Cut the archive off after a number of bytes
+pub fn truncate(data: &[u8], len: usize) -> Vec<u8> {
+    data[..len].to_vec()
+}
+
+/// Overwrite the deflate length of the first block of the file with a CRC
+pub fn corrupt_deflate_length(data: &[u8], crc: u32, deflate_length: u32) -> Vec<u8> {
+    let pos = find_entry(data, crc);
+    let offset = read_u32(data, pos + 4) as usize;
+    let mut ret = data.to_vec();
+    write_u32(&mut ret, offset, deflate_length);
+    ret
+}
+
+/// Overwrite the inflate length of the first block of the file with a CRC
+pub fn corrupt_inflate_length(data: &[u8], crc: u32, inflate_length: u32) -> Vec<u8> {
+    let pos = find_entry(data, crc);
+    let offset = read_u32(data, pos + 4) as usize;
+    let mut ret = data.to_vec();
+    write_u32(&mut ret, offset + 4, inflate_length);
+    ret
+}
+
+/// Point the directory entry of a file at the second block of another file so their data
+/// overlaps
+pub fn overlap_block(data: &[u8], crc: u32, other_crc: u32) -> Vec<u8> {
+    let other = read_u32(data, find_entry(data, other_crc) + 4) as usize;
+    let second = other + 8 + read_u32(data, other) as usize;
+    let mut ret = data.to_vec();
+    write_u32(&mut ret, find_entry(data, crc) + 4, second as u32);
+    ret
+}
+
+/// Overwrite the compressed data of the first block of the file with a CRC so it won't inflate
+pub fn corrupt_block(data: &[u8], crc: u32) -> Vec<u8> {
+    let pos = find_entry(data, crc);
+    let offset = read_u32(data, pos + 4) as usize;
+    let deflate_length = read_u32(data, offset) as usize;
+    let mut ret = data.to_vec();
+    ret[offset + 8..offset + 8 + deflate_length].fill(0xff);
+    ret
+}
+
+/// Remove the filename table from the directory
+/// Its data is left in place but nothing references it.
+pub fn strip_filename_table(data: &[u8]) -> Vec<u8> {
+    let (dir_offset, entry_size) = directory(data);
+    let pos = find_entry(data, FILENAMES_CRC_VALUE);
+    let count = read_u32(data, dir_offset);
+
+    let mut ret = data.to_vec();
+    ret.drain(pos..pos + entry_size);
+    write_u32(&mut ret, dir_offset, count - 1);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::pfs::common::{crc_label, pfs_crc};
+    use crate::archive::prelude::*;
+
+    fn archive_bytes() -> Vec<u8> {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", vec![7u8; 10000]).unwrap();
+        writable.save_to_bytes().unwrap()
+    }
+
+    fn open(data: Vec<u8>) -> Result<ReadableArchive, ArchiveError> {
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(data)?;
+        Ok(archive)
+    }
+
+    #[test]
+    fn flip_version_test() {
+        assert!(matches!(
+            open(flip_version(&archive_bytes())),
+            Err(ArchiveError::WrongVersion {
+                version: 0xdeadbeef
+            })
+        ));
+    }
+
+    #[test]
+    fn zero_dir_offset_test() {
+        // the directory would start at the header itself
+        assert!(matches!(
+            open(zero_dir_offset(&archive_bytes())),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn move_dir_offset_test() {
+        // only two bytes are left for the four byte entry count
+        let data = archive_bytes();
+        let data = move_dir_offset(&data, data.len() as u64 - 2);
+        match open(data.clone()) {
+            Err(ArchiveError::ParseAt { offset, .. }) => assert_eq!(offset, data.len() - 2),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        match ReadWriteArchive::new().open_from_bytes(&data) {
+            Err(ArchiveError::ParseAt { offset, .. }) => assert_eq!(offset, data.len() - 2),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn move_entry_test() {
+        // the file starts after the directory
+        let data = archive_bytes();
+        let data = move_entry(&data, pfs_crc("test.txt"), directory(&data).0 as u32 + 1);
+        assert!(matches!(
+            open(data.clone()),
+            Err(ArchiveError::FilesOutsideData(names)) if names == ["test.txt"]
+        ));
+        assert!(matches!(
+            ReadWriteArchive::new().open_from_bytes(&data),
+            Err(ArchiveError::FilesOutsideData(names)) if names == ["test.txt"]
+        ));
+        assert!(matches!(
+            ReadableArchive::new().open_crc_only(&data),
+            Err(ArchiveError::FilesOutsideData(names)) if names == [crc_label(pfs_crc("test.txt"))]
+        ));
+
+        // the filename table runs into the directory so only its CRC can name it
+        let data = archive_bytes();
+        let offset = read_u32(&data, find_entry(&data, FILENAMES_CRC_VALUE) + 4) as usize;
+        let length = read_u32(&data, offset);
+        let data = corrupt_deflate_length(&data, FILENAMES_CRC_VALUE, length + 8);
+        assert!(matches!(
+            open(data.clone()),
+            Err(ArchiveError::FilesOutsideData(names)) if names == [crc_label(FILENAMES_CRC_VALUE)]
+        ));
+    }
+
+    #[test]
+    fn truncate_test() {
+        let data = archive_bytes();
+        // the header is cut off part way through the magic that follows the directory offset
+        assert!(matches!(
+            open(truncate(&data, 6)),
+            Err(ArchiveError::ParseAt { offset: 4, .. })
+        ));
+        assert!(matches!(
+            open(truncate(&data, data.len() - 10)),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn corrupt_deflate_length_test() {
+        let data = corrupt_deflate_length(&archive_bytes(), pfs_crc("test.txt"), 0xffff);
+        let block = read_u32(&data, find_entry(&data, pfs_crc("test.txt")) + 4) as usize;
+        match open(data) {
+            Err(ArchiveError::ParseAt { offset, .. }) => assert_eq!(offset, block + 8),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn out_of_range_deflate_length_test() {
+        let data = corrupt_deflate_length(&archive_bytes(), pfs_crc("test.txt"), u32::MAX);
+        assert!(matches!(
+            open(data.clone()),
+            Err(ArchiveError::ParseAt { .. })
+        ));
+        assert!(matches!(
+            ReadWriteArchive::new().open_from_bytes(data),
+            Err(ArchiveError::ParseAt { .. })
+        ));
+    }
+
+    #[test]
+    fn corrupt_inflate_length_test() {
+        let data = corrupt_inflate_length(&archive_bytes(), pfs_crc("test.txt"), 0);
+        assert!(matches!(open(data.clone()), Err(ArchiveError::Parse(_))));
+        assert!(matches!(
+            ReadWriteArchive::new().open_from_bytes(data),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn overlap_block_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", vec![7u8; 20000]).unwrap();
+        writable.set("small.txt", b"small").unwrap();
+        let data = overlap_block(
+            &writable.save_to_bytes().unwrap(),
+            pfs_crc("small.txt"),
+            pfs_crc("big.wld"),
+        );
+
+        let archive = open(data).unwrap();
+        assert_eq!(archive.overlapping_files(), vec!["big.wld", "small.txt"]);
+    }
+
+    #[test]
+    fn corrupt_block_test() {
+        let archive = open(corrupt_block(&archive_bytes(), pfs_crc("test.txt"))).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::Decompression)
+        ));
+    }
+
+    #[test]
+    fn strip_filename_table_test() {
+        let archive = open(strip_filename_table(&archive_bytes())).unwrap();
+        assert!(matches!(
+            archive.get("test.txt"),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+        assert_eq!(
+            archive.get_by_crc(pfs_crc("test.txt")).unwrap(),
+            vec![7u8; 10000]
+        );
+    }
+}
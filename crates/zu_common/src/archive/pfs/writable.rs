@@ -1,13 +1,28 @@
 use crate::archive::{
     archive_error::ArchiveError,
-    archive_trait::{IArchive, IWritableArchive},
-    pfs::constants::{FILENAMES_CRC_VALUE, PFS_CRC_ALGO},
-    pfs::{common::write_filenames, constants::MAX_BLOCK_SIZE},
+    archive_trait::{IArchive, IReadableArchive, IWritableArchive},
+    merge::{merge_into, MergeSummary, MergeTarget},
+    pfs::constants::{COMPRESSION_TABLE_CRC_VALUE, FILENAMES_CRC_VALUE},
+    pfs::metadata::write_compression_table,
+    pfs::{
+        codec::{
+            BlockCodec, BlockCompressor, BufferPool, FileCompression, DEFAULT_COMPRESSION_LEVEL,
+        },
+        common::{
+            check_block_size, check_filename, check_reserved_crc, footer_date_now, header_size,
+            write_archive, write_encoded_filenames, write_filename_table_blocks, DirectoryEntry,
+            NameEncoding,
+        },
+        constants::MAX_BLOCK_SIZE,
+        options::ArchiveSettings,
+        raw::RawFile,
+    },
 };
 use bytes::{BufMut, Bytes, BytesMut};
-use crc::Crc;
-use flate2::{write::ZlibEncoder, Compression};
-use std::{collections::HashMap, io::Write};
+use log::debug;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::collections::{hash_map::Entry, HashMap};
 
 /// A writable PFS archive
 /// Simplier than the read+write variant
@@ -16,15 +31,203 @@ use std::{collections::HashMap, io::Write};
 /// at save time.
 pub struct WritableArchive {
     files: HashMap<String, WritableArchiveFile>,
+    extended_format: bool,
+    block_codec: BlockCodec,
+    compression_level: u32,
+    block_size: usize,
+    /// Date written to the footer, the time of saving if not set
+    footer_date: Option<u32>,
 }
 
+/// Files are keyed by their lowercased name and keep the name they were given for saving
 struct WritableArchiveFile {
+    name: String,
+    encoding: NameEncoding,
     data: Vec<u8>,
+    codec: Option<BlockCodec>,
+    /// Blocks that are already compressed and written as is, data is empty when these are set
+    raw: Option<RawFile>,
+}
+
+impl WritableArchive {
+    /// Save using the extended format with 64-bit offsets and sizes
+    /// Only needed for archives larger than 4GB; standard PFS readers will reject these.
+    pub fn set_extended_format(&mut self, extended: bool) {
+        self.extended_format = extended;
+    }
+
+    /// Set the codec used to compress blocks when saving
+    /// The client only reads zlib blocks, archives saved with any other codec can only be read
+    /// by tools like this one.
+    pub fn set_block_codec(&mut self, codec: BlockCodec) {
+        self.block_codec = codec;
+    }
+
+    /// Set the compression level from 0 (none) to 9 (best) used when saving
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression_level = level;
+    }
+
+    /// Set the size of the blocks files are split into, 8192 by default to match the client
+    /// Larger blocks compress a little better but older clients can't read them, see
+    /// `ReadableArchive::min_client_era`.
+    pub fn set_block_size(&mut self, size: usize) -> Result<(), ArchiveError> {
+        self.block_size = check_block_size(size)?;
+        Ok(())
+    }
+
+    /// Set the date written to the footer, by default the time the archive is saved
+    pub fn set_footer_date(&mut self, date: u32) {
+        self.footer_date = Some(date);
+    }
+
+    /// Create an empty archive that's saved with the given settings
+    pub fn with_settings(settings: ArchiveSettings) -> Result<WritableArchive, ArchiveError> {
+        let mut archive = WritableArchive::new();
+        archive.set_extended_format(settings.extended_format);
+        archive.set_block_codec(settings.block_codec);
+        archive.set_compression_level(settings.compression_level);
+        archive.set_block_size(settings.block_size)?;
+        Ok(archive)
+    }
+
+    /// Sets a file in the archive to be stored uncompressed
+    /// Meant for tools working with data such as textures and audio that's already compressed;
+    /// the client only reads zlib blocks so it can't load files stored this way.
+    pub fn set_stored<T>(&mut self, in_archive_path: &str, input: T) -> Result<(), ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        check_filename(in_archive_path)?;
+        let new_file = WritableArchiveFile {
+            name: in_archive_path.to_string(),
+            encoding: NameEncoding::Utf8,
+            data: input.as_ref().to_vec(),
+            codec: Some(BlockCodec::Stored),
+            raw: None,
+        };
+
+        self.files.insert(in_archive_path.to_lowercase(), new_file);
+        Ok(())
+    }
+
+    /// Sets a file in the archive to blocks that are already compressed
+    /// The blocks are written as is when saving, so a file taken from another archive with
+    /// `ReadableArchive::raw_blocks` is copied without being recompressed.
+    pub fn set_raw(&mut self, in_archive_path: &str, raw: RawFile) -> Result<(), ArchiveError> {
+        check_filename(in_archive_path)?;
+        let new_file = WritableArchiveFile {
+            name: in_archive_path.to_string(),
+            encoding: NameEncoding::Utf8,
+            data: Vec::new(),
+            codec: None,
+            raw: Some(raw),
+        };
+
+        self.files.insert(in_archive_path.to_lowercase(), new_file);
+        Ok(())
+    }
+
+    /// Sets a file in the archive to a specific block of bytes
+    /// Returns the contents of the file it replaced, if there was one. The archive can't be
+    /// read from so this is used instead of the `IWritableArchive` default.
+    pub fn replace<T>(
+        &mut self,
+        in_archive_path: &str,
+        input: T,
+    ) -> Result<Option<Vec<u8>>, ArchiveError>
+    where
+        T: AsRef<[u8]>,
+    {
+        check_filename(in_archive_path)?;
+        let in_archive_path_lower = in_archive_path.to_lowercase();
+        let input_ref = input.as_ref();
+        let new_file = WritableArchiveFile {
+            name: in_archive_path.to_string(),
+            encoding: NameEncoding::Utf8,
+            data: input_ref.to_vec(),
+            codec: None,
+            raw: None,
+        };
+
+        self.files
+            .insert(in_archive_path_lower, new_file)
+            .map(WritableArchiveFile::into_data)
+            .transpose()
+    }
+
+    /// Removes a file in the archive, returning its contents
+    pub fn take(&mut self, in_archive_path: &str) -> Result<Vec<u8>, ArchiveError> {
+        let in_archive_path_lower = in_archive_path.to_lowercase();
+        match self.files.remove(&in_archive_path_lower) {
+            Some(f) => f.into_data(),
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+
+    /// Copy every file from another archive into this one
+    /// Files with the same name are replaced when overwrite is set and skipped otherwise.
+    pub fn merge<A>(&mut self, other: &A, overwrite: bool) -> Result<MergeSummary, ArchiveError>
+    where
+        A: IReadableArchive,
+    {
+        merge_into(self, other, overwrite)
+    }
+
+    /// Set how the name of a file is encoded in the filename table
+    /// Only needed to keep a Latin-1 name a legacy tool wrote, and its CRC, when copying it from
+    /// another archive. Names that are set, renamed or copied are written as UTF-8.
+    pub fn set_name_encoding(
+        &mut self,
+        in_archive_path: &str,
+        encoding: NameEncoding,
+    ) -> Result<(), ArchiveError> {
+        check_reserved_crc(in_archive_path, encoding.crc(in_archive_path))?;
+        match self.files.get_mut(&in_archive_path.to_lowercase()) {
+            Some(file) => {
+                file.encoding = encoding;
+                Ok(())
+            }
+            None => Err(ArchiveError::SrcFileNotFound),
+        }
+    }
+}
+
+impl MergeTarget for WritableArchive {
+    fn contains(&self, in_archive_path: &str) -> bool {
+        self.files.contains_key(&in_archive_path.to_lowercase())
+    }
+
+    fn set_name_encoding(
+        &mut self,
+        in_archive_path: &str,
+        encoding: NameEncoding,
+    ) -> Result<(), ArchiveError> {
+        WritableArchive::set_name_encoding(self, in_archive_path, encoding)
+    }
 }
 
 impl WritableArchiveFile {
-    fn deflate(&self) -> Result<Bytes, ArchiveError> {
+    /// Size of the file once inflated
+    fn len(&self) -> usize {
+        match &self.raw {
+            Some(raw) => raw.len(),
+            None => self.data.len(),
+        }
+    }
+
+    /// Get the contents of the file, inflating them if it was set from raw blocks
+    fn into_data(self) -> Result<Vec<u8>, ArchiveError> {
+        match self.raw {
+            Some(raw) => raw.inflate(),
+            None => Ok(self.data),
+        }
+    }
+
+    fn deflate(&self, codec: BlockCodec, level: u32) -> Result<Bytes, ArchiveError> {
         let mut buffer = BytesMut::with_capacity(1024);
+        let mut compressor = BlockCompressor::new();
+        let mut compressed = Vec::new();
         let mut remain = self.data.len();
         let mut pos = 0usize;
 
@@ -38,9 +241,8 @@ impl WritableArchiveFile {
                 remain = 0;
             }
 
-            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&self.data[pos..pos + sz])?;
-            let compressed = encoder.finish()?;
+            compressed.clear();
+            compressor.compress_into(codec, &self.data[pos..pos + sz], level, &mut compressed)?;
 
             buffer.put_u32_le(compressed.len() as u32);
             buffer.put_u32_le(sz as u32);
@@ -52,93 +254,297 @@ impl WritableArchiveFile {
     }
 }
 
+impl Default for WritableArchive {
+    fn default() -> Self {
+        WritableArchive::new()
+    }
+}
+
+/// Files are added in order with `set` so a later entry replaces an earlier one with the same
+/// name, ignoring case.
+///
+/// # Panics
+/// If a file has an invalid name, use `try_extend` to get the error instead.
+impl<S, D> Extend<(S, D)> for WritableArchive
+where
+    S: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    fn extend<I: IntoIterator<Item = (S, D)>>(&mut self, iter: I) {
+        if let Err(e) = self.try_extend(iter) {
+            panic!("unable to add file: {}", e);
+        }
+    }
+}
+
+/// # Panics
+/// If a file has an invalid name.
+impl<S, D> FromIterator<(S, D)> for WritableArchive
+where
+    S: AsRef<str>,
+    D: AsRef<[u8]>,
+{
+    fn from_iter<I: IntoIterator<Item = (S, D)>>(iter: I) -> Self {
+        let mut archive = WritableArchive::new();
+        archive.extend(iter);
+        archive
+    }
+}
+
 impl IArchive for WritableArchive {
     fn new() -> Self {
         WritableArchive {
             files: HashMap::new(),
+            extended_format: false,
+            block_codec: BlockCodec::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            block_size: MAX_BLOCK_SIZE,
+            footer_date: None,
         }
     }
 
     fn close(&mut self) {
         self.files.clear();
     }
+
+    fn len(&self) -> usize {
+        self.files.len()
+    }
 }
 
-impl IWritableArchive for WritableArchive {
-    fn save_to_bytes(&self) -> Result<Vec<u8>, ArchiveError> {
+impl WritableArchive {
+    fn file_compression(&self, file: &WritableArchiveFile) -> FileCompression {
+        match file.codec.unwrap_or(self.block_codec) {
+            BlockCodec::Stored => FileCompression {
+                codec: BlockCodec::Stored,
+                level: 0,
+            },
+            codec => FileCompression {
+                codec,
+                level: self.compression_level,
+            },
+        }
+    }
+
+    fn save<F>(&self, parallel: bool, mut progress: F) -> Result<Vec<u8>, ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
         let mut data = BytesMut::with_capacity(1024);
-        let mut directory = BytesMut::with_capacity(1024);
-        directory.put_u32_le(self.files.len() as u32 + 1);
+        let mut directory = Vec::with_capacity(self.files.len() + 1);
+        let header_size = header_size(self.extended_format);
+
+        // lay files out in the same order on every run rather than the map's
+        let mut files: Vec<&WritableArchiveFile> = self.files.values().collect();
+        files.sort_by_key(|f| (f.encoding.crc(&f.name), f.name.as_str()));
+
+        // files with identical contents share one copy of their blocks
+        let mut unique: HashMap<(BlockCodec, &[u8]), usize> = HashMap::new();
+        let mut sources = Vec::with_capacity(files.len());
+        let mut jobs = Vec::with_capacity(files.len());
+        for (i, file) in files.iter().enumerate() {
+            if file.raw.is_some() {
+                sources.push(None);
+                jobs.push(Vec::new());
+                continue;
+            }
 
-        let crc_provider = Crc::<u32>::new(&PFS_CRC_ALGO);
+            let codec = file.codec.unwrap_or(self.block_codec);
+            match unique.entry((codec, &file.data[..])) {
+                Entry::Occupied(e) => {
+                    sources.push(Some(*e.get()));
+                    jobs.push(Vec::new());
+                }
+                Entry::Vacant(e) => {
+                    e.insert(i);
+                    sources.push(None);
+                    jobs.push(
+                        file.data
+                            .chunks(self.block_size)
+                            .map(|chunk| (codec, chunk))
+                            .collect(),
+                    );
+                }
+            }
+        }
+
+        // every block is independent so a batch of files has all its blocks compressed at once
+        // then laid out, the buffers of written blocks are reused by the next batch
+        let pool = BufferPool::new();
+        let mut block_count = 0;
         let mut filenames = Vec::new();
-        for (filename, file) in &self.files {
-            let blocks = file.deflate()?;
-            let offset = data.len() + 12;
-            let mut digest = crc_provider.digest();
-            digest.update(filename.to_lowercase().as_bytes());
-            digest.update(b"\0");
+        let mut compression = Vec::with_capacity(files.len());
+        let mut offsets = Vec::with_capacity(files.len());
+        for start in (0..files.len()).step_by(PROGRESS_BATCH_FILES) {
+            let end = files.len().min(start + PROGRESS_BATCH_FILES);
+            let blocks: Vec<(BlockCodec, &[u8])> =
+                jobs[start..end].iter().flatten().copied().collect();
+            block_count += blocks.len();
+            let mut compressed =
+                compress_blocks(&blocks, self.compression_level, parallel, &pool)?.into_iter();
+
+            for (file, source) in files[start..end].iter().zip(&sources[start..end]) {
+                let offset = match source {
+                    Some(i) => offsets[*i],
+                    None => {
+                        let offset = data.len() + header_size;
+                        if let Some(raw) = &file.raw {
+                            for block in &raw.blocks {
+                                data.put_u32_le(block.data.len() as u32);
+                                data.put_u32_le(block.inflate_length as u32);
+                                data.put(&block.data[..]);
+                            }
+                        }
 
-            let crc = digest.finalize();
+                        for chunk in file.data.chunks(self.block_size) {
+                            let block = compressed.next().ok_or(ArchiveError::Unknown)?;
+                            data.put_u32_le(block.len() as u32);
+                            data.put_u32_le(chunk.len() as u32);
+                            data.put(&block[..]);
+                            pool.give(block);
+                        }
+                        offset
+                    }
+                };
 
-            data.put(blocks);
-            directory.put_u32_le(crc);
-            directory.put_u32_le(offset as u32);
-            directory.put_u32_le(file.data.len() as u32);
-            filenames.push(filename.clone());
+                let crc = file.encoding.crc(&file.name);
+                match &file.raw {
+                    Some(raw) => compression.extend(raw.compression.map(|c| (crc, c))),
+                    None => compression.push((crc, self.file_compression(file))),
+                }
+                offsets.push(offset);
+                directory.push(DirectoryEntry {
+                    crc,
+                    offset: offset as u64,
+                    size: file.len() as u64,
+                });
+                filenames.push((file.name.as_str(), file.encoding));
+            }
+            progress(end, files.len());
+        }
+        debug!(
+            "Compressed {} blocks for {} files",
+            block_count,
+            files.len()
+        );
+
+        if compression.iter().any(|(_, c)| !c.is_default()) {
+            let table = WritableArchiveFile {
+                name: String::new(),
+                encoding: NameEncoding::Utf8,
+                data: write_compression_table(&compression),
+                codec: None,
+                raw: None,
+            };
+
+            let offset = data.len() + header_size;
+            data.put(table.deflate(BlockCodec::Stored, 0)?);
+            directory.push(DirectoryEntry {
+                crc: COMPRESSION_TABLE_CRC_VALUE,
+                offset: offset as u64,
+                size: table.data.len() as u64,
+            });
         }
 
         //do filename file
-        let filenames_data = write_filenames(&filenames);
-        let filenames_file = WritableArchiveFile {
-            data: filenames_data.to_vec(),
-        };
+        let filenames_data = write_encoded_filenames(filenames);
+        let offset = data.len() + header_size;
+        data.put(write_filename_table_blocks(
+            &filenames_data,
+            self.compression_level,
+        )?);
+        directory.push(DirectoryEntry {
+            crc: FILENAMES_CRC_VALUE,
+            offset: offset as u64,
+            size: filenames_data.len() as u64,
+        });
 
-        let blocks = filenames_file.deflate()?;
-        let offset = data.len() + 12;
-        data.put(blocks);
-        directory.put_u32_le(FILENAMES_CRC_VALUE);
-        directory.put_u32_le(offset as u32);
-        directory.put_u32_le(filenames_file.data.len() as u32);
-
-        let data = data.freeze();
-        let directory = directory.freeze();
-
-        let mut final_data = BytesMut::with_capacity(12 + data.len() + directory.len());
-        final_data.put_u32_le(data.len() as u32 + 12);
-        final_data.put_u8(b'P');
-        final_data.put_u8(b'F');
-        final_data.put_u8(b'S');
-        final_data.put_u8(b' ');
-        final_data.put_u32_le(131072);
-        final_data.put(data);
-        final_data.put(directory);
-
-        let final_data = final_data.freeze();
-        Ok(final_data.to_vec())
-    }
-
-    fn save_to_file(&self, filename: &str) -> Result<(), ArchiveError> {
-        let data = self.save_to_bytes()?;
-        std::fs::write(filename, data)?;
-        Ok(())
+        write_archive(
+            data.freeze(),
+            directory,
+            self.extended_format,
+            Some(self.footer_date.unwrap_or_else(footer_date_now)),
+        )
+    }
+}
+
+/// Number of files compressed between progress reports when saving
+const PROGRESS_BATCH_FILES: usize = 64;
+
+#[cfg(all(test, feature = "rayon"))]
+thread_local! {
+    /// Number of batches this thread has handed to rayon to compress
+    static PARALLEL_BATCHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Compress blocks in order, spread across threads when the `rayon` feature is enabled
+#[cfg(feature = "rayon")]
+fn compress_blocks(
+    jobs: &[(BlockCodec, &[u8])],
+    level: u32,
+    parallel: bool,
+    pool: &BufferPool,
+) -> Result<Vec<Vec<u8>>, ArchiveError> {
+    if parallel {
+        #[cfg(test)]
+        PARALLEL_BATCHES.with(|batches| batches.set(batches.get() + 1));
+        jobs.par_iter()
+            .map_init(BlockCompressor::new, |compressor, (codec, chunk)| {
+                compress_block(compressor, *codec, chunk, level, pool)
+            })
+            .collect()
+    } else {
+        let mut compressor = BlockCompressor::new();
+        jobs.iter()
+            .map(|(codec, chunk)| compress_block(&mut compressor, *codec, chunk, level, pool))
+            .collect()
+    }
+}
+
+/// Compress blocks in order, spread across threads when the `rayon` feature is enabled
+#[cfg(not(feature = "rayon"))]
+fn compress_blocks(
+    jobs: &[(BlockCodec, &[u8])],
+    level: u32,
+    _parallel: bool,
+    pool: &BufferPool,
+) -> Result<Vec<Vec<u8>>, ArchiveError> {
+    let mut compressor = BlockCompressor::new();
+    jobs.iter()
+        .map(|(codec, chunk)| compress_block(&mut compressor, *codec, chunk, level, pool))
+        .collect()
+}
+
+/// Compress a block into a buffer from the pool
+fn compress_block(
+    compressor: &mut BlockCompressor,
+    codec: BlockCodec,
+    chunk: &[u8],
+    level: u32,
+    pool: &BufferPool,
+) -> Result<Vec<u8>, ArchiveError> {
+    let mut block = pool.take();
+    compressor.compress_into(codec, chunk, level, &mut block)?;
+    Ok(block)
+}
+
+impl IWritableArchive for WritableArchive {
+    fn save_to_bytes(&self) -> Result<Vec<u8>, ArchiveError> {
+        self.save(true, |_, _| {})
+    }
+
+    fn save_to_bytes_with_progress<F>(&self, progress: F) -> Result<Vec<u8>, ArchiveError>
+    where
+        F: FnMut(usize, usize),
+    {
+        self.save(true, progress)
     }
 
     fn set<T>(&mut self, in_archive_path: &str, input: T) -> Result<(), ArchiveError>
     where
         T: AsRef<[u8]>,
     {
-        let in_archive_path_lower = in_archive_path.to_lowercase();
-        if self.files.contains_key(&in_archive_path_lower) {
-            return Err(ArchiveError::DestFileAlreadyExists);
-        }
-
-        let input_ref = input.as_ref();
-        let new_file = WritableArchiveFile {
-            data: input_ref.to_vec(),
-        };
-
-        self.files.insert(in_archive_path_lower, new_file);
+        self.replace(in_archive_path, input)?;
         Ok(())
     }
 
@@ -155,15 +561,21 @@ impl IWritableArchive for WritableArchive {
         in_archive_path: &str,
         new_in_archive_path: &str,
     ) -> Result<(), ArchiveError> {
+        check_filename(new_in_archive_path)?;
         let in_archive_path_lower = in_archive_path.to_lowercase();
         let new_in_archive_path_lower = new_in_archive_path.to_lowercase();
 
-        if self.files.contains_key(&new_in_archive_path_lower) {
+        // renaming to the same name with different case only changes the saved name
+        if in_archive_path_lower != new_in_archive_path_lower
+            && self.files.contains_key(&new_in_archive_path_lower)
+        {
             return Err(ArchiveError::DestFileAlreadyExists);
         }
 
         match self.files.remove(&in_archive_path_lower) {
-            Some(f) => {
+            Some(mut f) => {
+                f.name = new_in_archive_path.to_string();
+                f.encoding = NameEncoding::Utf8;
                 self.files.insert(new_in_archive_path_lower, f);
                 Ok(())
             }
@@ -176,6 +588,7 @@ impl IWritableArchive for WritableArchive {
         in_archive_path: &str,
         new_in_archive_path: &str,
     ) -> Result<(), ArchiveError> {
+        check_filename(new_in_archive_path)?;
         let in_archive_path_lower = in_archive_path.to_lowercase();
         let new_in_archive_path_lower = new_in_archive_path.to_lowercase();
 
@@ -188,7 +601,11 @@ impl IWritableArchive for WritableArchive {
 
         if let Some(f) = existing {
             new_file = WritableArchiveFile {
+                name: new_in_archive_path.to_string(),
+                encoding: NameEncoding::Utf8,
                 data: f.data.to_vec(),
+                codec: f.codec,
+                raw: f.raw.clone(),
             }
         } else {
             return Err(ArchiveError::SrcFileNotFound);
@@ -198,3 +615,452 @@ impl IWritableArchive for WritableArchive {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::pfs::testing::{
+        build_encoded_archive, filename_table_block, zlib_filename_table,
+    };
+    use crate::archive::prelude::*;
+
+    #[test]
+    fn set_overwrite_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"first").unwrap();
+        writable.set("TEST.txt", b"second").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.search(".*").unwrap(), vec!["TEST.txt"]);
+        assert_eq!(archive.get("test.txt").unwrap(), b"second");
+    }
+
+    #[test]
+    fn small_filename_table_zlib_test() {
+        let mut writable = WritableArchive::new();
+        writable.set_block_codec(BlockCodec::Stored);
+        writable.set("a.txt", b"aaaa").unwrap();
+        writable.set("b.txt", b"bbbb").unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        // level 0 only adds the zlib header, one stored deflate block header and the checksum
+        let (deflate_length, inflate_length) = filename_table_block(&data);
+        assert!(deflate_length <= inflate_length + 11);
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        let raw = archive.raw_filename_table().unwrap();
+        assert_eq!(zlib_filename_table(&data), raw);
+        assert!(BlockCodec::Zlib.compress(&raw).unwrap().len() >= raw.len());
+        assert_eq!(archive.get("a.txt").unwrap(), b"aaaa");
+        assert_eq!(archive.get("b.txt").unwrap(), b"bbbb");
+
+        let mut writable = WritableArchive::new();
+        for i in 0..100 {
+            writable.set(&format!("file{}.txt", i), b"data").unwrap();
+        }
+        let data = writable.save_to_bytes().unwrap();
+        let (deflate_length, inflate_length) = filename_table_block(&data);
+        assert!(deflate_length < inflate_length);
+        assert_eq!(zlib_filename_table(&data).len(), inflate_length as usize);
+    }
+
+    #[test]
+    fn compression_level_test() {
+        let contents: Vec<u8> = (0..50000u32).map(|i| (i % 97) as u8).collect();
+
+        let mut sizes = Vec::new();
+        for level in [0, 9] {
+            let mut writable = WritableArchive::new();
+            writable.set_compression_level(level);
+            writable.set("test.wld", &contents).unwrap();
+            let data = writable.save_to_bytes().unwrap();
+            sizes.push(data.len());
+
+            let mut archive = ReadableArchive::new();
+            archive.open_from_bytes(data).unwrap();
+            assert_eq!(archive.get("test.wld").unwrap(), contents);
+        }
+
+        assert!(sizes[0] > sizes[1]);
+    }
+
+    #[test]
+    fn block_size_test() {
+        let contents: Vec<u8> = (0..100000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        let mut inflated = Vec::new();
+        for size in [8192, 32768] {
+            let mut writable = WritableArchive::new();
+            writable.set_block_size(size).unwrap();
+            writable.set("test.wld", &contents).unwrap();
+
+            let mut archive = ReadableArchive::new();
+            archive
+                .open_from_bytes(writable.save_to_bytes().unwrap())
+                .unwrap();
+            assert!(archive
+                .compressed_blocks("test.wld")
+                .unwrap()
+                .all(|(_, len)| len <= size));
+            assert_eq!(
+                archive.compressed_blocks("test.wld").unwrap().count(),
+                contents.len().div_ceil(size)
+            );
+            inflated.push(archive.get("test.wld").unwrap());
+        }
+
+        assert_eq!(inflated[0], contents);
+        assert_eq!(inflated[1], contents);
+
+        assert!(matches!(
+            WritableArchive::new().set_block_size(0),
+            Err(ArchiveError::InvalidBlockSize { size: 0 })
+        ));
+    }
+
+    #[test]
+    fn invalid_name_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("test.txt", b"hello world").unwrap();
+        assert!(matches!(
+            writable.set("bad\0.txt", b"bad"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert!(matches!(
+            writable.set_stored("bad\n.txt", b"bad"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert!(matches!(
+            writable.rename("test.txt", "test\0.txt"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert!(matches!(
+            writable.copy("test.txt", "copy\0.txt"),
+            Err(ArchiveError::InvalidName(_))
+        ));
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.search(".*").unwrap(), vec!["test.txt"]);
+    }
+
+    #[test]
+    fn try_extend_test() {
+        let mut writable = WritableArchive::new();
+        assert!(matches!(
+            writable.try_extend([("one.txt", b"1"), ("bad\0.txt", b"2"), ("three.txt", b"3")]),
+            Err(ArchiveError::InvalidName(_))
+        ));
+        assert_eq!(writable.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid name")]
+    fn extend_invalid_name_test() {
+        let mut writable = WritableArchive::new();
+        writable.extend([("bad\0.txt", b"bad")]);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_save_test() {
+        let mut writable = WritableArchive::new();
+        for i in 0..20 {
+            let contents: Vec<u8> = (0..(i * 3000)).map(|j| (j % (i + 2)) as u8).collect();
+            writable.set(&format!("file{}.wld", i), &contents).unwrap();
+        }
+        writable.set_stored("stored.dds", vec![9u8; 20000]).unwrap();
+        writable.set_footer_date(1);
+
+        let serial = writable.save(false, |_, _| {}).unwrap();
+        assert_eq!(super::PARALLEL_BATCHES.with(|batches| batches.get()), 0);
+        assert_eq!(writable.save_to_bytes().unwrap(), serial);
+        assert!(super::PARALLEL_BATCHES.with(|batches| batches.get()) > 0);
+    }
+
+    #[test]
+    fn deterministic_save_test() {
+        let files: Vec<(String, Vec<u8>)> = (0..50)
+            .map(|i| (format!("file{}.wld", i), vec![(i % 7) as u8; 100 + i % 7]))
+            .collect();
+
+        let mut forward = WritableArchive::new();
+        let mut backward = WritableArchive::new();
+        for (name, contents) in &files {
+            forward.set(name, contents).unwrap();
+        }
+        for (name, contents) in files.iter().rev() {
+            backward.set(name, contents).unwrap();
+        }
+        assert_eq!(
+            forward.save_to_bytes().unwrap(),
+            backward.save_to_bytes().unwrap()
+        );
+
+        let mut forward_rw = ReadWriteArchive::new();
+        let mut backward_rw = ReadWriteArchive::new();
+        for (name, contents) in &files {
+            forward_rw.set(name, contents).unwrap();
+        }
+        for (name, contents) in files.iter().rev() {
+            backward_rw.set(name, contents).unwrap();
+        }
+        assert_eq!(
+            forward_rw.save_to_bytes().unwrap(),
+            backward_rw.save_to_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn default_test() {
+        let mut writable = WritableArchive::default();
+        assert!(writable.is_empty());
+        writable.set("test.txt", b"test").unwrap();
+
+        let readable = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+        assert_eq!(readable.len(), 1);
+        assert!(ReadableArchive::default().is_empty());
+        assert!(ReadWriteArchive::default().is_empty());
+    }
+
+    #[test]
+    fn from_iter_test() {
+        let mut writable: WritableArchive = [
+            ("one.txt", "first"),
+            ("two.txt", "two"),
+            ("ONE.txt", "last"),
+        ]
+        .into_iter()
+        .collect();
+        writable.extend((0..3).map(|i| (format!("{}.bin", i), vec![i as u8; 4])));
+        assert_eq!(writable.len(), 5);
+
+        let readable = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+        assert_eq!(readable.get("one.txt").unwrap(), b"last");
+        assert_eq!(
+            readable.search(".*").unwrap(),
+            vec!["0.bin", "1.bin", "2.bin", "ONE.txt", "two.txt"]
+        );
+    }
+
+    #[test]
+    fn replace_take_test() {
+        let mut writable = WritableArchive::new();
+        assert_eq!(writable.replace("test.txt", b"one").unwrap(), None);
+        assert_eq!(
+            writable.replace("TEST.txt", b"two").unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(writable.take("test.txt").unwrap(), b"two");
+        assert!(writable.is_empty());
+        assert!(matches!(
+            writable.take("test.txt"),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+    }
+
+    #[test]
+    fn set_raw_test() {
+        let big: Vec<u8> = (0..30000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("big.wld", &big).unwrap();
+        writable.set_stored("image.dds", b"DDS data").unwrap();
+        let src = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+
+        let mut dest = WritableArchive::new();
+        dest.set_raw("copy.wld", src.raw_blocks("big.wld").unwrap())
+            .unwrap();
+        dest.set_raw("image.dds", src.raw_blocks("image.dds").unwrap())
+            .unwrap();
+        dest.set("other.txt", b"other").unwrap();
+        let copy = ReadableArchive::try_from(dest.save_to_bytes().unwrap()).unwrap();
+
+        assert_eq!(copy.get("copy.wld").unwrap(), big);
+        assert_eq!(copy.get("image.dds").unwrap(), b"DDS data");
+        assert_eq!(copy.get("other.txt").unwrap(), b"other");
+        assert_eq!(
+            copy.raw_blocks("copy.wld").unwrap().blocks,
+            src.raw_blocks("big.wld").unwrap().blocks
+        );
+        assert_eq!(
+            copy.entry_compression("image.dds").unwrap().codec,
+            BlockCodec::Stored
+        );
+
+        assert_eq!(dest.take("copy.wld").unwrap(), big);
+    }
+
+    #[test]
+    fn dedup_test() {
+        let payload: Vec<u8> = (0..16384u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set("one.bmp", &payload).unwrap();
+        writable.set("two.bmp", &payload).unwrap();
+        writable.set("three.bmp", &payload).unwrap();
+        let data = writable.save_to_bytes().unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&data).unwrap();
+        for name in ["one.bmp", "two.bmp", "three.bmp"] {
+            assert_eq!(archive.get(name).unwrap(), payload);
+        }
+
+        let (block, _) = archive
+            .compressed_blocks("one.bmp")
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(data.windows(block.len()).filter(|w| *w == block).count(), 1);
+        assert!(archive.health_report().is_healthy());
+    }
+
+    #[test]
+    fn preserve_case_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("MixedCase.BMP", b"mixed").unwrap();
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.search(".*").unwrap(), vec!["MixedCase.BMP"]);
+        assert_eq!(archive.get("mixedcase.bmp").unwrap(), b"mixed");
+        assert_eq!(archive.entries()[0].name, "MixedCase.BMP");
+    }
+
+    #[test]
+    fn entry_compression_test() {
+        let contents = vec![5u8; 10000];
+
+        let mut writable = WritableArchive::new();
+        writable.set("default.wld", &contents).unwrap();
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.entry_compression("default.wld"), None);
+
+        writable.set_compression_level(9);
+        writable.set_stored("stored.dds", &contents).unwrap();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(
+            archive.entry_compression("default.wld"),
+            Some(FileCompression {
+                codec: BlockCodec::Zlib,
+                level: 9
+            })
+        );
+        assert_eq!(
+            archive.entry_compression("stored.dds"),
+            Some(FileCompression {
+                codec: BlockCodec::Stored,
+                level: 0
+            })
+        );
+
+        // the table isn't a file
+        assert_eq!(archive.iter().count(), 2);
+        assert_eq!(archive.crc_entries().count(), 2);
+        assert!(archive.health_report().unresolved_entries == 0);
+    }
+
+    #[test]
+    fn len_test() {
+        let mut writable = WritableArchive::new();
+        assert!(writable.is_empty());
+        writable.set("one.txt", b"1").unwrap();
+        writable.set("two.txt", b"2").unwrap();
+        writable.set("ONE.txt", b"3").unwrap();
+        assert_eq!(writable.len(), 2);
+
+        let data = writable.save_to_bytes().unwrap();
+        let mut readable = ReadableArchive::new();
+        assert!(readable.is_empty());
+        readable.open_from_bytes(&data).unwrap();
+        assert_eq!(readable.len(), 2);
+
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite.open_from_bytes(&data).unwrap();
+        readwrite.remove("one.txt").unwrap();
+        assert_eq!(readwrite.len(), 1);
+        readwrite.close();
+        assert!(readwrite.is_empty());
+    }
+
+    #[test]
+    fn merge_test() {
+        let mut other = WritableArchive::new();
+        other.set("shared.txt", b"theirs").unwrap();
+        other.set("new.txt", b"new").unwrap();
+        let mut src = ReadableArchive::new();
+        src.open_from_bytes(other.save_to_bytes().unwrap()).unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set("SHARED.txt", b"ours").unwrap();
+        writable.set("mine.txt", b"mine").unwrap();
+        let mut readwrite = ReadWriteArchive::new();
+        readwrite
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let summary = writable.merge(&src, false).unwrap();
+        assert_eq!(
+            summary,
+            MergeSummary {
+                added: 1,
+                replaced: 0,
+                skipped: 1
+            }
+        );
+
+        let summary = readwrite.merge(&src, true).unwrap();
+        assert_eq!(
+            summary,
+            MergeSummary {
+                added: 1,
+                replaced: 1,
+                skipped: 0
+            }
+        );
+        assert_eq!(readwrite.len(), 3);
+        assert_eq!(readwrite.get("shared.txt").unwrap(), b"theirs");
+
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.get("shared.txt").unwrap(), b"ours");
+        assert_eq!(archive.get("new.txt").unwrap(), b"new");
+    }
+
+    #[test]
+    fn merge_latin1_test() {
+        let data = build_encoded_archive(&[("caf\u{e9}.txt", b"coffee")], NameEncoding::Latin1);
+        let src = ReadableArchive::try_from(data).unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.merge(&src, false).unwrap();
+        writable.set("th\u{e9}.txt", b"tea").unwrap();
+        let archive = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+        assert_eq!(archive.get("caf\u{e9}.txt").unwrap(), b"coffee");
+        assert_eq!(
+            archive.name_encoding("caf\u{e9}.txt").unwrap(),
+            NameEncoding::Latin1
+        );
+        assert_eq!(
+            archive.name_encoding("th\u{e9}.txt").unwrap(),
+            NameEncoding::Utf8
+        );
+        assert!(matches!(
+            writable.set_name_encoding("missing.txt", NameEncoding::Latin1),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+    }
+}
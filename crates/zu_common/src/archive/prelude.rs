@@ -1,5 +1,19 @@
+pub use super::archive_entry::{ArchiveEntry, ArchiveSummary};
 pub use super::archive_error::ArchiveError;
 pub use super::archive_trait::{IArchive, IReadableArchive, IWritableArchive};
+pub use super::diff::{diff, ArchiveDiff};
+pub use super::merge::MergeSummary;
+pub use super::open::{edit_file, open_edit, open_read, pack_file};
+pub use super::path::{extract_path, prepare_extract_path};
+pub use super::pfs::codec::{BlockCodec, BlockCompressor, BufferPool, FileCompression};
+pub use super::pfs::eqg::{ArchiveKind, EqgArchive};
+pub use super::pfs::health::{ClientEra, HealthReport};
+pub use super::pfs::options::{ArchiveSettings, OpenOptions};
+pub use super::pfs::raw::{RawBlock, RawFile};
 pub use super::pfs::readable::ReadableArchive;
 pub use super::pfs::readwrite::ReadWriteArchive;
+pub use super::pfs::stream::StreamPacker;
 pub use super::pfs::writable::WritableArchive;
+pub use super::pfs::{pfs_crc, NameEncoding};
+pub use super::searcher::{is_glob, SearchOptions, Searcher};
+pub use super::transcode::transcode;
@@ -0,0 +1,194 @@
+//! Reusable archive searches
+//!
+//! Compiles a search regex once so it can be run against many archives.
+//! Shell style glob patterns are translated to regexes.
+
+use super::archive_error::ArchiveError;
+use super::archive_trait::IReadableArchive;
+use regex::{Regex, RegexBuilder};
+
+/// Options that change how a search regex is matched
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOptions {
+    /// Match letters regardless of case
+    pub case_insensitive: bool,
+    /// The regex must match the whole name instead of any part of it
+    pub full_match: bool,
+}
+
+impl SearchOptions {
+    /// Compile a search regex with these options
+    pub fn compile(&self, search_regex: &str) -> Result<Regex, ArchiveError> {
+        let pattern = if self.full_match {
+            format!("^(?:{})$", search_regex)
+        } else {
+            search_regex.to_string()
+        };
+
+        Ok(RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .build()?)
+    }
+}
+
+/// A precompiled search that can be reused across archives
+pub struct Searcher {
+    regex: Regex,
+}
+
+impl Searcher {
+    /// Create a new searcher by compiling a regex string
+    pub fn new(search_regex: &str) -> Result<Self, ArchiveError> {
+        Ok(Searcher {
+            regex: Regex::new(search_regex)?,
+        })
+    }
+
+    /// Create a new searcher from a glob pattern matched against the whole name ignoring case
+    /// `*` matches any run of characters and `?` any one character, neither matching `/`.
+    /// `[abc]`, `[a-z]` and `[!abc]` match one character from, or not from, a set.
+    pub fn glob(pattern: &str) -> Result<Self, ArchiveError> {
+        Searcher::new(&glob_to_regex(pattern))
+    }
+
+    /// Get the names of all files in the archive that match this search
+    pub fn matches(&self, archive: &impl IReadableArchive) -> Vec<String> {
+        archive.search_compiled(&self.regex)
+    }
+}
+
+/// Whether a string has any glob wildcards rather than being a literal name
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+fn glob_to_regex(pattern: &str) -> String {
+    let mut ret = String::from("(?i)^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => ret.push_str("[^/]*"),
+            '?' => ret.push_str("[^/]"),
+            '[' => {
+                ret.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    ret.push('^');
+                }
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => break,
+                        '\\' | '[' | '&' | '~' | '^' => {
+                            ret.push('\\');
+                            ret.push(c);
+                        }
+                        _ => ret.push(c),
+                    }
+                }
+                ret.push(']');
+            }
+            _ => ret.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    ret.push('$');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::prelude::*;
+
+    #[test]
+    fn searcher_reuse_test() {
+        let mut first = ReadWriteArchive::new();
+        first.set("one.wld", b"1").unwrap();
+        first.set("two.bmp", b"2").unwrap();
+
+        let mut writable = WritableArchive::new();
+        writable.set("three.wld", b"3").unwrap();
+        writable.set("four.wld", b"4").unwrap();
+        writable.set("five.dds", b"5").unwrap();
+        let mut second = ReadableArchive::new();
+        second
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let searcher = Searcher::new(r"\.wld$").unwrap();
+
+        let found = searcher.matches(&first);
+        assert_eq!(found, vec!["one.wld"]);
+
+        let found = searcher.matches(&second);
+        assert_eq!(found, vec!["four.wld", "three.wld"]);
+    }
+
+    #[test]
+    fn glob_test() {
+        let mut archive = ReadWriteArchive::new();
+        for name in [
+            "zone.wld",
+            "objects.wld",
+            "textures/rock1.bmp",
+            "textures/rock2.BMP",
+            "textures/rockA.bmp",
+            "textures/deep/rock3.bmp",
+            "a+b.txt",
+        ] {
+            archive.set(name, b"data").unwrap();
+        }
+
+        let glob = |pattern: &str| Searcher::glob(pattern).unwrap().matches(&archive);
+        assert_eq!(glob("*.wld"), vec!["objects.wld", "zone.wld"]);
+        assert_eq!(
+            glob("textures/*.bmp"),
+            vec![
+                "textures/rock1.bmp",
+                "textures/rock2.BMP",
+                "textures/rockA.bmp"
+            ]
+        );
+        assert_eq!(glob("textures/rock?.bmp").len(), 3);
+        assert_eq!(
+            glob("textures/rock[0-9].bmp"),
+            vec!["textures/rock1.bmp", "textures/rock2.BMP"]
+        );
+        assert_eq!(glob("textures/rock[!12].bmp"), vec!["textures/rockA.bmp"]);
+        assert_eq!(glob("textures/*/*.bmp"), vec!["textures/deep/rock3.bmp"]);
+        assert_eq!(glob("a+b.txt"), vec!["a+b.txt"]);
+        assert!(glob("zone").is_empty());
+
+        assert!(is_glob("*.wld"));
+        assert!(!is_glob("zone.wld"));
+    }
+
+    #[test]
+    fn search_opts_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("Rock.bmp", b"1").unwrap();
+        writable.set("bedrock.bmp", b"2").unwrap();
+        writable.set("rock.wld", b"3").unwrap();
+        let mut archive = ReadableArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let search = |pattern: &str, case_insensitive: bool, full_match: bool| {
+            let options = SearchOptions {
+                case_insensitive,
+                full_match,
+            };
+            archive.search_opts(pattern, &options).unwrap()
+        };
+
+        assert_eq!(search("ROCK", false, false), Vec::<String>::new());
+        assert_eq!(
+            search("ROCK", true, false),
+            vec!["Rock.bmp", "bedrock.bmp", "rock.wld"]
+        );
+        assert_eq!(search(r"rock\.bmp", false, true), vec!["Rock.bmp"]);
+        assert_eq!(
+            search(r"ROCK\.BMP|rock\.wld", true, true),
+            vec!["Rock.bmp", "rock.wld"]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Rewriting an archive with different compression settings
+
+use super::archive_error::ArchiveError;
+use super::archive_trait::{IReadableArchive, IWritableArchive};
+use super::pfs::options::ArchiveSettings;
+use super::pfs::readable::ReadableArchive;
+use super::pfs::writable::WritableArchive;
+use std::io::Read;
+
+/// Copy every file of an archive into a new one that's saved with the given settings
+/// Each file is inflated and compressed again when the new archive is saved. The footer date and
+/// the encoding of each name are kept so the rewritten archive only differs in how its files are
+/// compressed.
+pub fn transcode(
+    src: &ReadableArchive,
+    settings: ArchiveSettings,
+) -> Result<WritableArchive, ArchiveError> {
+    let mut ret = WritableArchive::with_settings(settings)?;
+    if let Some(date) = src.footer_date() {
+        ret.set_footer_date(date);
+    }
+
+    let mut data = Vec::new();
+    for (name, mut reader) in src {
+        data.clear();
+        reader.read_to_end(&mut data)?;
+        ret.set(name, &data)?;
+        ret.set_name_encoding(name, src.name_encoding(name)?)?;
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::pfs::testing::build_encoded_archive;
+    use crate::archive::prelude::*;
+
+    #[test]
+    fn transcode_test() {
+        let big: Vec<u8> = (0..100000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut writable = WritableArchive::new();
+        writable.set_compression_level(0);
+        writable.set_footer_date(1234);
+        writable.set("big.wld", &big).unwrap();
+        writable.set("Small.txt", b"small").unwrap();
+        let original = writable.save_to_bytes().unwrap();
+
+        let mut src = ReadableArchive::new();
+        src.open_from_bytes(&original).unwrap();
+        let settings = ArchiveSettings {
+            compression_level: 9,
+            block_size: 32768,
+            ..ArchiveSettings::default()
+        };
+        let transcoded = transcode(&src, settings).unwrap().save_to_bytes().unwrap();
+        assert!(transcoded.len() < original.len());
+
+        let mut archive = ReadableArchive::new();
+        archive.open_from_bytes(&transcoded).unwrap();
+        assert!(diff(&src, &archive).unwrap().is_empty());
+        assert_eq!(archive.search(".*").unwrap(), vec!["Small.txt", "big.wld"]);
+        assert_eq!(archive.footer_date(), Some(1234));
+        assert_eq!(archive.compressed_blocks("big.wld").unwrap().count(), 4);
+
+        let settings = ArchiveSettings {
+            block_size: 0,
+            ..ArchiveSettings::default()
+        };
+        assert!(transcode(&src, settings).is_err());
+    }
+
+    #[test]
+    fn transcode_latin1_test() {
+        let data = build_encoded_archive(&[("caf\u{e9}.txt", b"coffee")], NameEncoding::Latin1);
+        let src = ReadableArchive::try_from(data).unwrap();
+
+        let transcoded = transcode(&src, ArchiveSettings::default()).unwrap();
+        let archive = ReadableArchive::try_from(transcoded.save_to_bytes().unwrap()).unwrap();
+        assert_eq!(archive.get("caf\u{e9}.txt").unwrap(), b"coffee");
+        assert_eq!(
+            archive.name_encoding("caf\u{e9}.txt").unwrap(),
+            NameEncoding::Latin1
+        );
+        assert_eq!(
+            archive
+                .crc_entries()
+                .map(|(crc, _)| crc)
+                .collect::<Vec<_>>(),
+            vec![NameEncoding::Latin1.crc("caf\u{e9}.txt")]
+        );
+    }
+}
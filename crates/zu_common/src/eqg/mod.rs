@@ -0,0 +1,16 @@
+//! EQG zone files
+//!
+//! The files found in `.eqg` archives share a layout: a four byte magic, a version, the size
+//! of a table of null terminated strings and then counts of the records that follow the
+//! table. Records refer to names by their byte offset into the table.
+
+pub mod ter;
+pub mod zon;
+
+/// Get the string in a string table at an offset
+/// None if the offset is past the end of the table or the string isn't valid UTF-8.
+pub(crate) fn string_at(table: &[u8], offset: u32) -> Option<&str> {
+    let rest = table.get(offset as usize..)?;
+    let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+    std::str::from_utf8(&rest[..end]).ok()
+}
@@ -0,0 +1,375 @@
+//! Terrain and model meshes
+//!
+//! A `.ter` starts with "EQGT" and holds a zone's terrain, a `.mod` starts with "EQGM" and
+//! holds an object placed in a zone. Both list materials, vertices and then triangles; models
+//! add a bone count to the header and bones after the triangles, which aren't read.
+//!
+//! Materials are a name, a shader and a list of properties. The texture is the string value of
+//! the `e_TextureDiffuse0` property. Versions 1 and 2 store a vertex as a position, normal and
+//! texture coordinate; version 3 adds a color after the normal and a second texture coordinate.
+
+use super::string_at;
+use crate::archive::{archive_error::ArchiveError, archive_trait::IReadableArchive};
+use crate::geometry::{Material, Triangle, Vertex, ZoneMesh};
+use nom::Err::Error;
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_f32, le_i32, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// Magic number terrain files start with
+pub const TER_MAGIC: &[u8; 4] = b"EQGT";
+/// Magic number model files start with
+pub const MOD_MAGIC: &[u8; 4] = b"EQGM";
+
+/// Triangle flag for triangles that are drawn but players walk through
+pub const TRIANGLE_NON_COLLIDABLE: u32 = 0x01;
+
+/// Property holding a material's texture
+const TEXTURE_PROPERTY: &str = "e_TextureDiffuse0";
+/// Property type for values that are string offsets
+const PROPERTY_STRING: u32 = 2;
+
+/// Terrain decoded to the mesh type the exporters take
+pub type TerrainMesh = ZoneMesh;
+
+/// Parse a terrain or model file
+/// The mesh is unnamed; `load_terrain` names it after the file.
+pub fn parse(data: &[u8]) -> Result<TerrainMesh, ArchiveError> {
+    match do_parse(data) {
+        Ok((_, mesh)) => Ok(mesh),
+        Err(e) => Err(ArchiveError::from_nom(e, data)),
+    }
+}
+
+/// Get a terrain or model file from an archive and parse it
+pub fn load_terrain(
+    archive: &impl IReadableArchive,
+    name: &str,
+) -> Result<TerrainMesh, ArchiveError> {
+    let mut mesh = parse(&archive.get(name)?)?;
+    mesh.name = name.to_string();
+    Ok(mesh)
+}
+
+fn do_parse(input: &[u8]) -> IResult<&[u8], TerrainMesh, ArchiveError> {
+    let (input, magic) = take(4usize)(input)?;
+    let is_model = match magic {
+        m if m == TER_MAGIC => false,
+        m if m == MOD_MAGIC => true,
+        _ => {
+            return Err(Error(ArchiveError::Parse(
+                "Not a terrain or model file".to_string(),
+            )))
+        }
+    };
+
+    let (input, (version, table_size, material_count, vertex_count, triangle_count)) =
+        tuple((le_u32, le_u32, le_u32, le_u32, le_u32))(input)?;
+    if !(1..=3).contains(&version) {
+        return Err(Error(ArchiveError::WrongVersion { version }));
+    }
+
+    let (input, _bones) = if is_model { le_u32(input)? } else { (input, 0) };
+    let (mut input, table) = take(table_size as usize)(input)?;
+
+    // a material is at least 16 bytes, a vertex 32 bytes and a triangle 20 bytes
+    let needed = material_count as u64 * 16 + vertex_count as u64 * 32 + triangle_count as u64 * 20;
+    if needed > input.len() as u64 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "Terrain has {} materials, {} vertices and {} triangles but only {} bytes remain",
+            material_count,
+            vertex_count,
+            triangle_count,
+            input.len()
+        ))));
+    }
+
+    let mut materials = Vec::with_capacity(material_count as usize);
+    for _ in 0..material_count {
+        let (rest, (_index, name, _shader, property_count)) =
+            tuple((le_u32, le_u32, le_u32, le_u32))(input)?;
+        input = rest;
+
+        let mut texture = None;
+        for _ in 0..property_count {
+            let (rest, (property, kind, value)) = tuple((le_u32, le_u32, le_u32))(input)?;
+            input = rest;
+
+            if kind == PROPERTY_STRING && table_string(table, property)? == TEXTURE_PROPERTY {
+                texture = Some(table_string(table, value)?.to_lowercase());
+            }
+        }
+
+        materials.push(Material {
+            name: table_string(table, name)?,
+            texture,
+        });
+    }
+
+    let mut vertices = Vec::with_capacity(vertex_count as usize);
+    for _ in 0..vertex_count {
+        let (rest, (x, y, z, i, j, k)) =
+            tuple((le_f32, le_f32, le_f32, le_f32, le_f32, le_f32))(input)?;
+        let (rest, color) = if version >= 3 {
+            let (rest, color) = take(4usize)(rest)?;
+            (rest, [color[0], color[1], color[2], color[3]])
+        } else {
+            (rest, [255; 4])
+        };
+        let (rest, (u, v)) = tuple((le_f32, le_f32))(rest)?;
+        // the second texture coordinate isn't used
+        let rest = if version >= 3 {
+            take(8usize)(rest)?.0
+        } else {
+            rest
+        };
+
+        vertices.push(Vertex {
+            position: [x, y, z],
+            normal: [i, j, k],
+            uv: [u, v],
+            color,
+        });
+        input = rest;
+    }
+
+    // triangles without a material get an untextured one added after the listed materials
+    let mut untextured = None;
+    let mut triangles = Vec::with_capacity(triangle_count as usize);
+    for _ in 0..triangle_count {
+        let (rest, (a, b, c, material, flags)) =
+            tuple((le_u32, le_u32, le_u32, le_i32, le_u32))(input)?;
+        input = rest;
+
+        if let Some(i) = [a, b, c].iter().find(|i| **i as usize >= vertices.len()) {
+            return Err(Error(ArchiveError::Parse(format!(
+                "Terrain triangle refers to vertex {} of {}",
+                i,
+                vertices.len()
+            ))));
+        }
+
+        let material = match usize::try_from(material) {
+            Ok(m) if m < material_count as usize => m,
+            Err(_) => *untextured.get_or_insert(material_count as usize),
+            Ok(m) => {
+                return Err(Error(ArchiveError::Parse(format!(
+                    "Terrain triangle uses material {} of {}",
+                    m, material_count
+                ))))
+            }
+        };
+
+        triangles.push(Triangle {
+            indices: [a, b, c],
+            material,
+            collidable: flags & TRIANGLE_NON_COLLIDABLE == 0,
+            flags,
+        });
+    }
+
+    if untextured.is_some() {
+        materials.push(Material::default());
+    }
+
+    Ok((
+        input,
+        ZoneMesh {
+            name: String::new(),
+            vertices,
+            triangles,
+            materials,
+        },
+    ))
+}
+
+fn table_string(table: &[u8], offset: u32) -> Result<String, nom::Err<ArchiveError>> {
+    match string_at(table, offset) {
+        Some(s) => Ok(s.to_string()),
+        None => Err(Error(ArchiveError::Parse(format!(
+            "Terrain string offset {} is past the end of the {} byte string table",
+            offset,
+            table.len()
+        )))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::prelude::*;
+    use bytes::{BufMut, BytesMut};
+
+    /// Offsets of the strings in `TABLE`
+    const TABLE: &[u8] =
+        b"grass\0Opaque_MaxCB1.fx\0e_TextureDiffuse0\0Grass01.DDS\0e_fShininess0\0";
+    const GRASS: u32 = 0;
+    const SHADER: u32 = 6;
+    const TEXTURE: u32 = 23;
+    const GRASS_DDS: u32 = 41;
+    const SHININESS: u32 = 53;
+
+    /// A square of two triangles, the second water that isn't collidable, and a third
+    /// triangle without a material
+    fn terrain_file(magic: &[u8; 4], version: u32) -> Vec<u8> {
+        let mut data = BytesMut::new();
+        data.put(&magic[..]);
+        data.put_u32_le(version);
+        data.put_u32_le(TABLE.len() as u32);
+        data.put_u32_le(1);
+        data.put_u32_le(4);
+        data.put_u32_le(3);
+        if magic == MOD_MAGIC {
+            data.put_u32_le(0);
+        }
+        data.put(TABLE);
+
+        data.put_u32_le(0);
+        data.put_u32_le(GRASS);
+        data.put_u32_le(SHADER);
+        data.put_u32_le(2);
+        data.put_u32_le(SHININESS);
+        data.put_u32_le(0);
+        data.put_f32_le(0.5);
+        data.put_u32_le(TEXTURE);
+        data.put_u32_le(PROPERTY_STRING);
+        data.put_u32_le(GRASS_DDS);
+
+        for (x, y) in [(0.0, 0.0), (10.0, 0.0), (0.0, 10.0), (10.0, 10.0)] {
+            for v in [x, y, -2.5, 0.0, 0.0, 1.0] {
+                data.put_f32_le(v);
+            }
+            if version >= 3 {
+                data.put(&[0x10, 0x20, 0x30, 0x40][..]);
+            }
+            data.put_f32_le(x / 10.0);
+            data.put_f32_le(y / 10.0);
+            if version >= 3 {
+                data.put_f32_le(0.0);
+                data.put_f32_le(0.0);
+            }
+        }
+
+        for (indices, material, flags) in [
+            ([0, 1, 2], 0, 0),
+            ([1, 3, 2], 0, TRIANGLE_NON_COLLIDABLE),
+            ([0, 3, 2], -1, 0x02),
+        ] {
+            for i in indices {
+                data.put_u32_le(i);
+            }
+            data.put_i32_le(material);
+            data.put_u32_le(flags);
+        }
+        data.to_vec()
+    }
+
+    #[test]
+    fn load_terrain_test() {
+        let mut writable = WritableArchive::new();
+        writable
+            .set("ter_tutorial.ter", terrain_file(TER_MAGIC, 2))
+            .unwrap();
+        let mut archive = EqgArchive::new();
+        archive
+            .open_from_bytes(writable.save_to_bytes().unwrap())
+            .unwrap();
+
+        let mesh = load_terrain(&archive, "ter_tutorial.ter").unwrap();
+        assert_eq!(mesh.name, "ter_tutorial.ter");
+        assert_eq!(
+            mesh.materials,
+            vec![
+                Material {
+                    name: "grass".to_string(),
+                    texture: Some("grass01.dds".to_string()),
+                },
+                Material::default(),
+            ]
+        );
+
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(
+            mesh.vertices[3],
+            Vertex {
+                position: [10.0, 10.0, -2.5],
+                normal: [0.0, 0.0, 1.0],
+                uv: [1.0, 1.0],
+                color: [255; 4],
+            }
+        );
+
+        assert_eq!(
+            mesh.triangles,
+            vec![
+                Triangle {
+                    indices: [0, 1, 2],
+                    material: 0,
+                    collidable: true,
+                    flags: 0,
+                },
+                Triangle {
+                    indices: [1, 3, 2],
+                    material: 0,
+                    collidable: false,
+                    flags: TRIANGLE_NON_COLLIDABLE,
+                },
+                Triangle {
+                    indices: [0, 3, 2],
+                    material: 1,
+                    collidable: true,
+                    flags: 0x02,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_versions_test() {
+        let mesh = parse(&terrain_file(TER_MAGIC, 3)).unwrap();
+        assert_eq!(mesh.vertices[1].position, [10.0, 0.0, -2.5]);
+        assert_eq!(mesh.vertices[1].color, [0x10, 0x20, 0x30, 0x40]);
+        assert_eq!(mesh.vertices[1].uv, [1.0, 0.0]);
+        assert_eq!(mesh.triangles.len(), 3);
+
+        // models have the same layout with a bone count in the header
+        let model = parse(&terrain_file(MOD_MAGIC, 1)).unwrap();
+        assert_eq!(model.vertices.len(), 4);
+        assert_eq!(model.materials[0].texture.as_deref(), Some("grass01.dds"));
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        let data = terrain_file(TER_MAGIC, 3);
+        for len in 0..data.len() {
+            assert!(parse(&data[..len]).is_err());
+        }
+
+        let mut bad = data.clone();
+        bad[4..8].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(
+            parse(&bad),
+            Err(ArchiveError::WrongVersion { version: 4 })
+        ));
+
+        // the last triangle's second index
+        let mut bad = data.clone();
+        let index = bad.len() - 16;
+        bad[index..index + 4].copy_from_slice(&4u32.to_le_bytes());
+        assert!(matches!(parse(&bad), Err(ArchiveError::Parse(_))));
+
+        // the last triangle's material
+        let mut bad = data;
+        let material = bad.len() - 8;
+        bad[material..material + 4].copy_from_slice(&1i32.to_le_bytes());
+        assert!(matches!(parse(&bad), Err(ArchiveError::Parse(_))));
+
+        assert!(matches!(
+            parse(b"EQGZ\x01\0\0\0"),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+}
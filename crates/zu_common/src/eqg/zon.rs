@@ -0,0 +1,237 @@
+//! Zone placement files
+//!
+//! A binary `.zon` starts with "EQGZ" and lists the models a zone uses followed by every
+//! placed instance of them. Regions and lights come after the placements and aren't read.
+//! Newer zones may instead have a text `.zon` starting with "EQTZP", which isn't supported.
+
+use super::string_at;
+use crate::archive::{archive_error::ArchiveError, archive_trait::IReadableArchive};
+use nom::Err::Error;
+use nom::{
+    bytes::complete::{tag, take},
+    number::complete::{le_f32, le_i32, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// Magic number binary zone files start with
+pub const ZON_MAGIC: &[u8; 4] = b"EQGZ";
+/// Magic number text zone files start with
+pub const ZON_TEXT_MAGIC: &[u8; 5] = b"EQTZP";
+/// The only version of binary zone files that's understood
+pub const ZON_VERSION: u32 = 1;
+
+/// The models and their placements in a zone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Zone {
+    /// Names of the model files used by the zone, such as `ter_tutorial.ter` or `tree.mod`
+    pub models: Vec<String>,
+    pub placements: Vec<Placement>,
+}
+
+/// A placed instance of a model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Placement {
+    /// Index into the zone's models
+    pub model: usize,
+    /// Name of the instance
+    pub name: String,
+    pub position: [f32; 3],
+    /// Rotation around the x, y and z axes in radians
+    pub rotation: [f32; 3],
+    /// Uniform scale
+    pub scale: f32,
+}
+
+impl Zone {
+    /// Parse a binary zone file
+    pub fn parse(data: &[u8]) -> Result<Zone, ArchiveError> {
+        if data.starts_with(ZON_TEXT_MAGIC) {
+            return Err(ArchiveError::Parse(
+                "Text zone files aren't supported".to_string(),
+            ));
+        }
+
+        match Zone::do_parse(data) {
+            Ok((_, zone)) => Ok(zone),
+            Err(e) => Err(ArchiveError::from_nom(e, data)),
+        }
+    }
+
+    /// The model a placement is an instance of
+    pub fn model(&self, placement: &Placement) -> &str {
+        &self.models[placement.model]
+    }
+
+    fn do_parse(input: &[u8]) -> IResult<&[u8], Zone, ArchiveError> {
+        let (input, (_, version)) =
+            tuple((tag(&ZON_MAGIC[..]), le_u32))(input).map_err(|_: nom::Err<ArchiveError>| {
+                Error(ArchiveError::Parse("Not a zone file".to_string()))
+            })?;
+
+        if version != ZON_VERSION {
+            return Err(Error(ArchiveError::WrongVersion { version }));
+        }
+
+        let (input, (table_size, model_count, placement_count, _regions, _lights)) =
+            tuple((le_u32, le_u32, le_u32, le_u32, le_u32))(input)?;
+        let (mut input, table) = take(table_size as usize)(input)?;
+
+        // every model is a 4 byte offset and every placement is 36 bytes
+        let needed = model_count as u64 * 4 + placement_count as u64 * 36;
+        if needed > input.len() as u64 {
+            return Err(Error(ArchiveError::Parse(format!(
+                "Zone has {} models and {} placements but only {} bytes remain",
+                model_count,
+                placement_count,
+                input.len()
+            ))));
+        }
+
+        let mut models = Vec::with_capacity(model_count as usize);
+        for _ in 0..model_count {
+            let (rest, offset) = le_u32(input)?;
+            models.push(table_string(table, offset)?);
+            input = rest;
+        }
+
+        let mut placements = Vec::with_capacity(placement_count as usize);
+        for _ in 0..placement_count {
+            let (rest, (model, name, x, y, z, rx, ry, rz, scale)) = tuple((
+                le_i32, le_u32, le_f32, le_f32, le_f32, le_f32, le_f32, le_f32, le_f32,
+            ))(input)?;
+
+            if model < 0 || model as usize >= models.len() {
+                return Err(Error(ArchiveError::Parse(format!(
+                    "Zone placement refers to model {} of {}",
+                    model,
+                    models.len()
+                ))));
+            }
+
+            placements.push(Placement {
+                model: model as usize,
+                name: table_string(table, name)?,
+                position: [x, y, z],
+                rotation: [rx, ry, rz],
+                scale,
+            });
+            input = rest;
+        }
+
+        Ok((input, Zone { models, placements }))
+    }
+}
+
+/// Get the zone file from an archive and parse it
+pub fn load_zone(archive: &impl IReadableArchive, name: &str) -> Result<Zone, ArchiveError> {
+    Zone::parse(&archive.get(name)?)
+}
+
+fn table_string(table: &[u8], offset: u32) -> Result<String, nom::Err<ArchiveError>> {
+    match string_at(table, offset) {
+        Some(s) => Ok(s.to_string()),
+        None => Err(Error(ArchiveError::Parse(format!(
+            "Zone string offset {} is past the end of the {} byte string table",
+            offset,
+            table.len()
+        )))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::prelude::*;
+    use bytes::{BufMut, BytesMut};
+
+    /// A zone with the terrain and a tree model, the tree placed twice
+    fn zone_file() -> Vec<u8> {
+        let table = b"ter_tutorial.ter\0tree.mod\0ground\0tree_a\0tree_b\0";
+        let mut data = BytesMut::new();
+        data.put(&ZON_MAGIC[..]);
+        data.put_u32_le(ZON_VERSION);
+        data.put_u32_le(table.len() as u32);
+        data.put_u32_le(2);
+        data.put_u32_le(3);
+        data.put_u32_le(0);
+        data.put_u32_le(0);
+        data.put(&table[..]);
+        data.put_u32_le(0);
+        data.put_u32_le(17);
+
+        let placements: [(i32, u32, [f32; 7]); 3] = [
+            (0, 26, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0]),
+            (1, 33, [10.5, -20.0, 3.25, 0.0, 0.0, 1.5, 2.0]),
+            (1, 40, [-4.0, 8.0, 0.0, 0.25, 0.5, 0.75, 0.5]),
+        ];
+        for (model, name, values) in placements {
+            data.put_i32_le(model);
+            data.put_u32_le(name);
+            for v in values {
+                data.put_f32_le(v);
+            }
+        }
+        data.to_vec()
+    }
+
+    #[test]
+    fn load_zone_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("tutorial.zon", zone_file()).unwrap();
+        let data = writable.save_to_bytes().unwrap();
+        let mut archive = EqgArchive::new();
+        archive.open_from_bytes(data).unwrap();
+
+        let zone = load_zone(&archive, "tutorial.zon").unwrap();
+        assert_eq!(zone.models, vec!["ter_tutorial.ter", "tree.mod"]);
+        assert_eq!(zone.placements.len(), 3);
+        assert_eq!(zone.model(&zone.placements[0]), "ter_tutorial.ter");
+        assert_eq!(
+            zone.placements[1],
+            Placement {
+                model: 1,
+                name: "tree_a".to_string(),
+                position: [10.5, -20.0, 3.25],
+                rotation: [0.0, 0.0, 1.5],
+                scale: 2.0,
+            }
+        );
+        assert_eq!(zone.placements[2].name, "tree_b");
+        assert_eq!(zone.placements[2].rotation, [0.25, 0.5, 0.75]);
+        assert_eq!(zone.placements[2].scale, 0.5);
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        let data = zone_file();
+        for len in 0..data.len() {
+            assert!(Zone::parse(&data[..len]).is_err());
+        }
+
+        let mut bad = data.clone();
+        bad[4..8].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(
+            Zone::parse(&bad),
+            Err(ArchiveError::WrongVersion { version: 2 })
+        ));
+
+        // the header is 28 bytes followed by the 47 byte string table, then make the second
+        // model's name offset point past the end of the table
+        let mut bad = data.clone();
+        let model = 28 + 47 + 4;
+        bad[model..model + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(Zone::parse(&bad), Err(ArchiveError::Parse(_))));
+
+        // the first placement's model
+        let mut bad = data;
+        let placement = 28 + 47 + 8;
+        bad[placement..placement + 4].copy_from_slice(&2i32.to_le_bytes());
+        assert!(matches!(Zone::parse(&bad), Err(ArchiveError::Parse(_))));
+
+        assert!(matches!(
+            Zone::parse(b"EQTZP\r\n*NAME tutorial"),
+            Err(ArchiveError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::geometry::{Material, ZoneMesh};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Magic number at the start of a binary glTF
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+/// Version of the binary glTF container
+const GLB_VERSION: u32 = 2;
+/// Chunk holding the JSON document
+const CHUNK_JSON: u32 = 0x4E4F534A;
+/// Chunk holding the buffer the document refers to
+const CHUNK_BIN: u32 = 0x004E4942;
+
+/// Buffer view target for vertex attributes
+const ARRAY_BUFFER: u32 = 34962;
+/// Buffer view target for indices
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+/// Accessor component types
+const UNSIGNED_BYTE: u32 = 5121;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+/// Name of the PNG a texture is expected to be exported as next to the glTF
+pub fn png_name(texture: &str) -> String {
+    Path::new(texture)
+        .with_extension("png")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write meshes as a binary glTF (.glb) with a node for each mesh
+/// Positions and normals are remapped from EQ's z up to glTF's y up and the triangle winding
+/// is reversed to match. Materials refer to their textures as the external PNGs named by
+/// `png_name`. Meshes without triangles are left out.
+pub fn to_gltf(meshes: &[ZoneMesh], writer: &mut impl Write) -> io::Result<()> {
+    let mut doc = Document::default();
+
+    // materials and textures are shared between meshes
+    let mut materials: Vec<&Material> = Vec::new();
+    let mut textures: Vec<&str> = Vec::new();
+    for material in meshes.iter().flat_map(|m| &m.materials) {
+        if !materials.contains(&material) {
+            materials.push(material);
+        }
+        if let Some(texture) = &material.texture {
+            if !textures.contains(&texture.as_str()) {
+                textures.push(texture);
+            }
+        }
+    }
+
+    let mut gltf_meshes = Vec::new();
+    let mut nodes = Vec::new();
+    for mesh in meshes.iter().filter(|m| !m.is_empty()) {
+        let mut positions = Vec::with_capacity(mesh.vertices.len() * 12);
+        let mut normals = Vec::with_capacity(mesh.vertices.len() * 12);
+        let mut uvs = Vec::with_capacity(mesh.vertices.len() * 8);
+        let mut colors = Vec::with_capacity(mesh.vertices.len() * 4);
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for v in &mesh.vertices {
+            let [x, y, z] = v.position;
+            let position = [x, z, y];
+            for i in 0..3 {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            }
+            put_floats(&mut positions, &position);
+            put_floats(&mut normals, &unit_normal(v.normal));
+            put_floats(&mut uvs, &v.uv);
+            colors.extend_from_slice(&v.color);
+        }
+
+        let count = mesh.vertices.len();
+        let attributes = json!({
+            "POSITION": doc.accessor(&positions, ARRAY_BUFFER, json!({
+                "componentType": FLOAT, "count": count, "type": "VEC3", "min": min, "max": max,
+            })),
+            "NORMAL": doc.accessor(&normals, ARRAY_BUFFER, json!({
+                "componentType": FLOAT, "count": count, "type": "VEC3",
+            })),
+            "TEXCOORD_0": doc.accessor(&uvs, ARRAY_BUFFER, json!({
+                "componentType": FLOAT, "count": count, "type": "VEC2",
+            })),
+            "COLOR_0": doc.accessor(&colors, ARRAY_BUFFER, json!({
+                "componentType": UNSIGNED_BYTE, "normalized": true, "count": count, "type": "VEC4",
+            })),
+        });
+
+        // a primitive for every material the mesh uses
+        let mut groups: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for t in &mesh.triangles {
+            let indices = groups.entry(t.material).or_default();
+            for i in [t.indices[0], t.indices[2], t.indices[1]] {
+                indices.extend_from_slice(&i.to_le_bytes());
+            }
+        }
+
+        let mut primitives = Vec::new();
+        for (material, indices) in groups {
+            let mut primitive = json!({
+                "attributes": attributes,
+                "indices": doc.accessor(&indices, ELEMENT_ARRAY_BUFFER, json!({
+                    "componentType": UNSIGNED_INT, "count": indices.len() / 4, "type": "SCALAR",
+                })),
+            });
+            if let Some(m) = mesh.materials.get(material) {
+                primitive["material"] = json!(materials.iter().position(|x| *x == m));
+            }
+            primitives.push(primitive);
+        }
+
+        nodes.push(json!({ "name": mesh.name, "mesh": gltf_meshes.len() }));
+        gltf_meshes.push(json!({ "name": mesh.name, "primitives": primitives }));
+    }
+
+    let gltf_materials: Vec<Value> = materials
+        .iter()
+        .map(|m| {
+            let mut pbr = json!({ "metallicFactor": 0.0, "roughnessFactor": 1.0 });
+            let mut material = json!({ "name": m.name });
+            if let Some(texture) = &m.texture {
+                let index = textures.iter().position(|t| t == texture);
+                pbr["baseColorTexture"] = json!({ "index": index });
+                // masked out magenta shows up as fully transparent texels
+                material["alphaMode"] = json!("MASK");
+            }
+            material["pbrMetallicRoughness"] = pbr;
+            material
+        })
+        .collect();
+    let gltf_textures: Vec<Value> = (0..textures.len())
+        .map(|i| json!({ "source": i }))
+        .collect();
+    let images: Vec<Value> = textures
+        .iter()
+        .map(|t| json!({ "uri": png_name(t) }))
+        .collect();
+
+    // glTF doesn't allow empty top level arrays so they're left out instead
+    let mut root = Map::new();
+    root.insert(
+        "asset".to_string(),
+        json!({ "version": "2.0", "generator": "zone-utilities" }),
+    );
+    root.insert("scene".to_string(), json!(0));
+    root.insert(
+        "scenes".to_string(),
+        json!([{ "nodes": (0..nodes.len()).collect::<Vec<_>>() }]),
+    );
+    let bin_len = doc.bin.len();
+    for (key, values) in [
+        ("nodes", nodes),
+        ("meshes", gltf_meshes),
+        ("materials", gltf_materials),
+        ("textures", gltf_textures),
+        ("images", images),
+        ("accessors", doc.accessors),
+        ("bufferViews", doc.buffer_views),
+    ] {
+        if !values.is_empty() {
+            root.insert(key.to_string(), Value::Array(values));
+        }
+    }
+    if bin_len > 0 {
+        root.insert("buffers".to_string(), json!([{ "byteLength": bin_len }]));
+    }
+
+    let mut json = serde_json::to_vec(&Value::Object(root))?;
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+
+    let mut length = 12 + 8 + json.len();
+    if bin_len > 0 {
+        length += 8 + bin_len;
+    }
+
+    writer.write_all(GLB_MAGIC)?;
+    writer.write_all(&GLB_VERSION.to_le_bytes())?;
+    writer.write_all(&(length as u32).to_le_bytes())?;
+    writer.write_all(&(json.len() as u32).to_le_bytes())?;
+    writer.write_all(&CHUNK_JSON.to_le_bytes())?;
+    writer.write_all(&json)?;
+    if bin_len > 0 {
+        writer.write_all(&(bin_len as u32).to_le_bytes())?;
+        writer.write_all(&CHUNK_BIN.to_le_bytes())?;
+        writer.write_all(&doc.bin)?;
+    }
+
+    Ok(())
+}
+
+/// The binary buffer and the views and accessors into it
+#[derive(Default)]
+struct Document {
+    bin: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl Document {
+    /// Add data to the buffer with a view and an accessor for it returning the accessor's index
+    fn accessor(&mut self, data: &[u8], target: u32, mut accessor: Value) -> usize {
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+            "target": target,
+        }));
+        self.bin.extend_from_slice(data);
+
+        accessor["bufferView"] = json!(self.buffer_views.len() - 1);
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+}
+
+fn put_floats(out: &mut Vec<u8>, values: &[f32]) {
+    for v in values {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+/// Remap a normal to y up and make it unit length the way glTF requires
+fn unit_normal(normal: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = normal;
+    let len = (x * x + y * y + z * z).sqrt();
+    if len == 0.0 {
+        return [0.0, 1.0, 0.0];
+    }
+
+    [x / len, z / len, y / len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Triangle, Vertex};
+
+    fn mesh(name: &str, materials: Vec<Material>) -> ZoneMesh {
+        let vertex = |x: f32, y: f32, z: f32| Vertex {
+            position: [x, y, z],
+            normal: [0.0, 0.0, 0.5],
+            uv: [x, y],
+            color: [0x10, 0x20, 0x30, 0xff],
+        };
+
+        ZoneMesh {
+            name: name.to_string(),
+            vertices: vec![
+                vertex(0.0, 0.0, 1.0),
+                vertex(2.0, 0.0, 1.0),
+                vertex(0.0, 3.0, 1.0),
+                vertex(2.0, 3.0, 4.0),
+            ],
+            triangles: vec![
+                Triangle {
+                    indices: [0, 1, 2],
+                    material: 0,
+                    collidable: true,
+                    flags: 0,
+                },
+                Triangle {
+                    indices: [1, 3, 2],
+                    material: materials.len() - 1,
+                    collidable: false,
+                    flags: 0,
+                },
+            ],
+            materials,
+        }
+    }
+
+    fn material(name: &str, texture: Option<&str>) -> Material {
+        Material {
+            name: name.to_string(),
+            texture: texture.map(|t| t.to_string()),
+        }
+    }
+
+    /// Split a GLB into its JSON document and binary buffer
+    fn read_glb(data: &[u8]) -> (Value, &[u8]) {
+        let u32_at = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        assert_eq!(&data[..4], GLB_MAGIC);
+        assert_eq!(u32_at(4), GLB_VERSION);
+        assert_eq!(u32_at(8) as usize, data.len());
+
+        let json_len = u32_at(12) as usize;
+        assert_eq!(u32_at(16), CHUNK_JSON);
+        let json: Value = serde_json::from_slice(&data[20..20 + json_len]).unwrap();
+
+        let bin = &data[20 + json_len..];
+        if bin.is_empty() {
+            return (json, bin);
+        }
+
+        let bin_len = u32::from_le_bytes(bin[..4].try_into().unwrap()) as usize;
+        assert_eq!(u32::from_le_bytes(bin[4..8].try_into().unwrap()), CHUNK_BIN);
+        assert_eq!(bin.len(), 8 + bin_len);
+        (json, &bin[8..])
+    }
+
+    /// Get the bytes an accessor covers, checking they fit its view and the buffer
+    fn accessor_data<'a>(json: &Value, bin: &'a [u8], accessor: &Value) -> &'a [u8] {
+        let view = &json["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+        let offset = view["byteOffset"].as_u64().unwrap() as usize;
+        let len = view["byteLength"].as_u64().unwrap() as usize;
+
+        let components = match accessor["type"].as_str().unwrap() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            _ => 4,
+        };
+        let size = match accessor["componentType"].as_u64().unwrap() as u32 {
+            UNSIGNED_BYTE => 1,
+            _ => 4,
+        };
+        assert_eq!(
+            accessor["count"].as_u64().unwrap() as usize * components * size,
+            len
+        );
+        assert_eq!(offset % 4, 0);
+        &bin[offset..offset + len]
+    }
+
+    fn floats(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn to_gltf_test() {
+        let grass = material("GRASS_MDF", Some("grass.bmp"));
+        let water = material("WATER_MDF", Some("water1.dds"));
+        let meshes = vec![
+            mesh("R1_DMSPRITEDEF", vec![grass.clone(), water.clone()]),
+            ZoneMesh::new(),
+            mesh("R2_DMSPRITEDEF", vec![material("BLANK_MDF", None), grass]),
+        ];
+
+        let mut out = Vec::new();
+        to_gltf(&meshes, &mut out).unwrap();
+        let (json, bin) = read_glb(&out);
+
+        assert_eq!(json["asset"]["version"], "2.0");
+        assert_eq!(json["scenes"][0]["nodes"], json!([0, 1]));
+        assert_eq!(json["nodes"][1]["name"], "R2_DMSPRITEDEF");
+        assert_eq!(json["meshes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["materials"].as_array().unwrap().len(), 3);
+        assert_eq!(json["materials"][2]["name"], "BLANK_MDF");
+        assert!(json["materials"][2]["pbrMetallicRoughness"]["baseColorTexture"].is_null());
+        assert_eq!(
+            json["images"],
+            json!([{ "uri": "grass.png" }, { "uri": "water1.png" }])
+        );
+
+        // the second mesh's materials map to the shared list
+        let primitives = json["meshes"][1]["primitives"].as_array().unwrap();
+        assert_eq!(primitives[0]["material"], 2);
+        assert_eq!(primitives[1]["material"], 0);
+
+        for accessor in json["accessors"].as_array().unwrap() {
+            accessor_data(&json, bin, accessor);
+        }
+        assert_eq!(
+            json["buffers"][0]["byteLength"].as_u64().unwrap() as usize,
+            bin.len()
+        );
+
+        let primitive = &json["meshes"][0]["primitives"][0];
+        let attributes = &primitive["attributes"];
+        let accessor = |name: &str| &json["accessors"][attributes[name].as_u64().unwrap() as usize];
+
+        // z up becomes y up
+        let positions = floats(accessor_data(&json, bin, accessor("POSITION")));
+        assert_eq!(&positions[9..12], &[2.0, 4.0, 3.0]);
+        assert_eq!(accessor("POSITION")["min"], json!([0.0, 1.0, 0.0]));
+        assert_eq!(accessor("POSITION")["max"], json!([2.0, 4.0, 3.0]));
+
+        let normals = floats(accessor_data(&json, bin, accessor("NORMAL")));
+        assert_eq!(&normals[..3], &[0.0, 1.0, 0.0]);
+
+        let uvs = floats(accessor_data(&json, bin, accessor("TEXCOORD_0")));
+        assert_eq!(&uvs[6..8], &[2.0, 3.0]);
+
+        let colors = accessor_data(&json, bin, accessor("COLOR_0"));
+        assert_eq!(&colors[..4], &[0x10, 0x20, 0x30, 0xff]);
+
+        // and the winding is reversed to keep triangles facing the same way
+        let indices_accessor = &json["accessors"][primitive["indices"].as_u64().unwrap() as usize];
+        let indices: Vec<u32> = accessor_data(&json, bin, indices_accessor)
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(indices, vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn to_gltf_empty_test() {
+        let mut out = Vec::new();
+        to_gltf(&[], &mut out).unwrap();
+
+        let (json, bin) = read_glb(&out);
+        assert!(bin.is_empty());
+        assert!(json.get("meshes").is_none());
+        assert!(json.get("buffers").is_none());
+    }
+
+    #[test]
+    fn png_name_test() {
+        assert_eq!(png_name("grass.bmp"), "grass.png");
+        assert_eq!(png_name("water1.dds"), "water1.png");
+        assert_eq!(png_name("sky"), "sky.png");
+    }
+}
@@ -0,0 +1,11 @@
+//! Exporting zone geometry
+//!
+//! Writes a `ZoneMesh` out in formats other tools can open. glTF needs the `gltf` feature.
+
+#[cfg(feature = "gltf")]
+mod gltf;
+mod obj;
+
+#[cfg(feature = "gltf")]
+pub use gltf::{png_name, to_gltf};
+pub use obj::{to_mtl, to_obj};
@@ -0,0 +1,161 @@
+use crate::geometry::ZoneMesh;
+use std::io::{self, Write};
+
+/// Write a mesh as a Wavefront OBJ
+/// Triangles are grouped by material with `usemtl`, name the MTL written by `to_mtl` with an
+/// `mtllib` line before this to have the materials found.
+pub fn to_obj(mesh: &ZoneMesh, writer: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        writer,
+        "# {} vertices, {} triangles",
+        mesh.vertices.len(),
+        mesh.triangles.len()
+    )?;
+
+    for v in &mesh.vertices {
+        writeln!(
+            writer,
+            "v {} {} {}",
+            v.position[0], v.position[1], v.position[2]
+        )?;
+    }
+    for v in &mesh.vertices {
+        writeln!(writer, "vt {} {}", v.uv[0], v.uv[1])?;
+    }
+    for v in &mesh.vertices {
+        writeln!(writer, "vn {} {} {}", v.normal[0], v.normal[1], v.normal[2])?;
+    }
+
+    // a stable sort keeps triangles in their original order within each material
+    let mut triangles: Vec<_> = mesh.triangles.iter().collect();
+    triangles.sort_by_key(|t| t.material);
+
+    let mut current = None;
+    for triangle in triangles {
+        if current != Some(triangle.material) {
+            writeln!(writer, "usemtl {}", material_name(mesh, triangle.material))?;
+            current = Some(triangle.material);
+        }
+
+        // indices start from 1
+        let [a, b, c] = triangle.indices.map(|i| i + 1);
+        writeln!(writer, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+    }
+
+    Ok(())
+}
+
+/// Write the materials of a mesh as a Wavefront MTL
+/// Textures are referred to by their file name so they're expected next to the MTL.
+pub fn to_mtl(mesh: &ZoneMesh, writer: &mut impl Write) -> io::Result<()> {
+    for i in 0..mesh.materials.len() {
+        if i > 0 {
+            writeln!(writer)?;
+        }
+
+        writeln!(writer, "newmtl {}", material_name(mesh, i))?;
+        writeln!(writer, "Kd 1 1 1")?;
+        if let Some(texture) = &mesh.materials[i].texture {
+            writeln!(writer, "map_Kd {}", texture)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of a material in the OBJ and MTL, unnamed materials are named by their index
+fn material_name(mesh: &ZoneMesh, material: usize) -> String {
+    match mesh.materials.get(material) {
+        Some(m) if !m.name.is_empty() => m.name.clone(),
+        _ => format!("material_{}", material),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Material, Triangle, Vertex};
+
+    fn mesh() -> ZoneMesh {
+        let vertex = |x: f32, y: f32| Vertex {
+            position: [x, y, 0.5],
+            normal: [0.0, 0.0, 1.0],
+            uv: [x, y],
+            color: [255; 4],
+        };
+
+        ZoneMesh {
+            name: "ZONE_DMSPRITEDEF".to_string(),
+            vertices: vec![
+                vertex(0.0, 0.0),
+                vertex(1.0, 0.0),
+                vertex(0.0, 1.0),
+                vertex(1.0, 1.0),
+            ],
+            triangles: vec![
+                Triangle {
+                    indices: [1, 3, 2],
+                    material: 1,
+                    collidable: false,
+                    flags: 0,
+                },
+                Triangle {
+                    indices: [0, 1, 2],
+                    material: 0,
+                    collidable: true,
+                    flags: 0,
+                },
+            ],
+            materials: vec![
+                Material {
+                    name: "GRASS_MDF".to_string(),
+                    texture: Some("grass.bmp".to_string()),
+                },
+                Material::default(),
+            ],
+        }
+    }
+
+    #[test]
+    fn to_obj_test() {
+        let mut out = Vec::new();
+        to_obj(&mesh(), &mut out).unwrap();
+
+        let expected = "\
+# 4 vertices, 2 triangles
+v 0 0 0.5
+v 1 0 0.5
+v 0 1 0.5
+v 1 1 0.5
+vt 0 0
+vt 1 0
+vt 0 1
+vt 1 1
+vn 0 0 1
+vn 0 0 1
+vn 0 0 1
+vn 0 0 1
+usemtl GRASS_MDF
+f 1/1/1 2/2/2 3/3/3
+usemtl material_1
+f 2/2/2 4/4/4 3/3/3
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn to_mtl_test() {
+        let mut out = Vec::new();
+        to_mtl(&mesh(), &mut out).unwrap();
+
+        let expected = "\
+newmtl GRASS_MDF
+Kd 1 1 1
+map_Kd grass.bmp
+
+newmtl material_1
+Kd 1 1 1
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,128 @@
+//! Zone geometry
+//!
+//! Meshes decoded from the different zone formats share this representation so they can be
+//! exported or turned into collision data the same way regardless of where they came from.
+
+/// A triangle mesh along with the materials its triangles are drawn with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZoneMesh {
+    /// Name of the mesh, such as the name of the fragment it was decoded from
+    pub name: String,
+    pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
+    pub materials: Vec<Material>,
+}
+
+/// A single vertex, positions are in EQ's coordinate system with z up
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+    /// RGBA vertex color
+    pub color: [u8; 4],
+}
+
+/// A triangle referring to vertices and a material by index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Triangle {
+    pub indices: [u32; 3],
+    pub material: usize,
+    /// Whether players collide with the triangle, false for things like water surfaces
+    pub collidable: bool,
+    /// Flags as stored by the format the triangle was decoded from
+    /// Kept so flags other than collision aren't lost, see the format's module for their meaning.
+    pub flags: u32,
+}
+
+/// How a group of triangles is drawn
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Material {
+    pub name: String,
+    /// Name of the texture file in the archive, None for untextured materials
+    pub texture: Option<String>,
+}
+
+impl ZoneMesh {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the vertices and triangles of another mesh to this one
+    /// Materials that are the same in both meshes are only kept once and this mesh keeps its
+    /// name.
+    pub fn append(&mut self, other: ZoneMesh) {
+        let base = self.vertices.len() as u32;
+        let materials: Vec<usize> = other
+            .materials
+            .into_iter()
+            .map(
+                |material| match self.materials.iter().position(|m| *m == material) {
+                    Some(i) => i,
+                    None => {
+                        self.materials.push(material);
+                        self.materials.len() - 1
+                    }
+                },
+            )
+            .collect();
+
+        self.vertices.extend(other.vertices);
+        self.triangles
+            .extend(other.triangles.into_iter().map(|t| Triangle {
+                indices: t.indices.map(|i| i + base),
+                material: materials.get(t.material).copied().unwrap_or(t.material),
+                ..t
+            }));
+    }
+
+    /// Whether the mesh has no triangles
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(name: &str) -> Material {
+        Material {
+            name: name.to_string(),
+            texture: Some(format!("{}.bmp", name)),
+        }
+    }
+
+    fn triangle_mesh(materials: Vec<Material>, material: usize) -> ZoneMesh {
+        ZoneMesh {
+            name: String::new(),
+            vertices: vec![Vertex::default(); 3],
+            triangles: vec![Triangle {
+                indices: [0, 1, 2],
+                material,
+                collidable: true,
+                flags: 0,
+            }],
+            materials,
+        }
+    }
+
+    #[test]
+    fn append_test() {
+        let mut mesh = ZoneMesh::new();
+        assert!(mesh.is_empty());
+
+        mesh.append(triangle_mesh(vec![material("grass")], 0));
+        mesh.append(triangle_mesh(vec![material("rock"), material("grass")], 1));
+        mesh.append(triangle_mesh(vec![material("rock")], 0));
+
+        assert_eq!(mesh.vertices.len(), 9);
+        assert_eq!(mesh.materials, vec![material("grass"), material("rock")]);
+        assert_eq!(mesh.triangles[0].indices, [0, 1, 2]);
+        assert_eq!(mesh.triangles[0].material, 0);
+        assert_eq!(mesh.triangles[1].indices, [3, 4, 5]);
+        assert_eq!(mesh.triangles[1].material, 0);
+        assert_eq!(mesh.triangles[2].indices, [6, 7, 8]);
+        assert_eq!(mesh.triangles[2].material, 1);
+    }
+}
@@ -1 +1,7 @@
 pub mod archive;
+pub mod eqg;
+pub mod export;
+pub mod geometry;
+pub mod map;
+pub mod texture;
+pub mod wld;
@@ -0,0 +1,316 @@
+//! EQEmu collision maps
+//!
+//! The server uses `.map` files for line of sight and finding the ground. A version 2 map is
+//! the version, the compressed and inflated sizes of its data, and then the zlib compressed
+//! data. The data is a list of counts followed by the collidable vertices and indices, the
+//! non-collidable ones, and then models, placements and terrain tiles, which are left empty
+//! here since every triangle is written out directly.
+
+use crate::archive::archive_error::ArchiveError;
+use crate::geometry::ZoneMesh;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+/// Version of the map format that's written
+pub const MAP_VERSION: u32 = 0x02000000;
+
+/// Number of counts at the start of the map data
+const COUNT_FIELDS: usize = 10;
+
+/// A triangle in a map
+/// Positions are written as they're given, in the same coordinate system as the zone geometry.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Triangle {
+    pub vertices: [[f32; 3]; 3],
+    /// Whether the triangle blocks movement and line of sight
+    pub collidable: bool,
+}
+
+/// Get the triangles of a mesh with their vertices looked up
+/// Fails if a triangle refers to a vertex the mesh doesn't have.
+pub fn triangles(mesh: &ZoneMesh) -> Result<Vec<Triangle>, ArchiveError> {
+    mesh.triangles
+        .iter()
+        .map(|t| {
+            let mut vertices = [[0.0; 3]; 3];
+            for (vertex, i) in vertices.iter_mut().zip(t.indices) {
+                *vertex = mesh
+                    .vertices
+                    .get(i as usize)
+                    .ok_or_else(|| {
+                        ArchiveError::Parse(format!(
+                            "Triangle uses vertex {} of a mesh with {} vertices",
+                            i,
+                            mesh.vertices.len()
+                        ))
+                    })?
+                    .position;
+            }
+
+            Ok(Triangle {
+                vertices,
+                collidable: t.collidable,
+            })
+        })
+        .collect()
+}
+
+/// Write triangles as a version 2 map
+/// Vertices shared by several triangles are only written once.
+pub fn write_map(tris: &[Triangle], writer: &mut impl Write) -> io::Result<()> {
+    let (verts, indices) = index_triangles(tris.iter().filter(|t| t.collidable));
+    let (nc_verts, nc_indices) = index_triangles(tris.iter().filter(|t| !t.collidable));
+
+    let mut data = Vec::new();
+    for count in [verts.len(), indices.len(), nc_verts.len(), nc_indices.len()] {
+        data.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+    // models, placements, placement groups, tiles and quads per tile
+    data.extend_from_slice(&[0; 20]);
+    // units per vertex
+    data.extend_from_slice(&1.0f32.to_le_bytes());
+
+    for (verts, indices) in [(verts, indices), (nc_verts, nc_indices)] {
+        for v in verts.iter().flatten() {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        for i in indices {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&data)?;
+    let compressed = encoder.finish()?;
+
+    writer.write_all(&MAP_VERSION.to_le_bytes())?;
+    writer.write_all(&(compressed.len() as u32).to_le_bytes())?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(&compressed)
+}
+
+/// Read the triangles out of a version 2 map
+/// Collidable triangles come first followed by the non-collidable ones. Models and terrain
+/// tiles aren't read so they must be empty.
+pub fn read_map(reader: &mut impl Read) -> Result<Vec<Triangle>, ArchiveError> {
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    let field =
+        |data: &[u8], i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+
+    let version = field(&header, 0);
+    if version != MAP_VERSION {
+        return Err(ArchiveError::WrongVersion { version });
+    }
+
+    let compressed_size = field(&header, 1) as usize;
+    let inflated_size = field(&header, 2) as usize;
+    let mut compressed = Vec::new();
+    reader
+        .take(compressed_size as u64)
+        .read_to_end(&mut compressed)?;
+    if compressed.len() != compressed_size {
+        return Err(ArchiveError::Parse(format!(
+            "Map data is truncated to {} of {} bytes",
+            compressed.len(),
+            compressed_size
+        )));
+    }
+
+    // read one byte past the stated size to catch data that inflates larger
+    let mut data = Vec::new();
+    ZlibDecoder::new(&compressed[..])
+        .take(inflated_size as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|_| ArchiveError::Decompression)?;
+    if data.len() != inflated_size || data.len() < COUNT_FIELDS * 4 {
+        return Err(ArchiveError::Parse(format!(
+            "Map data inflated to {} bytes but should be {}",
+            data.len(),
+            inflated_size
+        )));
+    }
+
+    let counts: Vec<usize> = (0..COUNT_FIELDS)
+        .map(|i| field(&data, i) as usize)
+        .collect();
+    if counts[4..8].iter().any(|c| *c != 0) {
+        return Err(ArchiveError::Parse(
+            "Maps with models or terrain tiles aren't supported".to_string(),
+        ));
+    }
+
+    let mut ret = Vec::new();
+    let mut pos = COUNT_FIELDS * 4;
+    for (vert_count, index_count, collidable) in
+        [(counts[0], counts[1], true), (counts[2], counts[3], false)]
+    {
+        let needed = vert_count as u64 * 12 + index_count as u64 * 4;
+        if needed > (data.len() - pos) as u64 || index_count % 3 != 0 {
+            return Err(ArchiveError::Parse(format!(
+                "Map has {} vertices and {} indices which don't fit in {} bytes",
+                vert_count,
+                index_count,
+                data.len() - pos
+            )));
+        }
+
+        let float = |i: usize| f32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let verts: Vec<[f32; 3]> = (0..vert_count)
+            .map(|v| {
+                let i = pos + v * 12;
+                [float(i), float(i + 4), float(i + 8)]
+            })
+            .collect();
+        pos += vert_count * 12;
+
+        for triangle in data[pos..pos + index_count * 4].chunks_exact(12) {
+            let mut vertices = [[0.0; 3]; 3];
+            for (i, vertex) in vertices.iter_mut().enumerate() {
+                let index = field(triangle, i) as usize;
+                *vertex = *verts.get(index).ok_or_else(|| {
+                    ArchiveError::Parse(format!(
+                        "Map triangle refers to vertex {} of {}",
+                        index,
+                        verts.len()
+                    ))
+                })?;
+            }
+            ret.push(Triangle {
+                vertices,
+                collidable,
+            });
+        }
+        pos += index_count * 4;
+    }
+
+    Ok(ret)
+}
+
+/// Turn triangles into a list of unique vertices and indices into it
+fn index_triangles<'a, I>(tris: I) -> (Vec<[f32; 3]>, Vec<u32>)
+where
+    I: Iterator<Item = &'a Triangle>,
+{
+    let mut verts = Vec::new();
+    let mut indices = Vec::new();
+    // keyed by the bits of the position so identical vertices are merged
+    let mut seen: HashMap<[u32; 3], u32> = HashMap::new();
+    for t in tris {
+        for v in &t.vertices {
+            let index = *seen.entry(v.map(f32::to_bits)).or_insert_with(|| {
+                verts.push(*v);
+                verts.len() as u32 - 1
+            });
+            indices.push(index);
+        }
+    }
+
+    (verts, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{self, Vertex};
+
+    fn square() -> ZoneMesh {
+        let vertex = |x: f32, y: f32| Vertex {
+            position: [x, y, 1.5],
+            ..Default::default()
+        };
+
+        ZoneMesh {
+            vertices: vec![
+                vertex(0.0, 0.0),
+                vertex(4.0, 0.0),
+                vertex(0.0, 4.0),
+                vertex(4.0, 4.0),
+            ],
+            triangles: vec![
+                geometry::Triangle {
+                    indices: [0, 1, 2],
+                    material: 0,
+                    collidable: true,
+                    flags: 0,
+                },
+                geometry::Triangle {
+                    indices: [1, 3, 2],
+                    material: 0,
+                    collidable: true,
+                    flags: 0,
+                },
+                geometry::Triangle {
+                    indices: [0, 3, 2],
+                    material: 0,
+                    collidable: false,
+                    flags: 0,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn write_read_map_test() {
+        let tris = triangles(&square()).unwrap();
+        assert_eq!(tris[1].vertices[1], [4.0, 4.0, 1.5]);
+
+        let mut out = Vec::new();
+        write_map(&tris, &mut out).unwrap();
+        assert_eq!(&out[..4], &MAP_VERSION.to_le_bytes());
+
+        // the two collidable triangles share two vertices
+        let mut data = Vec::new();
+        ZlibDecoder::new(&out[12..]).read_to_end(&mut data).unwrap();
+        assert_eq!(
+            &data[..16],
+            &[4, 0, 0, 0, 6, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0]
+        );
+        assert_eq!(
+            u32::from_le_bytes(out[8..12].try_into().unwrap()) as usize,
+            data.len()
+        );
+
+        assert_eq!(read_map(&mut &out[..]).unwrap(), tris);
+    }
+
+    #[test]
+    fn triangles_bad_index_test() {
+        let mut mesh = square();
+        mesh.triangles[1].indices[2] = 4;
+        assert!(matches!(triangles(&mesh), Err(ArchiveError::Parse(_))));
+    }
+
+    #[test]
+    fn read_map_errors_test() {
+        let mut out = Vec::new();
+        write_map(&triangles(&square()).unwrap(), &mut out).unwrap();
+
+        for len in 0..out.len() {
+            assert!(read_map(&mut &out[..len]).is_err());
+        }
+
+        let mut bad = out.clone();
+        bad[..4].copy_from_slice(&0x01000000u32.to_le_bytes());
+        assert!(matches!(
+            read_map(&mut &bad[..]),
+            Err(ArchiveError::WrongVersion {
+                version: 0x01000000
+            })
+        ));
+
+        let mut bad = out;
+        let last = bad.len() - 1;
+        bad[last] ^= 0xff;
+        assert!(read_map(&mut &bad[..]).is_err());
+    }
+
+    #[test]
+    fn write_empty_map_test() {
+        let mut out = Vec::new();
+        write_map(&[], &mut out).unwrap();
+        assert!(read_map(&mut &out[..]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use super::{check_size, checked_len, read_u16, read_u32, slice, Image};
+use crate::archive::archive_error::ArchiveError;
+
+/// Size of the file header in front of the info header
+const FILE_HEADER_SIZE: usize = 14;
+/// Size of the smallest info header with the fields that are read
+const INFO_HEADER_SIZE: usize = 40;
+
+/// Decode an uncompressed BMP with 1, 4 or 8 bits per pixel and a palette, or 24 or 32 bits
+pub fn decode(data: &[u8]) -> Result<Image, ArchiveError> {
+    let pixel_offset = read_u32(data, 10)? as usize;
+    let header_size = read_u32(data, FILE_HEADER_SIZE)? as usize;
+    if header_size < INFO_HEADER_SIZE {
+        return Err(ArchiveError::Parse(format!(
+            "BMP info header of {} bytes isn't supported",
+            header_size
+        )));
+    }
+
+    let width = read_u32(data, 18)? as i32;
+    let height = read_u32(data, 22)? as i32;
+    let bpp = read_u16(data, 28)?;
+    let compression = read_u32(data, 30)?;
+    let colors_used = read_u32(data, 46)? as usize;
+
+    if compression != 0 {
+        return Err(ArchiveError::Parse(format!(
+            "BMP compression {} isn't supported",
+            compression
+        )));
+    }
+
+    if width <= 0 {
+        return Err(ArchiveError::Parse(format!(
+            "BMP has a size of {}x{}",
+            width, height
+        )));
+    }
+
+    // a negative height means the rows are stored top down instead of bottom up
+    let top_down = height < 0;
+    let width = width as usize;
+    let height = height.unsigned_abs() as usize;
+    check_size("BMP", width, height)?;
+
+    let palette = match bpp {
+        1 | 4 | 8 => {
+            let count = if colors_used == 0 {
+                1 << bpp
+            } else {
+                colors_used
+            };
+            let start = FILE_HEADER_SIZE + header_size;
+            let palette = slice(data, start, checked_len(&[count, 4])?)?;
+            palette
+                .chunks_exact(4)
+                .map(|c| [c[2], c[1], c[0], 255])
+                .collect()
+        }
+        24 | 32 => Vec::new(),
+        _ => {
+            return Err(ArchiveError::Parse(format!(
+                "BMP with {} bits per pixel isn't supported",
+                bpp
+            )))
+        }
+    };
+
+    // rows are padded to a multiple of 4 bytes
+    let stride = (width * bpp as usize).div_ceil(32) * 4;
+    let pixel_data = slice(data, pixel_offset, checked_len(&[stride, height])?)?;
+
+    let mut pixels = Vec::with_capacity(checked_len(&[width, height, 4])?);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let row = &pixel_data[row * stride..(row + 1) * stride];
+
+        for x in 0..width {
+            let color = match bpp {
+                24 | 32 => {
+                    let i = x * bpp as usize / 8;
+                    [row[i + 2], row[i + 1], row[i], 255]
+                }
+                _ => {
+                    let bit = x * bpp as usize;
+                    let shift = 8 - bpp as usize - bit % 8;
+                    let index = (row[bit / 8] >> shift) & ((1u16 << bpp) - 1) as u8;
+                    *palette.get(index as usize).ok_or_else(|| {
+                        ArchiveError::Parse(format!(
+                            "BMP palette index {} is past the end of the palette",
+                            index
+                        ))
+                    })?
+                }
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+
+    Ok(Image {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::palettized_bmp;
+    use super::*;
+
+    #[test]
+    fn decode_top_down_test() {
+        let mut data = palettized_bmp();
+        data[22..26].copy_from_slice(&(-2i32).to_le_bytes());
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixel(0, 0), [0, 255, 0, 255]);
+        assert_eq!(image.pixel(0, 1), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn decode_24_bit_test() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&1i32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixels, vec![3, 2, 1, 255, 6, 5, 4, 255]);
+
+        for len in 0..data.len() {
+            assert!(decode(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_too_large_test() {
+        for (width, height) in [(16385, 2), (3, i32::MIN), (i32::MAX, i32::MAX), (3, 0)] {
+            let mut data = palettized_bmp();
+            data[18..22].copy_from_slice(&width.to_le_bytes());
+            data[22..26].copy_from_slice(&height.to_le_bytes());
+            assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+        }
+
+        let mut data = palettized_bmp();
+        data[46..50].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+    }
+
+    #[test]
+    fn decode_unsupported_test() {
+        let mut data = palettized_bmp();
+        data[30..34].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+
+        let mut data = palettized_bmp();
+        data[28..30].copy_from_slice(&16u16.to_le_bytes());
+        assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+    }
+}
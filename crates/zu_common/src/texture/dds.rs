@@ -0,0 +1,347 @@
+use super::{check_size, checked_len, read_u32, slice, truncated, Image};
+use crate::archive::archive_error::ArchiveError;
+
+/// Size of the magic and header in front of the pixel data
+const HEADER_SIZE: usize = 128;
+/// Pixel format flag for uncompressed data described by bit masks
+const DDPF_RGB: u32 = 0x40;
+/// Pixel format flag for data compressed with the codec named by the four character code
+const DDPF_FOURCC: u32 = 0x4;
+/// Pixel format flag marking the alpha mask as valid
+const DDPF_ALPHAPIXELS: u32 = 0x1;
+
+/// Decode the largest mipmap of a DXT1, DXT3, DXT5 or uncompressed 24 or 32-bit DDS
+pub fn decode(data: &[u8]) -> Result<Image, ArchiveError> {
+    let height = read_u32(data, 12)? as usize;
+    let width = read_u32(data, 16)? as usize;
+    let flags = read_u32(data, 80)?;
+    let fourcc = data.get(84..88).ok_or_else(truncated)?;
+
+    check_size("DDS", width, height)?;
+
+    let pixels = if flags & DDPF_FOURCC != 0 {
+        let block_size = match fourcc {
+            b"DXT1" => 8,
+            b"DXT3" | b"DXT5" => 16,
+            _ => {
+                return Err(ArchiveError::Parse(format!(
+                    "DDS codec {} isn't supported",
+                    String::from_utf8_lossy(fourcc)
+                )))
+            }
+        };
+
+        let blocks_wide = width.div_ceil(4);
+        let blocks_high = height.div_ceil(4);
+        let blocks = slice(
+            data,
+            HEADER_SIZE,
+            checked_len(&[blocks_wide, blocks_high, block_size])?,
+        )?;
+
+        let mut pixels = vec![0u8; checked_len(&[width, height, 4])?];
+        for (i, block) in blocks.chunks_exact(block_size).enumerate() {
+            let decoded = match fourcc {
+                b"DXT1" => decode_color_block(block, true),
+                b"DXT3" => decode_dxt3_block(block),
+                _ => decode_dxt5_block(block),
+            };
+
+            // blocks at the right and bottom edges can hang past the image
+            let (bx, by) = (i % blocks_wide * 4, i / blocks_wide * 4);
+            for (j, color) in decoded.iter().enumerate() {
+                let (x, y) = (bx + j % 4, by + j / 4);
+                if x < width && y < height {
+                    let p = (y * width + x) * 4;
+                    pixels[p..p + 4].copy_from_slice(color);
+                }
+            }
+        }
+        pixels
+    } else if flags & DDPF_RGB != 0 {
+        decode_uncompressed(data, width, height, flags)?
+    } else {
+        return Err(ArchiveError::Parse(format!(
+            "DDS pixel format flags {:#x} aren't supported",
+            flags
+        )));
+    };
+
+    Ok(Image {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+fn decode_uncompressed(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    flags: u32,
+) -> Result<Vec<u8>, ArchiveError> {
+    let bit_count = read_u32(data, 88)?;
+    let masks = [
+        read_u32(data, 92)?,
+        read_u32(data, 96)?,
+        read_u32(data, 100)?,
+        if flags & DDPF_ALPHAPIXELS != 0 {
+            read_u32(data, 104)?
+        } else {
+            0
+        },
+    ];
+
+    let bytes = match bit_count {
+        24 | 32 => bit_count as usize / 8,
+        _ => {
+            return Err(ArchiveError::Parse(format!(
+                "DDS with {} bits per pixel isn't supported",
+                bit_count
+            )))
+        }
+    };
+
+    let pixel_data = slice(data, HEADER_SIZE, checked_len(&[width, height, bytes])?)?;
+
+    let mut pixels = Vec::with_capacity(checked_len(&[width, height, 4])?);
+    for pixel in pixel_data.chunks_exact(bytes) {
+        let mut value = [0u8; 4];
+        value[..bytes].copy_from_slice(pixel);
+        let value = u32::from_le_bytes(value);
+
+        for mask in masks {
+            pixels.push(match mask {
+                0 => 255,
+                mask => {
+                    // masks can be up to 32 bits wide so scale in 64 bits
+                    let bits = ((value & mask) >> mask.trailing_zeros()) as u64;
+                    let max = (mask >> mask.trailing_zeros()) as u64;
+                    (bits * 255 / max) as u8
+                }
+            });
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Expand a 5:6:5 color to 8 bits per channel
+fn rgb565(color: u16) -> [u8; 4] {
+    let r = ((color >> 11) & 0x1f) as u8;
+    let g = ((color >> 5) & 0x3f) as u8;
+    let b = (color & 0x1f) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2, 255]
+}
+
+/// Decode the 8 byte color part of a DXT block into 16 pixels
+/// Only DXT1 blocks can use the mode with a transparent color.
+fn decode_color_block(block: &[u8], dxt1: bool) -> [[u8; 4]; 16] {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let (a, b) = (rgb565(c0), rgb565(c1));
+
+    let mix = |x: u8, y: u8, wx: u16, wy: u16| -> u8 {
+        ((x as u16 * wx + y as u16 * wy) / (wx + wy)) as u8
+    };
+    let blend = |wa: u16, wb: u16| -> [u8; 4] {
+        [
+            mix(a[0], b[0], wa, wb),
+            mix(a[1], b[1], wa, wb),
+            mix(a[2], b[2], wa, wb),
+            255,
+        ]
+    };
+
+    let colors = if c0 > c1 || !dxt1 {
+        [a, b, blend(2, 1), blend(1, 2)]
+    } else {
+        [a, b, blend(1, 1), [0, 0, 0, 0]]
+    };
+
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let mut ret = [[0u8; 4]; 16];
+    for (i, pixel) in ret.iter_mut().enumerate() {
+        *pixel = colors[((indices >> (i * 2)) & 0x3) as usize];
+    }
+    ret
+}
+
+/// Decode a DXT3 block, 4 bits of alpha for every pixel followed by a color block
+fn decode_dxt3_block(block: &[u8]) -> [[u8; 4]; 16] {
+    let mut ret = decode_color_block(&block[8..], false);
+    for (i, pixel) in ret.iter_mut().enumerate() {
+        let alpha = (block[i / 2] >> (i % 2 * 4)) & 0xf;
+        pixel[3] = alpha << 4 | alpha;
+    }
+    ret
+}
+
+/// Decode a DXT5 block, two alpha endpoints with 3 bit indices followed by a color block
+fn decode_dxt5_block(block: &[u8]) -> [[u8; 4]; 16] {
+    let (a0, a1) = (block[0] as u16, block[1] as u16);
+    let mut alphas = [0u16; 8];
+    alphas[0] = a0;
+    alphas[1] = a1;
+    if a0 > a1 {
+        for i in 1..7 {
+            alphas[i + 1] = ((7 - i as u16) * a0 + i as u16 * a1) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            alphas[i + 1] = ((5 - i as u16) * a0 + i as u16 * a1) / 5;
+        }
+        alphas[6] = 0;
+        alphas[7] = 255;
+    }
+
+    let mut bits = [0u8; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(bits);
+
+    let mut ret = decode_color_block(&block[8..], false);
+    for (i, pixel) in ret.iter_mut().enumerate() {
+        pixel[3] = alphas[((indices >> (i * 3)) & 0x7) as usize] as u8;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32, flags: u32, fourcc: &[u8; 4], masks: [u32; 5]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[..4].copy_from_slice(b"DDS ");
+        data[4..8].copy_from_slice(&124u32.to_le_bytes());
+        data[12..16].copy_from_slice(&height.to_le_bytes());
+        data[16..20].copy_from_slice(&width.to_le_bytes());
+        data[76..80].copy_from_slice(&32u32.to_le_bytes());
+        data[80..84].copy_from_slice(&flags.to_le_bytes());
+        data[84..88].copy_from_slice(fourcc);
+        for (i, mask) in masks.iter().enumerate() {
+            data[88 + i * 4..92 + i * 4].copy_from_slice(&mask.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn decode_dxt1_test() {
+        // red and blue endpoints, top row all red, then blue, then a 2:1 mix, then a 1:2 mix
+        let mut data = header(4, 4, DDPF_FOURCC, b"DXT1", [0; 5]);
+        data.extend_from_slice(&0xf800u16.to_le_bytes());
+        data.extend_from_slice(&0x001fu16.to_le_bytes());
+        data.extend_from_slice(&[0b00000000, 0b01010101, 0b10101010, 0b11111111]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixel(3, 0), [255, 0, 0, 255]);
+        assert_eq!(image.pixel(0, 1), [0, 0, 255, 255]);
+        assert_eq!(image.pixel(2, 2), [170, 0, 85, 255]);
+        assert_eq!(image.pixel(1, 3), [85, 0, 170, 255]);
+
+        for len in 0..data.len() {
+            assert!(decode(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_dxt1_transparent_test() {
+        // a 2x2 image still takes a whole block, the lower endpoint first selects the
+        // mode with a transparent color
+        let mut data = header(2, 2, DDPF_FOURCC, b"DXT1", [0; 5]);
+        data.extend_from_slice(&0x001fu16.to_le_bytes());
+        data.extend_from_slice(&0xf800u16.to_le_bytes());
+        data.extend_from_slice(&[0b00001011, 0, 0, 0]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixels.len(), 16);
+        assert_eq!(image.pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(1, 0), [127, 0, 127, 255]);
+        assert_eq!(image.pixel(0, 1), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn decode_dxt5_test() {
+        let mut data = header(4, 4, DDPF_FOURCC, b"DXT5", [0; 5]);
+        // alpha endpoints of 255 and 0, the first pixel uses the first and the second the last
+        data.extend_from_slice(&[255, 0, 0b00001000, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&0xffffu16.to_le_bytes());
+        data.extend_from_slice(&0xffffu16.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixel(0, 0), [255, 255, 255, 255]);
+        assert_eq!(image.pixel(1, 0), [255, 255, 255, 0]);
+        assert_eq!(image.pixel(2, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn decode_dxt3_test() {
+        let mut data = header(4, 4, DDPF_FOURCC, b"DXT3", [0; 5]);
+        data.extend_from_slice(&[0xf0, 0, 0, 0, 0, 0, 0, 0x0f]);
+        data.extend_from_slice(&[0; 8]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(1, 0), [0, 0, 0, 255]);
+        assert_eq!(image.pixel(2, 3), [0, 0, 0, 255]);
+        assert_eq!(image.pixel(3, 3), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn decode_uncompressed_test() {
+        let mut data = header(
+            2,
+            1,
+            DDPF_RGB | DDPF_ALPHAPIXELS,
+            &[0; 4],
+            [32, 0x00ff0000, 0x0000ff00, 0x000000ff, 0xff000000],
+        );
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixels, vec![3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+
+    #[test]
+    fn decode_wide_mask_test() {
+        // a malformed header giving one channel every bit of the pixel
+        let mut data = header(
+            2,
+            1,
+            DDPF_RGB,
+            &[0; 4],
+            [32, 0xffffffff, 0x0000ff00, 0x000000ff, 0],
+        );
+        data.extend_from_slice(&0xffffffffu32.to_le_bytes());
+        data.extend_from_slice(&0x80000000u32.to_le_bytes());
+
+        let image = decode(&data).unwrap();
+        assert_eq!(image.pixels, vec![255, 255, 255, 255, 127, 0, 0, 255]);
+    }
+
+    #[test]
+    fn decode_too_large_test() {
+        for (width, height) in [(0, 4), (4, 0), (16385, 4), (u32::MAX, u32::MAX)] {
+            let mut data = header(width, height, DDPF_FOURCC, b"DXT1", [0; 5]);
+            data.extend_from_slice(&[0; 8]);
+            assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+        }
+
+        let data = header(
+            u32::MAX,
+            u32::MAX,
+            DDPF_RGB,
+            &[0; 4],
+            [32, 0x00ff0000, 0x0000ff00, 0x000000ff, 0],
+        );
+        assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+    }
+
+    #[test]
+    fn decode_unsupported_test() {
+        let mut data = header(4, 4, DDPF_FOURCC, b"ATI2", [0; 5]);
+        data.extend_from_slice(&[0; 16]);
+        assert!(matches!(decode(&data), Err(ArchiveError::Parse(_))));
+    }
+}
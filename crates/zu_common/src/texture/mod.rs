@@ -0,0 +1,183 @@
+//! Zone textures
+//!
+//! Textures are stored in archives as BMP files, usually 8-bit palettized, or as DDS files in
+//! later zones. Both are decoded to 8-bit RGBA so they can be previewed or re-exported as PNG.
+
+mod bmp;
+mod dds;
+mod png;
+
+use crate::archive::{archive_error::ArchiveError, archive_trait::IReadableArchive};
+
+/// The color EQ draws as transparent in textures without an alpha channel
+pub const TRANSPARENT_COLOR: [u8; 3] = [255, 0, 255];
+
+/// The largest width or height decoded, well past any texture the client loads
+pub const MAX_TEXTURE_SIZE: usize = 16384;
+
+/// A decoded texture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixels row by row from the top left
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Get the RGBA value of a pixel
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    /// Encode the image as a PNG
+    /// Fails for an image with no pixels or pixels that don't match its size.
+    pub fn to_png(&self) -> Result<Vec<u8>, ArchiveError> {
+        png::encode(self)
+    }
+
+    /// Make every magenta pixel fully transparent, the way the client draws them
+    pub fn mask_transparent_color(&mut self) {
+        for pixel in self.pixels.chunks_exact_mut(4) {
+            if pixel[..3] == TRANSPARENT_COLOR {
+                pixel[3] = 0;
+            }
+        }
+    }
+}
+
+/// Get a texture from an archive and decode it
+pub fn load_texture(archive: &impl IReadableArchive, name: &str) -> Result<Image, ArchiveError> {
+    decode_texture(&archive.get(name)?)
+}
+
+/// Decode a BMP or DDS texture, telling them apart by their header
+pub fn decode_texture(data: &[u8]) -> Result<Image, ArchiveError> {
+    if data.starts_with(b"BM") {
+        bmp::decode(data)
+    } else if data.starts_with(b"DDS ") {
+        dds::decode(data)
+    } else {
+        Err(ArchiveError::Parse(
+            "Texture is neither a BMP or DDS file".to_string(),
+        ))
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, ArchiveError> {
+    match data.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(truncated()),
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, ArchiveError> {
+    match data.get(pos..pos + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(truncated()),
+    }
+}
+
+/// Check the size in a texture's header before anything is allocated for it
+fn check_size(format: &str, width: usize, height: usize) -> Result<(), ArchiveError> {
+    if width == 0 || height == 0 || width > MAX_TEXTURE_SIZE || height > MAX_TEXTURE_SIZE {
+        return Err(ArchiveError::Parse(format!(
+            "{} has a size of {}x{}",
+            format, width, height
+        )));
+    }
+
+    Ok(())
+}
+
+/// Multiply the parts of a length taken from a texture's header
+fn checked_len(parts: &[usize]) -> Result<usize, ArchiveError> {
+    parts
+        .iter()
+        .try_fold(1usize, |len, part| len.checked_mul(*part))
+        .ok_or_else(|| ArchiveError::Parse("Texture size is too large".to_string()))
+}
+
+/// Get len bytes of texture data from start
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], ArchiveError> {
+    start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(truncated)
+}
+
+fn truncated() -> ArchiveError {
+    ArchiveError::Parse("Texture data is truncated".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::prelude::*;
+
+    /// A 3x2 8-bit palettized BMP, stored bottom up with rows padded to 4 bytes
+    pub(super) fn palettized_bmp() -> Vec<u8> {
+        let palette: [[u8; 4]; 3] = [[0, 0, 255, 0], [255, 0, 255, 0], [0, 255, 0, 0]];
+        let rows: [[u8; 3]; 2] = [[0, 1, 2], [2, 2, 0]];
+
+        let pixel_offset = 14 + 40 + palette.len() * 4;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&((pixel_offset + 8) as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&3i32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for color in palette {
+            data.extend_from_slice(&color);
+        }
+
+        // the bottom row comes first
+        for row in rows.iter().rev() {
+            data.extend_from_slice(row);
+            data.push(0);
+        }
+        data
+    }
+
+    #[test]
+    fn load_texture_test() {
+        let mut writable = WritableArchive::new();
+        writable.set("sky.bmp", palettized_bmp()).unwrap();
+        writable.set("bad.bmp", b"not an image").unwrap();
+        let archive = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+
+        let mut image = load_texture(&archive, "sky.bmp").unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(image.pixel(1, 0), [255, 0, 255, 255]);
+        assert_eq!(image.pixel(2, 0), [0, 255, 0, 255]);
+        assert_eq!(image.pixel(0, 1), [0, 255, 0, 255]);
+        assert_eq!(image.pixel(2, 1), [255, 0, 0, 255]);
+
+        image.mask_transparent_color();
+        assert_eq!(image.pixel(1, 0), [255, 0, 255, 0]);
+        assert_eq!(image.pixel(0, 0), [255, 0, 0, 255]);
+
+        assert!(matches!(
+            load_texture(&archive, "bad.bmp"),
+            Err(ArchiveError::Parse(_))
+        ));
+        assert!(matches!(
+            load_texture(&archive, "missing.bmp"),
+            Err(ArchiveError::SrcFileNotFound)
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use super::Image;
+use crate::archive::archive_error::ArchiveError;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use flate2::{write::ZlibEncoder, Compression};
+use std::io::Write;
+
+/// Signature every PNG starts with
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// Color type for 8-bit RGBA
+const COLOR_TYPE_RGBA: u8 = 6;
+
+/// Encode an image as an 8-bit RGBA PNG without filtering
+/// PNGs can't be empty so images with a width or height of 0 are rejected.
+pub fn encode(image: &Image) -> Result<Vec<u8>, ArchiveError> {
+    let len = (image.width as usize)
+        .checked_mul(image.height as usize)
+        .and_then(|len| len.checked_mul(4));
+    if image.width == 0 || image.height == 0 || len != Some(image.pixels.len()) {
+        return Err(ArchiveError::Parse(format!(
+            "Can't encode a {}x{} image with {} bytes of pixels as a PNG",
+            image.width,
+            image.height,
+            image.pixels.len()
+        )));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&image.width.to_be_bytes());
+    header.extend_from_slice(&image.height.to_be_bytes());
+    // bit depth, color type, compression, filter and interlace methods
+    header.extend_from_slice(&[8, COLOR_TYPE_RGBA, 0, 0, 0]);
+
+    // every row starts with the filter it was encoded with, 0 for none
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in image.pixels.chunks_exact(image.width as usize * 4) {
+        // writing to a Vec can't fail
+        encoder.write_all(&[0]).unwrap();
+        encoder.write_all(row).unwrap();
+    }
+    let compressed = encoder.finish().unwrap();
+
+    let mut ret = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut ret, b"IHDR", &header);
+    write_chunk(&mut ret, b"IDAT", &compressed);
+    write_chunk(&mut ret, b"IEND", &[]);
+    Ok(ret)
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let mut digest = crc.digest();
+    digest.update(kind);
+    digest.update(data);
+
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&digest.finalize().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn encode_test() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            pixels: vec![255, 0, 0, 255, 255, 0, 255, 0],
+        };
+        let png = encode(&image).unwrap();
+
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+
+        // IEND always ends with the same CRC
+        assert_eq!(
+            &png[png.len() - 12..],
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut pixels)
+            .unwrap();
+        assert_eq!(pixels, vec![0, 255, 0, 0, 255, 255, 0, 255, 0]);
+    }
+
+    #[test]
+    fn encode_empty_test() {
+        for (width, height, len) in [(0, 1, 0), (1, 0, 0), (0, 0, 0), (2, 1, 4)] {
+            let image = Image {
+                width,
+                height,
+                pixels: vec![0; len],
+            };
+            assert!(matches!(encode(&image), Err(ArchiveError::Parse(_))));
+        }
+    }
+}
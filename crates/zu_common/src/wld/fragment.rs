@@ -0,0 +1,46 @@
+use crate::archive::archive_error::ArchiveError;
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_i32, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// A single fragment of a WLD file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment<'a> {
+    /// Position of the fragment in the file, other fragments refer to it by this plus one
+    pub index: usize,
+    /// Type of the fragment, which decides the layout of the body
+    pub kind: u32,
+    /// Reference to the fragment's name, a negated offset into the string hash or 0 if unnamed
+    pub name_ref: i32,
+    /// Data following the name reference
+    pub body: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    pub(super) fn parse(
+        input: &'a [u8],
+        index: usize,
+    ) -> IResult<&'a [u8], Fragment<'a>, ArchiveError> {
+        let (input, (size, kind)) = tuple((le_u32, le_u32))(input)?;
+        let (input, data) = take(size as usize)(input)?;
+
+        // every fragment type starts with a name reference but guard against tiny bodies
+        let (body, name_ref) = match le_i32::<_, ArchiveError>(data) {
+            Ok((body, name_ref)) => (body, name_ref),
+            Err(_) => (data, 0),
+        };
+
+        Ok((
+            input,
+            Fragment {
+                index,
+                kind,
+                name_ref,
+                body,
+            },
+        ))
+    }
+}
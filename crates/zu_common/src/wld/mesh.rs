@@ -0,0 +1,432 @@
+//! Mesh fragments
+//!
+//! A mesh (0x36) stores its vertices as 16-bit integers scaled by a power of two around a
+//! center point, followed by texture coordinates, normals, colors and triangles. Triangles are
+//! assigned materials in runs, each material coming from the material list (0x31) the mesh
+//! refers to. A material's texture is found by following it to a sprite (0x05), the sprite's
+//! definition (0x04) and finally the bitmap name (0x03).
+
+use super::{decode_string_hash, Fragment, Wld};
+use crate::archive::archive_error::ArchiveError;
+use crate::geometry::{Material, Triangle, Vertex, ZoneMesh};
+use nom::Err::Error;
+use nom::{
+    bytes::complete::take,
+    combinator::map,
+    multi::count,
+    number::complete::{le_f32, le_i16, le_i32, le_i8, le_u16, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// Bitmap name fragment
+const BITMAP_NAME: u32 = 0x03;
+/// Sprite definition fragment
+const SPRITE_DEF: u32 = 0x04;
+/// Sprite fragment
+const SPRITE: u32 = 0x05;
+/// Material fragment
+const MATERIAL: u32 = 0x30;
+/// Material list fragment
+const MATERIAL_LIST: u32 = 0x31;
+/// Mesh fragment
+const MESH: u32 = 0x36;
+
+/// Polygon flag for polygons players walk through, such as water surfaces
+const POLYGON_PASSABLE: u16 = 0x10;
+
+/// A mesh fragment's fields before they're resolved into a `ZoneMesh`
+struct MeshFragment {
+    material_list: i32,
+    center: [f32; 3],
+    scale: u16,
+    vertices: Vec<[i16; 3]>,
+    uvs: Vec<[f32; 2]>,
+    normals: Vec<[i8; 3]>,
+    colors: Vec<u32>,
+    polygons: Vec<(u16, [u16; 3])>,
+    polygon_materials: Vec<(u16, u16)>,
+}
+
+impl<'a> Wld<'a> {
+    /// Decode every mesh fragment into a single mesh, for a zone's WLD this is the zone geometry
+    pub fn zone_mesh(&self) -> Result<ZoneMesh, ArchiveError> {
+        let mut mesh = ZoneMesh::new();
+        for other in self.meshes()? {
+            mesh.append(other);
+        }
+
+        Ok(mesh)
+    }
+
+    /// Decode every mesh fragment separately in the order they appear in the file
+    pub fn meshes(&self) -> Result<Vec<ZoneMesh>, ArchiveError> {
+        self.fragments
+            .iter()
+            .filter(|f| f.kind == MESH)
+            .map(|f| self.mesh(f))
+            .collect()
+    }
+
+    /// Decode a single mesh fragment
+    pub fn mesh(&self, fragment: &Fragment) -> Result<ZoneMesh, ArchiveError> {
+        if fragment.kind != MESH {
+            return Err(ArchiveError::Parse(format!(
+                "Fragment {} has type {:#x} and isn't a mesh",
+                fragment.index, fragment.kind
+            )));
+        }
+
+        let raw = match parse_mesh(fragment.body, self.header.is_old_format()) {
+            Ok((_, raw)) => raw,
+            Err(e) => return Err(ArchiveError::from_nom(e, fragment.body)),
+        };
+
+        let scale = 1.0 / 2f32.powi(raw.scale as i32);
+        let vertices = raw
+            .vertices
+            .iter()
+            .enumerate()
+            .map(|(i, v)| Vertex {
+                position: [
+                    raw.center[0] + v[0] as f32 * scale,
+                    raw.center[1] + v[1] as f32 * scale,
+                    raw.center[2] + v[2] as f32 * scale,
+                ],
+                normal: raw
+                    .normals
+                    .get(i)
+                    .map(|n| n.map(|c| c as f32 / 128.0))
+                    .unwrap_or_default(),
+                uv: raw.uvs.get(i).copied().unwrap_or_default(),
+                // colors are stored as BGRA
+                color: raw
+                    .colors
+                    .get(i)
+                    .map(|c| {
+                        let [b, g, r, a] = c.to_le_bytes();
+                        [r, g, b, a]
+                    })
+                    .unwrap_or([255; 4]),
+            })
+            .collect::<Vec<_>>();
+
+        let mut triangles = Vec::with_capacity(raw.polygons.len());
+        for (flags, indices) in &raw.polygons {
+            if let Some(i) = indices.iter().find(|i| **i as usize >= vertices.len()) {
+                return Err(ArchiveError::Parse(format!(
+                    "Mesh polygon refers to vertex {} of {}",
+                    i,
+                    vertices.len()
+                )));
+            }
+
+            triangles.push(Triangle {
+                indices: indices.map(|i| i as u32),
+                material: 0,
+                collidable: flags & POLYGON_PASSABLE == 0,
+                flags: u32::from(*flags),
+            });
+        }
+
+        // materials are assigned to runs of polygons in order
+        let mut start = 0;
+        for (run, material) in &raw.polygon_materials {
+            let end = (start + *run as usize).min(triangles.len());
+            for triangle in &mut triangles[start..end] {
+                triangle.material = *material as usize;
+            }
+            start = end;
+        }
+
+        let materials = self.materials(raw.material_list)?;
+        if let Some(t) = triangles.iter().find(|t| t.material >= materials.len()) {
+            return Err(ArchiveError::Parse(format!(
+                "Mesh polygon uses material {} of {}",
+                t.material,
+                materials.len()
+            )));
+        }
+
+        Ok(ZoneMesh {
+            name: self.name(fragment).unwrap_or_default().to_string(),
+            vertices,
+            triangles,
+            materials,
+        })
+    }
+
+    /// Resolve the materials in a material list fragment
+    fn materials(&self, reference: i32) -> Result<Vec<Material>, ArchiveError> {
+        let list = match self.fragment(reference) {
+            Some(f) if f.kind == MATERIAL_LIST => f,
+            _ => {
+                return Err(ArchiveError::Parse(format!(
+                    "Mesh material list {} isn't a material list fragment",
+                    reference
+                )))
+            }
+        };
+
+        let refs = match parse_material_list(list.body) {
+            Ok((_, refs)) => refs,
+            Err(e) => return Err(ArchiveError::from_nom(e, list.body)),
+        };
+
+        refs.iter()
+            .map(|r| match self.fragment(*r) {
+                Some(material) if material.kind == MATERIAL => Ok(Material {
+                    name: self.name(material).unwrap_or_default().to_string(),
+                    texture: self.material_texture(material),
+                }),
+                _ => Err(ArchiveError::Parse(format!(
+                    "Material list entry {} isn't a material fragment",
+                    r
+                ))),
+            })
+            .collect()
+    }
+
+    /// Follow a material to the name of its texture, None if it's untextured
+    fn material_texture(&self, material: &Fragment) -> Option<String> {
+        let sprite = self.fragment(read_i32(material.body, 20)?)?;
+        if sprite.kind != SPRITE {
+            return None;
+        }
+
+        let sprite_def = self.fragment(read_i32(sprite.body, 0)?)?;
+        if sprite_def.kind != SPRITE_DEF {
+            return None;
+        }
+
+        // optional current frame and sleep fields come before the bitmap references
+        let flags = read_i32(sprite_def.body, 0)?;
+        let mut pos = 8;
+        if flags & 0x4 != 0 {
+            pos += 4;
+        }
+        if flags & 0x8 != 0 {
+            pos += 4;
+        }
+
+        let bitmap = self.fragment(read_i32(sprite_def.body, pos)?)?;
+        if bitmap.kind != BITMAP_NAME {
+            return None;
+        }
+
+        let len = u16::from_le_bytes(bitmap.body.get(4..6)?.try_into().ok()?) as usize;
+        let mut name = bitmap.body.get(6..6 + len)?.to_vec();
+        decode_string_hash(&mut name);
+        let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        let name = std::str::from_utf8(&name[..end]).ok()?;
+        Some(name.to_lowercase())
+    }
+}
+
+fn read_i32(data: &[u8], pos: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn parse_material_list(input: &[u8]) -> IResult<&[u8], Vec<i32>, ArchiveError> {
+    let (input, (_flags, material_count)) = tuple((le_u32, le_u32))(input)?;
+    if material_count as usize > input.len() / 4 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "Material list has {} materials but only {} bytes remain",
+            material_count,
+            input.len()
+        ))));
+    }
+
+    count(le_i32, material_count as usize)(input)
+}
+
+fn parse_mesh(input: &[u8], old_format: bool) -> IResult<&[u8], MeshFragment, ArchiveError> {
+    let (input, (_flags, material_list, _animation, _, _)) =
+        tuple((le_u32, le_i32, le_i32, le_i32, le_i32))(input)?;
+    let (input, center) = tuple((le_f32, le_f32, le_f32))(input)?;
+    // unknown parameters, max distance and the bounding box
+    let (input, _) = take(40usize)(input)?;
+    let (
+        input,
+        (
+            vertex_count,
+            uv_count,
+            normal_count,
+            color_count,
+            polygon_count,
+            vertex_piece_count,
+            polygon_material_count,
+            _vertex_material_count,
+            _,
+            scale,
+        ),
+    ) = tuple((
+        le_u16, le_u16, le_u16, le_u16, le_u16, le_u16, le_u16, le_u16, le_u16, le_u16,
+    ))(input)?;
+
+    let (input, vertices) = count(
+        map(tuple((le_i16, le_i16, le_i16)), |(x, y, z)| [x, y, z]),
+        vertex_count as usize,
+    )(input)?;
+
+    // the original client stores texture coordinates in half the space
+    let (input, uvs) = if old_format {
+        count(
+            map(tuple((le_i16, le_i16)), |(u, v)| {
+                [u as f32 / 256.0, v as f32 / 256.0]
+            }),
+            uv_count as usize,
+        )(input)?
+    } else {
+        count(
+            map(tuple((le_i32, le_i32)), |(u, v)| {
+                [u as f32 / 256.0, v as f32 / 256.0]
+            }),
+            uv_count as usize,
+        )(input)?
+    };
+
+    let (input, normals) = count(
+        map(tuple((le_i8, le_i8, le_i8)), |(x, y, z)| [x, y, z]),
+        normal_count as usize,
+    )(input)?;
+    let (input, colors) = count(le_u32, color_count as usize)(input)?;
+    let (input, polygons) = count(
+        map(tuple((le_u16, le_u16, le_u16, le_u16)), |(f, a, b, c)| {
+            (f, [a, b, c])
+        }),
+        polygon_count as usize,
+    )(input)?;
+    // bone assignments for animated meshes
+    let (input, _) = take(vertex_piece_count as usize * 4)(input)?;
+    let (input, polygon_materials) =
+        count(tuple((le_u16, le_u16)), polygon_material_count as usize)(input)?;
+
+    Ok((
+        input,
+        MeshFragment {
+            material_list,
+            center: [center.0, center.1, center.2],
+            scale,
+            vertices,
+            uvs,
+            normals,
+            colors,
+            polygons,
+            polygon_materials,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::WldBuilder;
+    use super::*;
+
+    #[test]
+    fn zone_mesh_test() {
+        let mut builder = WldBuilder::new();
+        let grass = builder.add_material("GRASS_MDF", "Grass.BMP");
+        let water = builder.add_material("WATER_MDF", "water1.bmp");
+        builder.add_mesh(
+            "R1_DMSPRITEDEF",
+            &[grass, water],
+            &[[0, 0, 0], [256, 0, 0], [0, 256, 0], [256, 256, 0]],
+            &[(0, [0, 1, 2], 0), (POLYGON_PASSABLE, [1, 3, 2], 1)],
+        );
+        builder.add_mesh(
+            "R2_DMSPRITEDEF",
+            &[water],
+            &[[0, 0, 512], [512, 0, 512], [0, 512, 512]],
+            &[(0, [0, 1, 2], 0)],
+        );
+        let data = builder.build();
+        let wld = Wld::parse(&data).unwrap();
+
+        let meshes = wld.meshes().unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].name, "R1_DMSPRITEDEF");
+        assert_eq!(meshes[1].name, "R2_DMSPRITEDEF");
+        assert_eq!(meshes[1].materials.len(), 1);
+
+        let mesh = wld.zone_mesh().unwrap();
+        assert_eq!(mesh.name, "");
+        assert_eq!(mesh.vertices.len(), 7);
+        assert_eq!(mesh.triangles.len(), 3);
+        assert_eq!(
+            mesh.materials,
+            vec![
+                Material {
+                    name: "GRASS_MDF".to_string(),
+                    texture: Some("grass.bmp".to_string()),
+                },
+                Material {
+                    name: "WATER_MDF".to_string(),
+                    texture: Some("water1.bmp".to_string()),
+                },
+            ]
+        );
+
+        // the builder uses a scale of 1/2^8
+        let vertex = mesh.vertices[3];
+        assert_eq!(vertex.position, [1.0, 1.0, 0.0]);
+        assert_eq!(vertex.normal, [0.0, 0.0, 127.0 / 128.0]);
+        assert_eq!(vertex.uv, [1.0, 1.0]);
+        assert_eq!(vertex.color, [0x10, 0x20, 0x30, 0xff]);
+        assert_eq!(mesh.vertices[5].position, [2.0, 0.0, 2.0]);
+
+        assert_eq!(mesh.triangles[0].material, 0);
+        assert!(mesh.triangles[0].collidable);
+        assert_eq!(mesh.triangles[1].indices, [1, 3, 2]);
+        assert_eq!(mesh.triangles[1].material, 1);
+        assert!(!mesh.triangles[1].collidable);
+        assert_eq!(mesh.triangles[2].indices, [4, 5, 6]);
+        assert_eq!(mesh.triangles[2].material, 1);
+    }
+
+    #[test]
+    fn mesh_errors_test() {
+        let mut builder = WldBuilder::new();
+        let grass = builder.add_material("GRASS_MDF", "grass.bmp");
+        builder.add_mesh(
+            "R1_DMSPRITEDEF",
+            &[grass],
+            &[[0, 0, 0]],
+            &[(0, [0, 1, 2], 0)],
+        );
+        builder.add_mesh(
+            "R1_DMSPRITEDEF",
+            &[grass],
+            &[[0, 0, 0]; 3],
+            &[(0, [0, 1, 2], 4)],
+        );
+        builder.add_mesh(
+            "R1_DMSPRITEDEF",
+            &[grass],
+            &[[0, 0, 0]; 3],
+            &[(0, [0, 1, 2], 0)],
+        );
+        let data = builder.build();
+        let wld = Wld::parse(&data).unwrap();
+
+        let meshes: Vec<_> = wld.fragments().iter().filter(|f| f.kind == MESH).collect();
+        assert!(matches!(wld.mesh(meshes[0]), Err(ArchiveError::Parse(_))));
+        assert!(matches!(wld.mesh(meshes[1]), Err(ArchiveError::Parse(_))));
+        assert!(matches!(
+            wld.mesh(&wld.fragments()[0]),
+            Err(ArchiveError::Parse(_))
+        ));
+        assert!(wld.zone_mesh().is_err());
+
+        // every truncation of a mesh body fails instead of reading past it
+        let mesh = meshes[2];
+        assert!(wld.mesh(mesh).is_ok());
+        for len in 0..mesh.body.len() {
+            let truncated = Fragment {
+                body: &mesh.body[..len],
+                ..*mesh
+            };
+            assert!(wld.mesh(&truncated).is_err());
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! WLD files
+//!
+//! The zone geometry, objects and characters in an `.s3d` archive are described by WLD files.
+//! A WLD file is a header, an obfuscated table of strings and then a list of fragments. Every
+//! fragment has a type that decides the layout of its body; fragments refer to each other by
+//! index and to names in the string table by negative offset.
+
+mod fragment;
+mod mesh;
+mod region;
+mod string_hash;
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use fragment::Fragment;
+pub use region::{Plane, Region, RegionType};
+pub use string_hash::decode_string_hash;
+
+use crate::archive::archive_error::ArchiveError;
+use nom::Err::Error;
+use nom::{
+    bytes::complete::{tag, take},
+    number::complete::le_u32,
+    sequence::tuple,
+    IResult,
+};
+
+/// Magic number every WLD file starts with
+pub const WLD_MAGIC: u32 = 0x54503D02;
+/// Version of WLD files written for the original client
+pub const WLD_VERSION_OLD: u32 = 0x00015500;
+/// Version of WLD files written for later clients
+pub const WLD_VERSION_NEW: u32 = 0x1000C800;
+
+/// The header at the start of a WLD file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WldHeader {
+    pub version: u32,
+    /// Number of fragments that follow the string hash
+    pub fragment_count: u32,
+    /// Number of BSP regions in a zone, 0 for other files
+    pub region_count: u32,
+    /// Size in bytes of the largest object fragment
+    pub max_object_bytes: u32,
+    /// Size in bytes of the string hash
+    pub string_hash_size: u32,
+    /// Number of strings in the string hash
+    pub string_count: u32,
+}
+
+impl WldHeader {
+    /// Whether the file was written for the original client
+    /// The old format stores some values, such as texture coordinates, at a smaller size.
+    pub fn is_old_format(&self) -> bool {
+        self.version == WLD_VERSION_OLD
+    }
+}
+
+/// A parsed WLD file
+/// Fragments borrow their bodies from the data the file was parsed from.
+pub struct Wld<'a> {
+    pub header: WldHeader,
+    strings: Vec<u8>,
+    fragments: Vec<Fragment<'a>>,
+}
+
+impl<'a> Wld<'a> {
+    /// Parse a WLD file, such as one read from an archive with `get`
+    pub fn parse(data: &'a [u8]) -> Result<Wld<'a>, ArchiveError> {
+        match Wld::do_parse(data) {
+            Ok((_, wld)) => Ok(wld),
+            Err(e) => Err(ArchiveError::from_nom(e, data)),
+        }
+    }
+
+    /// Every fragment in the order they appear in the file
+    pub fn fragments(&self) -> &[Fragment<'a>] {
+        &self.fragments
+    }
+
+    /// Get the fragment a reference points to
+    /// Positive references are a fragment's index plus one and negative references are the
+    /// name of a fragment.
+    pub fn fragment(&self, reference: i32) -> Option<&Fragment<'a>> {
+        match reference {
+            r if r > 0 => self.fragments.get(r as usize - 1),
+            r if r < 0 => self.fragments.iter().find(|f| f.name_ref == r),
+            _ => None,
+        }
+    }
+
+    /// The decoded string hash
+    pub fn strings(&self) -> &[u8] {
+        &self.strings
+    }
+
+    /// Get the string in the string hash at an offset
+    /// Fragments store name references as negated offsets so -5 and 5 both get the string
+    /// starting 5 bytes into the string hash. None if the offset is past the end of the string
+    /// hash or the string isn't valid UTF-8.
+    pub fn string_at(&self, offset: i32) -> Option<&str> {
+        let rest = self.strings.get(offset.unsigned_abs() as usize..)?;
+        let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        std::str::from_utf8(&rest[..end]).ok()
+    }
+
+    /// Get the name of a fragment, None if it's unnamed
+    pub fn name(&self, fragment: &Fragment) -> Option<&str> {
+        if fragment.name_ref >= 0 {
+            return None;
+        }
+
+        self.string_at(fragment.name_ref)
+    }
+
+    fn do_parse(input: &'a [u8]) -> IResult<&'a [u8], Wld<'a>, ArchiveError> {
+        let (input, (_, version, fragment_count, region_count, max_object_bytes)) =
+            tuple((
+                tag(&WLD_MAGIC.to_le_bytes()[..]),
+                le_u32,
+                le_u32,
+                le_u32,
+                le_u32,
+            ))(input)
+            .map_err(|_: nom::Err<ArchiveError>| {
+                Error(ArchiveError::Parse("Not a WLD file".to_string()))
+            })?;
+
+        if version != WLD_VERSION_OLD && version != WLD_VERSION_NEW {
+            return Err(Error(ArchiveError::WrongVersion { version }));
+        }
+
+        let (input, (string_hash_size, string_count)) = tuple((le_u32, le_u32))(input)?;
+        let (mut input, encoded) = take(string_hash_size as usize)(input)?;
+
+        // every fragment is at least its 8 byte header
+        if fragment_count as usize > input.len() / 8 {
+            return Err(Error(ArchiveError::Parse(format!(
+                "WLD has {} fragments but only {} bytes remain",
+                fragment_count,
+                input.len()
+            ))));
+        }
+
+        let mut fragments = Vec::with_capacity(fragment_count as usize);
+        for index in 0..fragment_count as usize {
+            let (pos, fragment) = Fragment::parse(input, index)?;
+            fragments.push(fragment);
+            input = pos;
+        }
+
+        let mut strings = encoded.to_vec();
+        decode_string_hash(&mut strings);
+        Ok((
+            input,
+            Wld {
+                header: WldHeader {
+                    version,
+                    fragment_count,
+                    region_count,
+                    max_object_bytes,
+                    string_hash_size,
+                    string_count,
+                },
+                strings,
+                fragments,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::WldBuilder;
+    use super::*;
+    use crate::archive::prelude::*;
+
+    #[test]
+    fn parse_test() {
+        let mut builder = WldBuilder::new();
+        let name = builder.add_string("ZONE_DMSPRITEDEF");
+        builder.add_fragment(0x36, name, &[1, 2, 3, 4]);
+        builder.add_fragment(0x35, 0, &[]);
+
+        // read back out of an archive the way zone files are
+        let mut writable = WritableArchive::new();
+        writable.set("zone.wld", builder.build()).unwrap();
+        let archive = ReadableArchive::try_from(writable.save_to_bytes().unwrap()).unwrap();
+        let data = archive.get("zone.wld").unwrap();
+
+        let wld = Wld::parse(&data).unwrap();
+        assert_eq!(wld.header.version, WLD_VERSION_NEW);
+        assert!(!wld.header.is_old_format());
+        assert_eq!(wld.header.fragment_count, 2);
+        assert_eq!(wld.header.string_count, 1);
+
+        let fragments = wld.fragments();
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0].index, 0);
+        assert_eq!(fragments[0].kind, 0x36);
+        assert_eq!(fragments[0].name_ref, name);
+        assert_eq!(fragments[0].body, &[1, 2, 3, 4]);
+        assert_eq!(fragments[1].kind, 0x35);
+        assert!(fragments[1].body.is_empty());
+
+        let offset = -name as usize;
+        assert_eq!(&wld.strings()[offset..offset + 16], b"ZONE_DMSPRITEDEF");
+        assert_eq!(wld.name(&fragments[0]), Some("ZONE_DMSPRITEDEF"));
+        assert_eq!(wld.name(&fragments[1]), None);
+
+        assert_eq!(wld.fragment(1), Some(&fragments[0]));
+        assert_eq!(wld.fragment(2), Some(&fragments[1]));
+        assert_eq!(wld.fragment(name), Some(&fragments[0]));
+        assert_eq!(wld.fragment(0), None);
+        assert_eq!(wld.fragment(3), None);
+    }
+
+    #[test]
+    fn string_at_test() {
+        let mut builder = WldBuilder::new();
+        let first = builder.add_string("FIRST");
+        let second = builder.add_string("SECOND");
+        let data = builder.build();
+        let wld = Wld::parse(&data).unwrap();
+
+        assert_eq!(wld.string_at(first), Some("FIRST"));
+        assert_eq!(wld.string_at(second), Some("SECOND"));
+        assert_eq!(wld.string_at(-second), Some("SECOND"));
+        assert_eq!(wld.string_at(first - 2), Some("RST"));
+        assert_eq!(wld.string_at(-1000), None);
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        assert!(matches!(
+            Wld::parse(b"not a wld file at all, not even close"),
+            Err(ArchiveError::Parse(_))
+        ));
+
+        let mut data = WldBuilder::new().build();
+        data[4..8].copy_from_slice(&7u32.to_le_bytes());
+        assert!(matches!(
+            Wld::parse(&data),
+            Err(ArchiveError::WrongVersion { version: 7 })
+        ));
+
+        let mut builder = WldBuilder::new();
+        builder.add_fragment(0x03, 0, &[0; 32]);
+        let data = builder.build();
+        for len in 0..data.len() {
+            assert!(Wld::parse(&data[..len]).is_err());
+        }
+    }
+}
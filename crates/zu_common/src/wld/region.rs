@@ -0,0 +1,409 @@
+//! Region fragments
+//!
+//! A zone is split into regions by a BSP tree (0x21). Every node of the tree is a plane with
+//! a child on each side of it and every leaf is a region, so a region is the space bounded by
+//! the planes on the path from the root to its leaf. Special regions such as water are listed
+//! by region flag fragments (0x29), whose name says what kind of region they are.
+
+use super::{decode_string_hash, Fragment, Wld};
+use crate::archive::archive_error::ArchiveError;
+use nom::Err::Error;
+use nom::{
+    bytes::complete::take,
+    multi::count,
+    number::complete::{le_f32, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// BSP tree fragment
+const BSP_TREE: u32 = 0x21;
+/// Region flag fragment
+const REGION_FLAG: u32 = 0x29;
+
+/// What happens to players inside a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegionType {
+    #[default]
+    Normal,
+    Water,
+    Lava,
+    PvP,
+    Zoneline,
+    Slippery,
+}
+
+impl RegionType {
+    /// Get the type of region a region flag fragment's name describes
+    pub fn from_name(name: &str) -> RegionType {
+        let name = name.to_uppercase();
+        if name.starts_with("WT") {
+            RegionType::Water
+        } else if name.starts_with("LA") {
+            RegionType::Lava
+        } else if name.starts_with("DRNTP") {
+            RegionType::Zoneline
+        } else if name.starts_with("DRP_") {
+            RegionType::PvP
+        } else if name.starts_with("SL") {
+            RegionType::Slippery
+        } else {
+            RegionType::Normal
+        }
+    }
+}
+
+/// One side of a plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: [f32; 3],
+    pub distance: f32,
+}
+
+impl Plane {
+    /// Whether a point is on the inside of the plane, that is `normal · point + distance >= 0`
+    pub fn contains(&self, point: [f32; 3]) -> bool {
+        let [x, y, z] = point;
+        self.normal[0] * x + self.normal[1] * y + self.normal[2] * z + self.distance >= 0.0
+    }
+}
+
+/// A region of a zone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Region {
+    /// Number of the region, counting from 0
+    pub index: usize,
+    pub kind: RegionType,
+    /// Name of the region flag fragment that gave the region its type, such as the
+    /// destination of a zone line
+    pub name: String,
+    /// Convex volumes making up the region, usually one, each bounded by its planes
+    pub volumes: Vec<Vec<Plane>>,
+}
+
+impl Region {
+    /// Whether a point is inside the region
+    pub fn contains(&self, point: [f32; 3]) -> bool {
+        self.volumes
+            .iter()
+            .any(|planes| planes.iter().all(|p| p.contains(point)))
+    }
+}
+
+/// A node of the BSP tree
+struct Node {
+    plane: Plane,
+    /// Region of a leaf counting from 1, 0 for nodes that aren't leaves
+    region: u32,
+    /// Children counting from 1, 0 for none
+    left: u32,
+    right: u32,
+}
+
+impl<'a> Wld<'a> {
+    /// Decode the regions of a zone in order, empty if the file has no BSP tree
+    pub fn regions(&self) -> Result<Vec<Region>, ArchiveError> {
+        let tree = match self.fragments.iter().find(|f| f.kind == BSP_TREE) {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+
+        let nodes = match parse_nodes(tree.body) {
+            Ok((_, nodes)) => nodes,
+            Err(e) => return Err(ArchiveError::from_nom(e, tree.body)),
+        };
+
+        let mut regions: Vec<Region> = Vec::new();
+        let mut visited = vec![false; nodes.len()];
+        let mut stack = vec![(1u32, Vec::new())];
+        while let Some((node, planes)) = stack.pop() {
+            if node == 0 {
+                continue;
+            }
+
+            let i = node as usize - 1;
+            match visited.get(i) {
+                None => {
+                    return Err(ArchiveError::Parse(format!(
+                        "BSP node {} is past the end of the {} node tree",
+                        node,
+                        nodes.len()
+                    )))
+                }
+                Some(true) => {
+                    return Err(ArchiveError::Parse(format!(
+                        "BSP node {} is reached more than once",
+                        node
+                    )))
+                }
+                Some(false) => visited[i] = true,
+            }
+
+            let n = &nodes[i];
+            if n.region > 0 {
+                // a tree can't have more leaves than nodes
+                if n.region as usize > nodes.len() {
+                    return Err(ArchiveError::Parse(format!(
+                        "BSP node {} has region {} but the tree only has {} nodes",
+                        node,
+                        n.region,
+                        nodes.len()
+                    )));
+                }
+                let index = n.region as usize - 1;
+                if regions.len() <= index {
+                    regions.resize_with(index + 1, Region::default);
+                }
+                regions[index].volumes.push(planes);
+                continue;
+            }
+
+            // points with a positive distance from the plane go left
+            let mut left = planes.clone();
+            left.push(n.plane);
+            let mut right = planes;
+            right.push(Plane {
+                normal: n.plane.normal.map(|c| -c),
+                distance: -n.plane.distance,
+            });
+            stack.push((n.right, right));
+            stack.push((n.left, left));
+        }
+
+        for (index, region) in regions.iter_mut().enumerate() {
+            region.index = index;
+        }
+
+        let region_count = regions.len();
+        for fragment in self.fragments.iter().filter(|f| f.kind == REGION_FLAG) {
+            let (listed, name) = match parse_region_flag(fragment.body) {
+                Ok((_, flag)) => flag,
+                Err(e) => return Err(ArchiveError::from_nom(e, fragment.body)),
+            };
+
+            // the encoded name is the full description when present, otherwise fall back to
+            // the fragment's own name
+            let name = match name {
+                Some(name) => name,
+                None => self.name(fragment).unwrap_or_default().to_string(),
+            };
+            let kind = RegionType::from_name(&name);
+
+            for index in listed {
+                let region = regions
+                    .get_mut(index as usize)
+                    .ok_or_else(|| region_error(fragment, index, region_count))?;
+                region.kind = kind;
+                region.name = name.clone();
+            }
+        }
+
+        Ok(regions)
+    }
+}
+
+fn region_error(fragment: &Fragment, index: u32, count: usize) -> ArchiveError {
+    ArchiveError::Parse(format!(
+        "Region flag fragment {} lists region {} of {}",
+        fragment.index, index, count
+    ))
+}
+
+fn parse_nodes(input: &[u8]) -> IResult<&[u8], Vec<Node>, ArchiveError> {
+    let (input, node_count) = le_u32(input)?;
+    if node_count as u64 * 28 > input.len() as u64 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "BSP tree has {} nodes but only {} bytes remain",
+            node_count,
+            input.len()
+        ))));
+    }
+
+    count(
+        |input| {
+            let (input, (x, y, z, distance, region, left, right)) =
+                tuple((le_f32, le_f32, le_f32, le_f32, le_u32, le_u32, le_u32))(input)?;
+            Ok((
+                input,
+                Node {
+                    plane: Plane {
+                        normal: [x, y, z],
+                        distance,
+                    },
+                    region,
+                    left,
+                    right,
+                },
+            ))
+        },
+        node_count as usize,
+    )(input)
+}
+
+/// Parse a region flag fragment into the regions it lists and its encoded name, if it has one
+fn parse_region_flag(input: &[u8]) -> IResult<&[u8], (Vec<u32>, Option<String>), ArchiveError> {
+    let (input, (_flags, region_count)) = tuple((le_u32, le_u32))(input)?;
+    if region_count as u64 * 4 > input.len() as u64 {
+        return Err(Error(ArchiveError::Parse(format!(
+            "Region flag fragment has {} regions but only {} bytes remain",
+            region_count,
+            input.len()
+        ))));
+    }
+
+    let (input, regions) = count(le_u32, region_count as usize)(input)?;
+    let (input, size) = le_u32(input)?;
+    let (input, encoded) = take(size as usize)(input)?;
+
+    let mut name = encoded.to_vec();
+    decode_string_hash(&mut name);
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    let name = String::from_utf8_lossy(&name[..end]).into_owned();
+
+    Ok((input, (regions, Some(name).filter(|n| !n.is_empty()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::WldBuilder;
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+
+    fn region_flag(regions: &[u32], name: &str) -> Vec<u8> {
+        let mut encoded = name.as_bytes().to_vec();
+        if !encoded.is_empty() {
+            encoded.push(0);
+        }
+        decode_string_hash(&mut encoded);
+
+        let mut body = BytesMut::new();
+        body.put_u32_le(0);
+        body.put_u32_le(regions.len() as u32);
+        for region in regions {
+            body.put_u32_le(*region);
+        }
+        body.put_u32_le(encoded.len() as u32);
+        body.put(&encoded[..]);
+        body.to_vec()
+    }
+
+    /// Split on x = 0 and then on y = 10 for the negative side, giving three regions
+    fn tree() -> Vec<u8> {
+        let nodes: [([f32; 3], f32, u32, u32, u32); 5] = [
+            ([1.0, 0.0, 0.0], 0.0, 0, 2, 3),
+            ([0.0, 0.0, 0.0], 0.0, 1, 0, 0),
+            ([0.0, 1.0, 0.0], -10.0, 0, 4, 5),
+            ([0.0, 0.0, 0.0], 0.0, 2, 0, 0),
+            ([0.0, 0.0, 0.0], 0.0, 3, 0, 0),
+        ];
+
+        let mut body = BytesMut::new();
+        body.put_u32_le(nodes.len() as u32);
+        for (normal, distance, region, left, right) in nodes {
+            for c in normal {
+                body.put_f32_le(c);
+            }
+            body.put_f32_le(distance);
+            body.put_u32_le(region);
+            body.put_u32_le(left);
+            body.put_u32_le(right);
+        }
+        body.to_vec()
+    }
+
+    #[test]
+    fn regions_test() {
+        let mut builder = WldBuilder::new();
+        builder.add_fragment(BSP_TREE, 0, &tree());
+        let water = builder.add_string("WT_ZONE");
+        builder.add_fragment(REGION_FLAG, water, &region_flag(&[0], ""));
+        let zoneline = builder.add_string("DRN_ZONE");
+        builder.add_fragment(
+            REGION_FLAG,
+            zoneline,
+            &region_flag(&[2], "DRNTP00025000000000000000000___000000000000"),
+        );
+        let data = builder.build();
+        let wld = Wld::parse(&data).unwrap();
+
+        let regions = wld.regions().unwrap();
+        assert_eq!(regions.len(), 3);
+        assert_eq!(regions[0].kind, RegionType::Water);
+        assert_eq!(regions[0].name, "WT_ZONE");
+        assert_eq!(regions[1].kind, RegionType::Normal);
+        assert_eq!(regions[1].index, 1);
+        assert_eq!(regions[2].kind, RegionType::Zoneline);
+        assert!(regions[2].name.starts_with("DRNTP00025"));
+
+        assert_eq!(
+            regions[0].volumes,
+            vec![vec![Plane {
+                normal: [1.0, 0.0, 0.0],
+                distance: 0.0,
+            }]]
+        );
+        assert_eq!(regions[2].volumes[0].len(), 2);
+
+        assert!(regions[0].contains([5.0, 50.0, 0.0]));
+        assert!(!regions[0].contains([-5.0, 50.0, 0.0]));
+        assert!(regions[1].contains([-5.0, 50.0, 0.0]));
+        assert!(regions[2].contains([-5.0, 5.0, 0.0]));
+        assert!(!regions[2].contains([-5.0, 15.0, 0.0]));
+    }
+
+    #[test]
+    fn region_type_test() {
+        assert_eq!(RegionType::from_name("WTN__01521"), RegionType::Water);
+        assert_eq!(RegionType::from_name("LAN_ZONE"), RegionType::Lava);
+        assert_eq!(RegionType::from_name("DRP_ZONE"), RegionType::PvP);
+        assert_eq!(RegionType::from_name("drntp00025"), RegionType::Zoneline);
+        assert_eq!(RegionType::from_name("SLN_ZONE"), RegionType::Slippery);
+        assert_eq!(RegionType::from_name("DRN_ZONE"), RegionType::Normal);
+        assert_eq!(RegionType::from_name(""), RegionType::Normal);
+    }
+
+    #[test]
+    fn regions_errors_test() {
+        let mut builder = WldBuilder::new();
+        builder.add_fragment(BSP_TREE, 0, &tree());
+        builder.add_fragment(REGION_FLAG, 0, &region_flag(&[3], "WT"));
+        let data = builder.build();
+        assert!(matches!(
+            Wld::parse(&data).unwrap().regions(),
+            Err(ArchiveError::Parse(_))
+        ));
+
+        // the root's left child pointing back at the root
+        let mut cycle = tree();
+        cycle[4 + 20..4 + 24].copy_from_slice(&1u32.to_le_bytes());
+        let mut builder = WldBuilder::new();
+        builder.add_fragment(BSP_TREE, 0, &cycle);
+        let data = builder.build();
+        assert!(matches!(
+            Wld::parse(&data).unwrap().regions(),
+            Err(ArchiveError::Parse(_))
+        ));
+
+        // a leaf with a region number far past anything the tree could hold
+        let mut huge = tree();
+        huge[4 + 28 + 16..4 + 28 + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut builder = WldBuilder::new();
+        builder.add_fragment(BSP_TREE, 0, &huge);
+        let data = builder.build();
+        assert!(matches!(
+            Wld::parse(&data).unwrap().regions(),
+            Err(ArchiveError::Parse(_))
+        ));
+
+        let tree = tree();
+        for len in 0..tree.len() {
+            let mut builder = WldBuilder::new();
+            builder.add_fragment(BSP_TREE, 0, &tree[..len]);
+            let data = builder.build();
+            assert!(Wld::parse(&data).unwrap().regions().is_err());
+        }
+
+        let data = WldBuilder::new().build();
+        assert!(Wld::parse(&data).unwrap().regions().unwrap().is_empty());
+    }
+}
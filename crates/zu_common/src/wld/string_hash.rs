@@ -0,0 +1,34 @@
+//! The WLD string hash
+//!
+//! Names are stored together in one block of null terminated strings that's obfuscated by
+//! XORing it with a repeating eight byte key.
+
+/// The key the string hash is XORed with
+const STRING_HASH_KEY: [u8; 8] = [0x95, 0x3A, 0xC5, 0x2A, 0x95, 0x7A, 0x95, 0x6A];
+
+/// Decode an encoded string hash in place
+/// The cipher is its own inverse so this also encodes a decoded string hash.
+pub fn decode_string_hash(data: &mut [u8]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= STRING_HASH_KEY[i % STRING_HASH_KEY.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_string_hash_test() {
+        let mut data = [
+            0xC1, 0x68, 0x80, 0x6F, 0xCA, 0x3E, 0xD8, 0x39, 0xC5, 0x68, 0x8C, 0x7E, 0xD0, 0x3E,
+            0xD0, 0x2C, 0x95,
+        ];
+        decode_string_hash(&mut data);
+        assert_eq!(&data, b"TREE_DMSPRITEDEF\0");
+
+        decode_string_hash(&mut data);
+        assert_eq!(data[0], 0xC1);
+        assert_eq!(data[16], 0x95);
+    }
+}
@@ -0,0 +1,176 @@
+//! Helpers that build WLD files for tests
+
+use super::string_hash::decode_string_hash;
+use super::{WLD_MAGIC, WLD_VERSION_NEW};
+use bytes::{BufMut, BytesMut};
+
+/// Builds a WLD file from strings and raw fragment bodies
+pub struct WldBuilder {
+    version: u32,
+    region_count: u32,
+    strings: Vec<u8>,
+    string_count: u32,
+    fragments: BytesMut,
+    fragment_count: u32,
+}
+
+impl WldBuilder {
+    pub fn new() -> Self {
+        WldBuilder {
+            version: WLD_VERSION_NEW,
+            region_count: 0,
+            // offset 0 is reserved so every name has a negative reference
+            strings: vec![0],
+            string_count: 0,
+            fragments: BytesMut::new(),
+            fragment_count: 0,
+        }
+    }
+
+    /// Add a string to the string hash returning its name reference
+    pub fn add_string(&mut self, name: &str) -> i32 {
+        let offset = self.strings.len() as i32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_count += 1;
+        -offset
+    }
+
+    /// Add a fragment returning the reference other fragments use for it
+    pub fn add_fragment(&mut self, kind: u32, name_ref: i32, body: &[u8]) -> i32 {
+        self.fragments.put_u32_le(body.len() as u32 + 4);
+        self.fragments.put_u32_le(kind);
+        self.fragments.put_i32_le(name_ref);
+        self.fragments.put(body);
+        self.fragment_count += 1;
+        self.fragment_count as i32
+    }
+
+    /// Add a material textured with a bitmap returning its reference
+    /// Adds the bitmap name, sprite definition and sprite the material refers to.
+    pub fn add_material(&mut self, name: &str, texture: &str) -> i32 {
+        let mut bitmap = BytesMut::new();
+        let mut encoded = texture.as_bytes().to_vec();
+        encoded.push(0);
+        decode_string_hash(&mut encoded);
+        bitmap.put_u32_le(1);
+        bitmap.put_u16_le(encoded.len() as u16);
+        bitmap.put(&encoded[..]);
+        let bitmap = self.add_fragment(0x03, 0, &bitmap);
+
+        let mut sprite_def = BytesMut::new();
+        sprite_def.put_u32_le(0);
+        sprite_def.put_u32_le(1);
+        sprite_def.put_i32_le(bitmap);
+        let sprite_def = self.add_fragment(0x04, 0, &sprite_def);
+
+        let mut sprite = BytesMut::new();
+        sprite.put_i32_le(sprite_def);
+        sprite.put_u32_le(0);
+        let sprite = self.add_fragment(0x05, 0, &sprite);
+
+        let mut material = BytesMut::new();
+        material.put_u32_le(0);
+        material.put_u32_le(1);
+        material.put_u32_le(0);
+        material.put_f32_le(1.0);
+        material.put_f32_le(1.0);
+        material.put_i32_le(sprite);
+        let name = self.add_string(name);
+        self.add_fragment(0x30, name, &material)
+    }
+
+    /// Add a material list and a named mesh using it returning the mesh's reference
+    /// Vertices are scaled by 1/2^8 with a texture coordinate of their x and y, a normal
+    /// pointing up and the color (0x10, 0x20, 0x30). Polygons are flags, vertex indices and
+    /// an index into `materials`.
+    pub fn add_mesh(
+        &mut self,
+        name: &str,
+        materials: &[i32],
+        vertices: &[[i16; 3]],
+        polygons: &[(u16, [u16; 3], u16)],
+    ) -> i32 {
+        let mut list = BytesMut::new();
+        list.put_u32_le(0);
+        list.put_u32_le(materials.len() as u32);
+        for material in materials {
+            list.put_i32_le(*material);
+        }
+        let list = self.add_fragment(0x31, 0, &list);
+
+        // materials are assigned to runs of polygons
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for (_, _, material) in polygons {
+            match runs.last_mut() {
+                Some((count, m)) if m == material => *count += 1,
+                _ => runs.push((1, *material)),
+            }
+        }
+
+        let mut mesh = BytesMut::new();
+        mesh.put_u32_le(0);
+        mesh.put_i32_le(list);
+        // animation and two unknown references, then the center, bounds and other unknowns
+        mesh.put_bytes(0, 12);
+        mesh.put_bytes(0, 52);
+        for count in [
+            vertices.len(),
+            vertices.len(),
+            vertices.len(),
+            vertices.len(),
+            polygons.len(),
+            0,
+            runs.len(),
+            0,
+            0,
+            8,
+        ] {
+            mesh.put_u16_le(count as u16);
+        }
+        for v in vertices {
+            for c in v {
+                mesh.put_i16_le(*c);
+            }
+        }
+        for v in vertices {
+            mesh.put_i32_le(v[0] as i32);
+            mesh.put_i32_le(v[1] as i32);
+        }
+        for _ in vertices {
+            mesh.put(&[0, 0, 127][..]);
+        }
+        for _ in vertices {
+            mesh.put(&[0x30, 0x20, 0x10, 0xff][..]);
+        }
+        for (flags, indices, _) in polygons {
+            mesh.put_u16_le(*flags);
+            for i in indices {
+                mesh.put_u16_le(*i);
+            }
+        }
+        for (count, material) in runs {
+            mesh.put_u16_le(count);
+            mesh.put_u16_le(material);
+        }
+        let name = self.add_string(name);
+        self.add_fragment(0x36, name, &mesh)
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut strings = self.strings.clone();
+        decode_string_hash(&mut strings);
+
+        let mut data = BytesMut::new();
+        data.put_u32_le(WLD_MAGIC);
+        data.put_u32_le(self.version);
+        data.put_u32_le(self.fragment_count);
+        data.put_u32_le(self.region_count);
+        data.put_u32_le(0);
+        data.put_u32_le(strings.len() as u32);
+        data.put_u32_le(self.string_count);
+        data.put(&strings[..]);
+        data.put(&self.fragments[..]);
+        data.to_vec()
+    }
+}
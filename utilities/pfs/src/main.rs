@@ -1,7 +1,15 @@
-use clap::{Parser, Subcommand};
-use std::fs::{create_dir_all, read, read_dir, write};
-use std::path::Path;
+use clap::{Parser, Subcommand, ValueEnum};
+use rayon::{prelude::*, ThreadPoolBuilder};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{create_dir_all, metadata, read, read_dir, write, File};
+use std::io::{stdin, stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use zu_common::archive::prelude::*;
+use zu_common::export::{png_name, to_gltf, to_mtl, to_obj};
+use zu_common::texture::decode_texture;
+use zu_common::wld::Wld;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -9,6 +17,10 @@ use zu_common::archive::prelude::*;
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    #[clap(long, global = true, value_parser)]
+    /// Show what a command would change without saving the archive
+    dry_run: bool,
 }
 
 #[derive(Subcommand)]
@@ -22,6 +34,34 @@ enum Commands {
         #[clap(value_parser)]
         /// Files to add to the archive
         files: Vec<String>,
+
+        #[clap(short, long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        /// Compression level from 0 (none) to 9 (best)
+        level: Option<u32>,
+    },
+    /// Write files from the archive to stdout
+    Cat {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(value_parser, required = true)]
+        /// Files to write, one after another in the order given
+        files: Vec<String>,
+    },
+    /// Copy a file inside the archive
+    Copy {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with
+        archive: String,
+
+        #[clap(value_parser)]
+        /// File in the archive to copy
+        src: String,
+
+        #[clap(value_parser)]
+        /// Name of the copy
+        dst: String,
     },
     /// Delete files from the archive
     Delete {
@@ -30,13 +70,56 @@ enum Commands {
         archive: String,
 
         #[clap(value_parser)]
-        /// Files to delete from the archive
+        /// Files to delete from the archive, glob patterns such as *.bmp are matched against
+        /// the files in the archive
         files: Vec<String>,
     },
+    /// Show which files differ between two archives
+    Diff {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to compare from, - reads it from stdin
+        archive: String,
+
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to compare to
+        other: String,
+    },
+    /// Export a zone's geometry as a binary glTF along with its textures as PNGs
+    ExportGltf {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(value_parser)]
+        /// Name of the zone, such as qeynos2, or of the WLD file in the archive
+        zone: String,
+
+        #[clap(value_parser)]
+        /// Path of the .glb to write, the textures are written next to it
+        output: String,
+    },
+    /// Export a zone's geometry as a Wavefront OBJ and MTL along with its textures
+    ExportObj {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(value_parser)]
+        /// Name of the zone, such as qeynos2, or of the WLD file in the archive
+        zone: String,
+
+        #[clap(value_parser)]
+        /// Path of the OBJ to write, the MTL and textures are written next to it
+        output: String,
+
+        #[clap(long, value_parser)]
+        /// Overwrite textures that already exist next to the OBJ
+        force: bool,
+    },
     /// Extract files from the archive
     Extract {
         #[clap(value_parser)]
-        /// Path to the EverQuest archive to work with
+        /// Path to the EverQuest archive to work with, - reads it from stdin
         archive: String,
 
         #[clap(short, long, value_parser)]
@@ -44,18 +127,78 @@ enum Commands {
         output_dir: Option<String>,
 
         #[clap(short, long, value_parser)]
-        /// Files to extract from the archive
+        /// Files to extract from the archive, glob patterns such as *.wld are matched against
+        /// the files in the archive
         files: Option<Vec<String>>,
+
+        #[clap(short, long, default_value_t = 0, value_parser)]
+        /// Number of files to extract at once, 0 uses every core
+        jobs: usize,
+
+        #[clap(long, value_parser)]
+        /// Overwrite files that already exist in the output directory
+        force: bool,
+
+        #[clap(long, value_parser)]
+        /// Only extract if the archive's footer date is after this many seconds since the
+        /// Unix epoch, archives without a footer are skipped
+        newer_than: Option<u32>,
+    },
+    /// Report structural problems with the archive
+    Health {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+    },
+    /// Show a summary of the archive's size
+    Info {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(short, long, default_value_t = 10, value_parser)]
+        /// Number of the largest files to show
+        top: usize,
+
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        /// How to print the summary
+        format: OutputFormat,
     },
     /// List files in the archive
     List {
         #[clap(value_parser)]
-        /// Path to the EverQuest archive to work with
+        /// Path to the EverQuest archive to work with, - reads it from stdin
         archive: String,
 
         #[clap(default_value_t = String::from(".*"), value_parser)]
         /// Regex to search for files by
         search_regex: String,
+
+        #[clap(short, long, value_parser)]
+        /// Match the regex regardless of case
+        ignore_case: bool,
+
+        #[clap(short, long, value_parser)]
+        /// The regex must match the whole file name
+        exact: bool,
+
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        /// How to print the files
+        format: OutputFormat,
+    },
+    /// Copy every file from one archive into another
+    Merge {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to copy files into
+        archive: String,
+
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to copy files from
+        source: String,
+
+        #[clap(short, long, value_parser)]
+        /// Keep files already in the archive instead of replacing them
+        keep_existing: bool,
     },
     /// Pack all files in a directory into an archive
     Pack {
@@ -66,93 +209,567 @@ enum Commands {
         #[clap(value_parser)]
         /// Input directory to pack files from
         input_dir: String,
+
+        #[clap(short, long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        /// Compression level from 0 (none) to 9 (best)
+        level: Option<u32>,
+
+        #[clap(long, value_parser)]
+        /// Compress files straight into the archive instead of holding them all in memory,
+        /// files with identical contents aren't shared and names that only differ in case fail
+        stream: bool,
+    },
+    /// Write a copy of the archive with its files compressed again using new settings
+    Recompress {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(value_parser)]
+        /// Path of the recompressed archive to write
+        output: String,
+
+        #[clap(short, long, value_parser = clap::value_parser!(u32).range(0..=9))]
+        /// Compression level from 0 (none) to 9 (best)
+        level: Option<u32>,
+
+        #[clap(short, long, value_parser)]
+        /// Size files are split into blocks of, 8192 is what the client writes
+        block_size: Option<usize>,
+    },
+    /// Rename a file inside the archive
+    Rename {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with
+        archive: String,
+
+        #[clap(value_parser)]
+        /// File in the archive to rename
+        old: String,
+
+        #[clap(value_parser)]
+        /// New name of the file
+        new: String,
+    },
+    /// Show size statistics for the archive
+    Stats {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
+
+        #[clap(short, long, default_value_t = 10, value_parser)]
+        /// Number of the largest files to show
+        top: usize,
     },
     /// Unpack all files in an archive into a directory
     Unpack {
         #[clap(value_parser)]
-        /// Path to the EverQuest archive to work with
+        /// Path to the EverQuest archive to work with, - reads it from stdin
         archive: String,
 
         #[clap(value_parser)]
         /// Output directory to unpack files to
         output_dir: String,
+
+        #[clap(short, long, default_value_t = 0, value_parser)]
+        /// Number of files to extract at once, 0 uses every core
+        jobs: usize,
+
+        #[clap(long, value_parser)]
+        /// Overwrite files that already exist in the output directory
+        force: bool,
+    },
+    /// Check that every file in the archive inflates correctly
+    Verify {
+        #[clap(value_parser)]
+        /// Path to the EverQuest archive to work with, - reads it from stdin
+        archive: String,
     },
 }
 
-fn main() -> Result<(), ArchiveError> {
+/// How listings and summaries are printed
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable lines
+    Text,
+    /// Pretty printed JSON
+    Json,
+}
+
+/// An archive error along with the archive it happened on
+struct CliError {
+    archive: String,
+    error: ArchiveError,
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "error: unable to process {}: {}",
+            self.archive, self.error
+        )?;
+        let mut source = self.error.source();
+        while let Some(err) = source {
+            write!(f, ": {}", err)?;
+            source = err.source();
+        }
+
+        match self.error {
+            ArchiveError::Parse(_)
+            | ArchiveError::ParseAt { .. }
+            | ArchiveError::WrongVersion { .. }
+            | ArchiveError::Utf8(_) => {
+                write!(
+                    f,
+                    "\nhint: {} may not be an EverQuest archive or it may be corrupt",
+                    self.archive
+                )
+            }
+            ArchiveError::NotPfs => write!(
+                f,
+                "\nhint: {} is not an EverQuest archive, check the right file was given",
+                self.archive
+            ),
+            ArchiveError::Decompression => write!(
+                f,
+                "\nhint: run pfs verify {} to find which files are corrupt",
+                self.archive
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn main() {
+    // warnings about repaired archives are shown by default, RUST_LOG=zu_common=trace shows
+    // how an archive is being parsed
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let args = Cli::parse();
 
-    match &args.command {
-        Commands::Add { archive, files } => {
-            add_to_archive(archive, files)?;
+    if let Err(err) = run(&args.command, args.dry_run) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn run(command: &Commands, dry_run: bool) -> Result<(), CliError> {
+    let (archive, result) = match command {
+        Commands::Add {
+            archive,
+            files,
+            level,
+        } => (archive, add_to_archive(archive, files, *level, dry_run)),
+        Commands::Cat { archive, files } => (archive, cat_files(archive, files)),
+        Commands::Copy { archive, src, dst } => {
+            (archive, copy_in_archive(archive, src, dst, dry_run))
         }
         Commands::Delete { archive, files } => {
-            delete_from_archive(archive, files)?;
+            (archive, delete_from_archive(archive, files, dry_run))
         }
+        Commands::Diff { archive, other } => (archive, diff_archives(archive, other)),
+        Commands::ExportGltf {
+            archive,
+            zone,
+            output,
+        } => (archive, export_gltf(archive, zone, output)),
+        Commands::ExportObj {
+            archive,
+            zone,
+            output,
+            force,
+        } => (archive, export_obj(archive, zone, output, *force)),
         Commands::Extract {
             archive,
             output_dir,
             files,
-        } => {
-            extract_from_archive(archive, output_dir, files)?;
-        }
+            jobs,
+            force,
+            newer_than,
+        } => (
+            archive,
+            extract_from_archive(archive, output_dir, files, *jobs, *force, *newer_than),
+        ),
+        Commands::Health { archive } => (archive, report_health(archive)),
+        Commands::Info {
+            archive,
+            top,
+            format,
+        } => (archive, show_info(archive, *top, *format)),
         Commands::List {
             archive,
             search_regex,
+            ignore_case,
+            exact,
+            format,
         } => {
-            list_archive(archive, search_regex)?;
+            let options = SearchOptions {
+                case_insensitive: *ignore_case,
+                full_match: *exact,
+            };
+            (
+                archive,
+                list_archive(archive, search_regex, &options, *format),
+            )
         }
-        Commands::Pack { archive, input_dir } => {
-            pack_directory(archive, input_dir)?;
+        Commands::Merge {
+            archive,
+            source,
+            keep_existing,
+        } => (
+            archive,
+            merge_archives(archive, source, *keep_existing, dry_run),
+        ),
+        Commands::Pack {
+            archive,
+            input_dir,
+            level,
+            stream,
+        } => (
+            archive,
+            pack_directory(archive, input_dir, *level, *stream, dry_run),
+        ),
+        Commands::Recompress {
+            archive,
+            output,
+            level,
+            block_size,
+        } => (
+            archive,
+            recompress_archive(archive, output, *level, *block_size, dry_run),
+        ),
+        Commands::Rename { archive, old, new } => {
+            (archive, rename_in_archive(archive, old, new, dry_run))
         }
+        Commands::Stats { archive, top } => (archive, show_stats(archive, *top)),
         Commands::Unpack {
             archive,
             output_dir,
-        } => {
-            unpack_to_directory(archive, output_dir)?;
-        }
+            jobs,
+            force,
+        } => (
+            archive,
+            unpack_to_directory(archive, output_dir, *jobs, *force),
+        ),
+        Commands::Verify { archive } => (archive, verify_archive(archive)),
+    };
+
+    result.map_err(|error| CliError {
+        archive: archive.clone(),
+        error,
+    })
+}
+
+/// Open an archive for reading, reading all of stdin when the path is -
+/// An archive read from stdin has nowhere to be saved back to so this is only for reading.
+fn open_readable(filename: &str) -> Result<ReadableArchive, ArchiveError> {
+    let mut archive = ReadableArchive::new();
+    if filename == "-" {
+        archive.open_from_reader(stdin().lock())?;
+    } else {
+        archive.open_file(filename)?;
+    }
+
+    Ok(archive)
+}
+
+/// Save an edited archive back to its file unless this is a dry run
+fn save_archive<A>(archive: &A, filename: &str, dry_run: bool) -> Result<(), ArchiveError>
+where
+    A: IArchive + IWritableArchive,
+{
+    if dry_run {
+        println!(
+            "dry run: {} would hold {} files, not saving",
+            filename,
+            archive.len()
+        );
+        return Ok(());
     }
 
+    println!("saving...");
+    let progress = Progress::new("saving", archive.len());
+    archive.save_to_file_with_progress(filename, |done, _| progress.set(done))?;
+    progress.finish();
+    println!("saved to {}", filename);
     Ok(())
 }
 
-fn add_to_archive(filename: &str, files: &Vec<String>) -> Result<(), ArchiveError> {
-    let mut archive = ReadWriteArchive::new();
+/// Change an archive that already exists and save it back unless this is a dry run
+/// The archive is replaced only once the new one is completely written.
+fn edit_archive<F>(filename: &str, dry_run: bool, f: F) -> Result<(), ArchiveError>
+where
+    F: FnOnce(&mut ReadWriteArchive) -> Result<(), ArchiveError>,
+{
+    if dry_run {
+        let mut archive = open_edit(filename)?;
+        f(&mut archive)?;
+        return save_archive(&archive, filename, dry_run);
+    }
+
+    edit_file(filename, f)?;
+    println!("saved to {}", filename);
+    Ok(())
+}
+
+/// A progress bar drawn on stderr for operations over many files
+/// Only drawn when built with the `indicatif` feature, otherwise every method does nothing.
+struct Progress {
+    #[cfg(feature = "indicatif")]
+    bar: indicatif::ProgressBar,
+}
+
+impl Progress {
+    #[cfg(feature = "indicatif")]
+    fn new(message: &'static str, total: usize) -> Self {
+        let style = indicatif::ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len}")
+            .unwrap_or_else(|_| indicatif::ProgressStyle::default_bar())
+            .progress_chars("=> ");
+        let bar = indicatif::ProgressBar::new(total as u64)
+            .with_style(style)
+            .with_message(message);
+        Progress { bar }
+    }
+
+    #[cfg(not(feature = "indicatif"))]
+    fn new(_message: &'static str, _total: usize) -> Self {
+        Progress {}
+    }
+
+    /// Set the number of files done so far
+    fn set(&self, _done: usize) {
+        #[cfg(feature = "indicatif")]
+        self.bar.set_position(_done as u64);
+    }
 
-    match archive.open_file(filename) {
-        Ok(_) => println!("{} opened", filename),
-        Err(_) => println!("creating a blank archive for {}", filename),
+    /// Count one more file as done, safe to call from several threads
+    fn inc(&self) {
+        #[cfg(feature = "indicatif")]
+        self.bar.inc(1);
     }
 
+    /// Print a line to stdout without it being drawn over by the bar
+    fn println(&self, line: &str) {
+        #[cfg(feature = "indicatif")]
+        self.bar.suspend(|| println!("{}", line));
+        #[cfg(not(feature = "indicatif"))]
+        println!("{}", line);
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "indicatif")]
+        self.bar.finish_and_clear();
+    }
+}
+
+fn add_to_archive(
+    filename: &str,
+    files: &Vec<String>,
+    level: Option<u32>,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    let add = |archive: &mut ReadWriteArchive| {
+        if let Some(level) = level {
+            archive.set_compression_level(level);
+        }
+
+        for file in files {
+            let path = Path::new(file);
+            let fname = path.file_name();
+
+            if let Some(insert_fname) = fname {
+                let name = insert_fname.to_str().unwrap();
+                println!("adding {} to {}", name, filename);
+                let data = read(file)?;
+                archive.set(name, data)?;
+            }
+        }
+
+        Ok(())
+    };
+
+    if Path::new(filename).exists() {
+        println!("{} opened", filename);
+        return edit_archive(filename, dry_run, add);
+    }
+
+    println!("creating a blank archive for {}", filename);
+    let mut archive = ReadWriteArchive::new();
+    add(&mut archive)?;
+    save_archive(&archive, filename, dry_run)
+}
+
+fn cat_files(filename: &str, files: &[String]) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let mut out = stdout().lock();
+    let mut data = Vec::new();
     for file in files {
-        let path = Path::new(file);
-        let fname = path.file_name();
-
-        if let Some(insert_fname) = fname {
-            let name = insert_fname.to_str().unwrap();
-            println!("adding {} to {}", name, filename);
-            let data = read(file)?;
-            archive.set(name, data)?;
+        archive.get_into(file, &mut data)?;
+        out.write_all(&data)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+fn copy_in_archive(
+    filename: &str,
+    src: &str,
+    dst: &str,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    edit_archive(filename, dry_run, |archive| {
+        println!("copying {} to {}", src, dst);
+        archive.copy(src, dst)
+    })
+}
+
+fn delete_from_archive(
+    filename: &str,
+    files: &[String],
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    edit_archive(filename, dry_run, |archive| {
+        for file in expand_globs(archive, files)? {
+            println!("removing {} from {}", file, filename);
+            archive.remove(&file)?;
         }
+
+        Ok(())
+    })
+}
+
+fn diff_archives(filename: &str, other: &str) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let mut other_archive = ReadableArchive::new();
+    other_archive.open_file(other)?;
+
+    let changes = diff(&archive, &other_archive)?;
+    for file in &changes.only_in_a {
+        println!("- {}", file);
+    }
+    for file in &changes.only_in_b {
+        println!("+ {}", file);
+    }
+    for file in &changes.changed {
+        println!("M {}", file);
+    }
+
+    if changes.is_empty() {
+        println!("{} and {} have the same files", filename, other);
     }
 
-    println!("saving...");
-    archive.save_to_file(filename)?;
-    println!("saved to {}", filename);
     Ok(())
 }
 
-fn delete_from_archive(filename: &str, files: &Vec<String>) -> Result<(), ArchiveError> {
-    let mut archive = ReadWriteArchive::new();
-    archive.open_file(filename)?;
+/// Get a zone's WLD from an archive by the zone's name or the WLD's name
+fn read_zone_wld(archive: &ReadableArchive, zone: &str) -> Result<(String, Vec<u8>), ArchiveError> {
+    let wld_name = if zone.to_lowercase().ends_with(".wld") {
+        zone.to_string()
+    } else {
+        format!("{}.wld", zone)
+    };
 
-    for file in files {
-        archive.remove(file)?;
+    let data = archive.get(&wld_name)?;
+    Ok((wld_name, data))
+}
+
+fn export_gltf(filename: &str, zone: &str, output: &str) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let (wld_name, data) = read_zone_wld(&archive, zone)?;
+    let meshes = Wld::parse(&data)?.meshes()?;
+
+    let output = Path::new(output);
+    let mut writer = BufWriter::new(File::create(output)?);
+    to_gltf(&meshes, &mut writer)?;
+    writer.flush()?;
+
+    println!(
+        "wrote {} meshes from {} to {}",
+        meshes.len(),
+        wld_name,
+        output.display()
+    );
+
+    let mut textures: Vec<&String> = meshes
+        .iter()
+        .flat_map(|m| &m.materials)
+        .filter_map(|m| m.texture.as_ref())
+        .collect();
+    textures.sort();
+    textures.dedup();
+
+    // glTF viewers can't read BMP or DDS so textures are converted
+    let output_dir = output.parent().map(|dir| dir.to_string_lossy().to_string());
+    for texture in textures {
+        let image = archive.get(texture).and_then(|data| decode_texture(&data));
+        let mut image = match image {
+            Ok(image) => image,
+            Err(err) => {
+                println!(
+                    "unable to convert {} in archive {}: {}",
+                    texture, filename, err
+                );
+                continue;
+            }
+        };
+        image.mask_transparent_color();
+
+        let path = get_path(&png_name(texture), &output_dir)?;
+        write(&path, image.to_png()?)?;
+        println!("wrote {} to {}", texture, path.display());
+    }
+
+    Ok(())
+}
+
+fn export_obj(filename: &str, zone: &str, output: &str, force: bool) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let (wld_name, data) = read_zone_wld(&archive, zone)?;
+    let mesh = Wld::parse(&data)?.zone_mesh()?;
+
+    let output = Path::new(output);
+    let mtl = output.with_extension("mtl");
+    let mut obj_writer = BufWriter::new(File::create(output)?);
+    if let Some(name) = mtl.file_name() {
+        writeln!(obj_writer, "mtllib {}", name.to_string_lossy())?;
+    }
+    to_obj(&mesh, &mut obj_writer)?;
+    obj_writer.flush()?;
+
+    let mut mtl_writer = BufWriter::new(File::create(&mtl)?);
+    to_mtl(&mesh, &mut mtl_writer)?;
+    mtl_writer.flush()?;
+
+    println!(
+        "wrote {} triangles from {} to {}",
+        mesh.triangles.len(),
+        wld_name,
+        output.display()
+    );
+
+    // textures go next to the MTL which refers to them by name
+    let mut textures: Vec<&String> = mesh
+        .materials
+        .iter()
+        .filter_map(|m| m.texture.as_ref())
+        .collect();
+    textures.sort();
+    textures.dedup();
+
+    let output_dir = output.parent().map(|dir| dir.to_string_lossy().to_string());
+    let mut data = Vec::new();
+    for texture in textures {
+        println!(
+            "{}",
+            extract_file(&archive, filename, &output_dir, texture, force, &mut data)
+        );
     }
 
-    println!("saving...");
-    archive.save_to_file(filename)?;
-    println!("saved to {}", filename);
     Ok(())
 }
 
@@ -160,61 +777,214 @@ fn extract_from_archive(
     filename: &str,
     output_dir: &Option<String>,
     files: &Option<Vec<String>>,
+    jobs: usize,
+    force: bool,
+    newer_than: Option<u32>,
 ) -> Result<(), ArchiveError> {
-    let mut archive = ReadableArchive::new();
-    archive.open_file(filename)?;
+    let archive = open_readable(filename)?;
+
+    if let Some(date) = newer_than {
+        if !archive.is_newer_than(date) {
+            match archive.footer_date() {
+                Some(footer) => println!(
+                    "{} has a footer date of {} which isn't newer than {}, skipping",
+                    filename, footer, date
+                ),
+                None => println!("{} has no footer date, skipping", filename),
+            }
+            return Ok(());
+        }
+    }
 
     if let Some(output_dir) = output_dir {
         create_dir_all(output_dir)?;
     }
 
     if let Some(files) = files {
-        extract_files(&archive, filename, output_dir, files);
+        let files = expand_globs(&archive, files)?;
+        extract_files(&archive, filename, output_dir, &files, jobs, force);
     } else {
         let files = archive.search(".*")?;
-        extract_files(&archive, filename, output_dir, &files);
+        extract_files(&archive, filename, output_dir, &files, jobs, force);
     }
 
     Ok(())
 }
 
+/// Below this many files it isn't worth starting a thread pool
+const PARALLEL_EXTRACT_MIN_FILES: usize = 16;
+
 fn extract_files(
     archive: &ReadableArchive,
     filename: &str,
     output_dir: &Option<String>,
     files: &Vec<String>,
+    jobs: usize,
+    force: bool,
 ) {
-    for file in files {
-        let data = match archive.get(file) {
-            Ok(v) => v,
-            Err(err) => {
-                println!("unable to get {} in archive {}: {}", file, filename, err);
-                continue;
+    let progress = Progress::new("extracting", files.len());
+    if jobs != 1 && files.len() >= PARALLEL_EXTRACT_MIN_FILES {
+        match ThreadPoolBuilder::new().num_threads(jobs).build() {
+            Ok(pool) => {
+                // collected so the output stays in order
+                let messages: Vec<String> = pool.install(|| {
+                    files
+                        .par_iter()
+                        .map_init(Vec::new, |data, file| {
+                            let message =
+                                extract_file(archive, filename, output_dir, file, force, data);
+                            progress.inc();
+                            message
+                        })
+                        .collect()
+                });
+
+                progress.finish();
+                for message in messages {
+                    println!("{}", message);
+                }
+                return;
             }
-        };
+            Err(err) => println!(
+                "unable to start {} jobs, extracting one at a time: {}",
+                jobs, err
+            ),
+        }
+    }
+
+    let mut data = Vec::new();
+    for file in files {
+        progress.println(&extract_file(
+            archive, filename, output_dir, file, force, &mut data,
+        ));
+        progress.inc();
+    }
+    progress.finish();
+}
+
+fn extract_file(
+    archive: &ReadableArchive,
+    filename: &str,
+    output_dir: &Option<String>,
+    file: &str,
+    force: bool,
+    data: &mut Vec<u8>,
+) -> String {
+    let path = match get_path(file, output_dir) {
+        Ok(path) => path,
+        Err(err) => return format!("unable to extract {}: {}", file, err),
+    };
+
+    if !force && path.exists() {
+        return format!(
+            "skipping {}: {} already exists, use --force to overwrite it",
+            file,
+            path.display()
+        );
+    }
+
+    if let Err(err) = archive.get_into(file, data) {
+        return format!("unable to get {} in archive {}: {}", file, filename, err);
+    }
 
-        let path = get_path(file, output_dir);
-        let len = data.len();
-        match write(&path, data) {
-            Ok(_) => println!("wrote {} bytes to {}", len, path),
-            Err(err) => println!("unable to write {} to {}: {}", file, path, err),
+    match write(&path, &data) {
+        Ok(_) => format!("wrote {} bytes to {}", data.len(), path.display()),
+        Err(err) => format!("unable to write {} to {}: {}", file, path.display(), err),
+    }
+}
+
+/// Replace every glob pattern with the names of the files in the archive it matches
+/// Arguments without wildcards are kept as literal names.
+fn expand_globs<A>(archive: &A, files: &[String]) -> Result<Vec<String>, ArchiveError>
+where
+    A: IReadableArchive,
+{
+    let mut ret = Vec::new();
+    for file in files {
+        if is_glob(file) {
+            ret.extend(Searcher::glob(file)?.matches(archive));
+        } else {
+            ret.push(file.clone());
         }
     }
+
+    Ok(ret)
+}
+
+fn get_path(filename: &str, output_dir: &Option<String>) -> Result<PathBuf, ArchiveError> {
+    prepare_extract_path(Path::new(output_dir.as_deref().unwrap_or("")), filename)
 }
 
-fn get_path(filename: &str, output_dir: &Option<String>) -> String {
-    if let Some(dir) = output_dir {
-        format!("{}/{}", dir, filename)
+fn report_health(filename: &str) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let report = archive.health_report();
+    println!("health of {}:", filename);
+    println!("files: {}", report.file_count);
+    println!("unresolved entries: {}", report.unresolved_entries);
+    println!("duplicate content: {}", report.duplicate_content);
+    println!("incompressible files: {}", report.incompressible_files);
+    println!("tiny files: {}", report.tiny_files);
+    println!("stored files: {}", report.stored_files);
+    println!("footer: {}", if report.has_footer { "yes" } else { "no" });
+
+    if report.is_healthy() {
+        println!("{} is healthy", filename);
     } else {
-        filename.to_string()
+        println!("{} has problems", filename);
     }
+
+    Ok(())
 }
 
-fn list_archive(filename: &str, search_regex: &str) -> Result<(), ArchiveError> {
-    let mut archive = ReadableArchive::new();
-    archive.open_file(filename)?;
+fn show_info(filename: &str, top: usize, format: OutputFormat) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let summary = archive.summary(top);
+    if format == OutputFormat::Json {
+        return print_json(&summary);
+    }
+
+    println!("info for {}:", filename);
+    print_totals(&summary);
+    println!("compression ratio: {:.1}%", summary.ratio() * 100.0);
+
+    println!("largest {} files:", summary.largest.len());
+    for entry in &summary.largest {
+        println!(
+            "{} {} {}",
+            entry.uncompressed_size, entry.compressed_size, entry.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the file count and sizes shared by `info` and `stats`
+fn print_totals(summary: &ArchiveSummary) {
+    println!("files: {}", summary.file_count);
+    println!("compressed size: {}", summary.compressed_size);
+    println!("uncompressed size: {}", summary.uncompressed_size);
+}
+
+fn list_archive(
+    filename: &str,
+    search_regex: &str,
+    options: &SearchOptions,
+    format: OutputFormat,
+) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let files = archive.search_opts(search_regex, options)?;
+    if format == OutputFormat::Json {
+        let entries: Vec<ArchiveEntry> = archive
+            .entries()
+            .into_iter()
+            .filter(|e| files.binary_search(&e.name).is_ok())
+            .collect();
+        return print_json(&entries);
+    }
 
-    let files = archive.search(search_regex)?;
     println!("files in {} matching {}:", filename, search_regex);
     for file in &files {
         println!("{}", file);
@@ -223,39 +993,209 @@ fn list_archive(filename: &str, search_regex: &str) -> Result<(), ArchiveError>
     Ok(())
 }
 
-fn pack_directory(filename: &str, input_dir: &String) -> Result<(), ArchiveError> {
-    let mut archive = WritableArchive::new();
-    let paths = read_dir(input_dir)?;
+fn print_json<T: serde::Serialize + ?Sized>(value: &T) -> Result<(), ArchiveError> {
+    let json = serde_json::to_string_pretty(value).map_err(std::io::Error::from)?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn merge_archives(
+    filename: &str,
+    source: &str,
+    keep_existing: bool,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    let mut src = ReadableArchive::new();
+    src.open_file(source)?;
+
+    let merge = |archive: &mut ReadWriteArchive| {
+        let summary = archive.merge(&src, !keep_existing)?;
+        println!(
+            "merged {} into {}: {} added, {} replaced, {} skipped",
+            source, filename, summary.added, summary.replaced, summary.skipped
+        );
+        Ok(())
+    };
+
+    if Path::new(filename).exists() {
+        println!("{} opened", filename);
+        return edit_archive(filename, dry_run, merge);
+    }
+
+    println!("creating a blank archive for {}", filename);
+    let mut archive = ReadWriteArchive::new();
+    merge(&mut archive)?;
+    save_archive(&archive, filename, dry_run)
+}
+
+fn pack_directory(
+    filename: &str,
+    input_dir: &String,
+    level: Option<u32>,
+    stream: bool,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    let mut files = Vec::new();
+    find_pack_files(Path::new(input_dir), "", &mut files)?;
+
+    if !stream {
+        // a later file replaces an earlier one whose name only differs in case
+        let mut archive = WritableArchive::new();
+        if let Some(level) = level {
+            archive.set_compression_level(level);
+        }
+        for (name, path) in &files {
+            archive.set(name, read(path)?)?;
+        }
+        return save_archive(&archive, filename, dry_run);
+    }
 
-    for path in paths {
+    if dry_run {
+        println!(
+            "dry run: {} would hold {} files, not saving",
+            filename,
+            files.len()
+        );
+        return Ok(());
+    }
+
+    // files are compressed straight into the archive so they're never all held in memory
+    let progress = Progress::new("packing", files.len());
+    pack_file(filename, |packer| {
+        if let Some(level) = level {
+            packer.set_compression_level(level);
+        }
+
+        for (i, (name, path)) in files.iter().enumerate() {
+            packer.add(name, File::open(path)?)?;
+            progress.set(i + 1);
+        }
+        Ok(())
+    })?;
+    progress.finish();
+    println!("saved to {}", filename);
+    Ok(())
+}
+
+fn recompress_archive(
+    filename: &str,
+    output: &str,
+    level: Option<u32>,
+    block_size: Option<usize>,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    let src = open_readable(filename)?;
+
+    let mut settings = ArchiveSettings::default();
+    if let Some(level) = level {
+        settings.compression_level = level;
+    }
+    if let Some(block_size) = block_size {
+        settings.block_size = block_size;
+    }
+
+    let archive = transcode(&src, settings)?;
+    save_archive(&archive, output, dry_run)?;
+    if !dry_run {
+        println!("{} is {} bytes", output, metadata(output)?.len());
+    }
+
+    Ok(())
+}
+
+/// Find every file underneath a directory named by its path relative to the input root
+fn find_pack_files(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), ArchiveError> {
+    for path in read_dir(dir)? {
         let p = path?;
+        let osfname = p.file_name();
+        let name = format!("{}{}", prefix, osfname.to_string_lossy());
 
         match p.file_type() {
             Ok(ty) => {
-                if ty.is_file() {
-                    let data = read(p.path())?;
-                    let osfname = p.file_name();
-                    let fname = osfname.to_string_lossy();
-                    archive.set(&fname, data)?;
+                if ty.is_dir() {
+                    find_pack_files(&p.path(), &format!("{}/", name), files)?;
+                } else if ty.is_file() {
+                    println!("adding {}", name);
+                    files.push((name, p.path()));
                 }
             }
             Err(err) => println!("error packing {}: {}", p.path().to_string_lossy(), err),
         }
     }
 
-    archive.save_to_file(filename)?;
+    Ok(())
+}
+
+fn rename_in_archive(
+    filename: &str,
+    old: &str,
+    new: &str,
+    dry_run: bool,
+) -> Result<(), ArchiveError> {
+    edit_archive(filename, dry_run, |archive| {
+        println!("renaming {} to {}", old, new);
+        archive.rename(old, new)
+    })
+}
+
+fn show_stats(filename: &str, top: usize) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    println!("stats for {}:", filename);
+    print_totals(&archive.summary(0));
+
+    println!("largest {} files by compressed size:", top);
+    for (name, size) in archive.top_by_compressed_size(top) {
+        println!("{} {}", size, name);
+    }
 
     Ok(())
 }
 
-fn unpack_to_directory(filename: &str, output_dir: &String) -> Result<(), ArchiveError> {
-    let mut archive = ReadableArchive::new();
-    archive.open_file(filename)?;
+fn unpack_to_directory(
+    filename: &str,
+    output_dir: &String,
+    jobs: usize,
+    force: bool,
+) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
 
     create_dir_all(output_dir)?;
 
     let files = archive.search(".*")?;
-    extract_files(&archive, filename, &Some(output_dir.to_string()), &files);
+    extract_files(
+        &archive,
+        filename,
+        &Some(output_dir.to_string()),
+        &files,
+        jobs,
+        force,
+    );
 
     Ok(())
 }
+
+fn verify_archive(filename: &str) -> Result<(), ArchiveError> {
+    let archive = open_readable(filename)?;
+
+    let bad = archive.verify()?;
+    if bad.is_empty() {
+        println!("all {} files in {} are ok", archive.len(), filename);
+        return Ok(());
+    }
+
+    for file in &bad {
+        println!("{} is corrupt", file);
+    }
+    println!(
+        "{} of {} files in {} are corrupt",
+        bad.len(),
+        archive.len(),
+        filename
+    );
+    exit(1);
+}
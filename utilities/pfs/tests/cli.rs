@@ -0,0 +1,620 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, metadata, read, remove_dir_all, remove_file, write};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use zu_common::archive::prelude::*;
+
+#[test]
+fn list_non_pfs_file_test() {
+    let path = temp_dir().join("pfs_cli_list_non_pfs_file_test.png");
+    write(&path, b"\x89PNG\r\n\x1a\nnot an archive at all").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("list")
+        .arg(&path)
+        .output()
+        .unwrap();
+    remove_file(&path).unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(path.to_str().unwrap()));
+    assert!(stderr.contains("Not a PFS archive"));
+    assert!(stderr.contains("is not an EverQuest archive"));
+}
+
+#[test]
+fn unpack_parallel_test() {
+    let root = temp_dir().join("pfs_cli_unpack_parallel_test");
+    let input = root.join("input");
+    let output = root.join("output");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&input).unwrap();
+
+    for i in 0..20 {
+        write(
+            input.join(format!("file{}.txt", i)),
+            vec![i as u8; i * 1000],
+        )
+        .unwrap();
+    }
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("pack")
+        .arg(&archive)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("unpack")
+        .arg(&archive)
+        .arg(&output)
+        .arg("--jobs")
+        .arg("4")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for i in 0..20 {
+        let name = format!("file{}.txt", i);
+        assert_eq!(read(output.join(&name)).unwrap(), vec![i as u8; i * 1000]);
+    }
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn verify_test() {
+    let root = temp_dir().join("pfs_cli_verify_test");
+    let input = root.join("input");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&input).unwrap();
+    write(input.join("one.txt"), vec![1u8; 5000]).unwrap();
+    write(input.join("two.txt"), vec![2u8; 5000]).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("pack")
+        .arg(&archive)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let verify = || {
+        Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .arg("verify")
+            .arg(&archive)
+            .output()
+            .unwrap()
+    };
+    assert!(verify().status.success());
+
+    // the first block in the archive belongs to one of the files
+    let mut data = read(&archive).unwrap();
+    let deflate_length = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+    data[20..20 + deflate_length].fill(0xff);
+    write(&archive, data).unwrap();
+
+    let output = verify();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("is corrupt"));
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn extract_nested_path_test() {
+    let root = temp_dir().join("pfs_cli_extract_nested_path_test");
+    let output = root.join("output");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("textures/rock.bmp", b"rock").unwrap();
+    writable.set("legacy\\dirt.bmp", b"dirt").unwrap();
+    writable.set("../escape.txt", b"escape").unwrap();
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("unpack")
+        .arg(&archive)
+        .arg(&output)
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert!(String::from_utf8_lossy(&result.stdout).contains("Unsafe path"));
+
+    assert_eq!(
+        read(output.join("textures").join("rock.bmp")).unwrap(),
+        b"rock"
+    );
+    assert_eq!(
+        read(output.join("legacy").join("dirt.bmp")).unwrap(),
+        b"dirt"
+    );
+    assert!(!root.join("escape.txt").exists());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn pack_recursive_test() {
+    let root = temp_dir().join("pfs_cli_pack_recursive_test");
+    let input = root.join("input");
+    let output = root.join("output");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(input.join("textures").join("stone")).unwrap();
+    write(input.join("zone.wld"), b"zone").unwrap();
+    write(input.join("textures").join("grass.bmp"), b"grass").unwrap();
+    write(
+        input.join("textures").join("stone").join("rock.bmp"),
+        b"rock",
+    )
+    .unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("pack")
+        .arg(&archive)
+        .arg(&input)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let readable = open_read(archive.to_str().unwrap()).unwrap();
+    assert_eq!(
+        readable.search(".*").unwrap(),
+        vec!["textures/grass.bmp", "textures/stone/rock.bmp", "zone.wld"]
+    );
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("unpack")
+        .arg(&archive)
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        read(output.join("textures").join("stone").join("rock.bmp")).unwrap(),
+        b"rock"
+    );
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn pack_stream_test() {
+    let root = temp_dir().join("pfs_cli_pack_stream_test");
+    let input = root.join("input");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&input).unwrap();
+    let contents: Vec<u8> = (0..20000u32).map(|i| (i % 97) as u8).collect();
+    write(input.join("one.wld"), &contents).unwrap();
+    write(input.join("two.wld"), &contents).unwrap();
+
+    let pack = |stream: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pfs"));
+        command.arg("pack").arg(&archive).arg(&input);
+        if stream {
+            command.arg("--stream");
+        }
+        command.output().unwrap()
+    };
+
+    // identical files share their blocks unless streamed
+    assert!(pack(false).status.success());
+    let shared = metadata(&archive).unwrap().len();
+    let readable = open_read(archive.to_str().unwrap()).unwrap();
+    let compressed_size = readable.entries()[0].compressed_size as u64;
+
+    assert!(pack(true).status.success());
+    assert!(metadata(&archive).unwrap().len() >= shared + compressed_size);
+    let readable = open_read(archive.to_str().unwrap()).unwrap();
+    assert_eq!(readable.get("two.wld").unwrap(), contents);
+
+    // names that only differ in case are one file unless streamed
+    write(input.join("ONE.wld"), b"upper").unwrap();
+    assert!(pack(false).status.success());
+    let readable = open_read(archive.to_str().unwrap()).unwrap();
+    assert_eq!(readable.len(), 2);
+    assert!(!pack(true).status.success());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn recompress_test() {
+    let root = temp_dir().join("pfs_cli_recompress_test");
+    let archive = root.join("test.s3d");
+    let output = root.join("recompressed.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    let big: Vec<u8> = (0..100000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut writable = WritableArchive::new();
+    writable.set_compression_level(0);
+    writable.set("big.wld", &big).unwrap();
+    writable.set("small.txt", b"small").unwrap();
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("recompress")
+        .arg(&archive)
+        .arg(&output)
+        .args(["--level", "9", "--block-size", "32768"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(read(&output).unwrap().len() < read(&archive).unwrap().len());
+
+    let original = open_read(archive.to_str().unwrap()).unwrap();
+    let recompressed = open_read(output.to_str().unwrap()).unwrap();
+    assert!(diff(&original, &recompressed).unwrap().is_empty());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn rename_copy_test() {
+    let path = temp_dir().join("pfs_cli_rename_copy_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("one.txt", b"one").unwrap();
+    writable.set("two.txt", b"two").unwrap();
+    writable.save_to_file(archive).unwrap();
+
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert!(run(&["rename", archive, "one.txt", "first.txt"])
+        .status
+        .success());
+    assert!(run(&["copy", archive, "two.txt", "second.txt"])
+        .status
+        .success());
+
+    let output = run(&["copy", archive, "two.txt", "first.txt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+
+    let output = run(&["rename", archive, "missing.txt", "other.txt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("doesn't exist"));
+
+    let readable = open_read(archive).unwrap();
+    assert_eq!(
+        readable.search(".*").unwrap(),
+        vec!["first.txt", "second.txt", "two.txt"]
+    );
+    assert_eq!(readable.get("second.txt").unwrap(), b"two");
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn cat_test() {
+    let path = temp_dir().join("pfs_cli_cat_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("one.txt", b"one\n").unwrap();
+    writable.set("two.bin", [0u8, 1, 2, 255]).unwrap();
+    writable.save_to_file(archive).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["cat", archive, "two.bin", "one.txt"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x00\x01\x02\xffone\n");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["cat", archive, "missing.txt"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn list_stdin_test() {
+    let mut writable = WritableArchive::new();
+    writable.set("piped.wld", b"piped").unwrap();
+    let data = writable.save_to_bytes().unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["list", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&data).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("piped.wld"));
+}
+
+#[test]
+fn dry_run_test() {
+    let path = temp_dir().join("pfs_cli_dry_run_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("one.txt", b"one").unwrap();
+    writable.set("two.txt", b"two").unwrap();
+    writable.save_to_file(archive).unwrap();
+    let before = read(&path).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["delete", archive, "one.txt", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("removing one.txt"));
+    assert!(stdout.contains("would hold 1 files"));
+    assert_eq!(read(&path).unwrap(), before);
+
+    // the edits still run so mistakes are reported
+    let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["--dry-run", "delete", archive, "missing.txt"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(read(&path).unwrap(), before);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn delete_glob_test() {
+    let path = temp_dir().join("pfs_cli_delete_glob_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    for name in [
+        "zone.wld",
+        "textures/rock.bmp",
+        "textures/dirt.bmp",
+        "sky.bmp",
+    ] {
+        writable.set(name, b"data").unwrap();
+    }
+    writable.save_to_file(archive).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .args(["delete", archive, "textures/*.bmp", "zone.wld"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let readable = open_read(archive).unwrap();
+    assert_eq!(readable.search(".*").unwrap(), vec!["sky.bmp"]);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn list_options_test() {
+    let path = temp_dir().join("pfs_cli_list_options_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("Rock.bmp", b"rock").unwrap();
+    writable.set("bedrock.bmp", b"bedrock").unwrap();
+    writable.save_to_file(archive).unwrap();
+
+    let list = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .args(["list", archive])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = list(&["ROCK.BMP", "--ignore-case", "--exact"]);
+    assert!(stdout.contains("\nRock.bmp"));
+    assert!(!stdout.contains("bedrock.bmp"));
+
+    let stdout = list(&["ROCK.BMP"]);
+    assert!(!stdout.contains(".bmp\n"));
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn json_format_test() {
+    let path = temp_dir().join("pfs_cli_json_format_test.s3d");
+    let archive = path.to_str().unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("zone.wld", vec![1u8; 20000]).unwrap();
+    writable.set("rock.bmp", b"rock").unwrap();
+    writable.save_to_file(archive).unwrap();
+    let readable = open_read(archive).unwrap();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+
+    let entries: Vec<ArchiveEntry> =
+        serde_json::from_slice(&run(&["list", archive, "--format", "json"])).unwrap();
+    assert_eq!(entries, readable.entries());
+
+    let entries: Vec<ArchiveEntry> =
+        serde_json::from_slice(&run(&["list", archive, r"\.wld$", "--format", "json"])).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "zone.wld");
+
+    let summary: ArchiveSummary =
+        serde_json::from_slice(&run(&["info", archive, "--format", "json", "--top", "1"])).unwrap();
+    assert_eq!(summary, readable.summary(1));
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn extract_force_test() {
+    let root = temp_dir().join("pfs_cli_extract_force_test");
+    let output = root.join("output");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("zone.wld", b"original").unwrap();
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let extract = |force: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pfs"));
+        command.arg("extract").arg(&archive).arg("-o").arg(&output);
+        if force {
+            command.arg("--force");
+        }
+        let result = command.output().unwrap();
+        assert!(result.status.success());
+        String::from_utf8_lossy(&result.stdout).into_owned()
+    };
+
+    extract(false);
+    let path = output.join("zone.wld");
+    assert_eq!(read(&path).unwrap(), b"original");
+
+    write(&path, b"hand edited").unwrap();
+    assert!(extract(false).contains("already exists"));
+    assert_eq!(read(&path).unwrap(), b"hand edited");
+
+    extract(true);
+    assert_eq!(read(&path).unwrap(), b"original");
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn extract_newer_than_test() {
+    let root = temp_dir().join("pfs_cli_extract_newer_than_test");
+    let output = root.join("output");
+    let archive = root.join("test.s3d");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    let mut writable = WritableArchive::new();
+    writable.set("zone.wld", b"zone").unwrap();
+    writable.set_footer_date(1_000_000);
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let extract = |date: &str| {
+        let result = Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .arg("extract")
+            .arg(&archive)
+            .arg("-o")
+            .arg(&output)
+            .arg("--newer-than")
+            .arg(date)
+            .output()
+            .unwrap();
+        assert!(result.status.success());
+        String::from_utf8_lossy(&result.stdout).into_owned()
+    };
+
+    let path = output.join("zone.wld");
+    assert!(extract("1000000").contains("isn't newer than 1000000"));
+    assert!(!path.exists());
+
+    extract("999999");
+    assert_eq!(read(&path).unwrap(), b"zone");
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn export_obj_test() {
+    let root = temp_dir().join("pfs_cli_export_obj_test");
+    let archive = root.join("test.s3d");
+    let obj = root.join("test.obj");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    // a WLD without any fragments
+    let mut wld = Vec::new();
+    for value in [0x54503D02u32, 0x1000C800, 0, 0, 0, 0, 0] {
+        wld.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let mut writable = WritableArchive::new();
+    writable.set("test.wld", wld).unwrap();
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let export = |zone: &str, force: bool| {
+        Command::new(env!("CARGO_BIN_EXE_pfs"))
+            .arg("export-obj")
+            .arg(&archive)
+            .arg(zone)
+            .arg(&obj)
+            .args(force.then_some("--force"))
+            .output()
+            .unwrap()
+    };
+
+    assert!(export("test", false).status.success());
+    let written = String::from_utf8(read(&obj).unwrap()).unwrap();
+    assert!(written.starts_with("mtllib test.mtl\n# 0 vertices, 0 triangles\n"));
+    assert!(root.join("test.mtl").exists());
+    assert!(export("test", true).status.success());
+
+    assert!(!export("missing", false).status.success());
+
+    remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn export_gltf_test() {
+    let root = temp_dir().join("pfs_cli_export_gltf_test");
+    let archive = root.join("test.s3d");
+    let glb = root.join("test.glb");
+    let _ = remove_dir_all(&root);
+    create_dir_all(&root).unwrap();
+
+    // a WLD without any fragments
+    let mut wld = Vec::new();
+    for value in [0x54503D02u32, 0x1000C800, 0, 0, 0, 0, 0] {
+        wld.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let mut writable = WritableArchive::new();
+    writable.set("test.wld", wld).unwrap();
+    writable.save_to_file(archive.to_str().unwrap()).unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_pfs"))
+        .arg("export-gltf")
+        .arg(&archive)
+        .arg("test")
+        .arg(&glb)
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+
+    let written = read(&glb).unwrap();
+    assert_eq!(&written[..4], b"glTF");
+    assert_eq!(
+        u32::from_le_bytes(written[8..12].try_into().unwrap()) as usize,
+        written.len()
+    );
+
+    remove_dir_all(&root).unwrap();
+}